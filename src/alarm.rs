@@ -0,0 +1,367 @@
+//! This module handles alarms: times of day at which a playlist starts playing
+//! on its own.
+//!
+//! Alarms are checked by whatever UI is running (see `poll`). They don't go
+//! off if Tsong isn't running at the time.
+
+use crate::*;
+
+use log::error;
+use std::{
+    sync::RwLock,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use lazy_static::lazy_static;
+
+/// If we didn't get a chance to check the alarms at the exact minute one was
+/// supposed to go off (because the computer was busy, or asleep, or Tsong was
+/// just starting up), it still goes off if it's no more than this many
+/// minutes late.
+const GRACE_MINUTES: u32 = 5;
+
+const MINUTES_PER_DAY: u32 = 24 * 60;
+
+/// The names of the days of the week, in the order of the bits in
+/// `Alarm::days`.
+// TODO: i18n
+pub const DAY_NAMES: [&str; 7] = ["Sun", "Mon", "Tue", "Wed", "Thu", "Fri",
+                                  "Sat"];
+
+/// A moment in time, as far as alarms are concerned.
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub struct WallTime {
+    /// Seconds since the UNIX epoch.
+    pub unix: i64,
+    /// Day of the week, in local time. 0 = Sunday.
+    pub weekday: u32,
+    /// Minutes since local midnight.
+    pub minute: u32,
+}
+
+/// Somewhere to find out what time it is. The alarm logic only ever asks one
+/// of these, so that it can be driven by a fake clock.
+pub trait Clock {
+    fn now(&self) -> WallTime;
+}
+
+/// A moment in time, broken down into local time.
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub struct LocalTime {
+    /// Day of the week. 0 = Sunday.
+    pub weekday: u32,
+    pub hour: u32,
+    pub minute: u32,
+    pub second: u32,
+}
+
+/// Breaks down a time, in seconds since the UNIX epoch, into local time.
+/// Returns `None` if we can't find out what local time is.
+pub fn local_time(unix: i64) -> Option<LocalTime> {
+    #[cfg(unix)]
+    unsafe {
+        let t = unix as libc::time_t;
+        let mut tm: libc::tm = std::mem::zeroed();
+        if !libc::localtime_r(&t, &mut tm).is_null() {
+            return Some(LocalTime {
+                weekday: tm.tm_wday as u32,
+                hour: tm.tm_hour as u32,
+                minute: tm.tm_min as u32,
+                second: tm.tm_sec as u32,
+            })
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = unix;
+    None
+}
+
+/// The clock on the wall. Uses local time if we can find out what that is,
+/// and UTC otherwise.
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> WallTime {
+        let unix = SystemTime::now().duration_since(UNIX_EPOCH)
+            .map(|x| x.as_secs() as i64).unwrap_or(0);
+        if let Some(local) = local_time(unix) {
+            return WallTime {
+                unix,
+                weekday: local.weekday,
+                minute: local.hour * 60 + local.minute,
+            }
+        }
+        let days = unix.div_euclid(86400);
+        WallTime {
+            unix,
+            // the epoch was a Thursday
+            weekday: (days + 4).rem_euclid(7) as u32,
+            minute: (unix.rem_euclid(86400) / 60) as u32,
+        }
+    }
+}
+
+#[derive(Clone,Debug,PartialEq)]
+pub struct Alarm {
+    pub id: u64,
+    /// When the alarm goes off, in minutes since local midnight.
+    pub minute: u32,
+    /// Which days of the week the alarm goes off, as a bitmask. Bit 0 is
+    /// Sunday. If no bits are set, the alarm goes off once and then disables
+    /// itself.
+    pub days: u8,
+    /// The playlist that starts playing.
+    pub playlist_id: PlaylistID,
+    /// Whether to shuffle the playlist first.
+    pub shuffle: bool,
+    /// What to set the volume to (0–200, like `prefs::get_volume`), if
+    /// anything.
+    pub volume: Option<i32>,
+    /// How many seconds to take bringing the volume up from silence.
+    pub ramp: f64,
+    pub enabled: bool,
+    /// When the alarm last went off, in seconds since the UNIX epoch.
+    pub last_fired: Option<i64>,
+}
+
+impl Alarm {
+    /// Makes a new alarm, which will go off at 7:00 AM (once) and start the
+    /// given playlist.
+    fn new(playlist_id: PlaylistID) -> Alarm {
+        Alarm {
+            id: 0, minute: 7 * 60, days: 0, playlist_id, shuffle: false,
+            volume: None, ramp: 60.0, enabled: true, last_fired: None,
+        }
+    }
+    pub fn is_one_shot(&self) -> bool { self.days == 0 }
+    pub fn goes_off_on(&self, weekday: u32) -> bool {
+        self.days & (1 << (weekday % 7)) != 0
+    }
+    /// Returns true if the alarm should go off at the given time.
+    pub fn is_due(&self, now: &WallTime) -> bool {
+        if !self.enabled { return false }
+        // how many minutes ago the alarm time was, looking back at most a day
+        let minute = self.minute % MINUTES_PER_DAY;
+        let late = (now.minute + MINUTES_PER_DAY - minute) % MINUTES_PER_DAY;
+        if late >= GRACE_MINUTES { return false }
+        // if the alarm time was just before midnight, it was yesterday's
+        let weekday = if late > now.minute { (now.weekday + 6) % 7 }
+        else { now.weekday };
+        if !self.is_one_shot() && !self.goes_off_on(weekday) { return false }
+        match self.last_fired {
+            Some(then) => now.unix - then >= (GRACE_MINUTES as i64 + 1) * 60,
+            None => true,
+        }
+    }
+    /// Records that the alarm went off at the given time. A one-shot alarm
+    /// disables itself.
+    fn fired(&mut self, now: &WallTime) {
+        self.last_fired = Some(now.unix);
+        if self.is_one_shot() { self.enabled = false }
+    }
+    /// Returns the time of day at which the alarm goes off, as `HH:MM`.
+    pub fn get_time_string(&self) -> String {
+        format!("{:02}:{:02}", self.minute / 60, self.minute % 60)
+    }
+    /// Returns a short description of which days the alarm goes off.
+    // TODO: i18n
+    pub fn get_days_string(&self) -> String {
+        match self.days & 0x7F {
+            0 => "Once".to_owned(),
+            0x7F => "Every day".to_owned(),
+            0x3E => "Weekdays".to_owned(),
+            0x41 => "Weekends".to_owned(),
+            days => DAY_NAMES.iter().enumerate()
+                .filter(|(n, _)| days & (1 << n) != 0)
+                .map(|(_, name)| *name)
+                .collect::<Vec<_>>().join(" "),
+        }
+    }
+}
+
+lazy_static! {
+    static ref ALARMS: RwLock<Vec<Alarm>> = RwLock::new(Vec::new());
+}
+
+/// Called by the database when loading alarms.
+pub fn add_alarm_from_db(alarm: Alarm) {
+    ALARMS.write().unwrap().push(alarm);
+}
+
+/// Returns every alarm, in the order they were created.
+pub fn get_alarms() -> Vec<Alarm> {
+    ALARMS.read().unwrap().clone()
+}
+
+/// Makes a new alarm that will start the given playlist. The caller can then
+/// change it and pass it to `update_alarm`.
+pub fn create_alarm(playlist_id: PlaylistID) -> anyhow::Result<Alarm> {
+    let mut alarm = Alarm::new(playlist_id);
+    alarm.id = db::create_alarm(&alarm)?;
+    ALARMS.write().unwrap().push(alarm.clone());
+    Ok(alarm)
+}
+
+/// Replaces the alarm with the same id.
+pub fn update_alarm(alarm: &Alarm) {
+    let mut alarms = ALARMS.write().unwrap();
+    if let Some(old) = alarms.iter_mut().find(|x| x.id == alarm.id) {
+        let mut alarm = alarm.clone();
+        // changing when the alarm goes off means it can go off again
+        if alarm.minute != old.minute || alarm.days != old.days {
+            alarm.last_fired = None;
+        }
+        db::update_alarm(&alarm);
+        *old = alarm;
+    }
+}
+
+pub fn delete_alarm(id: u64) {
+    ALARMS.write().unwrap().retain(|x| x.id != id);
+    db::delete_alarm(id);
+}
+
+/// Checks whether any alarms are due, according to the given clock. Any that
+/// are are marked as having gone off, and returned. The caller is responsible
+/// for actually starting playback. (If more than one is due, the caller
+/// should only heed the last one.)
+pub fn poll<C: Clock>(clock: &C) -> Vec<Alarm> {
+    let now = clock.now();
+    let mut ret = Vec::new();
+    let mut alarms = ALARMS.write().unwrap();
+    for alarm in alarms.iter_mut().filter(|x| x.is_due(&now)) {
+        alarm.fired(&now);
+        db::update_alarm(alarm);
+        ret.push(alarm.clone());
+    }
+    ret
+}
+
+/// Reports that an alarm couldn't go off.
+pub fn report_failure(alarm: &Alarm, why: &str) {
+    let wat = format!("The {} alarm didn't go off: {}",
+                      alarm.get_time_string(), why);
+    error!("{}", wat);
+    errors::from("Alarms", wat);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    /// A clock that says whatever time we tell it to, in UTC.
+    struct FakeClock {
+        unix: Cell<i64>,
+    }
+
+    /// The first Sunday after the epoch, at midnight.
+    const SUNDAY: i64 = 3 * 86400;
+
+    impl Clock for FakeClock {
+        fn now(&self) -> WallTime {
+            let unix = self.unix.get();
+            let days = unix.div_euclid(86400);
+            WallTime {
+                unix,
+                weekday: (days + 4).rem_euclid(7) as u32,
+                minute: (unix.rem_euclid(86400) / 60) as u32,
+            }
+        }
+    }
+
+    fn alarm_at(hour: u32, minute: u32, days: u8) -> Alarm {
+        let mut ret = Alarm::new(PlaylistID::from_inner(1));
+        ret.minute = hour * 60 + minute;
+        ret.days = days;
+        ret
+    }
+
+    /// Checks the alarm every `step` seconds from `start` until `end`, the
+    /// way `poll` would, and returns the times at which it went off.
+    fn run(alarm: &mut Alarm, start: i64, end: i64, step: i64) -> Vec<i64> {
+        let clock = FakeClock { unix: Cell::new(start) };
+        let mut ret = Vec::new();
+        while clock.unix.get() < end {
+            let now = clock.now();
+            if alarm.is_due(&now) {
+                alarm.fired(&now);
+                ret.push(now.unix);
+            }
+            clock.unix.set(now.unix + step);
+        }
+        ret
+    }
+
+    #[test]
+    fn fake_clock() {
+        let clock = FakeClock { unix: Cell::new(SUNDAY + 86400 + 7 * 3600
+                                                + 30 * 60 + 59) };
+        assert_eq!(clock.now().weekday, 1);
+        assert_eq!(clock.now().minute, 7 * 60 + 30);
+    }
+
+    #[test]
+    fn one_shot() {
+        let mut alarm = alarm_at(7, 0, 0);
+        let fired = run(&mut alarm, SUNDAY, SUNDAY + 3 * 86400, 10);
+        assert_eq!(fired, vec![SUNDAY + 7 * 3600]);
+        assert!(!alarm.enabled);
+        assert_eq!(alarm.last_fired, Some(SUNDAY + 7 * 3600));
+    }
+
+    #[test]
+    fn weekdays() {
+        let mut alarm = alarm_at(7, 0, 0x3E);
+        let fired = run(&mut alarm, SUNDAY, SUNDAY + 7 * 86400, 60);
+        let expected: Vec<i64> = (1 ..= 5)
+            .map(|day| SUNDAY + day * 86400 + 7 * 3600).collect();
+        assert_eq!(fired, expected);
+        assert!(alarm.enabled);
+    }
+
+    #[test]
+    fn late() {
+        // Checked a few minutes late, it still goes off...
+        let mut alarm = alarm_at(7, 0, 0);
+        let fired = run(&mut alarm, SUNDAY + 7 * 3600 + 4 * 60,
+                        SUNDAY + 86400, 60);
+        assert_eq!(fired, vec![SUNDAY + 7 * 3600 + 4 * 60]);
+        // ...but not if it's too late.
+        let mut alarm = alarm_at(7, 0, 0);
+        let fired = run(&mut alarm, SUNDAY + 7 * 3600
+                        + GRACE_MINUTES as i64 * 60, SUNDAY + 86400, 60);
+        assert!(fired.is_empty());
+        assert!(alarm.enabled);
+    }
+
+    #[test]
+    fn before_midnight() {
+        // A Saturday 23:58 alarm, checked just after midnight on Sunday, is
+        // yesterday's alarm, and goes off...
+        let mut alarm = alarm_at(23, 58, 1 << 6);
+        let fired = run(&mut alarm, SUNDAY + 60, SUNDAY + 3600, 60);
+        assert_eq!(fired, vec![SUNDAY + 60]);
+        // ...but a Sunday one doesn't.
+        let mut alarm = alarm_at(23, 58, 1 << 0);
+        let fired = run(&mut alarm, SUNDAY + 60, SUNDAY + 3600, 60);
+        assert!(fired.is_empty());
+    }
+
+    #[test]
+    fn disabled() {
+        let mut alarm = alarm_at(7, 0, 0x7F);
+        alarm.enabled = false;
+        assert!(run(&mut alarm, SUNDAY, SUNDAY + 7 * 86400, 60).is_empty());
+    }
+
+    #[test]
+    fn strings() {
+        assert_eq!(alarm_at(7, 5, 0).get_time_string(), "07:05");
+        assert_eq!(alarm_at(7, 0, 0).get_days_string(), "Once");
+        assert_eq!(alarm_at(7, 0, 0x7F).get_days_string(), "Every day");
+        assert_eq!(alarm_at(7, 0, 0x3E).get_days_string(), "Weekdays");
+        assert_eq!(alarm_at(7, 0, 0x41).get_days_string(), "Weekends");
+        assert_eq!(alarm_at(7, 0, 0x0A).get_days_string(), "Mon Wed");
+    }
+}
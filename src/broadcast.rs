@@ -0,0 +1,565 @@
+//! This module broadcasts whatever we're playing over HTTP, so that other
+//! computers (in other rooms, say) can listen in. It's a second sink, next to
+//! the output backend: the playback callback hands us a copy of each buffer
+//! it fills, after the volume, limiter, and fades have had their way with it,
+//! and we encode that and send it to everyone who's listening. Listeners that
+//! ask for ICY metadata get the title of the current song along with it.
+//!
+//! The callback only ever touches lock-free queues, and copies into buffers
+//! that were set aside ahead of time, so it never waits or allocates.
+//! Encoding happens on a thread of its own, and each listener has a thread of
+//! their own too, so a slow listener can't hold up anyone else (least of all
+//! the sound card).
+
+use crate::*;
+use crate::ffmpeg::Encoder;
+use prefs::BroadcastFormat;
+
+use anyhow::anyhow;
+use concurrent_queue::ConcurrentQueue;
+use lazy_static::lazy_static;
+use libsoxr::Soxr;
+use log::{error, info, warn};
+use std::{
+    io::{self, Read, Write},
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        mpsc::{SyncSender, TrySendError, sync_channel},
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+/// The sample rate we ask the encoder for. Opus insists on it, and MP3 is
+/// happy with it.
+const BROADCAST_SAMPLE_RATE: i32 = 48000;
+/// The broadcast is always stereo. Mono audio goes to both channels, and
+/// anything with more channels than that just gets its front left and front
+/// right channels sent.
+const BROADCAST_CHANNELS: i32 = 2;
+/// How many buffers from the playback callback can pile up before we start
+/// throwing them away. (If it comes to that, encoding can't keep up.)
+const MAX_QUEUED_BUFFERS: usize = 64;
+/// How many samples each of those buffers has room for to begin with. If the
+/// playback callback's buffers turn out to be bigger, ours grow to match.
+const INITIAL_BUFFER_CAPACITY: usize = 8192;
+/// How many chunks of encoded audio can pile up for one listener before we
+/// decide they can't keep up, and hang up on them.
+const MAX_LISTENER_BACKLOG: usize = 256;
+/// How many bytes of audio listeners who ask for ICY metadata get between
+/// metadata blocks.
+const ICY_METAINT: usize = 16000;
+/// How long a listener has to send us their request, and how long we'll wait
+/// for them to take some audio before giving up on them.
+const LISTENER_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long the broadcast threads sleep when they have nothing to do.
+const IDLE_SLEEP: Duration = Duration::from_millis(10);
+/// How long to wait for new listeners before checking whether we should stop.
+const ACCEPT_POLL: Duration = Duration::from_millis(100);
+/// When playback is stopped or paused, we send silence in chunks this long,
+/// so that listeners don't decide we've gone away.
+const SILENCE_INTERVAL: Duration = Duration::from_millis(100);
+/// The longest request we'll put up with from a listener, in bytes.
+const MAX_REQUEST_LENGTH: usize = 8192;
+
+/// A copy of one buffer from the playback callback.
+struct Chunk {
+    sample_rate: f64,
+    channel_count: i32,
+    data: Vec<f32>,
+}
+
+/// State shared between all the threads of a running broadcast.
+struct Shared {
+    /// Set when it's time for the broadcast to stop.
+    stop: AtomicBool,
+    /// One sender for each listener, who gets every chunk we encode.
+    listeners: Mutex<Vec<SyncSender<Arc<Vec<u8>>>>>,
+    /// How many listeners are connected, including ones we're still waiting
+    /// on a request from.
+    connections: AtomicUsize,
+    /// How many listeners we take at once. Anyone else is turned away.
+    max_connections: usize,
+    /// Whatever the encoder's muxer wrote before any audio. Each listener
+    /// gets this before anything else.
+    header: Vec<u8>,
+    /// What to put in the `Content-Type` header.
+    content_type: &'static str,
+}
+
+/// A running broadcast.
+struct Server {
+    shared: Arc<Shared>,
+    threads: Vec<JoinHandle<()>>,
+}
+
+lazy_static! {
+    static ref QUEUE: ConcurrentQueue<Chunk>
+        = ConcurrentQueue::bounded(MAX_QUEUED_BUFFERS);
+    /// Empty buffers for `feed` to copy into. The encoder thread puts them
+    /// back when it's done with them.
+    static ref FREE_BUFFERS: ConcurrentQueue<Vec<f32>>
+        = ConcurrentQueue::bounded(MAX_QUEUED_BUFFERS);
+    static ref STREAM_TITLE: Mutex<String> = Mutex::new(String::new());
+    static ref SERVER: Mutex<Option<Server>> = Mutex::new(None);
+}
+
+/// True while a broadcast is running, so that the playback callback knows
+/// whether to bother.
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+
+/// How many samples each buffer on `FREE_BUFFERS` should have room for: the
+/// most the playback callback has ever wanted to copy.
+static BUFFER_CAPACITY: AtomicUsize = AtomicUsize::new(INITIAL_BUFFER_CAPACITY);
+
+/// Starts or stops the broadcast according to the preferences, restarting it
+/// if it's already running. Call at startup, and whenever the broadcast
+/// preferences change.
+pub fn restart() {
+    let mut server = SERVER.lock().unwrap();
+    if let Some(old) = server.take() {
+        old.stop();
+    }
+    errors::reset_from("Broadcast");
+    if !prefs::get_broadcast() { return }
+    let port = prefs::get_broadcast_port();
+    match Server::start(port, prefs::get_broadcast_format(),
+                        prefs::get_broadcast_all_interfaces(),
+                        prefs::get_broadcast_max_listeners()) {
+        Ok(x) => *server = Some(x),
+        Err(x) => {
+            let wat = format!("Couldn't start broadcasting on port {}: {}",
+                              port, x);
+            error!("{}", wat);
+            errors::from("Broadcast", wat);
+        },
+    }
+}
+
+/// Called from the playback callback with each buffer it fills. Hands a copy
+/// to the encoder thread, if we're broadcasting. Never blocks or allocates.
+pub fn feed(buffer: &[f32], sample_rate: f64, channel_count: i32) {
+    if !ACTIVE.load(Ordering::Relaxed) { return }
+    let mut data = match FREE_BUFFERS.pop() {
+        Ok(x) => x,
+        // the encoder isn't keeping up; this bit just won't get broadcast
+        Err(_) => return,
+    };
+    if data.capacity() >= buffer.len() {
+        data.extend_from_slice(buffer);
+    }
+    else {
+        // Copying into this buffer would mean growing it. Send it back empty
+        // instead, so that the encoder thread can grow it (and all the rest)
+        // for next time. This bit won't get broadcast.
+        BUFFER_CAPACITY.fetch_max(buffer.len(), Ordering::Relaxed);
+    }
+    if let Err(x) = QUEUE.push(Chunk { sample_rate, channel_count, data }) {
+        let _ = FREE_BUFFERS.push(x.into_inner().data);
+    }
+}
+
+/// Puts a buffer from `QUEUE` back on `FREE_BUFFERS`, making sure it has
+/// enough room for whatever the playback callback will want to copy into it.
+fn recycle_buffer(mut data: Vec<f32>) {
+    data.clear();
+    data.reserve(BUFFER_CAPACITY.load(Ordering::Relaxed));
+    let _ = FREE_BUFFERS.push(data);
+}
+
+/// Tells listeners about the song that's now being heard.
+pub fn set_metadata(title: Option<&str>, artist: Option<&str>) {
+    let title = match (title, artist) {
+        (Some(title), Some(artist)) => format!("{} - {}", artist, title),
+        (Some(title), None) => title.to_owned(),
+        _ => String::new(),
+    };
+    *STREAM_TITLE.lock().unwrap() = title;
+}
+
+/// Returns the encoders to try, the container to mux into (if any), the
+/// `Content-Type`, and the bit rate for the given format.
+fn get_format_details(format: BroadcastFormat)
+-> (&'static [&'static str], Option<&'static str>, &'static str, i64) {
+    match format {
+        BroadcastFormat::Opus =>
+            (&["libopus", "opus"][..], Some("ogg"), "audio/ogg", 128000),
+        BroadcastFormat::Mp3 =>
+            (&["libmp3lame"][..], None, "audio/mpeg", 192000),
+    }
+}
+
+impl Server {
+    fn start(port: u16, format: BroadcastFormat, all_interfaces: bool,
+             max_listeners: u32) -> anyhow::Result<Server> {
+        let (encoders, container, content_type, bit_rate)
+            = get_format_details(format);
+        let encoder = Encoder::new(encoders, container, BROADCAST_SAMPLE_RATE,
+                                   BROADCAST_CHANNELS, bit_rate)?;
+        let listener = if all_interfaces {
+            // (on most systems, the IPv6 one accepts IPv4 connections too,
+            // but there might not be any IPv6)
+            TcpListener::bind(SocketAddr::from((Ipv6Addr::UNSPECIFIED, port)))
+                .or_else(|_| TcpListener::bind((Ipv4Addr::UNSPECIFIED, port)))?
+        }
+        else {
+            TcpListener::bind((Ipv4Addr::LOCALHOST, port))?
+        };
+        listener.set_nonblocking(true)?;
+        info!("Broadcasting on {}.", listener.local_addr()?);
+        let shared = Arc::new(Shared {
+            stop: AtomicBool::new(false),
+            listeners: Mutex::new(Vec::new()),
+            connections: AtomicUsize::new(0),
+            max_connections: max_listeners as usize,
+            header: encoder.get_header().to_vec(),
+            content_type,
+        });
+        let mut ret = Server { shared: shared.clone(), threads: Vec::new() };
+        // (anything left over from the last broadcast is stale)
+        while let Ok(chunk) = QUEUE.pop() {
+            recycle_buffer(chunk.data);
+        }
+        let capacity = BUFFER_CAPACITY.load(Ordering::Relaxed);
+        while !FREE_BUFFERS.is_full() {
+            let _ = FREE_BUFFERS.push(Vec::with_capacity(capacity));
+        }
+        ACTIVE.store(true, Ordering::Relaxed);
+        let encoder_shared = shared.clone();
+        match thread::Builder::new().name("broadcast encoder thread".to_owned())
+        .spawn(move || encoder_thread(encoder_shared, encoder)) {
+            Ok(x) => ret.threads.push(x),
+            Err(x) => {
+                ret.stop();
+                return Err(x.into())
+            },
+        }
+        match thread::Builder::new().name("broadcast accept thread".to_owned())
+        .spawn(move || accept_thread(shared, listener)) {
+            Ok(x) => ret.threads.push(x),
+            Err(x) => {
+                ret.stop();
+                return Err(x.into())
+            },
+        }
+        Ok(ret)
+    }
+    fn stop(self) {
+        ACTIVE.store(false, Ordering::Relaxed);
+        self.shared.stop.store(true, Ordering::Relaxed);
+        for thread in self.threads.into_iter() {
+            let _ = thread.join();
+        }
+        // hanging up on the listeners' channels makes their threads end
+        self.shared.listeners.lock().unwrap().clear();
+        // (no sense hanging on to the buffers until the next broadcast)
+        while QUEUE.pop().is_ok() {}
+        while FREE_BUFFERS.pop().is_ok() {}
+    }
+}
+
+/// Takes audio from the playback callback, converts it to what the encoder
+/// wants, encodes it, and hands the result to every listener.
+fn encoder_thread(shared: Arc<Shared>, mut encoder: Encoder) {
+    let out_rate = encoder.get_sample_rate() as f64;
+    // (input rate, resampler)
+    let mut resampler: Option<(f64, Soxr)> = None;
+    let mut stereo = Vec::new();
+    let mut resampled = Vec::new();
+    let mut out = Vec::new();
+    let mut last_audio = Instant::now();
+    while !shared.stop.load(Ordering::Relaxed) {
+        let (sample_rate, data) = match QUEUE.pop() {
+            Ok(chunk) if chunk.data.is_empty() => {
+                // (see `feed`)
+                recycle_buffer(chunk.data);
+                continue
+            },
+            Ok(chunk) => {
+                make_stereo(&chunk.data, chunk.channel_count, &mut stereo);
+                recycle_buffer(chunk.data);
+                last_audio = Instant::now();
+                (chunk.sample_rate, &stereo[..])
+            },
+            Err(_) => {
+                let now = Instant::now();
+                let elapsed = now.duration_since(last_audio);
+                if elapsed < SILENCE_INTERVAL
+                || playback::get_playback_status() == PlaybackStatus::Playing {
+                    thread::sleep(IDLE_SLEEP);
+                    continue
+                }
+                last_audio = now;
+                let frames = (elapsed.as_secs_f64().min(1.0) * out_rate)
+                    as usize;
+                stereo.clear();
+                stereo.resize(frames * BROADCAST_CHANNELS as usize, 0.0);
+                (out_rate, &stereo[..])
+            },
+        };
+        let data = if sample_rate == out_rate { data }
+        else {
+            match resample(&mut resampler, sample_rate, out_rate, data,
+                           &mut resampled) {
+                Ok(_) => &resampled[..],
+                Err(x) => {
+                    warn!("Couldn't resample the broadcast: {:?}", x);
+                    resampler = None;
+                    continue
+                },
+            }
+        };
+        if let Err(x) = encoder.encode(data, &mut out) {
+            let wat = format!("Broadcast encoding failed: {}", x);
+            error!("{}", wat);
+            errors::from("Broadcast", wat);
+            shared.stop.store(true, Ordering::Relaxed);
+            break
+        }
+        if out.is_empty() { continue }
+        let chunk = Arc::new(std::mem::take(&mut out));
+        shared.listeners.lock().unwrap().retain(|listener| {
+            match listener.try_send(chunk.clone()) {
+                Ok(_) => true,
+                Err(TrySendError::Full(_)) => {
+                    info!("Hanging up on a broadcast listener who isn't \
+                           keeping up.");
+                    false
+                },
+                Err(TrySendError::Disconnected(_)) => false,
+            }
+        });
+    }
+    ACTIVE.store(false, Ordering::Relaxed);
+    shared.listeners.lock().unwrap().clear();
+}
+
+/// Copies interleaved audio with the given number of channels into `out`,
+/// as stereo.
+fn make_stereo(data: &[f32], channel_count: i32, out: &mut Vec<f32>) {
+    out.clear();
+    match channel_count {
+        x if x <= 0 => (),
+        1 => {
+            for &el in data.iter() {
+                out.push(el);
+                out.push(el);
+            }
+        },
+        2 => out.extend_from_slice(data),
+        x => {
+            for frame in data.chunks_exact(x as usize) {
+                out.extend_from_slice(&frame[..2]);
+            }
+        },
+    }
+}
+
+/// Resamples some interleaved stereo audio into `out`, (re)creating the
+/// resampler if the input rate has changed.
+fn resample(resampler: &mut Option<(f64, Soxr)>, in_rate: f64, out_rate: f64,
+            data: &[f32], out: &mut Vec<f32>) -> anyhow::Result<()> {
+    if resampler.as_ref().map(|x| x.0) != Some(in_rate) {
+        *resampler = Some((in_rate,
+                           Soxr::create(in_rate, out_rate,
+                                        BROADCAST_CHANNELS as u32,
+                                        None, None, None)?));
+    }
+    let soxr = &resampler.as_ref().unwrap().1;
+    let channels = BROADCAST_CHANNELS as usize;
+    out.clear();
+    out.resize((data.len() as f64 * out_rate / in_rate).ceil() as usize + 200,
+               0.0);
+    let mut rem = data;
+    let mut out_pos = 0;
+    while rem.len() > 0 {
+        let (in_frames, out_frames) = soxr.process(Some(rem),
+                                                   &mut out[out_pos..])?;
+        rem = &rem[in_frames * channels..];
+        out_pos += out_frames * channels;
+        if rem.len() > 0 {
+            out.resize(out.len() * 2, 0.0);
+        }
+    }
+    out.truncate(out_pos);
+    Ok(())
+}
+
+/// Waits for listeners to connect, and gives each one a thread.
+fn accept_thread(shared: Arc<Shared>, listener: TcpListener) {
+    while !shared.stop.load(Ordering::Relaxed) {
+        let (socket, addr) = match listener.accept() {
+            Ok(x) => x,
+            Err(x) if x.kind() == io::ErrorKind::WouldBlock => {
+                thread::sleep(ACCEPT_POLL);
+                continue
+            },
+            Err(x) => {
+                warn!("Error accepting a broadcast listener: {}", x);
+                thread::sleep(ACCEPT_POLL);
+                continue
+            },
+        };
+        if shared.connections.load(Ordering::Relaxed)
+        >= shared.max_connections {
+            info!("Turned {} away from the broadcast, which already has as \
+                   many listeners as it takes.", addr);
+            turn_away(socket);
+            continue
+        }
+        shared.connections.fetch_add(1, Ordering::Relaxed);
+        let listener_shared = shared.clone();
+        let spawned = thread::Builder::new()
+            .name("broadcast listener thread".to_owned())
+            .spawn(move || {
+                info!("{} connected to the broadcast.", addr);
+                match serve_listener(listener_shared.clone(), socket) {
+                    Ok(_) => info!("{} left the broadcast.", addr),
+                    Err(x) => info!("{} left the broadcast: {}", addr, x),
+                }
+                listener_shared.connections.fetch_sub(1, Ordering::Relaxed);
+            });
+        if let Err(x) = spawned {
+            warn!("Couldn't spawn a broadcast listener thread: {}", x);
+            shared.connections.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Tells a would-be listener that we can't take them right now. (Without
+/// waiting for their request, or for them to take the answer. It's short
+/// enough that it will almost certainly fit in the socket's buffer.)
+fn turn_away(mut socket: TcpStream) {
+    let _ = socket.set_nonblocking(true);
+    let _ = socket.write_all(b"HTTP/1.0 503 Service Unavailable\r\n\
+                                Retry-After: 60\r\n\r\n");
+}
+
+/// Reads a listener's request, and then sends them audio until one of us
+/// hangs up.
+fn serve_listener(shared: Arc<Shared>, mut socket: TcpStream)
+-> anyhow::Result<()> {
+    // (on some systems, the accepted socket inherits non-blocking mode)
+    socket.set_nonblocking(false)?;
+    socket.set_read_timeout(Some(LISTENER_TIMEOUT))?;
+    socket.set_write_timeout(Some(LISTENER_TIMEOUT))?;
+    let request = read_request(&mut socket)?;
+    let mut lines = request.lines();
+    let method = lines.next().and_then(|x| x.split_whitespace().next());
+    if method != Some("GET") && method != Some("HEAD") {
+        socket.write_all(b"HTTP/1.0 405 Method Not Allowed\r\n\
+                           Allow: GET, HEAD\r\n\r\n")?;
+        return Ok(())
+    }
+    let wants_metadata = lines.any(|line| {
+        let mut split = line.splitn(2, ':');
+        let key = split.next().unwrap_or("").trim();
+        let value = split.next().unwrap_or("").trim();
+        key.eq_ignore_ascii_case("icy-metadata") && value == "1"
+    });
+    let mut headers = format!("HTTP/1.0 200 OK\r\n\
+                               Content-Type: {}\r\n\
+                               Cache-Control: no-cache, no-store\r\n\
+                               icy-name: Tsong\r\n",
+                              shared.content_type);
+    if wants_metadata {
+        headers.push_str(&format!("icy-metaint: {}\r\n", ICY_METAINT));
+    }
+    headers.push_str("\r\n");
+    socket.write_all(headers.as_bytes())?;
+    if method == Some("HEAD") { return Ok(()) }
+    let (tx, rx) = sync_channel(MAX_LISTENER_BACKLOG);
+    {
+        let mut listeners = shared.listeners.lock().unwrap();
+        if shared.stop.load(Ordering::Relaxed) { return Ok(()) }
+        listeners.push(tx);
+    }
+    let mut writer = IcyWriter {
+        socket,
+        until_metadata: if wants_metadata { Some(ICY_METAINT) } else { None },
+        last_title: None,
+    };
+    writer.write(&shared.header)?;
+    drop(shared);
+    while let Ok(chunk) = rx.recv() {
+        writer.write(&chunk)?;
+    }
+    Ok(())
+}
+
+/// Reads an HTTP request up to the end of its headers.
+fn read_request(socket: &mut TcpStream) -> anyhow::Result<String> {
+    let mut buf = Vec::new();
+    let mut byte = [0u8; 1];
+    while !buf.ends_with(b"\r\n\r\n") && !buf.ends_with(b"\n\n") {
+        if buf.len() >= MAX_REQUEST_LENGTH {
+            return Err(anyhow!("request too long"))
+        }
+        if socket.read(&mut byte)? == 0 {
+            return Err(anyhow!("hung up before finishing their request"))
+        }
+        buf.push(byte[0]);
+    }
+    Ok(String::from_utf8_lossy(&buf).into_owned())
+}
+
+/// Writes audio to a listener, with ICY metadata blocks mixed in if they
+/// asked for them.
+struct IcyWriter {
+    socket: TcpStream,
+    /// If the listener wants metadata, how many more bytes of audio to send
+    /// before the next block.
+    until_metadata: Option<usize>,
+    /// The title we last sent them.
+    last_title: Option<String>,
+}
+
+impl IcyWriter {
+    fn write(&mut self, mut data: &[u8]) -> io::Result<()> {
+        let until_metadata = match self.until_metadata.as_mut() {
+            Some(x) => x,
+            None => return self.socket.write_all(data),
+        };
+        while data.len() > 0 {
+            let amount = data.len().min(*until_metadata);
+            self.socket.write_all(&data[..amount])?;
+            data = &data[amount..];
+            *until_metadata -= amount;
+            if *until_metadata == 0 {
+                *until_metadata = ICY_METAINT;
+                let title = STREAM_TITLE.lock().unwrap().clone();
+                if self.last_title.as_ref() == Some(&title) {
+                    // an empty block means "nothing new"
+                    self.socket.write_all(&[0])?;
+                }
+                else {
+                    self.socket.write_all(&make_icy_block(&title))?;
+                    self.last_title = Some(title);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Makes an ICY metadata block announcing the given title: one byte giving
+/// the length in 16-byte units, followed by that many units of text, padded
+/// with zeroes.
+fn make_icy_block(title: &str) -> Vec<u8> {
+    const MAX_LENGTH: usize = 255 * 16;
+    let mut text = format!("StreamTitle='{}';", title);
+    while text.len() > MAX_LENGTH {
+        // (chop the title, not the closing quote)
+        let mut end = text.len() - 3;
+        while !text.is_char_boundary(end) { end -= 1 }
+        text.remove(end);
+    }
+    let units = (text.len() + 15) / 16;
+    let mut ret = Vec::with_capacity(1 + units * 16);
+    ret.push(units as u8);
+    ret.extend_from_slice(text.as_bytes());
+    ret.resize(1 + units * 16, 0);
+    ret
+}
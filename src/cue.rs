@@ -0,0 +1,340 @@
+//! This module reads *cue sheets*, the little text files that sometimes sit
+//! next to a whole-album rip and say where each track begins.
+//!
+//! We only care about enough of the format to split a file into songs and to
+//! get some metadata for those songs. Anything we don't understand is ignored.
+
+use crate::*;
+use physical::Track;
+
+use log::warn;
+use std::{
+    collections::BTreeMap,
+    ffi::OsStr,
+    fs,
+    path::Path,
+};
+
+/// Cue sheet timestamps are in minutes, seconds, and CD frames. There are 75
+/// CD frames in a second.
+const FRAMES_PER_SECOND: f64 = 75.0;
+
+#[derive(Debug,Default)]
+struct CueTrack {
+    number: u32,
+    /// Position of `INDEX 01`, in seconds from the beginning of the file.
+    start: Option<f64>,
+    metadata: BTreeMap<String, String>,
+}
+
+#[derive(Debug,Default)]
+struct CueFile {
+    name: String,
+    tracks: Vec<CueTrack>,
+}
+
+#[derive(Debug,Default)]
+struct CueSheet {
+    /// Metadata that applies to the whole disc.
+    metadata: BTreeMap<String, String>,
+    files: Vec<CueFile>,
+}
+
+/// Cue sheets in the wild are in all sorts of encodings. Most are UTF-8 (or
+/// ASCII), and most of the rest are Latin-1 or close enough to it.
+fn decode_text(bytes: &[u8]) -> String {
+    let bytes = if bytes.starts_with(b"\xEF\xBB\xBF") { &bytes[3..] }
+    else { bytes };
+    match std::str::from_utf8(bytes) {
+        Ok(x) => x.to_owned(),
+        Err(_) => bytes.iter().map(|&x| x as char).collect(),
+    }
+}
+
+/// Splits a line into words. A word in double quotes may contain spaces.
+fn split_words(line: &str) -> Vec<String> {
+    let mut ret = Vec::new();
+    let mut chars = line.trim().chars().peekable();
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        }
+        else if c == '"' {
+            chars.next();
+            let mut word = String::new();
+            for c in chars.by_ref() {
+                if c == '"' { break }
+                word.push(c);
+            }
+            ret.push(word);
+        }
+        else {
+            let mut word = String::new();
+            while let Some(&c) = chars.peek() {
+                if c.is_whitespace() { break }
+                word.push(c);
+                chars.next();
+            }
+            ret.push(word);
+        }
+    }
+    ret
+}
+
+/// Parses an `mm:ss:ff` timestamp into seconds.
+fn parse_timestamp(s: &str) -> Option<f64> {
+    let mut parts = s.split(':');
+    let minutes: u32 = parts.next()?.parse().ok()?;
+    let seconds: u32 = parts.next()?.parse().ok()?;
+    let frames: u32 = parts.next()?.parse().ok()?;
+    if parts.next().is_some() { return None }
+    Some(minutes as f64 * 60.0 + seconds as f64
+         + frames as f64 / FRAMES_PER_SECOND)
+}
+
+fn parse(text: &str) -> CueSheet {
+    let mut sheet = CueSheet::default();
+    for line in text.lines() {
+        let words = split_words(line);
+        if words.is_empty() { continue }
+        let command = words[0].to_ascii_uppercase();
+        let (key, value) = match (command.as_str(), words.len()) {
+            ("FILE", x) if x >= 2 => {
+                sheet.files.push(CueFile {
+                    name: words[1].clone(),
+                    tracks: Vec::new(),
+                });
+                continue
+            },
+            ("TRACK", x) if x >= 2 => {
+                // (TRACK before FILE is ignored)
+                if let Some(file) = sheet.files.last_mut() {
+                    file.tracks.push(CueTrack {
+                        number: words[1].parse().unwrap_or(0),
+                        ..Default::default()
+                    });
+                }
+                continue
+            },
+            ("INDEX", 3) => {
+                if words[1].parse::<u32>().ok() != Some(1) { continue }
+                if let Some(track) = sheet.files.last_mut()
+                    .and_then(|x| x.tracks.last_mut()) {
+                        track.start = parse_timestamp(&words[2]);
+                    }
+                continue
+            },
+            ("TITLE", 2) => ("title".to_owned(), words[1].clone()),
+            ("PERFORMER", 2) => ("artist".to_owned(), words[1].clone()),
+            ("SONGWRITER", 2) => ("composer".to_owned(), words[1].clone()),
+            ("ISRC", 2) => ("isrc".to_owned(), words[1].clone()),
+            ("CATALOG", 2) => ("catalog".to_owned(), words[1].clone()),
+            ("REM", x) if x >= 3 =>
+                (words[1].to_ascii_lowercase(), words[2..].join(" ")),
+            _ => continue,
+        };
+        // Metadata goes on the current track if there is one, or on the disc
+        // otherwise.
+        match sheet.files.last_mut().and_then(|x| x.tracks.last_mut()) {
+            Some(track) => track.metadata.insert(key, value),
+            None => sheet.metadata.insert(key, value),
+        };
+    }
+    sheet
+}
+
+impl CueSheet {
+    /// Turns the tracks of the given file into `Track`s, with the disc's
+    /// metadata merged in.
+    fn make_tracks(&self, file: &CueFile) -> Vec<Track> {
+        let mut ret: Vec<Track> = Vec::with_capacity(file.tracks.len());
+        let track_count = file.tracks.len();
+        for track in file.tracks.iter() {
+            let start = match track.start {
+                Some(x) => x,
+                None => {
+                    warn!("Cue sheet track {} has no INDEX 01, skipping it",
+                          track.number);
+                    continue
+                },
+            };
+            if let Some(prev) = ret.last_mut() {
+                prev.end = Some(start);
+            }
+            let mut metadata = BTreeMap::new();
+            for (k, v) in self.metadata.iter() {
+                match k.as_str() {
+                    // The disc's title and performer are the album's title and
+                    // artist.
+                    "title" => metadata.insert("album".to_owned(), v.clone()),
+                    "artist" => {
+                        metadata.insert("album_artist".to_owned(), v.clone());
+                        metadata.insert("artist".to_owned(), v.clone())
+                    },
+                    "discnumber" => {
+                        let disc = match self.metadata.get("totaldiscs") {
+                            Some(total) => format!("{}/{}", v, total),
+                            None => v.clone(),
+                        };
+                        metadata.insert("disc".to_owned(), disc)
+                    },
+                    "totaldiscs" => None,
+                    _ => metadata.insert(k.clone(), v.clone()),
+                };
+            }
+            for (k, v) in track.metadata.iter() {
+                metadata.insert(k.clone(), v.clone());
+            }
+            metadata.insert("track".to_owned(),
+                            format!("{}/{}", track.number, track_count));
+            ret.push(Track { start, end: None, metadata });
+        }
+        ret
+    }
+}
+
+/// Looks for a cue sheet, in the same directory as the given file, that
+/// describes the given file. If one is found, and it divides the file into
+/// more than one track, returns those tracks.
+///
+/// A cue sheet describes the file if it has a `FILE` line with the same
+/// filename, or with the same filename apart from the extension. (Rips are
+/// often re-encoded without editing the cue sheet.)
+pub fn find_tracks_for(path: &Path) -> Option<Vec<Track>> {
+    let dir = path.parent()?;
+    let filename = path.file_name()?.to_string_lossy().to_lowercase();
+    let stem = path.file_stem()?.to_string_lossy().to_lowercase();
+    for ent in fs::read_dir(dir).ok()? {
+        let cue_path = match ent {
+            Ok(x) => x.path(),
+            Err(_) => continue,
+        };
+        let is_cue = cue_path.extension().and_then(OsStr::to_str)
+            .map(|x| x.eq_ignore_ascii_case("cue")).unwrap_or(false);
+        if !is_cue { continue }
+        let text = match fs::read(&cue_path) {
+            Ok(x) => decode_text(&x[..]),
+            Err(x) => {
+                warn!("Unable to read cue sheet {:?}: {}", cue_path, x);
+                continue
+            },
+        };
+        let sheet = parse(&text);
+        for file in sheet.files.iter() {
+            // FILE may include a (relative, and possibly Windows-style) path
+            let name = file.name.rsplit(&['/', '\\'][..]).next()
+                .unwrap().to_lowercase();
+            let name_stem = match name.rfind('.') {
+                Some(x) => &name[..x],
+                None => &name[..],
+            };
+            if name == filename || name_stem == stem {
+                let tracks = sheet.make_tracks(file);
+                if tracks.len() > 1 { return Some(tracks) }
+            }
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SHEET: &str = "\
+REM GENRE Ambient
+REM DISCNUMBER 2
+REM TOTALDISCS 3
+PERFORMER \"Some Band\"
+TITLE \"Some Album\"
+FILE \"Some Album.flac\" WAVE
+  TRACK 01 AUDIO
+    TITLE \"First\"
+    INDEX 00 00:00:00
+    INDEX 01 00:00:32
+  TRACK 02 AUDIO
+    TITLE \"Second\"
+    PERFORMER \"Guest Star\"
+    INDEX 01 03:10:15
+  TRACK 03 AUDIO
+    TITLE \"Third\"
+";
+
+    #[test]
+    fn words() {
+        assert_eq!(split_words("  TITLE \"Two  Words\" x "),
+                   vec!["TITLE", "Two  Words", "x"]);
+        assert_eq!(split_words("FILE \"unterminated"),
+                   vec!["FILE", "unterminated"]);
+        assert!(split_words("   ").is_empty());
+    }
+
+    #[test]
+    fn timestamps() {
+        assert_eq!(parse_timestamp("00:00:00"), Some(0.0));
+        assert_eq!(parse_timestamp("03:10:15"), Some(190.2));
+        assert_eq!(parse_timestamp("03:10"), None);
+        assert_eq!(parse_timestamp("03:10:15:00"), None);
+        assert_eq!(parse_timestamp("aa:10:15"), None);
+    }
+
+    #[test]
+    fn text_encodings() {
+        assert_eq!(decode_text(b"\xEF\xBB\xBFTITLE \xC3\xA9"), "TITLE é");
+        assert_eq!(decode_text(b"TITLE \xE9"), "TITLE é");
+    }
+
+    #[test]
+    fn sheet() {
+        let sheet = parse(SHEET);
+        assert_eq!(sheet.metadata.get("genre").map(String::as_str),
+                   Some("Ambient"));
+        assert_eq!(sheet.files.len(), 1);
+        let file = &sheet.files[0];
+        assert_eq!(file.name, "Some Album.flac");
+        assert_eq!(file.tracks.len(), 3);
+        assert_eq!(file.tracks[0].start, Some(32.0 / 75.0));
+        assert_eq!(file.tracks[1].start, Some(190.2));
+        assert_eq!(file.tracks[2].start, None);
+        assert_eq!(file.tracks[1].metadata.get("artist").map(String::as_str),
+                   Some("Guest Star"));
+    }
+
+    #[test]
+    fn tracks() {
+        let sheet = parse(SHEET);
+        let tracks = sheet.make_tracks(&sheet.files[0]);
+        // (the third track has no INDEX 01)
+        assert_eq!(tracks.len(), 2);
+        assert_eq!(tracks[0].start, 32.0 / 75.0);
+        assert_eq!(tracks[0].end, Some(190.2));
+        assert_eq!(tracks[1].start, 190.2);
+        assert_eq!(tracks[1].end, None);
+        let get = |n: usize, key: &str| {
+            tracks[n].metadata.get(key).map(String::as_str)
+        };
+        assert_eq!(get(0, "title"), Some("First"));
+        assert_eq!(get(0, "album"), Some("Some Album"));
+        assert_eq!(get(0, "artist"), Some("Some Band"));
+        assert_eq!(get(0, "album_artist"), Some("Some Band"));
+        assert_eq!(get(0, "disc"), Some("2/3"));
+        assert_eq!(get(0, "totaldiscs"), None);
+        assert_eq!(get(0, "track"), Some("1/3"));
+        assert_eq!(get(1, "artist"), Some("Guest Star"));
+        assert_eq!(get(1, "album_artist"), Some("Some Band"));
+    }
+
+    #[test]
+    fn finding_sheets() {
+        let dir = std::env::temp_dir()
+            .join(format!("tsong-cue-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("sheet.CUE"), SHEET).unwrap();
+        // (a re-encoded rip whose cue sheet still names the original)
+        let found = find_tracks_for(&dir.join("some album.ogg"));
+        let not_found = find_tracks_for(&dir.join("Other Album.flac"));
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(found.map(|x| x.len()), Some(2));
+        assert!(not_found.is_none());
+    }
+}
@@ -6,6 +6,7 @@ use log::{debug, info, error};
 use std::{
     cell::RefCell,
     collections::BTreeMap,
+    path::PathBuf,
     sync::Mutex,
 };
 
@@ -13,10 +14,31 @@ use anyhow::anyhow;
 use lazy_static::lazy_static;
 use rusqlite::{
     Connection,
+    OptionalExtension,
     params,
 };
 use serde_json as json;
 
+/// The `user_version` of a fully up-to-date database.
+const CURRENT_VERSION: i64 = 13;
+
+/// Scripts that update the database from one version to the next. The first
+/// one updates from version 1 to version 2, and so on.
+const UPDATE_SCRIPTS: &[&str] = &[
+    include_str!("sql/update_1_to_2.sql"),
+    include_str!("sql/update_2_to_3.sql"),
+    include_str!("sql/update_3_to_4.sql"),
+    include_str!("sql/update_4_to_5.sql"),
+    include_str!("sql/update_5_to_6.sql"),
+    include_str!("sql/update_6_to_7.sql"),
+    include_str!("sql/update_7_to_8.sql"),
+    include_str!("sql/update_8_to_9.sql"),
+    include_str!("sql/update_9_to_10.sql"),
+    include_str!("sql/update_10_to_11.sql"),
+    include_str!("sql/update_11_to_12.sql"),
+    include_str!("sql/update_12_to_13.sql"),
+];
+
 lazy_static! {
     static ref DATABASE: Mutex<Option<RefCell<Connection>>>
         = Mutex::new(None);
@@ -41,24 +63,21 @@ pub fn open_database() -> anyhow::Result<()> {
             database.execute_batch(include_str!("sql/schema.sql"))?;
             debug!("Initialized database from schema.");
         },
-        1 => {
-            // TODO: prompt user for upgrades? try to back up the file?
-            info!("Updating database from schema version 1.");
-            database.execute_batch(include_str!("sql/update_1_to_2.sql"))?;
-            database.execute_batch(include_str!("sql/update_2_to_3.sql"))?;
-        },
-        2 => {
-            info!("Updating database from schema version 2.");
-            database.execute_batch(include_str!("sql/update_2_to_3.sql"))?;
-        },
-        3 => {
+        CURRENT_VERSION => {
             debug!("Database did not require initialization.");
         },
+        x if x > 0 && x < CURRENT_VERSION => {
+            // TODO: prompt user for upgrades? try to back up the file?
+            info!("Updating database from schema version {}.", x);
+            for script in UPDATE_SCRIPTS[x as usize - 1 ..].iter() {
+                database.execute_batch(script)?;
+            }
+        },
         _ => return Err(anyhow!("Unknown database format version. (Was it \
                                  created by a newer version of Tsong?)")),
     }
     let mut get_files = database.prepare("SELECT id, size, duration, \
-                                          relative_paths \
+                                          relative_paths, tracks \
                                           FROM PhysicalFiles;")?;
     let mut rows = get_files.query(rusqlite::NO_PARAMS)?;
     while let Some(row) = rows.next()? {
@@ -66,17 +85,40 @@ pub fn open_database() -> anyhow::Result<()> {
         let size: i64 = row.get_unwrap(1);
         let duration: i64 = row.get_unwrap(2);
         let relative_paths: String = row.get_unwrap(3);
+        let tracks: Option<String> = row.get_unwrap(4);
         let id = FileID::from_bytes(&id[..])?;
         let size = size as u64;
         let duration = duration as u32;
         let relative_paths = json::from_str(&relative_paths)?;
-        physical::add_file_from_db(id, size, duration, relative_paths);
+        let tracks = match tracks {
+            Some(x) => Some(json::from_str(&x)?),
+            None => None,
+        };
+        physical::add_file_from_db(id, size, duration, relative_paths,
+                                   tracks);
     }    
     drop(rows);
     drop(get_files);
+    let mut get_non_music = database.prepare("SELECT absolute_path, size, \
+                                              mtime, kind \
+                                              FROM NonMusicFiles;")?;
+    let mut rows = get_non_music.query(rusqlite::NO_PARAMS)?;
+    while let Some(row) = rows.next()? {
+        let absolute_path: String = row.get_unwrap(0);
+        let size: i64 = row.get_unwrap(1);
+        let mtime: i64 = row.get_unwrap(2);
+        let kind: i64 = row.get_unwrap(3);
+        physical::add_non_music_file_from_db
+            (absolute_path, size as u64, mtime as u64,
+             physical::NonMusicKind::from_db_value(kind));
+    }
+    drop(rows);
+    drop(get_non_music);
     let mut get_songs = database.prepare("SELECT id, user_metadata, \
                                           physical_files, similarity_recs, \
-                                          duration \
+                                          duration, physical_tracks, \
+                                          practice_loops, \
+                                          remembered_position, stream_url \
                                           FROM LogicalSongs;")?;
     let mut rows = get_songs.query(rusqlite::NO_PARAMS)?;
     while let Some(row) = rows.next()? {
@@ -85,24 +127,43 @@ pub fn open_database() -> anyhow::Result<()> {
         let physical_files: Vec<u8> = row.get_unwrap(2);
         let similarity_recs: Option<String> = row.get_unwrap(3);
         let duration: Option<i64> = row.get_unwrap(4);
+        let physical_tracks: Option<String> = row.get_unwrap(5);
+        let practice_loops: Option<String> = row.get_unwrap(6);
+        let remembered_position: Option<f64> = row.get_unwrap(7);
+        let stream_url: Option<String> = row.get_unwrap(8);
         let id = SongID::from_inner(id as u64);
         let user_metadata = json::from_str(&user_metadata)?;
-        let physical_files = physical_files.chunks_exact(physical::ID_SIZE)
+        let physical_files: Vec<FileID> = physical_files
+            .chunks_exact(physical::ID_SIZE)
             .map(FileID::from_bytes).map(|x| x.unwrap()).collect();
+        let mut physical_tracks: Vec<u32> = match physical_tracks {
+            Some(x) => json::from_str(&x)?,
+            None => Vec::new(),
+        };
+        // any file without a track number is played in its entirety
+        physical_tracks.resize(physical_files.len(), 0);
         let similarity_recs = match similarity_recs {
             Some(x) => json::from_str(&x)?,
             None => None,
         };
         let duration = duration.unwrap_or(296) as u32;
+        let practice_loops = match practice_loops {
+            Some(x) => json::from_str(&x)?,
+            None => BTreeMap::new(),
+        };
         logical::add_song_from_db(id, user_metadata, physical_files,
-                                  similarity_recs, duration);
+                                  physical_tracks, similarity_recs, duration,
+                                  practice_loops, remembered_position,
+                                  stream_url);
     }
     drop(rows);
     drop(get_songs);
     let mut get_playlists = database.prepare("SELECT id, parent_id, \
                                               parent_order, name, rule_code, \
                                               manually_added_ids, columns, \
-                                              sort_order, shuffled, playmode \
+                                              sort_order, shuffled, playmode, \
+                                              remember_positions, \
+                                              shuffle_albums, album_key \
                                               FROM Playlists;")?;
     let mut rows = get_playlists.query(rusqlite::NO_PARAMS)?;
     while let Some(row) = rows.next()? {
@@ -116,6 +177,9 @@ pub fn open_database() -> anyhow::Result<()> {
         let sort_order: Option<String> = row.get_unwrap(7);
         let shuffled: Option<bool> = row.get_unwrap(8);
         let playmode: Option<i64> = row.get_unwrap(9);
+        let remember_positions: Option<bool> = row.get_unwrap(10);
+        let shuffle_albums: Option<bool> = row.get_unwrap(11);
+        let album_key: Option<String> = row.get_unwrap(12);
         // massage the returned data
         let id = PlaylistID::from_inner(id as u64);
         let parent_id = parent_id.map(|x| x as u64)
@@ -138,19 +202,151 @@ pub fn open_database() -> anyhow::Result<()> {
         };
         let shuffled = shuffled.unwrap_or(false);
         let playmode = Playmode::from_db_value(playmode.unwrap_or(0));
+        let remember_positions = remember_positions.unwrap_or(false);
+        let shuffle_albums = shuffle_albums.unwrap_or(false);
+        let album_key = match album_key {
+            Some(x) => json::from_str(&x)?,
+            None => playlist::DEFAULT_ALBUM_KEY.clone(),
+        };
         playlist::add_playlist_from_db(id, parent_id, parent_order, name,
                                        rule_code, shuffled, playmode,
                                        manually_added_ids, columns,
-                                       sort_order);
+                                       sort_order, remember_positions,
+                                       shuffle_albums, album_key);
     }
     drop(rows);
     drop(get_playlists);
+    let mut get_alarms = database.prepare("SELECT id, minute, days, \
+                                           playlist_id, shuffle, volume, \
+                                           ramp, enabled, last_fired \
+                                           FROM Alarms;")?;
+    let mut rows = get_alarms.query(rusqlite::NO_PARAMS)?;
+    while let Some(row) = rows.next()? {
+        let id: i64 = row.get_unwrap(0);
+        let minute: i64 = row.get_unwrap(1);
+        let days: i64 = row.get_unwrap(2);
+        let playlist_id: i64 = row.get_unwrap(3);
+        let shuffle: bool = row.get_unwrap(4);
+        let volume: Option<i64> = row.get_unwrap(5);
+        let ramp: f64 = row.get_unwrap(6);
+        let enabled: bool = row.get_unwrap(7);
+        let last_fired: Option<i64> = row.get_unwrap(8);
+        alarm::add_alarm_from_db(alarm::Alarm {
+            id: id as u64,
+            minute: minute as u32,
+            days: days as u8,
+            playlist_id: PlaylistID::from_inner(playlist_id as u64),
+            shuffle,
+            volume: volume.map(|x| x as i32),
+            ramp,
+            enabled,
+            last_fired,
+        });
+    }
+    drop(rows);
+    drop(get_alarms);
+    let mut get_podcasts = database.prepare("SELECT id, url, title, \
+                                             playlist_id, keep_episodes, \
+                                             delete_played, last_refreshed \
+                                             FROM Podcasts;")?;
+    let mut rows = get_podcasts.query(rusqlite::NO_PARAMS)?;
+    while let Some(row) = rows.next()? {
+        let id: i64 = row.get_unwrap(0);
+        let url: String = row.get_unwrap(1);
+        let title: String = row.get_unwrap(2);
+        let playlist_id: Option<i64> = row.get_unwrap(3);
+        let keep_episodes: Option<i64> = row.get_unwrap(4);
+        let delete_played: bool = row.get_unwrap(5);
+        let last_refreshed: Option<i64> = row.get_unwrap(6);
+        podcast::add_podcast_from_db(podcast::Podcast {
+            id: id as u64,
+            url,
+            title,
+            playlist_id: playlist_id
+                .map(|x| PlaylistID::from_inner(x as u64)),
+            keep_episodes: keep_episodes.map(|x| x as u32),
+            delete_played,
+            last_refreshed,
+        });
+    }
+    drop(rows);
+    drop(get_podcasts);
+    let mut get_episodes = database.prepare("SELECT podcast_id, guid, \
+                                             position, url, metadata, path, \
+                                             file_id, played \
+                                             FROM PodcastEpisodes;")?;
+    let mut rows = get_episodes.query(rusqlite::NO_PARAMS)?;
+    while let Some(row) = rows.next()? {
+        let podcast_id: i64 = row.get_unwrap(0);
+        let guid: String = row.get_unwrap(1);
+        let position: i64 = row.get_unwrap(2);
+        let url: String = row.get_unwrap(3);
+        let metadata: String = row.get_unwrap(4);
+        let path: Option<String> = row.get_unwrap(5);
+        let file_id: Option<Vec<u8>> = row.get_unwrap(6);
+        let played: bool = row.get_unwrap(7);
+        let file_id = match file_id {
+            Some(x) => Some(FileID::from_bytes(&x[..])?),
+            None => None,
+        };
+        podcast::add_episode_from_db(podcast::Episode {
+            podcast_id: podcast_id as u64,
+            guid,
+            position: position as u32,
+            url,
+            metadata: json::from_str(&metadata)?,
+            path: path.map(PathBuf::from),
+            file_id,
+            played,
+        });
+    }
+    drop(rows);
+    drop(get_episodes);
+    let mut get_targets = database.prepare("SELECT id, name, directory, \
+                                            path_template, format, bit_rate, \
+                                            playlist_ids, last_synced \
+                                            FROM SyncTargets;")?;
+    let mut rows = get_targets.query(rusqlite::NO_PARAMS)?;
+    while let Some(row) = rows.next()? {
+        let id: i64 = row.get_unwrap(0);
+        let name: String = row.get_unwrap(1);
+        let directory: String = row.get_unwrap(2);
+        let path_template: String = row.get_unwrap(3);
+        let format: i64 = row.get_unwrap(4);
+        let bit_rate: i64 = row.get_unwrap(5);
+        let playlist_ids: String = row.get_unwrap(6);
+        let last_synced: Option<i64> = row.get_unwrap(7);
+        sync::add_target_from_db(sync::SyncTarget {
+            id: id as u64,
+            name,
+            directory: PathBuf::from(directory),
+            path_template,
+            format: sync::SyncFormat::from_db_value(format),
+            bit_rate: bit_rate as u32,
+            playlist_ids: json::from_str::<Vec<u64>>(&playlist_ids)?
+                .into_iter().map(PlaylistID::from_inner).collect(),
+            last_synced,
+        });
+    }
+    drop(rows);
+    drop(get_targets);
     *database_lock = Some(RefCell::new(database));
     drop(database_lock);
     playlist::rebuild_children();
     Ok(())
 }
 
+/// Opens an empty database that lives only in memory, for tests of code that
+/// writes to the database. Does nothing if a database is already open.
+#[cfg(test)]
+pub fn open_test_database() {
+    let mut database_lock = DATABASE.lock().unwrap();
+    if database_lock.is_some() { return }
+    let database = Connection::open_in_memory().unwrap();
+    database.execute_batch(include_str!("sql/schema.sql")).unwrap();
+    *database_lock = Some(RefCell::new(database));
+}
+
 pub fn create_playlist(new_playlist_name: &str, new_parent_order: u64)
 -> anyhow::Result<PlaylistID> {
     // well, this is a heckin' tangle
@@ -216,6 +412,26 @@ pub fn update_playlist_playmode(id: PlaylistID, playmode: Playmode) {
                                    id.as_inner() as i64]));
 }
 
+pub fn update_playlist_remember_positions(id: PlaylistID,
+                                         remember_positions: bool) {
+    let lock = DATABASE.lock();
+    let database = lock.as_ref().unwrap().as_ref().unwrap().borrow_mut();
+    dbtry(database.execute("UPDATE Playlists SET remember_positions = ? \
+                            WHERE id = ?;",
+                           params![remember_positions, id.as_inner() as i64]));
+}
+
+pub fn update_playlist_album_shuffle(id: PlaylistID, shuffle_albums: bool,
+                                     album_key: &[String]) {
+    let lock = DATABASE.lock();
+    let database = lock.as_ref().unwrap().as_ref().unwrap().borrow_mut();
+    let album_key = json::to_string(album_key).unwrap();
+    dbtry(database.execute("UPDATE Playlists SET shuffle_albums = ?, \
+                            album_key = ? WHERE id = ?;",
+                           params![shuffle_albums, album_key,
+                                   id.as_inner() as i64]));
+}
+
 pub fn update_playlist_parent_order(id: PlaylistID, order: u64) {
     let lock = DATABASE.lock();
     let database = lock.as_ref().unwrap().as_ref().unwrap().borrow_mut();
@@ -274,16 +490,27 @@ pub fn delete_playlist(id: PlaylistID) {
 }
 
 pub fn add_file(id: &FileID, size: u64,
-                duration: u32, relative_paths: &Vec<String>) {
+                duration: u32, relative_paths: &Vec<String>,
+                tracks: &[physical::Track]) {
     let relative_paths = json::to_string(relative_paths).unwrap();
+    let tracks = json::to_string(tracks).unwrap();
     let lock = DATABASE.lock();
     let database = lock.as_ref().unwrap().as_ref().unwrap().borrow_mut();
     dbtry(database.execute("INSERT INTO PhysicalFiles \
-                            (id, size, duration, relative_paths) \
-                            VALUES (?, ?, ?, ?);",
+                            (id, size, duration, relative_paths, tracks) \
+                            VALUES (?, ?, ?, ?, ?);",
                            params![&id.as_bytes()[..],
                                    size as i64, duration as i64,
-                                   relative_paths]));
+                                   relative_paths, tracks]));
+}
+
+pub fn update_file_tracks(id: &FileID, tracks: &[physical::Track]) {
+    let tracks = json::to_string(tracks).unwrap();
+    let lock = DATABASE.lock();
+    let database = lock.as_ref().unwrap().as_ref().unwrap().borrow_mut();
+    dbtry(database.execute("UPDATE PhysicalFiles SET tracks = ? \
+                            WHERE id = ?;",
+                           params![tracks, &id.as_bytes()[..]]));
 }
 
 pub fn update_file_relative_paths(id: &FileID, paths: &Vec<String>) {
@@ -295,8 +522,57 @@ pub fn update_file_relative_paths(id: &FileID, paths: &Vec<String>) {
                            params![paths, &id.as_bytes()[..]]));
 }
 
+/// Adds the new row and deletes the old one in the same transaction, so that
+/// the file is never in the database twice, or not at all.
+pub fn replace_file(old_id: &FileID, id: &FileID, size: u64,
+                    duration: u32, relative_paths: &Vec<String>,
+                    tracks: &[physical::Track]) {
+    let relative_paths = json::to_string(relative_paths).unwrap();
+    let tracks = json::to_string(tracks).unwrap();
+    let lock = DATABASE.lock();
+    let mut database = lock.as_ref().unwrap().as_ref().unwrap().borrow_mut();
+    dbtry(database.transaction().and_then(|transaction| {
+        transaction.execute("INSERT INTO PhysicalFiles \
+                             (id, size, duration, relative_paths, tracks) \
+                             VALUES (?, ?, ?, ?, ?);",
+                            params![&id.as_bytes()[..],
+                                    size as i64, duration as i64,
+                                    relative_paths, tracks])?;
+        transaction.execute("DELETE FROM PhysicalFiles WHERE id = ?;",
+                            params![&old_id.as_bytes()[..]])?;
+        transaction.commit()
+    }));
+}
+
+pub fn delete_file(id: &FileID) {
+    let lock = DATABASE.lock();
+    let database = lock.as_ref().unwrap().as_ref().unwrap().borrow_mut();
+    dbtry(database.execute("DELETE FROM PhysicalFiles WHERE id = ?;",
+                           params![&id.as_bytes()[..]]));
+}
+
+pub fn add_non_music_file(absolute_path: &str, size: u64, mtime: u64,
+                          kind: physical::NonMusicKind) {
+    let lock = DATABASE.lock();
+    let database = lock.as_ref().unwrap().as_ref().unwrap().borrow_mut();
+    dbtry(database.execute("INSERT OR REPLACE INTO NonMusicFiles \
+                            (absolute_path, size, mtime, kind) \
+                            VALUES (?, ?, ?, ?);",
+                           params![absolute_path, size as i64, mtime as i64,
+                                   kind.to_db_value()]));
+}
+
+pub fn delete_non_music_file(absolute_path: &str) {
+    let lock = DATABASE.lock();
+    let database = lock.as_ref().unwrap().as_ref().unwrap().borrow_mut();
+    dbtry(database.execute("DELETE FROM NonMusicFiles \
+                            WHERE absolute_path = ?;",
+                           params![absolute_path]));
+}
+
 pub fn add_song(user_metadata: &BTreeMap<String, String>,
                 physical_files_in: &Vec<FileID>,
+                physical_tracks: &[u32],
                 similarity_recs: &[logical::SimilarityRec],
                 duration: u32)
 -> anyhow::Result<SongID> {
@@ -311,43 +587,62 @@ pub fn add_song(user_metadata: &BTreeMap<String, String>,
     let database = lock.as_ref().unwrap().as_ref().unwrap().borrow_mut();
     database.execute("INSERT INTO LogicalSongs \
                       (user_metadata, physical_files, similarity_recs, \
-                      duration) \
-                      VALUES (?, ?, ?, ?);",
+                      duration, physical_tracks) \
+                      VALUES (?, ?, ?, ?, ?);",
                      params![user_metadata, physical_files,
                              json::to_string(similarity_recs).unwrap(),
-                             duration])?;
+                             duration,
+                             json::to_string(physical_tracks).unwrap()])?;
     Ok(SongID::from_inner(database.last_insert_rowid() as u64))
 }
 
-pub fn update_song_physical_files(id: SongID, physical_files_in:&Vec<FileID>){
+pub fn add_stream_song(url: &str, user_metadata: &BTreeMap<String, String>)
+-> anyhow::Result<SongID> {
+    let user_metadata = json::to_string(user_metadata).unwrap();
+    let lock = DATABASE.lock();
+    let database = lock.as_ref().unwrap().as_ref().unwrap().borrow_mut();
+    database.execute("INSERT INTO LogicalSongs \
+                      (user_metadata, physical_files, similarity_recs, \
+                      duration, physical_tracks, stream_url) \
+                      VALUES (?, ?, '[]', 0, '[]', ?);",
+                     params![user_metadata, Vec::<u8>::new(), url])?;
+    Ok(SongID::from_inner(database.last_insert_rowid() as u64))
+}
+
+pub fn update_song_physical_files(id: SongID, physical_files_in:&Vec<FileID>,
+                                  physical_tracks: &[u32]) {
     let mut physical_files: Vec<u8>
         = Vec::with_capacity(physical_files_in .len() * physical::ID_SIZE);
     for id in physical_files_in.iter() {
         physical_files.extend_from_slice(id.as_bytes());
     }
+    let physical_tracks = json::to_string(physical_tracks).unwrap();
     let lock = DATABASE.lock();
     let database = lock.as_ref().unwrap().as_ref().unwrap().borrow_mut();
-    dbtry(database.execute("UPDATE LogicalSongs SET physical_files = ? \
+    dbtry(database.execute("UPDATE LogicalSongs SET physical_files = ?, \
+                            physical_tracks = ? \
                             WHERE id = ?;",
-                           params![physical_files, id.as_inner() as i64]));
+                           params![physical_files, physical_tracks,
+                                   id.as_inner() as i64]));
 }
 
 pub fn update_song_physical_files_and_similarity_recs
-    (id: SongID, physical_files_in: &Vec<FileID>,
+    (id: SongID, physical_files_in: &Vec<FileID>, physical_tracks: &[u32],
      similarity_recs_in: &[logical::SimilarityRec]) {
     let mut physical_files: Vec<u8>
         = Vec::with_capacity(physical_files_in .len() * physical::ID_SIZE);
     for id in physical_files_in.iter() {
         physical_files.extend_from_slice(id.as_bytes());
     }
+    let physical_tracks = json::to_string(physical_tracks).unwrap();
     let similarity_recs = json::to_string(similarity_recs_in).unwrap();
     let lock = DATABASE.lock();
     let database = lock.as_ref().unwrap().as_ref().unwrap().borrow_mut();
     dbtry(database.execute("UPDATE LogicalSongs SET physical_files = ?, \
-                            similarity_recs = ? \
+                            physical_tracks = ?, similarity_recs = ? \
                             WHERE id = ?;",
-                           params![physical_files, similarity_recs,
-                                   id.as_inner() as i64]));
+                           params![physical_files, physical_tracks,
+                                   similarity_recs, id.as_inner() as i64]));
 }
 
 pub fn update_song_similarity_recs
@@ -377,6 +672,216 @@ pub fn update_song_duration(id: SongID, duration: u32) {
                            params![duration as i64, id.as_inner() as i64]));
 }
 
+pub fn update_song_practice_loops
+    (id: SongID, practice_loops: &BTreeMap<String, logical::PracticeLoop>) {
+    let practice_loops = json::to_string(practice_loops).unwrap();
+    let lock = DATABASE.lock();
+    let database = lock.as_ref().unwrap().as_ref().unwrap().borrow_mut();
+    dbtry(database.execute("UPDATE LogicalSongs SET practice_loops = ? \
+                            WHERE id = ?;",
+                           params![practice_loops, id.as_inner() as i64]));
+}
+
+pub fn update_song_remembered_position(id: SongID, position: Option<f64>) {
+    let lock = DATABASE.lock();
+    let database = lock.as_ref().unwrap().as_ref().unwrap().borrow_mut();
+    dbtry(database.execute("UPDATE LogicalSongs SET remembered_position = ? \
+                            WHERE id = ?;",
+                           params![position, id.as_inner() as i64]));
+}
+
+pub fn get_playback_state() -> Option<playback::SavedState> {
+    let lock = DATABASE.lock();
+    let database = lock.as_ref().unwrap().as_ref().unwrap().borrow_mut();
+    let row = dbtry(database.query_row
+                    ("SELECT playlist_id, song_id, position, status, \
+                      shuffle_order FROM PlaybackState WHERE id = 0;",
+                     rusqlite::NO_PARAMS, |row| {
+                         let playlist_id: Option<i64> = row.get(0)?;
+                         let song_id: Option<i64> = row.get(1)?;
+                         let position: Option<f64> = row.get(2)?;
+                         let status: Option<i64> = row.get(3)?;
+                         let shuffle_order: Option<String> = row.get(4)?;
+                         Ok((playlist_id, song_id, position, status,
+                             shuffle_order))
+                     }).optional()).flatten()?;
+    let (playlist_id, song_id, position, status, shuffle_order) = row;
+    let shuffle_order = match shuffle_order {
+        Some(x) => match json::from_str::<Vec<u64>>(&x) {
+            Ok(x) => Some(x.into_iter().map(SongID::from_inner).collect()),
+            Err(x) => {
+                error!("Saved shuffle order is corrupted: {}", x);
+                None
+            },
+        },
+        None => None,
+    };
+    Some(playback::SavedState {
+        playlist_id: playlist_id.map(|x| PlaylistID::from_inner(x as u64)),
+        song_id: song_id.map(|x| SongID::from_inner(x as u64)),
+        position: position.unwrap_or(0.0),
+        status: PlaybackStatus::from_db_value(status.unwrap_or(0)),
+        shuffle_order,
+    })
+}
+
+pub fn update_playback_state(saved: &playback::SavedState) {
+    let shuffle_order = saved.shuffle_order.as_ref().map(|x| {
+        json::to_string(&x.iter().map(SongID::as_inner).collect()
+                        as &Vec<u64>).unwrap()
+    });
+    let lock = DATABASE.lock();
+    let database = lock.as_ref().unwrap().as_ref().unwrap().borrow_mut();
+    dbtry(database.execute("INSERT OR REPLACE INTO PlaybackState \
+                            (id, playlist_id, song_id, position, status, \
+                            shuffle_order) VALUES (0, ?, ?, ?, ?, ?);",
+                           params![saved.playlist_id
+                                   .map(|x| x.as_inner() as i64),
+                                   saved.song_id.map(|x| x.as_inner() as i64),
+                                   saved.position,
+                                   saved.status.to_db_value(),
+                                   shuffle_order]));
+}
+
+pub fn create_alarm(alarm: &alarm::Alarm) -> anyhow::Result<u64> {
+    let lock = DATABASE.lock();
+    let database = lock.as_ref().unwrap().as_ref().unwrap().borrow_mut();
+    database.execute("INSERT INTO Alarms(minute, days, playlist_id, shuffle, \
+                      volume, ramp, enabled, last_fired) \
+                      VALUES (?, ?, ?, ?, ?, ?, ?, ?);",
+                     params![alarm.minute as i64, alarm.days as i64,
+                             alarm.playlist_id.as_inner() as i64,
+                             alarm.shuffle, alarm.volume.map(|x| x as i64),
+                             alarm.ramp, alarm.enabled, alarm.last_fired])?;
+    Ok(database.last_insert_rowid() as u64)
+}
+
+pub fn update_alarm(alarm: &alarm::Alarm) {
+    let lock = DATABASE.lock();
+    let database = lock.as_ref().unwrap().as_ref().unwrap().borrow_mut();
+    dbtry(database.execute("UPDATE Alarms SET minute = ?, days = ?, \
+                            playlist_id = ?, shuffle = ?, volume = ?, \
+                            ramp = ?, enabled = ?, last_fired = ? \
+                            WHERE id = ?;",
+                           params![alarm.minute as i64, alarm.days as i64,
+                                   alarm.playlist_id.as_inner() as i64,
+                                   alarm.shuffle,
+                                   alarm.volume.map(|x| x as i64),
+                                   alarm.ramp, alarm.enabled,
+                                   alarm.last_fired, alarm.id as i64]));
+}
+
+pub fn delete_alarm(id: u64) {
+    let lock = DATABASE.lock();
+    let database = lock.as_ref().unwrap().as_ref().unwrap().borrow_mut();
+    dbtry(database.execute("DELETE FROM Alarms WHERE id = ?;",
+                           params![id as i64]));
+}
+
+pub fn create_podcast(podcast: &podcast::Podcast) -> anyhow::Result<u64> {
+    let lock = DATABASE.lock();
+    let database = lock.as_ref().unwrap().as_ref().unwrap().borrow_mut();
+    database.execute("INSERT INTO Podcasts(url, title, playlist_id, \
+                      keep_episodes, delete_played, last_refreshed) \
+                      VALUES (?, ?, ?, ?, ?, ?);",
+                     params![podcast.url, podcast.title,
+                             podcast.playlist_id.map(|x| x.as_inner() as i64),
+                             podcast.keep_episodes.map(|x| x as i64),
+                             podcast.delete_played,
+                             podcast.last_refreshed])?;
+    Ok(database.last_insert_rowid() as u64)
+}
+
+pub fn update_podcast(podcast: &podcast::Podcast) {
+    let lock = DATABASE.lock();
+    let database = lock.as_ref().unwrap().as_ref().unwrap().borrow_mut();
+    dbtry(database.execute("UPDATE Podcasts SET url = ?, title = ?, \
+                            playlist_id = ?, keep_episodes = ?, \
+                            delete_played = ?, last_refreshed = ? \
+                            WHERE id = ?;",
+                           params![podcast.url, podcast.title,
+                                   podcast.playlist_id
+                                   .map(|x| x.as_inner() as i64),
+                                   podcast.keep_episodes.map(|x| x as i64),
+                                   podcast.delete_played,
+                                   podcast.last_refreshed,
+                                   podcast.id as i64]));
+}
+
+/// Deletes a podcast, and all of its episodes.
+pub fn delete_podcast(id: u64) {
+    let lock = DATABASE.lock();
+    let database = lock.as_ref().unwrap().as_ref().unwrap().borrow_mut();
+    dbtry(database.execute("DELETE FROM PodcastEpisodes \
+                            WHERE podcast_id = ?;",
+                           params![id as i64]));
+    dbtry(database.execute("DELETE FROM Podcasts WHERE id = ?;",
+                           params![id as i64]));
+}
+
+/// Adds an episode, or replaces the one with the same podcast and guid.
+pub fn put_episode(episode: &podcast::Episode) {
+    let metadata = json::to_string(&episode.metadata).unwrap();
+    let path = episode.path.as_ref()
+        .map(|x| x.to_string_lossy().into_owned());
+    let lock = DATABASE.lock();
+    let database = lock.as_ref().unwrap().as_ref().unwrap().borrow_mut();
+    dbtry(database.execute("INSERT OR REPLACE INTO PodcastEpisodes \
+                            (podcast_id, guid, position, url, metadata, \
+                            path, file_id, played) \
+                            VALUES (?, ?, ?, ?, ?, ?, ?, ?);",
+                           params![episode.podcast_id as i64, episode.guid,
+                                   episode.position as i64, episode.url,
+                                   metadata, path,
+                                   episode.file_id.as_ref()
+                                   .map(|x| &x.as_bytes()[..]),
+                                   episode.played]));
+}
+
+pub fn create_sync_target(target: &sync::SyncTarget) -> anyhow::Result<u64> {
+    let playlist_ids = sync_playlist_ids_to_json(target);
+    let lock = DATABASE.lock();
+    let database = lock.as_ref().unwrap().as_ref().unwrap().borrow_mut();
+    database.execute("INSERT INTO SyncTargets(name, directory, \
+                      path_template, format, bit_rate, playlist_ids, \
+                      last_synced) VALUES (?, ?, ?, ?, ?, ?, ?);",
+                     params![target.name,
+                             target.directory.to_string_lossy(),
+                             target.path_template,
+                             target.format.to_db_value(),
+                             target.bit_rate as i64, playlist_ids,
+                             target.last_synced])?;
+    Ok(database.last_insert_rowid() as u64)
+}
+
+pub fn update_sync_target(target: &sync::SyncTarget) {
+    let playlist_ids = sync_playlist_ids_to_json(target);
+    let lock = DATABASE.lock();
+    let database = lock.as_ref().unwrap().as_ref().unwrap().borrow_mut();
+    dbtry(database.execute("UPDATE SyncTargets SET name = ?, directory = ?, \
+                            path_template = ?, format = ?, bit_rate = ?, \
+                            playlist_ids = ?, last_synced = ? \
+                            WHERE id = ?;",
+                           params![target.name,
+                                   target.directory.to_string_lossy(),
+                                   target.path_template,
+                                   target.format.to_db_value(),
+                                   target.bit_rate as i64, playlist_ids,
+                                   target.last_synced, target.id as i64]));
+}
+
+pub fn delete_sync_target(id: u64) {
+    let lock = DATABASE.lock();
+    let database = lock.as_ref().unwrap().as_ref().unwrap().borrow_mut();
+    dbtry(database.execute("DELETE FROM SyncTargets WHERE id = ?;",
+                           params![id as i64]));
+}
+
+fn sync_playlist_ids_to_json(target: &sync::SyncTarget) -> String {
+    json::to_string(&target.playlist_ids.iter().map(PlaylistID::as_inner)
+                    .collect() as &Vec<u64>).unwrap()
+}
+
 /// If a database error occurred, log it and return nothing. Otherwise, return
 /// the returned value.
 fn dbtry<X>(x: rusqlite::Result<X>) -> Option<X> {
@@ -0,0 +1,550 @@
+//! This module processes decoded (and, if applicable, resampled) audio before
+//! it is queued for playback. Right now that means equalization: a 10-band
+//! graphic EQ and a parametric EQ, both made of biquad filters.
+//!
+//! An `EqPreset` says what both EQs should do. Presets are kept in the
+//! preferences, and a song can ask for a particular one with an `eq_preset`
+//! metadata tag.
+//!
+//! Before any of that, audio is remixed to the channel layout the user asked
+//! for (see `map_channels`), with balance and channel swap applied.
+
+use crate::*;
+
+use std::collections::BTreeMap;
+use std::f64::consts::PI;
+
+use serde::Deserialize;
+
+/// Center frequencies of the graphic EQ's bands, in Hz.
+pub const GRAPHIC_EQ_FREQUENCIES: [f64; GRAPHIC_EQ_BANDS] = [
+    31.25, 62.5, 125.0, 250.0, 500.0, 1000.0, 2000.0, 4000.0, 8000.0, 16000.0,
+];
+/// How many bands the graphic EQ has.
+pub const GRAPHIC_EQ_BANDS: usize = 10;
+/// The Q of each graphic EQ band. (About one octave wide.)
+const GRAPHIC_EQ_Q: f64 = 1.41;
+/// The most any band is allowed to boost or cut, in dB.
+pub const MAX_EQ_GAIN: f64 = 18.0;
+/// Bands at or above this fraction of the sample rate are left out. (A filter
+/// at or above the Nyquist frequency is nonsense.)
+const MAX_BAND_FRACTION: f64 = 0.45;
+
+/// The name of the preset that leaves audio alone.
+pub const FLAT_PRESET: &str = "Flat";
+
+#[derive(Clone,Copy,Debug,Deserialize,PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum BandKind {
+    /// Boosts or cuts around `frequency`.
+    Peak,
+    /// Boosts or cuts everything below `frequency`.
+    LowShelf,
+    /// Boosts or cuts everything above `frequency`.
+    HighShelf,
+}
+
+impl BandKind {
+    /// The name of this kind of band, as it appears in the preferences file.
+    pub fn get_name(&self) -> &'static str {
+        match self {
+            BandKind::Peak => "peak",
+            BandKind::LowShelf => "lowshelf",
+            BandKind::HighShelf => "highshelf",
+        }
+    }
+}
+
+/// One band of the parametric EQ.
+#[derive(Clone,Copy,Debug,Deserialize,PartialEq)]
+pub struct EqBand {
+    pub kind: BandKind,
+    /// Center (or corner) frequency, in Hz.
+    pub frequency: f64,
+    /// Boost (positive) or cut (negative), in dB.
+    pub gain: f64,
+    #[serde(default = "get_standard_q")]
+    pub q: f64,
+}
+
+fn get_standard_q() -> f64 { std::f64::consts::FRAC_1_SQRT_2 }
+
+#[derive(Clone,Debug,Default,Deserialize,PartialEq)]
+pub struct EqPreset {
+    /// Gain applied before any of the filters, in dB. Usually negative, to
+    /// leave room for boosts.
+    #[serde(default)]
+    pub preamp: f64,
+    /// Gain of each graphic EQ band, in dB.
+    #[serde(default)]
+    pub graphic: [f64; GRAPHIC_EQ_BANDS],
+    #[serde(default)]
+    pub parametric: Vec<EqBand>,
+}
+
+impl EqPreset {
+    /// Returns true if this preset wouldn't change the audio at all.
+    pub fn is_flat(&self) -> bool {
+        self.preamp == 0.0
+            && self.graphic.iter().all(|&x| x == 0.0)
+            && self.parametric.iter().all(|x| x.gain == 0.0)
+    }
+}
+
+/// Returns the presets that come with Tsong. Presets in the preferences with
+/// the same names take precedence.
+pub fn get_builtin_presets() -> BTreeMap<String, EqPreset> {
+    let mut ret = BTreeMap::new();
+    ret.insert(FLAT_PRESET.to_owned(), EqPreset::default());
+    ret.insert("Bass Boost".to_owned(), EqPreset {
+        preamp: -6.0,
+        graphic: [6.0, 5.0, 4.0, 2.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0],
+        parametric: vec![],
+    });
+    ret.insert("Treble Boost".to_owned(), EqPreset {
+        preamp: -6.0,
+        graphic: [0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 1.0, 2.0, 4.0, 6.0],
+        parametric: vec![],
+    });
+    ret.insert("Loudness".to_owned(), EqPreset {
+        preamp: -6.0,
+        graphic: [0.0; GRAPHIC_EQ_BANDS],
+        parametric: vec![
+            EqBand { kind: BandKind::LowShelf, frequency: 80.0, gain: 6.0,
+                     q: get_standard_q() },
+            EqBand { kind: BandKind::HighShelf, frequency: 10000.0,
+                     gain: 4.0, q: get_standard_q() },
+        ],
+    });
+    ret.insert("Vocal".to_owned(), EqPreset {
+        preamp: -3.0,
+        graphic: [0.0; GRAPHIC_EQ_BANDS],
+        parametric: vec![
+            EqBand { kind: BandKind::LowShelf, frequency: 120.0, gain: -3.0,
+                     q: get_standard_q() },
+            EqBand { kind: BandKind::Peak, frequency: 2500.0, gain: 3.0,
+                     q: 1.0 },
+        ],
+    });
+    ret
+}
+
+/// Returns the EQ that should be applied to the given song, if any. A song's
+/// `eq_preset` tag wins, if it names a preset that exists; otherwise, if the
+/// EQ is enabled, the preset chosen in the preferences is used.
+pub fn get_eq_for_song(metadata: &BTreeMap<String, String>)
+    -> Option<EqPreset> {
+    let preset = metadata.get("eq_preset")
+        .and_then(|x| prefs::get_eq_preset(x))
+        .or_else(|| {
+            if prefs::get_eq_enabled() {
+                prefs::get_eq_preset(&prefs::get_chosen_eq_preset())
+            } else { None }
+        })?;
+    if preset.is_flat() { None } else { Some(preset) }
+}
+
+/// Coefficients for one biquad filter, normalized so that `a0` is 1.
+#[derive(Clone,Copy,Debug)]
+struct Biquad {
+    b0: f64, b1: f64, b2: f64, a1: f64, a2: f64,
+}
+
+impl Biquad {
+    /// Makes a filter, using the formulas from Robert Bristow-Johnson's
+    /// "Audio EQ Cookbook".
+    fn new(band: &EqBand, sample_rate: f64) -> Biquad {
+        let a = 10f64.powf(band.gain / 40.0);
+        let w0 = 2.0 * PI * band.frequency / sample_rate;
+        let (sin, cos) = w0.sin_cos();
+        let alpha = sin / (2.0 * band.q.max(0.01));
+        let (b0, b1, b2, a0, a1, a2) = match band.kind {
+            BandKind::Peak => (
+                1.0 + alpha * a,
+                -2.0 * cos,
+                1.0 - alpha * a,
+                1.0 + alpha / a,
+                -2.0 * cos,
+                1.0 - alpha / a,
+            ),
+            BandKind::LowShelf => {
+                let sqa = 2.0 * a.sqrt() * alpha;
+                (
+                    a * ((a + 1.0) - (a - 1.0) * cos + sqa),
+                    2.0 * a * ((a - 1.0) - (a + 1.0) * cos),
+                    a * ((a + 1.0) - (a - 1.0) * cos - sqa),
+                    (a + 1.0) + (a - 1.0) * cos + sqa,
+                    -2.0 * ((a - 1.0) + (a + 1.0) * cos),
+                    (a + 1.0) + (a - 1.0) * cos - sqa,
+                )
+            },
+            BandKind::HighShelf => {
+                let sqa = 2.0 * a.sqrt() * alpha;
+                (
+                    a * ((a + 1.0) + (a - 1.0) * cos + sqa),
+                    -2.0 * a * ((a - 1.0) + (a + 1.0) * cos),
+                    a * ((a + 1.0) + (a - 1.0) * cos - sqa),
+                    (a + 1.0) - (a - 1.0) * cos + sqa,
+                    2.0 * ((a - 1.0) - (a + 1.0) * cos),
+                    (a + 1.0) - (a - 1.0) * cos - sqa,
+                )
+            },
+        };
+        Biquad {
+            b0: b0 / a0, b1: b1 / a0, b2: b2 / a0,
+            a1: a1 / a0, a2: a2 / a0,
+        }
+    }
+}
+
+/// The filters for one preset at one sample format, and their state.
+pub struct Chain {
+    /// The preset and sample format the filters were made for.
+    built_for: Option<(EqPreset, f64, i32)>,
+    filters: Vec<Biquad>,
+    /// Transposed Direct Form II state: two values per filter per channel,
+    /// filter-major.
+    states: Vec<[f64; 2]>,
+    /// Linear gain applied before filtering.
+    preamp: f64,
+}
+
+impl Chain {
+    pub fn new() -> Chain {
+        Chain {
+            built_for: None, filters: Vec::new(), states: Vec::new(),
+            preamp: 1.0,
+        }
+    }
+    fn rebuild(&mut self, eq: &EqPreset, sample_rate: f64,
+               channel_count: i32) {
+        let max_frequency = sample_rate * MAX_BAND_FRACTION;
+        let graphic = GRAPHIC_EQ_FREQUENCIES.iter().zip(eq.graphic.iter())
+            .map(|(&frequency, &gain)| EqBand {
+                kind: BandKind::Peak, frequency, gain, q: GRAPHIC_EQ_Q,
+            });
+        self.filters = graphic.chain(eq.parametric.iter().cloned())
+            .filter(|x| x.gain != 0.0 && x.frequency > 0.0
+                    && x.frequency < max_frequency)
+            .map(|mut x| {
+                x.gain = x.gain.clamp(-MAX_EQ_GAIN, MAX_EQ_GAIN);
+                Biquad::new(&x, sample_rate)
+            })
+            .collect();
+        self.states = vec![[0.0; 2];
+                           self.filters.len() * channel_count as usize];
+        self.preamp = 10f64.powf(eq.preamp.clamp(-MAX_EQ_GAIN, MAX_EQ_GAIN)
+                                 / 20.0);
+        self.built_for = Some((eq.clone(), sample_rate, channel_count));
+    }
+    /// Applies the given EQ to some interleaved audio, in place. Filter state
+    /// carries over from one call to the next, as long as the EQ and the
+    /// sample format stay the same.
+    pub fn process(&mut self, eq: Option<&EqPreset>, sample_rate: f64,
+                   channel_count: i32, data: &mut [f32]) {
+        let eq = match eq {
+            Some(x) => x,
+            None => {
+                self.built_for = None;
+                return
+            },
+        };
+        let up_to_date = match self.built_for.as_ref() {
+            Some((built_eq, built_rate, built_count)) =>
+                built_eq == eq && *built_rate == sample_rate
+                && *built_count == channel_count,
+            None => false,
+        };
+        if !up_to_date { self.rebuild(eq, sample_rate, channel_count) }
+        let channel_count = channel_count as usize;
+        let preamp = self.preamp;
+        for frame in data.chunks_mut(channel_count) {
+            for (channel, sample) in frame.iter_mut().enumerate() {
+                let mut x = *sample as f64 * preamp;
+                for (n, filter) in self.filters.iter().enumerate() {
+                    let state = &mut self.states[n * channel_count + channel];
+                    let y = filter.b0 * x + state[0];
+                    state[0] = filter.b1 * x - filter.a1 * y + state[1];
+                    state[1] = filter.b2 * x - filter.a2 * y;
+                    x = y;
+                }
+                *sample = x as f32;
+            }
+        }
+    }
+}
+
+impl Default for Chain {
+    fn default() -> Chain { Chain::new() }
+}
+
+/// The channel layout we send to the output device.
+#[derive(Clone,Copy,Debug,Deserialize,PartialEq,Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ChannelLayout {
+    /// Whatever the file has.
+    Original,
+    /// Two channels. Surround audio is downmixed, and mono audio is sent to
+    /// both speakers.
+    Stereo,
+    /// One channel, for single-speaker setups.
+    Mono,
+}
+
+impl Default for ChannelLayout {
+    fn default() -> ChannelLayout { ChannelLayout::Original }
+}
+
+impl ChannelLayout {
+    /// The name of this layout, as it appears in the preferences file.
+    pub fn get_name(&self) -> &'static str {
+        match self {
+            ChannelLayout::Original => "original",
+            ChannelLayout::Stereo => "stereo",
+            ChannelLayout::Mono => "mono",
+        }
+    }
+}
+
+/// Where a channel of a file is meant to be heard from.
+#[derive(Clone,Copy,Debug,PartialEq)]
+enum Speaker {
+    FrontLeft, FrontRight, FrontCenter, LowFrequency,
+    BackLeft, BackRight, BackCenter, SideLeft, SideRight,
+}
+
+/// Returns the speakers of the layout FFmpeg assumes for the given number of
+/// channels. (We don't get told the actual layout, but files that don't use
+/// these are rare.) Channels past the eighth are given no speaker, and are
+/// dropped when downmixing.
+fn get_speakers(channel_count: usize) -> &'static [Speaker] {
+    use Speaker::*;
+    match channel_count {
+        1 => &[FrontCenter],
+        2 => &[FrontLeft, FrontRight],
+        3 => &[FrontLeft, FrontRight, FrontCenter],
+        4 => &[FrontLeft, FrontRight, FrontCenter, BackCenter],
+        5 => &[FrontLeft, FrontRight, FrontCenter, BackLeft, BackRight],
+        6 => &[FrontLeft, FrontRight, FrontCenter, LowFrequency,
+               BackLeft, BackRight],
+        7 => &[FrontLeft, FrontRight, FrontCenter, LowFrequency,
+               BackCenter, SideLeft, SideRight],
+        _ => &[FrontLeft, FrontRight, FrontCenter, LowFrequency,
+               BackLeft, BackRight, SideLeft, SideRight],
+    }
+}
+
+/// How much of the given speaker goes into the left and right channels of a
+/// stereo downmix, before normalization. (These are the usual ITU-R BS.775
+/// coefficients. The LFE channel is left out, as most downmixers do.)
+fn get_stereo_weights(speaker: Speaker) -> (f32, f32) {
+    use std::f32::consts::FRAC_1_SQRT_2 as H;
+    match speaker {
+        Speaker::FrontLeft => (1.0, 0.0),
+        Speaker::FrontRight => (0.0, 1.0),
+        Speaker::FrontCenter => (H, H),
+        Speaker::LowFrequency => (0.0, 0.0),
+        Speaker::BackLeft | Speaker::SideLeft => (H, 0.0),
+        Speaker::BackRight | Speaker::SideRight => (0.0, H),
+        Speaker::BackCenter => (0.5, 0.5),
+    }
+}
+
+/// Makes the downmix matrix for the given layout: one row per output channel,
+/// one column per input channel. Returns `None` if no remixing is needed.
+fn make_matrix(layout: ChannelLayout, in_count: usize)
+    -> Option<(usize, Vec<f32>)> {
+    let stereo = || -> Vec<f32> {
+        let mut left = vec![0.0; in_count];
+        let mut right = vec![0.0; in_count];
+        if in_count == 1 {
+            // mono goes to both speakers at full volume
+            left[0] = 1.0;
+            right[0] = 1.0;
+        }
+        else {
+            for (n, &speaker) in get_speakers(in_count).iter().enumerate() {
+                let (l, r) = get_stereo_weights(speaker);
+                left[n] = l;
+                right[n] = r;
+            }
+            // Scale down so that full-scale input on every channel can't
+            // clip.
+            let scale = left.iter().sum::<f32>().max(1.0);
+            for x in left.iter_mut().chain(right.iter_mut()) { *x /= scale }
+        }
+        left.extend(right);
+        left
+    };
+    match layout {
+        ChannelLayout::Original => None,
+        ChannelLayout::Stereo if in_count == 2 => None,
+        ChannelLayout::Stereo => Some((2, stereo())),
+        ChannelLayout::Mono if in_count == 1 => None,
+        ChannelLayout::Mono => {
+            let stereo = stereo();
+            let (left, right) = stereo.split_at(in_count);
+            Some((1, left.iter().zip(right.iter())
+                  .map(|(l, r)| (l + r) * 0.5).collect()))
+        },
+    }
+}
+
+/// Remixes some interleaved audio according to the channel layout, balance
+/// and channel swap preferences. Returns the new channel count and the
+/// remixed audio, or `None` if the audio should be left alone.
+///
+/// Balance and swap only affect the first two output channels, and only if
+/// there are at least two.
+pub fn map_channels(channel_count: i32, data: &[f32])
+    -> Option<(i32, Vec<f32>)> {
+    let in_count = channel_count as usize;
+    let balance = prefs::get_balance() as f32;
+    let swap = prefs::get_swap_channels();
+    let (out_count, mut matrix) = match make_matrix(prefs::get_channel_layout(),
+                                                    in_count) {
+        Some(x) => x,
+        None => {
+            if in_count < 2 || (balance == 0.0 && !swap) { return None }
+            // identity, so that balance and swap have something to work on
+            let mut matrix = vec![0.0; in_count * in_count];
+            for n in 0 .. in_count { matrix[n * in_count + n] = 1.0 }
+            (in_count, matrix)
+        },
+    };
+    if out_count >= 2 {
+        if swap {
+            for n in 0 .. in_count { matrix.swap(n, in_count + n) }
+        }
+        let left_gain = (1.0 - balance).min(1.0);
+        let right_gain = (1.0 + balance).min(1.0);
+        for n in 0 .. in_count {
+            matrix[n] *= left_gain;
+            matrix[in_count + n] *= right_gain;
+        }
+    }
+    let mut ret = bufring::get_buf();
+    ret.reserve(data.len() / in_count * out_count);
+    for frame in data.chunks_exact(in_count) {
+        for row in matrix.chunks_exact(in_count) {
+            ret.push(row.iter().zip(frame.iter()).map(|(a, b)| a * b).sum());
+        }
+    }
+    Some((out_count as i32, ret))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: f64 = 48000.0;
+    const AMPLITUDE: f64 = 0.25;
+
+    /// Runs a sine wave of the given frequency through the given EQ, and
+    /// returns how much louder (or quieter) it came out, in dB.
+    fn measure(eq: &EqPreset, frequency: f64) -> f64 {
+        // Five seconds, of which the first is left for the filters to settle.
+        // (Four seconds is a whole number of cycles at every frequency we
+        // try, so the measurement comes out exact.)
+        let mut data: Vec<f32> = (0 .. SAMPLE_RATE as usize * 5).map(|n| {
+            let t = n as f64 / SAMPLE_RATE;
+            ((2.0 * PI * frequency * t).sin() * AMPLITUDE) as f32
+        }).collect();
+        let mut chain = Chain::new();
+        // (in pieces, so that state has to carry over between calls)
+        for chunk in data.chunks_mut(1000) {
+            chain.process(Some(eq), SAMPLE_RATE, 1, chunk);
+        }
+        let skip = SAMPLE_RATE as usize;
+        let (mut sin, mut cos) = (0.0, 0.0);
+        for (n, &y) in data.iter().enumerate().skip(skip) {
+            let w = 2.0 * PI * frequency * n as f64 / SAMPLE_RATE;
+            sin += y as f64 * w.sin();
+            cos += y as f64 * w.cos();
+        }
+        let count = (data.len() - skip) as f64;
+        let amplitude = (sin * sin + cos * cos).sqrt() * 2.0 / count;
+        20.0 * (amplitude / AMPLITUDE).log10()
+    }
+
+    fn assert_gain(eq: &EqPreset, frequency: f64, expected: f64) {
+        let gain = measure(eq, frequency);
+        assert!((gain - expected).abs() < 0.25,
+                "at {} Hz: expected {:.2} dB, got {:.2} dB",
+                frequency, expected, gain);
+    }
+
+    fn parametric(band: EqBand) -> EqPreset {
+        EqPreset { parametric: vec![band], ..Default::default() }
+    }
+
+    #[test]
+    fn flat_is_untouched() {
+        let original: Vec<f32> = (0 .. 4800).map(|n| (n as f32 * 0.01).sin())
+            .collect();
+        let flat = get_builtin_presets().remove(FLAT_PRESET).unwrap();
+        assert!(flat.is_flat());
+        let mut data = original.clone();
+        let mut chain = Chain::new();
+        chain.process(Some(&flat), SAMPLE_RATE, 2, &mut data);
+        assert_eq!(data, original);
+        chain.process(None, SAMPLE_RATE, 2, &mut data);
+        assert_eq!(data, original);
+    }
+
+    #[test]
+    fn graphic_bands() {
+        for (n, &frequency) in GRAPHIC_EQ_FREQUENCIES.iter().enumerate() {
+            let mut eq = EqPreset::default();
+            eq.graphic[n] = 6.0;
+            assert_gain(&eq, frequency, 6.0);
+            eq.graphic[n] = -6.0;
+            assert_gain(&eq, frequency, -6.0);
+            // A few octaves away, the band has no effect.
+            let away = if frequency < 1000.0 { frequency * 16.0 }
+            else { frequency / 16.0 };
+            assert_gain(&eq, away, 0.0);
+        }
+    }
+
+    #[test]
+    fn peak() {
+        let eq = parametric(EqBand { kind: BandKind::Peak, frequency: 2500.0,
+                                     gain: -9.0, q: 2.0 });
+        assert_gain(&eq, 2500.0, -9.0);
+        assert_gain(&eq, 50.0, 0.0);
+        assert_gain(&eq, 20000.0, 0.0);
+    }
+
+    #[test]
+    fn shelves() {
+        let low = parametric(EqBand { kind: BandKind::LowShelf,
+                                      frequency: 1000.0, gain: 6.0,
+                                      q: get_standard_q() });
+        assert_gain(&low, 25.0, 6.0);
+        assert_gain(&low, 1000.0, 3.0);
+        assert_gain(&low, 16000.0, 0.0);
+        let high = parametric(EqBand { kind: BandKind::HighShelf,
+                                       frequency: 1000.0, gain: -6.0,
+                                       q: get_standard_q() });
+        assert_gain(&high, 25.0, 0.0);
+        assert_gain(&high, 1000.0, -3.0);
+        assert_gain(&high, 16000.0, -6.0);
+    }
+
+    #[test]
+    fn limits() {
+        // Gains are clamped.
+        let eq = parametric(EqBand { kind: BandKind::Peak, frequency: 1000.0,
+                                     gain: 40.0, q: 1.0 });
+        assert_gain(&eq, 1000.0, MAX_EQ_GAIN);
+        // Bands too close to the Nyquist frequency are left out.
+        let eq = parametric(EqBand { kind: BandKind::Peak, frequency: 23000.0,
+                                     gain: 12.0, q: 1.0 });
+        assert_gain(&eq, 20000.0, 0.0);
+        // The preamp applies everywhere.
+        let eq = EqPreset { preamp: -6.0, ..Default::default() };
+        assert_gain(&eq, 100.0, -6.0);
+        assert_gain(&eq, 10000.0, -6.0);
+    }
+}
@@ -0,0 +1,448 @@
+//! This module reads podcast feeds, in either RSS or Atom format.
+//!
+//! We only care about enough XML to find the feed's title and the episodes'
+//! audio files and metadata. The XML reader is forgiving: anything it doesn't
+//! understand is ignored, and unclosed elements are closed for it.
+
+use anyhow::anyhow;
+
+/// A feed, as far as we care.
+#[derive(Debug,Default)]
+pub struct Feed {
+    pub title: Option<String>,
+    pub author: Option<String>,
+    /// The feed's episodes, in the order the feed lists them. (Usually, that's
+    /// newest first.)
+    pub episodes: Vec<FeedEpisode>,
+}
+
+/// One episode of a feed. Items in the feed that don't have an audio file
+/// attached aren't episodes.
+#[derive(Debug,Default,Clone)]
+pub struct FeedEpisode {
+    /// Something that identifies this episode forever, even if the feed
+    /// changes its title or moves its audio file. Falls back to the URL.
+    pub guid: String,
+    /// Where to download the episode's audio from.
+    pub url: String,
+    pub title: Option<String>,
+    pub author: Option<String>,
+    /// When the episode was published, exactly as the feed gave it.
+    pub date: Option<String>,
+    /// The episode's description, as plain text.
+    pub description: Option<String>,
+    /// The episode's number, if the feed gives one.
+    pub number: Option<String>,
+}
+
+#[derive(Debug)]
+enum Node {
+    Element(Element),
+    Text(String),
+}
+
+#[derive(Debug,Default)]
+struct Element {
+    name: String,
+    attributes: Vec<(String, String)>,
+    children: Vec<Node>,
+}
+
+impl Element {
+    fn new(name: &str) -> Element {
+        Element { name: name.to_owned(), ..Default::default() }
+    }
+    fn attribute(&self, name: &str) -> Option<&str> {
+        self.attributes.iter().find(|x| x.0 == name).map(|x| x.1.as_str())
+    }
+    fn children_named<'a>(&'a self, name: &'a str)
+    -> impl Iterator<Item=&'a Element> {
+        self.children.iter().filter_map(move |x| match x {
+            Node::Element(x) if x.name == name => Some(x),
+            _ => None,
+        })
+    }
+    /// Returns the (trimmed) text directly inside this element.
+    fn text(&self) -> String {
+        let mut ret = String::new();
+        for child in self.children.iter() {
+            if let Node::Text(x) = child { ret += x }
+        }
+        ret.trim().to_owned()
+    }
+    /// Returns the text of the first child with any of the given names, in
+    /// order of preference, skipping empty ones.
+    fn child_text(&self, names: &[&str]) -> Option<String> {
+        names.iter().filter_map(|name| {
+            self.children_named(name).map(Element::text)
+                .find(|x| !x.is_empty())
+        }).next()
+    }
+}
+
+/// Replaces XML (and HTML) character references with the characters they
+/// stand for. Ones we don't know are left alone.
+fn decode_entities(text: &str) -> String {
+    let mut ret = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(amp) = rest.find('&') {
+        ret += &rest[..amp];
+        rest = &rest[amp..];
+        let semi = match rest.bytes().take(12).position(|x| x == b';') {
+            Some(x) => x,
+            None => { ret.push('&'); rest = &rest[1..]; continue },
+        };
+        let entity = &rest[1..semi];
+        let decoded = match entity {
+            "amp" => Some('&'),
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            "nbsp" => Some(' '),
+            x if x.starts_with("#x") || x.starts_with("#X") =>
+                u32::from_str_radix(&x[2..], 16).ok()
+                .and_then(std::char::from_u32),
+            x if x.starts_with('#') =>
+                x[1..].parse().ok().and_then(std::char::from_u32),
+            _ => None,
+        };
+        match decoded {
+            Some(x) => { ret.push(x); rest = &rest[semi+1..] },
+            None => { ret.push('&'); rest = &rest[1..] },
+        }
+    }
+    ret += rest;
+    ret
+}
+
+/// Turns an HTML description into plain text, more or less.
+fn strip_html(text: &str) -> String {
+    let mut ret = String::with_capacity(text.len());
+    let mut in_tag = false;
+    for c in text.chars() {
+        match c {
+            '<' => { in_tag = true; ret.push(' ') },
+            '>' if in_tag => in_tag = false,
+            _ if in_tag => (),
+            _ => ret.push(c),
+        }
+    }
+    let ret = decode_entities(&ret);
+    ret.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// Parses the inside of a tag (between `<` and `>`) into a new element.
+fn parse_tag(tag: &str) -> Element {
+    let name_end = tag.find(char::is_whitespace).unwrap_or(tag.len());
+    let mut ret = Element::new(&tag[..name_end]);
+    let mut rest = tag[name_end..].trim_start();
+    while !rest.is_empty() {
+        let key_end = rest.find(|c: char| c == '=' || c.is_whitespace())
+            .unwrap_or(rest.len());
+        let key = &rest[..key_end];
+        rest = rest[key_end..].trim_start();
+        if !rest.starts_with('=') {
+            // an attribute with no value; ignore it
+            continue
+        }
+        rest = rest[1..].trim_start();
+        let quote = match rest.chars().next() {
+            Some(x) if x == '"' || x == '\'' => x,
+            _ => break,
+        };
+        let value_end = rest[1..].find(quote).map(|x| x + 1)
+            .unwrap_or(rest.len());
+        ret.attributes.push((key.to_owned(),
+                             decode_entities(&rest[1..value_end])));
+        rest = rest[(value_end + 1).min(rest.len())..].trim_start();
+    }
+    ret
+}
+
+/// Finds the `>` that ends the tag starting at the beginning of `text`,
+/// skipping any inside quoted attribute values.
+fn find_tag_end(text: &str) -> Option<usize> {
+    let mut quote = None;
+    for (n, c) in text.char_indices() {
+        match quote {
+            Some(q) if c == q => quote = None,
+            Some(_) => (),
+            None if c == '"' || c == '\'' => quote = Some(c),
+            None if c == '>' => return Some(n),
+            None => (),
+        }
+    }
+    None
+}
+
+/// Reads an XML document, returning its root element.
+fn parse_xml(text: &str) -> anyhow::Result<Element> {
+    // The bottom of the stack holds the root element (and any junk around
+    // it).
+    let mut stack = vec![Element::new("")];
+    fn close(stack: &mut Vec<Element>) {
+        let element = stack.pop().unwrap();
+        stack.last_mut().unwrap().children.push(Node::Element(element));
+    }
+    let mut rest = text;
+    while !rest.is_empty() {
+        if rest.starts_with("<!--") {
+            rest = match rest.find("-->") {
+                Some(x) => &rest[x+3..],
+                None => "",
+            };
+        }
+        else if rest.starts_with("<![CDATA[") {
+            let inner = &rest[9..];
+            let end = inner.find("]]>").unwrap_or(inner.len());
+            stack.last_mut().unwrap().children
+                .push(Node::Text(inner[..end].to_owned()));
+            rest = &inner[(end + 3).min(inner.len())..];
+        }
+        else if rest.starts_with("<?") || rest.starts_with("<!") {
+            rest = match rest.find('>') {
+                Some(x) => &rest[x+1..],
+                None => "",
+            };
+        }
+        else if rest.starts_with("</") {
+            let end = rest.find('>').unwrap_or(rest.len());
+            let name = rest[2..end].trim();
+            // close everything up to (and including) the matching element,
+            // if there is one
+            let open = stack.iter().skip(1).rposition(|x| x.name == name);
+            if let Some(pos) = open {
+                while stack.len() > pos + 1 { close(&mut stack) }
+            }
+            rest = &rest[(end + 1).min(rest.len())..];
+        }
+        else if rest.starts_with('<') {
+            let end = find_tag_end(rest).unwrap_or(rest.len());
+            let tag = &rest[1..end];
+            let self_closing = tag.ends_with('/');
+            let tag = if self_closing { &tag[..tag.len()-1] } else { tag };
+            stack.push(parse_tag(tag.trim()));
+            if self_closing { close(&mut stack) }
+            rest = &rest[(end + 1).min(rest.len())..];
+        }
+        else {
+            let end = rest.find('<').unwrap_or(rest.len());
+            stack.last_mut().unwrap().children
+                .push(Node::Text(decode_entities(&rest[..end])));
+            rest = &rest[end..];
+        }
+    }
+    while stack.len() > 1 { close(&mut stack) }
+    stack.pop().unwrap().children.into_iter().filter_map(|x| match x {
+        Node::Element(x) => Some(x),
+        _ => None,
+    }).next().ok_or_else(|| anyhow!("Not an XML document"))
+}
+
+/// Reads an RSS `<channel>`.
+fn read_rss(channel: &Element) -> Feed {
+    let author = channel.child_text(&["itunes:author", "author",
+                                      "managingEditor"]);
+    let episodes = channel.children_named("item").filter_map(|item| {
+        let url = item.children_named("enclosure")
+            .filter_map(|x| x.attribute("url"))
+            .map(str::to_owned).next()?;
+        Some(FeedEpisode {
+            guid: item.child_text(&["guid"]).unwrap_or_else(|| url.clone()),
+            title: item.child_text(&["title", "itunes:title"]),
+            author: item.child_text(&["itunes:author", "author",
+                                      "dc:creator"]),
+            date: item.child_text(&["pubDate", "dc:date"]),
+            description: item.child_text(&["itunes:summary", "description",
+                                           "content:encoded"])
+                .map(|x| strip_html(&x)),
+            number: item.child_text(&["itunes:episode"]),
+            url,
+        })
+    }).collect();
+    Feed { title: channel.child_text(&["title"]), author, episodes }
+}
+
+/// Reads an Atom `<feed>`.
+fn read_atom(feed: &Element) -> Feed {
+    let author_of = |element: &Element| {
+        element.children_named("author")
+            .filter_map(|x| x.child_text(&["name"])).next()
+    };
+    let episodes = feed.children_named("entry").filter_map(|entry| {
+        let url = entry.children_named("link")
+            .filter(|x| x.attribute("rel") == Some("enclosure"))
+            .filter_map(|x| x.attribute("href"))
+            .map(str::to_owned).next()?;
+        Some(FeedEpisode {
+            guid: entry.child_text(&["id"]).unwrap_or_else(|| url.clone()),
+            title: entry.child_text(&["title"]),
+            author: author_of(entry),
+            date: entry.child_text(&["published", "updated"]),
+            description: entry.child_text(&["summary", "content"])
+                .map(|x| strip_html(&x)),
+            number: None,
+            url,
+        })
+    }).collect();
+    Feed { title: feed.child_text(&["title"]), author: author_of(feed),
+           episodes }
+}
+
+/// Reads a feed. Feeds in the wild are nearly always UTF-8; anything that
+/// isn't valid UTF-8 gets patched up rather than rejected.
+pub fn parse(bytes: &[u8]) -> anyhow::Result<Feed> {
+    let text = String::from_utf8_lossy(bytes);
+    let text = text.trim_start_matches('\u{FEFF}');
+    let root = parse_xml(text)?;
+    match root.name.as_str() {
+        "rss" => root.children_named("channel").next().map(read_rss)
+            .ok_or_else(|| anyhow!("RSS feed has no channel")),
+        "feed" => Ok(read_atom(&root)),
+        x => Err(anyhow!("Not an RSS or Atom feed (root element is <{}>)",
+                         x)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RSS: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<rss version="2.0" xmlns:itunes="http://www.itunes.com/dtds/podcast-1.0.dtd">
+<channel>
+  <title>Tom &amp; Jerry&#39;s Show</title>
+  <itunes:author>Tom</itunes:author>
+  <!-- <item><enclosure url="http://example.com/comment.mp3"/></item> -->
+  <item>
+    <title><![CDATA[Fish & Chips]]></title>
+    <guid isPermaLink="false">episode-2</guid>
+    <pubDate>Tue, 10 Jun 2003 04:00:00 GMT</pubDate>
+    <itunes:episode>2</itunes:episode>
+    <itunes:author>Jerry</itunes:author>
+    <description><![CDATA[<p>Caf&eacute; <b>and</b>
+      caf&#233; &amp; caf&#xE9;</p>]]></description>
+    <enclosure url="http://example.com/2.mp3?a=1&amp;b=2" length="1"
+               type='audio/mpeg'/>
+  </item>
+  <item>
+    <title>Not an episode</title>
+    <link>http://example.com/blog</link>
+  </item>
+  <item>
+    <title>No guid</title>
+    <guid></guid>
+    <enclosure type="audio/ogg" url="http://example.com/1.ogg" />
+  </item>
+</channel>
+</rss>"#;
+
+    const ATOM: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<feed xmlns="http://www.w3.org/2005/Atom">
+  <title>An Atom Feed</title>
+  <author><name>Alice</name></author>
+  <entry>
+    <title>Second</title>
+    <id>urn:uuid:2</id>
+    <updated>2003-06-11T04:00:00Z</updated>
+    <published>2003-06-10T04:00:00Z</published>
+    <summary type="html">&lt;i&gt;Very&lt;/i&gt; good</summary>
+    <link rel="alternate" href="http://example.com/2.html"/>
+    <link rel="enclosure" href="http://example.com/2.m4a"/>
+  </entry>
+  <entry>
+    <title>Just a post</title>
+    <id>urn:uuid:post</id>
+    <link href="http://example.com/post.html"/>
+  </entry>
+  <entry>
+    <title>First</title>
+    <author><name>Bob</name></author>
+    <updated>2003-06-01T04:00:00Z</updated>
+    <link href="http://example.com/1.m4a" rel="enclosure"/>
+  </entry>
+</feed>"#;
+
+    #[test]
+    fn rss() {
+        let feed = parse(RSS.as_bytes()).unwrap();
+        assert_eq!(feed.title.as_deref(), Some("Tom & Jerry's Show"));
+        assert_eq!(feed.author.as_deref(), Some("Tom"));
+        assert_eq!(feed.episodes.len(), 2);
+        let first = &feed.episodes[0];
+        assert_eq!(first.guid, "episode-2");
+        assert_eq!(first.url, "http://example.com/2.mp3?a=1&b=2");
+        assert_eq!(first.title.as_deref(), Some("Fish & Chips"));
+        assert_eq!(first.author.as_deref(), Some("Jerry"));
+        assert_eq!(first.date.as_deref(),
+                   Some("Tue, 10 Jun 2003 04:00:00 GMT"));
+        assert_eq!(first.number.as_deref(), Some("2"));
+        // (`&eacute;` isn't one we know)
+        assert_eq!(first.description.as_deref(),
+                   Some("Caf&eacute; and café & café"));
+        let second = &feed.episodes[1];
+        assert_eq!(second.guid, "http://example.com/1.ogg");
+        assert_eq!(second.url, "http://example.com/1.ogg");
+        assert_eq!(second.title.as_deref(), Some("No guid"));
+        assert_eq!(second.author, None);
+        assert_eq!(second.date, None);
+        assert_eq!(second.description, None);
+    }
+
+    #[test]
+    fn atom() {
+        let feed = parse(ATOM.as_bytes()).unwrap();
+        assert_eq!(feed.title.as_deref(), Some("An Atom Feed"));
+        assert_eq!(feed.author.as_deref(), Some("Alice"));
+        assert_eq!(feed.episodes.len(), 2);
+        let first = &feed.episodes[0];
+        assert_eq!(first.guid, "urn:uuid:2");
+        assert_eq!(first.url, "http://example.com/2.m4a");
+        assert_eq!(first.title.as_deref(), Some("Second"));
+        // (the feed's author is applied later, by the podcast code)
+        assert_eq!(first.author, None);
+        assert_eq!(first.date.as_deref(), Some("2003-06-10T04:00:00Z"));
+        assert_eq!(first.description.as_deref(), Some("Very good"));
+        let second = &feed.episodes[1];
+        assert_eq!(second.guid, "http://example.com/1.m4a");
+        assert_eq!(second.author.as_deref(), Some("Bob"));
+        assert_eq!(second.date.as_deref(), Some("2003-06-01T04:00:00Z"));
+    }
+
+    #[test]
+    fn forgiving() {
+        // a byte order mark, bad UTF-8, and elements that are never closed
+        let mut bytes = "\u{FEFF}<rss><channel><title>Broken ".as_bytes()
+            .to_vec();
+        bytes.push(0xFF);
+        bytes.extend_from_slice(b"</title><item><title>Last</title>\
+                                  <enclosure url=\"http://x/a b.mp3\">");
+        let feed = parse(&bytes[..]).unwrap();
+        assert_eq!(feed.title.as_deref(), Some("Broken \u{FFFD}"));
+        assert_eq!(feed.episodes.len(), 1);
+        assert_eq!(feed.episodes[0].title.as_deref(), Some("Last"));
+        assert_eq!(feed.episodes[0].url, "http://x/a b.mp3");
+    }
+
+    #[test]
+    fn not_feeds() {
+        assert!(parse(b"").is_err());
+        assert!(parse(b"just some text").is_err());
+        assert!(parse(b"<html><body>Hi</body></html>").is_err());
+        assert!(parse(b"<rss version=\"2.0\"></rss>").is_err());
+        assert!(parse(b"<feed></feed>").unwrap().episodes.is_empty());
+    }
+
+    #[test]
+    fn entities() {
+        assert_eq!(decode_entities("a &lt;b&gt; &quot;c&quot; &apos;d&apos;"),
+                   "a <b> \"c\" 'd'");
+        assert_eq!(decode_entities("&#65;&#x42;&#X43;"), "ABC");
+        assert_eq!(decode_entities("AT&T; R&D &bogus; &#xZZ; &"),
+                   "AT&T; R&D &bogus; &#xZZ; &");
+        assert_eq!(decode_entities("&amp;amp;"), "&amp;");
+        assert_eq!(strip_html("<p>One</p>\n<p>Two&nbsp;&amp; three</p>"),
+                   "One Two & three");
+    }
+}
@@ -7,8 +7,9 @@ use ffmpeg_dev::extra::defs as ffdefs;
 use std::{
     collections::BTreeMap,
     ffi::{CStr, CString},
+    io::Write,
     path::Path,
-    ptr::null_mut,
+    ptr::{null, null_mut},
     mem::transmute,
 };
 use crate::bufring;
@@ -85,6 +86,265 @@ fn float_time_to_fftime(ftime: f64, inner: &ff::AVFormatContext,
 }
 // TODO: fftime_to_float_time
 
+/// How long to wait for a network stream to send us something before giving
+/// up on it, in microseconds. (Decoding happens with the playback state
+/// locked, so we mustn't wait forever.)
+const NETWORK_TIMEOUT: &str = "10000000";
+
+/// Picks the stream title out of an ICY metadata packet, which looks like
+/// `StreamTitle='Artist - Title';StreamUrl='...';`. The title may itself
+/// contain apostrophes.
+fn parse_icy_title(packet: &str) -> Option<String> {
+    const PREFIX: &str = "StreamTitle='";
+    let rest = &packet[packet.find(PREFIX)? + PREFIX.len() ..];
+    let end = rest.find("';").or_else(|| rest.rfind('\''))
+        .unwrap_or(rest.len());
+    Some(rest[..end].trim().to_owned()).filter(|x| !x.is_empty())
+}
+
+/// Makes an `AVDictionary` of options, to pass to FFMPEG. The caller must
+/// free it with `av_dict_free`.
+fn make_dict(options: &[(&str, &str)]) -> *mut ff::AVDictionary {
+    let mut dict: *mut ff::AVDictionary = null_mut();
+    for &(key, value) in options.iter() {
+        let key = CString::new(key).unwrap();
+        let value = CString::new(value).unwrap();
+        unsafe { ff::av_dict_set(&mut dict, key.as_ptr(), value.as_ptr(), 0) };
+    }
+    dict
+}
+
+/// Makes an `AVDictionary` of metadata, to give to a muxer. Keys or values
+/// containing null characters are left out.
+fn make_metadata_dict(metadata: &BTreeMap<String, String>)
+-> *mut ff::AVDictionary {
+    let options: Vec<(&str, &str)> = metadata.iter()
+        .filter(|(k, v)| !k.contains('\0') && !v.contains('\0'))
+        .map(|(k, v)| (k.as_str(), v.as_str())).collect();
+    make_dict(&options[..])
+}
+
+/// Converts a path into something we can hand to FFMPEG.
+fn path_to_cstring(path: &Path) -> anyhow::Result<CString> {
+    let path_str = path.to_str()
+        .ok_or_else(|| anyhow!("Path contains invalid UTF-8"))?;
+    CString::new(path_str)
+        .map_err(|_| anyhow!("Path contains a null character"))
+}
+
+/// Guesses which container format belongs in a file with the given name, the
+/// way FFMPEG would if asked to write to it. Returns the muxer's name.
+pub fn guess_container(path: &Path) -> Option<String> {
+    let path = path_to_cstring(path).ok()?;
+    let format = unsafe {
+        ff::av_guess_format(null(), path.as_ptr(), null()).as_ref()
+    }?;
+    Some(unsafe { CStr::from_ptr(format.name) }.to_string_lossy()
+         .into_owned())
+}
+
+/// An output `AVFormatContext` writing to a file. Closes the file and frees
+/// the context when dropped.
+struct OutputFile(*mut ff::AVFormatContext);
+
+impl Drop for OutputFile {
+    fn drop(&mut self) {
+        unsafe {
+            if let Some(muxer) = self.0.as_mut() {
+                ff::avio_closep(&mut muxer.pb);
+                ff::avformat_free_context(self.0);
+                self.0 = null_mut();
+            }
+        }
+    }
+}
+
+/// Puts the given metadata on top of an `AVDictionary`, replacing any entries
+/// with the same keys (ignoring case, as FFMPEG does). Keys or values
+/// containing null characters are left out.
+fn overlay_metadata_dict(dict: &mut *mut ff::AVDictionary,
+                         metadata: &BTreeMap<String, String>) {
+    for (key, value) in metadata.iter() {
+        let (key, value) = match (CString::new(key.as_str()),
+                                  CString::new(value.as_str())) {
+            (Ok(key), Ok(value)) => (key, value),
+            _ => continue,
+        };
+        unsafe { ff::av_dict_set(dict, key.as_ptr(), value.as_ptr(), 0) };
+    }
+}
+
+/// Copies the best audio stream of the file at `src` into a new file at `dst`,
+/// in the named container format, without decoding it. The new file gets
+/// `metadata` instead of whatever metadata `src` had.
+pub fn remux(src: &Path, dst: &Path, container: &str,
+             metadata: &BTreeMap<String, String>) -> anyhow::Result<()> {
+    copy_streams(src, dst, container, metadata, false)
+}
+
+/// Copies every stream of the file at `src` (cover art and all) into a new
+/// file at `dst`, in the named container format, without decoding anything.
+/// `metadata` is put on top of the metadata `src` already had. Fails, rather
+/// than leave something out, if `src` has chapters or streams that can't be
+/// copied this way.
+pub fn retag(src: &Path, dst: &Path, container: &str,
+             metadata: &BTreeMap<String, String>) -> anyhow::Result<()> {
+    copy_streams(src, dst, container, metadata, true)
+}
+
+/// Does the work of `remux` and `retag`.
+fn copy_streams(src: &Path, dst: &Path, container: &str,
+                metadata: &BTreeMap<String, String>, everything: bool)
+-> anyhow::Result<()> {
+    let mut input = AVFormat::open_input(src)?;
+    input.find_stream_info()?;
+    let best_stream = input.find_best_stream()?
+        .ok_or_else(|| anyhow!("Not a music file?"))?;
+    let in_ctx = unsafe { input.inner.as_ref() }.unwrap();
+    let streams: Vec<libc::c_int> = if everything {
+        if in_ctx.nb_chapters > 0 {
+            return Err(anyhow!("The file has chapters, which can't be \
+                                copied"))
+        }
+        (0 .. in_ctx.nb_streams as libc::c_int).collect()
+    }
+    else { vec![best_stream] };
+    let container = CString::new(container).unwrap();
+    let dst = path_to_cstring(dst)?;
+    let mut output = OutputFile(null_mut());
+    unsafe {
+        fferr_lt(ff::avformat_alloc_output_context2
+                 (&mut output.0, null_mut(), container.as_ptr(), null()))?;
+        let muxer = output.0.as_mut().unwrap();
+        // (input stream, output stream) for each input stream we copy, and
+        // `None` for each one we don't
+        let mut mapping: Vec<Option<(*const ff::AVStream, *mut ff::AVStream)>>
+            = vec![None; in_ctx.nb_streams as usize];
+        for &stream in streams.iter() {
+            let in_stream = input.get_stream_ref(stream);
+            let codec_type = (*in_stream.codecpar).codec_type;
+            if codec_type != ff::AVMediaType_AVMEDIA_TYPE_AUDIO
+                && codec_type != ff::AVMediaType_AVMEDIA_TYPE_VIDEO {
+                    return Err(anyhow!("The file has a stream that can't be \
+                                        copied"))
+                }
+            let out_stream = ff::avformat_new_stream(muxer, null()).as_mut()
+                .ok_or_else(|| anyhow!("Couldn't add a stream"))?;
+            fferr_lt(ff::avcodec_parameters_copy(out_stream.codecpar,
+                                                 in_stream.codecpar))?;
+            // (the source container's idea of the codec tag may not make
+            // sense in the new one)
+            (*out_stream.codecpar).codec_tag = 0;
+            out_stream.time_base = in_stream.time_base;
+            if everything {
+                out_stream.disposition = in_stream.disposition;
+                fferr_lt(ff::av_dict_copy(&mut out_stream.metadata,
+                                          in_stream.metadata, 0))?;
+                // Some containers (e.g. Ogg) keep their tags on the audio
+                // stream rather than on the file.
+                if stream == best_stream && !in_stream.metadata.is_null() {
+                    overlay_metadata_dict(&mut out_stream.metadata,
+                                          metadata);
+                }
+            }
+            mapping[stream as usize]
+                = Some((in_stream as *const ff::AVStream,
+                        out_stream as *mut ff::AVStream));
+        }
+        if everything {
+            fferr_lt(ff::av_dict_copy(&mut muxer.metadata, in_ctx.metadata,
+                                      0))?;
+            overlay_metadata_dict(&mut muxer.metadata, metadata);
+        }
+        else {
+            muxer.metadata = make_metadata_dict(metadata);
+        }
+        fferr_lt(ff::avio_open(&mut muxer.pb, dst.as_ptr(),
+                               ff::AVIO_FLAG_WRITE as libc::c_int))?;
+        fferr_lt(ff::avformat_write_header(muxer, null_mut()))?;
+        let mut packet: ff::AVPacket = std::mem::zeroed();
+        ff::av_init_packet(&mut packet);
+        loop {
+            match ff::av_read_frame(input.inner, &mut packet) {
+                0 => (),
+                x if x == ffdefs::averror_eof() => break,
+                x => return Err(anyhow!("{}", ffres_to_string(x))),
+            }
+            let (in_stream, out_stream) = match mapping
+                .get(packet.stream_index as usize).cloned().flatten() {
+                    Some(x) => x,
+                    None => {
+                        ff::av_packet_unref(&mut packet);
+                        continue
+                    },
+                };
+            packet.stream_index = (*out_stream).index;
+            packet.pos = -1;
+            // (writing the header may have changed the stream's time base)
+            ff::av_packet_rescale_ts(&mut packet, (*in_stream).time_base,
+                                     (*out_stream).time_base);
+            // (this takes the packet's data off our hands, even if it fails)
+            fferr_lt(ff::av_interleaved_write_frame(muxer, &mut packet))?;
+        }
+        fferr_lt(ff::av_write_trailer(muxer))?;
+    }
+    Ok(())
+}
+
+/// Downloads whatever is at the given URL (using FFMPEG's own HTTP client),
+/// writing it to `out` as it arrives.
+pub fn read_url(url: &str, out: &mut dyn Write) -> anyhow::Result<()> {
+    let url_cstring = CString::new(url)
+        .map_err(|_| anyhow!("URL contains a null character"))?;
+    let mut dict = make_dict(&[("rw_timeout", NETWORK_TIMEOUT),
+                               ("user_agent", "Tsong")]);
+    let mut ctx: *mut ff::AVIOContext = null_mut();
+    let res = unsafe {
+        ff::avio_open2(&mut ctx, url_cstring.as_ptr(),
+                       ff::AVIO_FLAG_READ as libc::c_int, null_mut(),
+                       &mut dict)
+    };
+    unsafe { ff::av_dict_free(&mut dict) };
+    fferr_lt(res)?;
+    let mut buf = vec![0u8; 65536];
+    let ret = loop {
+        let red = unsafe {
+            ff::avio_read(ctx, buf.as_mut_ptr(), buf.len() as libc::c_int)
+        };
+        if red == 0 || red == unsafe { ffdefs::averror_eof() } {
+            break Ok(())
+        }
+        else if red < 0 {
+            break Err(anyhow!("{}", ffres_to_string(red)))
+        }
+        else if let Err(x) = out.write_all(&buf[..red as usize]) {
+            break Err(x.into())
+        }
+    };
+    unsafe { ff::avio_closep(&mut ctx) };
+    ret
+}
+
+/// The error returned by `AVFormat::open_input` when FFMPEG doesn't recognize
+/// the file's format at all.
+#[derive(Debug)]
+pub struct UnrecognizedFormat;
+
+impl std::fmt::Display for UnrecognizedFormat {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        fmt.write_str("Not a recognized media format")
+    }
+}
+
+impl std::error::Error for UnrecognizedFormat {}
+
+/// A chapter of a file, as returned by `AVFormat::read_chapters`.
+pub struct Chapter {
+    pub start: f64,
+    pub end: f64,
+    pub metadata: BTreeMap<String, String>,
+}
+
 /// Wraps an (input!) `AVFormatContext`
 pub struct AVFormat {
     /// A pointer to the `AVFormatContext` that we're managing, or null if
@@ -139,13 +399,33 @@ impl AVFormat {
             Some(x) => x,
             None => return Err(anyhow!("Path contains invalid UTF-8")),
         };
-        let path_cstring = CString::new(path_str)
-            .expect("Internal error: Unable to convert path into C string?");
+        AVFormat::open(path_str, &[])
+    }
+    /// Calls `avformat_open_input` for the given network URL, asking the
+    /// server to tell us the titles of whatever it's streaming (see
+    /// `read_stream_title`).
+    pub fn open_url(url: &str) -> anyhow::Result<AVFormat> {
+        AVFormat::open(url, &[("icy", "1"),
+                              ("rw_timeout", NETWORK_TIMEOUT),
+                              ("user_agent", "Tsong")])
+    }
+    /// Does the work of `open_input` and `open_url`.
+    fn open(url: &str, options: &[(&str, &str)]) -> anyhow::Result<AVFormat> {
+        let url_cstring = CString::new(url)
+            .map_err(|_| anyhow!("Path contains a null character"))?;
+        let mut dict = make_dict(options);
         let mut inner: *mut ff::AVFormatContext = null_mut();
-        fferr_ne(unsafe { ff::avformat_open_input(&mut inner,
-                                                  path_cstring.as_ptr(),
-                                                  null_mut(),
-                                                  null_mut())})?;
+        let res = unsafe { ff::avformat_open_input(&mut inner,
+                                                   url_cstring.as_ptr(),
+                                                   null_mut(),
+                                                   &mut dict) };
+        // (whatever options weren't used are left in the dictionary)
+        unsafe { ff::av_dict_free(&mut dict) };
+        match res {
+            x if x == unsafe { ffdefs::averror_invaliddata() } =>
+                return Err(UnrecognizedFormat.into()),
+            x => fferr_ne(x)?,
+        }
         assert!(!inner.is_null());
         Ok(AVFormat { inner, codec_ctx: null_mut(), stream: -1,
                       frame: null_mut(),
@@ -177,6 +457,23 @@ impl AVFormat {
             x => fferr_lt(x).map(|x| Some(x) /* not reached */),
         }
     }
+    /// Returns true if the file contains at least one video stream. Cover art
+    /// embedded in an audio file doesn't count.
+    ///
+    /// Make sure to call `find_stream_info` first.
+    pub fn has_video_stream(&self) -> bool {
+        let inner = unsafe { self.inner.as_ref() }.unwrap();
+        (0 .. inner.nb_streams as libc::c_int).any(|n| {
+            let stream_ref = self.get_stream_ref(n);
+            let codecpar = match unsafe { stream_ref.codecpar.as_ref() } {
+                Some(x) => x,
+                None => return false,
+            };
+            codecpar.codec_type == ff::AVMediaType_AVMEDIA_TYPE_VIDEO
+                && (stream_ref.disposition as u32
+                    & ff::AV_DISPOSITION_ATTACHED_PIC) == 0
+        })
+    }
     /// Reads the metadata for the file, and for the given stream. Returns it
     /// in aggregate.
     pub fn read_metadata(&mut self, stream: Option<libc::c_int>)
@@ -190,6 +487,68 @@ impl AVFormat {
         }
         ret
     }
+    /// Reads the chapters of the file, if it has any. Chapter times are in
+    /// seconds, on the same timeline as the times passed to `decode_some`'s
+    /// handler.
+    pub fn read_chapters(&self) -> Vec<Chapter> {
+        let inner = unsafe { self.inner.as_ref() }.unwrap();
+        let mut ret = Vec::with_capacity(inner.nb_chapters as usize);
+        for n in 0 .. inner.nb_chapters as isize {
+            let chapter = match unsafe {
+                inner.chapters.offset(n).read().as_ref()
+            } {
+                Some(x) => x,
+                None => continue,
+            };
+            let time_base = chapter.time_base.num as f64
+                / chapter.time_base.den as f64;
+            let mut metadata = BTreeMap::new();
+            transcribe_dict(&mut metadata, chapter.metadata);
+            ret.push(Chapter {
+                start: chapter.start as f64 * time_base,
+                end: chapter.end as f64 * time_base,
+                metadata,
+            });
+        }
+        ret
+    }
+    /// Returns the title of whatever a network stream is playing right now,
+    /// if the server has told us. (This is the "ICY" metadata sent by
+    /// Shoutcast and Icecast servers, and it changes as the stream goes on.)
+    pub fn read_stream_title(&self) -> Option<String> {
+        let inner = unsafe { self.inner.as_ref() }?;
+        if inner.pb.is_null() { return None }
+        let name = CString::new("icy_metadata_packet").unwrap();
+        let mut packet = null_mut();
+        let res = unsafe {
+            ff::av_opt_get(inner.pb as *mut _, name.as_ptr(),
+                           ff::AV_OPT_SEARCH_CHILDREN as libc::c_int,
+                           &mut packet)
+        };
+        if res < 0 || packet.is_null() { return None }
+        let ret = parse_icy_title(&unsafe {
+            CStr::from_ptr(packet as *const _)
+        }.to_string_lossy());
+        unsafe { ff::av_free(packet as *mut _) };
+        ret
+    }
+    /// Returns the name of the codec the given stream is encoded with, e.g.
+    /// `"mp3"` or `"flac"`.
+    pub fn get_codec_name(&self, stream: libc::c_int) -> String {
+        let stream_ref = self.get_stream_ref(stream);
+        let codecpar = unsafe { stream_ref.codecpar.as_ref() }.unwrap();
+        unsafe { CStr::from_ptr(ff::avcodec_get_name(codecpar.codec_id)) }
+            .to_string_lossy().into_owned()
+    }
+    /// Returns the bit rate of the given stream (or, if the stream doesn't
+    /// say, of the whole file), in bits per second, if it's known.
+    pub fn get_bit_rate(&self, stream: libc::c_int) -> Option<i64> {
+        let inner = unsafe { self.inner.as_ref() }.unwrap();
+        let stream_ref = self.get_stream_ref(stream);
+        let codecpar = unsafe { stream_ref.codecpar.as_ref() }.unwrap();
+        Some(codecpar.bit_rate).filter(|&x| x > 0)
+            .or(Some(inner.bit_rate).filter(|&x| x > 0))
+    }
     /// Estimates the duration of the given stream, in seconds.
     pub fn estimate_duration(&mut self, stream: libc::c_int) -> u32 {
         let inner = unsafe { self.inner.as_ref() }.unwrap();
@@ -458,6 +817,349 @@ impl Drop for AVFormat {
     }
 }
 
+/// Wraps an `AVCodecContext` opened for encoding audio, and (optionally) an
+/// output `AVFormatContext` that muxes what it encodes into a container. Takes
+/// interleaved float samples, gives back bytes ready to send somewhere (or,
+/// if made with `create_file`, writes them to a file).
+pub struct Encoder {
+    /// The encoder.
+    codec_ctx: *mut ff::AVCodecContext,
+    /// The frame we fill up with samples to hand to the encoder.
+    frame: *mut ff::AVFrame,
+    /// A single packet of encoded data from the encoder.
+    packet: ff::AVPacket,
+    /// The muxer, or null if we're sending the encoded packets as they are.
+    muxer: *mut ff::AVFormatContext,
+    /// Where the muxer's `AVIOContext` writes to. Boxed, so that it stays put.
+    muxed: Box<Vec<u8>>,
+    /// True if the muxer's `AVIOContext` writes to a file instead.
+    file_output: bool,
+    /// Whatever the muxer wrote before the first packet.
+    header: Vec<u8>,
+    /// True if the encoder wants each channel in a separate plane.
+    planar: bool,
+    channel_count: usize,
+    /// Number of sample frames in each frame we hand to the encoder.
+    frame_size: usize,
+    /// True if the encoder will take a short frame at the very end.
+    small_last_frame: bool,
+    /// Samples that didn't make up a whole frame yet.
+    pending: Vec<f32>,
+    /// The timestamp of the next frame, in sample frames.
+    pts: i64,
+}
+
+/// This can be sent, as long as it's only used from one thread at a time.
+unsafe impl Send for Encoder {}
+
+/// Reads a list that FFMPEG terminates with the given value.
+fn read_terminated_list<T: Copy + PartialEq>(mut p: *const T, end: T)
+-> Vec<T> {
+    let mut ret = Vec::new();
+    if p.is_null() { return ret }
+    loop {
+        let el = unsafe { p.read() };
+        if el == end { break }
+        ret.push(el);
+        p = unsafe { p.offset(1) };
+    }
+    ret
+}
+
+/// The `write_packet` callback for an `Encoder`'s muxer. Appends whatever the
+/// muxer writes to the `Vec<u8>` that `opaque` points to.
+unsafe extern "C" fn write_to_vec(opaque: *mut libc::c_void, buf: *mut u8,
+                                  buf_size: libc::c_int) -> libc::c_int {
+    let vec = &mut *(opaque as *mut Vec<u8>);
+    vec.extend_from_slice(std::slice::from_raw_parts(buf, buf_size as usize));
+    buf_size
+}
+
+impl Encoder {
+    /// Opens the first of the named encoders that this copy of FFMPEG has, for
+    /// the given number of channels and bit rate. The encoder runs at
+    /// `sample_rate` if it can, or at the closest rate it supports if not
+    /// (see `get_sample_rate`). If `container` is given, the output is muxed
+    /// into that format.
+    pub fn new(codec_names: &[&str], container: Option<&str>,
+               sample_rate: i32, channel_count: i32, bit_rate: i64)
+    -> anyhow::Result<Encoder> {
+        Encoder::open(codec_names, container, None, sample_rate,
+                      channel_count, bit_rate)
+    }
+    /// As `new`, but the output is muxed into the given container format and
+    /// written to a new file at `path`, with the given metadata. Call `finish`
+    /// when done, or the file won't be complete.
+    pub fn create_file(path: &Path, codec_names: &[&str], container: &str,
+                       metadata: &BTreeMap<String, String>, sample_rate: i32,
+                       channel_count: i32, bit_rate: i64)
+    -> anyhow::Result<Encoder> {
+        Encoder::open(codec_names, Some(container), Some((path, metadata)),
+                      sample_rate, channel_count, bit_rate)
+    }
+    /// Does the work of `new` and `create_file`.
+    fn open(codec_names: &[&str], container: Option<&str>,
+            file: Option<(&Path, &BTreeMap<String, String>)>,
+            sample_rate: i32, channel_count: i32, bit_rate: i64)
+    -> anyhow::Result<Encoder> {
+        let codec = codec_names.iter().find_map(|name| {
+            let name = CString::new(*name).unwrap();
+            unsafe { ff::avcodec_find_encoder_by_name(name.as_ptr()).as_ref() }
+        }).ok_or_else(|| anyhow!("This copy of FFMPEG doesn't have the {} \
+                                  encoder", codec_names.join(" or ")))?;
+        let sample_fmts = read_terminated_list
+            (codec.sample_fmts, ff::AVSampleFormat_AV_SAMPLE_FMT_NONE);
+        let planar = if sample_fmts.contains
+            (&ff::AVSampleFormat_AV_SAMPLE_FMT_FLT) { false }
+        else if sample_fmts.contains
+            (&ff::AVSampleFormat_AV_SAMPLE_FMT_FLTP) { true }
+        else {
+            return Err(anyhow!("The encoder doesn't take floating point \
+                                samples"))
+        };
+        let sample_rates = read_terminated_list(codec.supported_samplerates,
+                                                0);
+        let sample_rate = if sample_rates.is_empty()
+            || sample_rates.contains(&sample_rate) { sample_rate }
+        else {
+            *sample_rates.iter().min_by_key(|&&x| (x - sample_rate).abs())
+                .unwrap()
+        };
+        // (from here on, `Drop` cleans up after us if anything goes wrong)
+        let mut ret = Encoder {
+            codec_ctx: null_mut(), frame: null_mut(),
+            packet: unsafe { std::mem::zeroed() },
+            muxer: null_mut(), muxed: Box::new(Vec::new()),
+            file_output: false, header: Vec::new(), planar,
+            channel_count: channel_count as usize,
+            frame_size: 0, small_last_frame: false, pending: Vec::new(),
+            pts: 0,
+        };
+        unsafe {
+            ret.codec_ctx = ff::avcodec_alloc_context3(codec);
+            let ctx = ret.codec_ctx.as_mut()
+                .ok_or_else(|| anyhow!("Couldn't allocate the encoder"))?;
+            ctx.sample_fmt = if planar {
+                ff::AVSampleFormat_AV_SAMPLE_FMT_FLTP
+            } else { ff::AVSampleFormat_AV_SAMPLE_FMT_FLT };
+            ctx.sample_rate = sample_rate;
+            ctx.channels = channel_count;
+            ctx.channel_layout
+                = ff::av_get_default_channel_layout(channel_count) as u64;
+            ctx.bit_rate = bit_rate;
+            ctx.time_base = ff::AVRational { num: 1, den: sample_rate };
+            // (FFMPEG's own Opus encoder is still "experimental")
+            ctx.strict_std_compliance
+                = ff::FF_COMPLIANCE_EXPERIMENTAL as libc::c_int;
+            if let Some(container) = container {
+                let container = CString::new(container).unwrap();
+                fferr_lt(ff::avformat_alloc_output_context2
+                         (&mut ret.muxer, null_mut(), container.as_ptr(),
+                          null()))?;
+                let oformat = (*ret.muxer).oformat.as_ref().unwrap();
+                if oformat.flags & ff::AVFMT_GLOBALHEADER as libc::c_int != 0 {
+                    ctx.flags |= ff::AV_CODEC_FLAG_GLOBAL_HEADER as libc::c_int;
+                }
+            }
+            fferr_ne(ff::avcodec_open2(ret.codec_ctx, codec, null_mut()))?;
+            // (encoders that take any number of samples say 0)
+            ret.frame_size = if ctx.frame_size > 0 { ctx.frame_size as usize }
+            else { 1024 };
+            ret.small_last_frame = codec.capabilities as u32
+                & (ff::AV_CODEC_CAP_SMALL_LAST_FRAME
+                   | ff::AV_CODEC_CAP_VARIABLE_FRAME_SIZE) != 0;
+            ret.frame = ff::av_frame_alloc();
+            let frame = ret.frame.as_mut()
+                .ok_or_else(|| anyhow!("Couldn't allocate a frame"))?;
+            frame.nb_samples = ret.frame_size as libc::c_int;
+            frame.format = ctx.sample_fmt;
+            frame.channel_layout = ctx.channel_layout;
+            frame.channels = channel_count;
+            frame.sample_rate = sample_rate;
+            fferr_lt(ff::av_frame_get_buffer(ret.frame, 0))?;
+            ff::av_init_packet(&mut ret.packet);
+            if let Some(muxer) = ret.muxer.as_mut() {
+                let stream = ff::avformat_new_stream(muxer, null()).as_mut()
+                    .ok_or_else(|| anyhow!("Couldn't add a stream"))?;
+                stream.time_base = ctx.time_base;
+                fferr_lt(ff::avcodec_parameters_from_context
+                         (stream.codecpar, ret.codec_ctx))?;
+                if let Some((path, metadata)) = file {
+                    muxer.metadata = make_metadata_dict(metadata);
+                    let path = path_to_cstring(path)?;
+                    ret.file_output = true;
+                    fferr_lt(ff::avio_open(&mut muxer.pb, path.as_ptr(),
+                                           ff::AVIO_FLAG_WRITE
+                                           as libc::c_int))?;
+                    fferr_lt(ff::avformat_write_header(muxer, null_mut()))?;
+                    return Ok(ret)
+                }
+                const IO_BUFFER_SIZE: usize = 4096;
+                let buffer = ff::av_malloc(IO_BUFFER_SIZE as _) as *mut u8;
+                if buffer.is_null() {
+                    return Err(anyhow!("Couldn't allocate a buffer"))
+                }
+                let opaque = &mut *ret.muxed as *mut Vec<u8>;
+                muxer.pb = ff::avio_alloc_context
+                    (buffer, IO_BUFFER_SIZE as libc::c_int, 1,
+                     opaque as *mut libc::c_void, None, Some(write_to_vec),
+                     None);
+                if muxer.pb.is_null() {
+                    ff::av_free(buffer as *mut _);
+                    return Err(anyhow!("Couldn't allocate an AVIOContext"))
+                }
+                fferr_lt(ff::avformat_write_header(muxer, null_mut()))?;
+                ff::avio_flush(muxer.pb);
+                ret.header = std::mem::take(&mut *ret.muxed);
+            }
+        }
+        Ok(ret)
+    }
+    /// Returns the sample rate that the encoder wants its input at.
+    pub fn get_sample_rate(&self) -> i32 {
+        unsafe { (*self.codec_ctx).sample_rate }
+    }
+    /// Returns whatever the muxer wrote before any audio. Anyone receiving
+    /// the output has to get this first. Empty if we aren't muxing.
+    pub fn get_header(&self) -> &[u8] {
+        &self.header[..]
+    }
+    /// Encodes some interleaved samples, appending whatever comes out to
+    /// `out`. Samples that don't make up a whole frame are held back until
+    /// next time.
+    ///
+    /// When muxing, `out` only ever gets whole pages (or whatever the
+    /// container calls them), so a listener can join at any call.
+    pub fn encode(&mut self, samples: &[f32], out: &mut Vec<u8>)
+    -> anyhow::Result<()> {
+        self.pending.extend_from_slice(samples);
+        let floats_per_frame = self.frame_size * self.channel_count;
+        let mut pending = std::mem::take(&mut self.pending);
+        let mut consumed = 0;
+        let mut ret = Ok(());
+        while pending.len() - consumed >= floats_per_frame {
+            let src = &pending[consumed .. consumed + floats_per_frame];
+            consumed += floats_per_frame;
+            ret = unsafe { self.encode_frame(src, out) };
+            if ret.is_err() { break }
+        }
+        pending.drain(..consumed);
+        self.pending = pending;
+        ret
+    }
+    /// Encodes whatever samples are still being held back (padded out with
+    /// silence, if the encoder only takes whole frames), gets the last of the
+    /// output out of the encoder, and has the muxer (if any) finish up.
+    /// Whatever comes out is appended to `out`. Don't encode anything else
+    /// afterward.
+    pub fn finish(&mut self, out: &mut Vec<u8>) -> anyhow::Result<()> {
+        let mut pending = std::mem::take(&mut self.pending);
+        if !pending.is_empty() {
+            if !self.small_last_frame {
+                pending.resize(self.frame_size * self.channel_count, 0.0);
+            }
+            unsafe { self.encode_frame(&pending, out) }?;
+        }
+        unsafe {
+            loop {
+                let mut got_packet: libc::c_int = 0;
+                fferr_lt(ff::avcodec_encode_audio2(self.codec_ctx,
+                                                   &mut self.packet,
+                                                   null_mut(),
+                                                   &mut got_packet))?;
+                if got_packet == 0 { break }
+                let ret = self.write_packet(out);
+                ff::av_packet_unref(&mut self.packet);
+                ret?;
+            }
+            if let Some(muxer) = self.muxer.as_mut() {
+                fferr_lt(ff::av_write_trailer(muxer))?;
+                ff::avio_flush(muxer.pb);
+                out.extend_from_slice(&self.muxed[..]);
+                self.muxed.clear();
+            }
+        }
+        Ok(())
+    }
+    /// Hands one frame's worth of samples to the encoder (or less, for the
+    /// last frame), and writes out whatever it gives back.
+    unsafe fn encode_frame(&mut self, src: &[f32], out: &mut Vec<u8>)
+    -> anyhow::Result<()> {
+        let nb_samples = src.len() / self.channel_count;
+        fferr_lt(ff::av_frame_make_writable(self.frame))?;
+        let frame = self.frame.as_mut().unwrap();
+        frame.nb_samples = nb_samples as libc::c_int;
+        if self.planar {
+            for c in 0 .. self.channel_count {
+                let raw_ptr = frame.extended_data.offset(c as isize).read();
+                let plane = std::slice::from_raw_parts_mut
+                    (raw_ptr as *mut f32, nb_samples);
+                for (n, el) in plane.iter_mut().enumerate() {
+                    *el = src[n * self.channel_count + c];
+                }
+            }
+        }
+        else {
+            let raw_ptr = frame.extended_data.read();
+            std::slice::from_raw_parts_mut(raw_ptr as *mut f32, src.len())
+                .copy_from_slice(src);
+        }
+        frame.pts = self.pts;
+        self.pts += nb_samples as i64;
+        let mut got_packet: libc::c_int = 0;
+        fferr_lt(ff::avcodec_encode_audio2(self.codec_ctx, &mut self.packet,
+                                           self.frame, &mut got_packet))?;
+        if got_packet == 0 { return Ok(()) }
+        let ret = self.write_packet(out);
+        ff::av_packet_unref(&mut self.packet);
+        ret
+    }
+    /// Writes the packet the encoder just gave us to `out`, through the muxer
+    /// if we have one.
+    unsafe fn write_packet(&mut self, out: &mut Vec<u8>)
+    -> anyhow::Result<()> {
+        let muxer = match self.muxer.as_ref() {
+            Some(x) => x,
+            None => {
+                out.extend_from_slice(std::slice::from_raw_parts
+                                      (self.packet.data,
+                                       self.packet.size as usize));
+                return Ok(())
+            },
+        };
+        let stream = muxer.streams.read().as_ref().unwrap();
+        self.packet.stream_index = 0;
+        ff::av_packet_rescale_ts(&mut self.packet,
+                                 (*self.codec_ctx).time_base,
+                                 stream.time_base);
+        let res = fferr_lt(ff::av_write_frame(self.muxer, &mut self.packet));
+        ff::avio_flush(muxer.pb);
+        out.extend_from_slice(&self.muxed[..]);
+        self.muxed.clear();
+        res.map(|_| ())
+    }
+}
+
+impl Drop for Encoder {
+    fn drop(&mut self) {
+        unsafe {
+            if let Some(muxer) = self.muxer.as_mut() {
+                if self.file_output {
+                    ff::avio_closep(&mut muxer.pb);
+                }
+                else if let Some(pb) = muxer.pb.as_mut() {
+                    ff::av_freep(&mut pb.buffer as *mut *mut u8 as *mut _);
+                    ff::avio_context_free(&mut muxer.pb);
+                }
+                ff::avformat_free_context(self.muxer);
+                self.muxer = null_mut();
+            }
+            ff::av_frame_free(&mut self.frame);
+            ff::avcodec_free_context(&mut self.codec_ctx);
+        }
+    }
+}
+
 fn expand_float_packed_audio(frame: &ff::AVFrame, buf: &mut Vec<f32>){
     let data_ptr: &[f32] = unsafe {
         std::slice::from_raw_parts(transmute(frame.extended_data.read()),
@@ -553,6 +1255,7 @@ impl Expandable for f64 {
 pub fn init() {
     unsafe {
         ff::av_log_set_callback(Some(ffmpeg_log_stub));
+        ff::avformat_network_init();
     }
 }
 
@@ -578,3 +1281,138 @@ pub extern "C" fn ffmpeg_log_backend(_p: libc::uintptr_t, level: libc::c_int,
     else if level >= ff::AV_LOG_WARNING { warn!("{}", text) }
     else { error!("{}", text) }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{db, logical};
+    use std::{io::Read, net::TcpListener};
+
+    /// How many bytes of audio our fake station sends between metadata
+    /// blocks.
+    const METAINT: usize = 4096;
+
+    /// Two seconds of 8kHz mono silence, as a WAV file.
+    fn silent_wav() -> Vec<u8> {
+        const RATE: u32 = 8000;
+        let data_len = RATE * 2 * 2;
+        let mut ret = Vec::new();
+        ret.extend(b"RIFF");
+        ret.extend(&(36 + data_len).to_le_bytes());
+        ret.extend(b"WAVEfmt ");
+        ret.extend(&16u32.to_le_bytes());
+        ret.extend(&1u16.to_le_bytes()); // PCM
+        ret.extend(&1u16.to_le_bytes()); // mono
+        ret.extend(&RATE.to_le_bytes());
+        ret.extend(&(RATE * 2).to_le_bytes());
+        ret.extend(&2u16.to_le_bytes());
+        ret.extend(&16u16.to_le_bytes());
+        ret.extend(b"data");
+        ret.extend(&data_len.to_le_bytes());
+        ret.resize(ret.len() + data_len as usize, 0);
+        ret
+    }
+
+    /// Serves `body` to everyone who connects, the way a SHOUTcast or
+    /// Icecast station would, with `title` as the stream title. Returns the
+    /// URL to connect to.
+    fn serve_icy(body: Vec<u8>, title: &'static str) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/stream", listener.local_addr().unwrap());
+        let meta = format!("StreamTitle='{}';StreamUrl='';", title);
+        let blocks = (meta.len() + 15) / 16;
+        let mut meta = meta.into_bytes();
+        meta.resize(blocks * 16, 0);
+        std::thread::spawn(move || {
+            for connection in listener.incoming() {
+                let mut connection = match connection {
+                    Ok(x) => x,
+                    Err(_) => continue,
+                };
+                let mut request = Vec::new();
+                let mut byte = [0u8];
+                while !request.ends_with(b"\r\n\r\n") {
+                    match connection.read(&mut byte) {
+                        Ok(1) => request.push(byte[0]),
+                        _ => break,
+                    }
+                }
+                let request = String::from_utf8_lossy(&request)
+                    .to_ascii_lowercase();
+                let mut out = Vec::new();
+                if request.contains("icy-metadata: 1") {
+                    write!(out, "HTTP/1.0 200 OK\r\n\
+                                 Content-Type: audio/wav\r\n\
+                                 icy-name: Test Radio\r\n\
+                                 icy-metaint: {}\r\n\r\n", METAINT).unwrap();
+                    for chunk in body.chunks(METAINT) {
+                        out.extend(chunk);
+                        if chunk.len() == METAINT {
+                            out.push(blocks as u8);
+                            out.extend(&meta);
+                        }
+                    }
+                }
+                else {
+                    out.extend(b"HTTP/1.0 200 OK\r\n\
+                                 Content-Type: audio/wav\r\n\r\n");
+                    out.extend(&body);
+                }
+                // The player may hang up on us early; that's fine.
+                let _ = connection.write_all(&out);
+            }
+        });
+        url
+    }
+
+    #[test]
+    fn icy_titles() {
+        assert_eq!(parse_icy_title("StreamTitle='Artist - Song';\
+                                    StreamUrl='http://example.com/';"),
+                   Some("Artist - Song".to_owned()));
+        assert_eq!(parse_icy_title("StreamTitle='It's Here';\0\0\0"),
+                   Some("It's Here".to_owned()));
+        assert_eq!(parse_icy_title("StreamTitle='Rock 'n' Roll'"),
+                   Some("Rock 'n' Roll".to_owned()));
+        assert_eq!(parse_icy_title("StreamTitle='  padded  ';"),
+                   Some("padded".to_owned()));
+        assert_eq!(parse_icy_title("StreamTitle='No end"),
+                   Some("No end".to_owned()));
+        assert_eq!(parse_icy_title("StreamTitle='';StreamUrl='x';"), None);
+        assert_eq!(parse_icy_title("StreamUrl='x';"), None);
+        assert_eq!(parse_icy_title(""), None);
+    }
+
+    #[test]
+    fn icy_stream() {
+        init();
+        db::open_test_database();
+        assert!(logical::create_stream_song("file:///dev/zero", "").is_err());
+        let url = serve_icy(silent_wav(), "Artist - It's Live");
+        let song_ref = logical::create_stream_song(&format!(" {} ", url),
+                                                   "Test Radio").unwrap();
+        let song = song_ref.read().unwrap();
+        assert!(song.is_stream());
+        assert_eq!(song.get_stream_url(), Some(url.as_str()));
+        assert_eq!(song.get_metadata().get("title").map(String::as_str),
+                   Some("Test Radio"));
+        let (mut avf, _) = song.open_stream().unwrap();
+        drop(song);
+        avf.find_stream_info().unwrap();
+        let stream = avf.find_best_stream().unwrap().unwrap();
+        avf.open_stream(stream).unwrap();
+        let mut samples = 0;
+        let mut title = None;
+        while title.is_none() {
+            let more = avf.decode_some(|_, _, _, data| samples += data.len());
+            title = avf.read_stream_title();
+            if !more { break }
+        }
+        assert!(samples > 0);
+        assert_eq!(title.as_deref(), Some("Artist - It's Live"));
+        // A blank title falls back to the URL.
+        let untitled = logical::create_stream_song(&url, "").unwrap();
+        assert_eq!(untitled.read().unwrap().get_metadata().get("title"),
+                   Some(&url));
+    }
+}
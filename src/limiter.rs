@@ -0,0 +1,200 @@
+//! This module contains a look-ahead peak limiter, which keeps audio that has
+//! been made louder than the original (by the volume slider, the EQ, etc.)
+//! from clipping.
+//!
+//! Audio passes through a short delay. Meanwhile, we work out how much each
+//! frame would have to be turned down to stay under `CEILING`, and bring the
+//! gain down smoothly *before* the loud part comes out of the delay. Audio
+//! that never goes over the ceiling comes out untouched (just delayed).
+
+use std::collections::VecDeque;
+
+/// The loudest any sample is allowed to be. (A hair under full scale, so that
+/// rounding to integer formats doesn't push it over.)
+const CEILING: f32 = 0.98;
+/// How far ahead the limiter looks, in seconds. This is also how long the
+/// gain takes to come down.
+const LOOKAHEAD: f64 = 0.005;
+/// How long it takes the gain to get most of the way back up after the loud
+/// part has passed, in seconds.
+const RELEASE: f64 = 0.1;
+/// Once the gain is this close to where it's going, it goes the rest of the
+/// way at once.
+const RELEASE_SNAP: f32 = 0.001;
+
+pub struct Limiter {
+    sample_rate: f64,
+    channel_count: usize,
+    /// How many frames the audio is delayed by.
+    delay_frames: usize,
+    /// Samples waiting to come out, oldest first.
+    delay: VecDeque<f32>,
+    /// For finding the smallest gain needed over the last `delay_frames`
+    /// frames: (frame number, gain) pairs with increasing gains.
+    minimums: VecDeque<(u64, f32)>,
+    /// The last `delay_frames` minimum gains, and their sum, for smoothing.
+    smoothing: VecDeque<f32>,
+    smoothing_sum: f32,
+    /// How many frames have gone in.
+    frames_in: u64,
+    /// The gain that was applied to the last frame that came out.
+    gain: f32,
+    /// How much of the distance back to full gain to cover each frame.
+    release_coef: f32,
+    /// The lowest gain applied since the last `take_lowest_gain` call.
+    lowest_gain: f32,
+}
+
+impl Limiter {
+    pub fn new(sample_rate: f64, channel_count: i32) -> Limiter {
+        let channel_count = channel_count.max(1) as usize;
+        let delay_frames = ((LOOKAHEAD * sample_rate).round() as usize).max(1);
+        let mut delay = VecDeque::with_capacity(delay_frames * channel_count);
+        delay.resize((delay_frames - 1) * channel_count, 0.0);
+        let mut smoothing = VecDeque::with_capacity(delay_frames);
+        smoothing.resize(delay_frames, 1.0);
+        Limiter {
+            sample_rate, channel_count, delay_frames, delay,
+            minimums: VecDeque::with_capacity(delay_frames),
+            smoothing, smoothing_sum: delay_frames as f32,
+            frames_in: 0, gain: 1.0,
+            release_coef: (1.0 - (-1.0 / (RELEASE * sample_rate)).exp())
+                as f32,
+            lowest_gain: 1.0,
+        }
+    }
+    /// Returns how long the limiter delays the audio, in seconds.
+    pub fn get_delay(&self) -> f64 {
+        (self.delay_frames - 1) as f64 / self.sample_rate
+    }
+    /// Limits the given interleaved audio in place.
+    pub fn process(&mut self, buf: &mut [f32]) {
+        let window = self.delay_frames as u64;
+        for frame in buf.chunks_exact_mut(self.channel_count) {
+            // How much this frame needs turning down, on its own...
+            let peak = frame.iter().fold(0.0f32, |a, b| a.max(b.abs()));
+            let needed = if peak > CEILING { CEILING / peak } else { 1.0 };
+            // ...the least gain needed by any frame in the window...
+            while self.minimums.back().map(|x| x.1 >= needed)
+                .unwrap_or(false) {
+                self.minimums.pop_back();
+            }
+            self.minimums.push_back((self.frames_in, needed));
+            while self.minimums.front()
+                .map(|x| x.0 + window <= self.frames_in).unwrap_or(false) {
+                self.minimums.pop_front();
+            }
+            let minimum = self.minimums.front().unwrap().1;
+            self.frames_in += 1;
+            // ...averaged over the window, so that the gain comes down
+            // smoothly, and is all the way down by the time that frame
+            // comes out...
+            self.smoothing_sum += minimum - self.smoothing.pop_front()
+                .unwrap_or(1.0);
+            self.smoothing.push_back(minimum);
+            let target = (self.smoothing_sum / window as f32).min(1.0);
+            // ...and let back up slowly. (Near the end, each step would be
+            // too small for an f32 to register, so finish the job.)
+            self.gain = if target - self.gain < RELEASE_SNAP { target }
+            else { self.gain + (target - self.gain) * self.release_coef };
+            if self.gain < self.lowest_gain { self.lowest_gain = self.gain }
+            self.delay.extend(frame.iter());
+            for el in frame.iter_mut() {
+                *el = self.delay.pop_front().unwrap() * self.gain;
+            }
+        }
+        // (the running sum drifts a little, so bring it back in line)
+        self.smoothing_sum = self.smoothing.iter().sum();
+    }
+    /// Returns the lowest gain the limiter has applied since the last time
+    /// this was called. 1.0 means it didn't have to do anything.
+    pub fn take_lowest_gain(&mut self) -> f32 {
+        std::mem::replace(&mut self.lowest_gain, self.gain)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: f64 = 48000.0;
+
+    /// Runs some interleaved audio through the limiter, in uneven pieces.
+    fn limit(limiter: &mut Limiter, input: &[f32]) -> Vec<f32> {
+        let mut ret = input.to_vec();
+        let frame = limiter.channel_count;
+        let mut rest = &mut ret[..];
+        let mut piece = 1;
+        while !rest.is_empty() {
+            let len = (piece * frame).min(rest.len());
+            let (now, later) = rest.split_at_mut(len);
+            limiter.process(now);
+            rest = later;
+            piece = piece * 3 % 1001;
+        }
+        ret
+    }
+
+    fn sine(frequency: f64, amplitude: f32, frames: usize) -> Vec<f32> {
+        (0 .. frames).map(|n| {
+            let t = n as f64 / SAMPLE_RATE;
+            (2.0 * std::f64::consts::PI * frequency * t).sin() as f32
+                * amplitude
+        }).collect()
+    }
+
+    #[test]
+    fn quiet_is_only_delayed() {
+        let mut limiter = Limiter::new(SAMPLE_RATE, 1);
+        let delay = (limiter.get_delay() * SAMPLE_RATE).round() as usize;
+        assert!(delay > 0);
+        let input = sine(440.0, CEILING, 48000);
+        let output = limit(&mut limiter, &input);
+        assert!(output[.. delay].iter().all(|&x| x == 0.0));
+        assert_eq!(&output[delay ..], &input[.. input.len() - delay]);
+        assert_eq!(limiter.take_lowest_gain(), 1.0);
+    }
+
+    #[test]
+    fn loud_is_kept_under_the_ceiling() {
+        let mut limiter = Limiter::new(SAMPLE_RATE, 1);
+        let output = limit(&mut limiter, &sine(440.0, 2.0, 48000));
+        let peak = output.iter().fold(0.0f32, |a, b| a.max(b.abs()));
+        assert!(peak <= CEILING, "peak was {}", peak);
+        // (and it isn't just turned down to nothing)
+        assert!(peak > CEILING * 0.95, "peak was {}", peak);
+        let lowest = limiter.take_lowest_gain();
+        assert!((lowest - CEILING / 2.0).abs() < 0.01, "gain was {}", lowest);
+    }
+
+    #[test]
+    fn spikes_recover() {
+        let mut limiter = Limiter::new(SAMPLE_RATE, 1);
+        let delay = (limiter.get_delay() * SAMPLE_RATE).round() as usize;
+        let mut input = vec![0.1; 48000];
+        input[1000] = 4.0;
+        let output = limit(&mut limiter, &input);
+        assert!(output.iter().all(|x| x.abs() <= CEILING));
+        // The gain comes down smoothly beforehand...
+        for pair in output[500 .. 1000 + delay].windows(2) {
+            assert!(pair[1] <= pair[0]);
+        }
+        // ...and goes back up afterward.
+        assert!((output[25000] - 0.1).abs() < 0.001);
+        assert_eq!(output[47999], 0.1);
+        assert!(limiter.take_lowest_gain() < 0.25);
+        assert!(limiter.take_lowest_gain() > 0.999);
+    }
+
+    #[test]
+    fn channels_are_linked() {
+        let mut limiter = Limiter::new(SAMPLE_RATE, 2);
+        let input: Vec<f32> = sine(440.0, 3.0, 4800).into_iter()
+            .flat_map(|x| vec![x, x * 0.1]).collect();
+        let output = limit(&mut limiter, &input);
+        for frame in output.chunks_exact(2) {
+            assert!(frame[0].abs() <= CEILING);
+            assert!((frame[1] - frame[0] * 0.1).abs() < 0.00001);
+        }
+    }
+}
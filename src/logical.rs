@@ -19,6 +19,7 @@ use std::{
     ffi::OsStr,
     fmt, fmt::{Display, Debug, Formatter},
     io::{Read, Write},
+    path::Path,
     sync::{Arc, Mutex, RwLock, RwLockReadGuard},
 };
 
@@ -54,6 +55,19 @@ impl SongID {
     }
 }
 
+/// A part of a song that the user wants to hear over and over, e.g. to practice
+/// along with it. Times are in seconds from the beginning of the (trimmed)
+/// song.
+#[derive(Clone,Copy,Debug,Serialize,Deserialize,PartialEq)]
+pub struct PracticeLoop {
+    pub start: f64,
+    pub end: f64,
+    /// How many times to play the loop before carrying on with the rest of
+    /// the song. `None` = forever.
+    #[serde(default)]
+    pub repeats: Option<u32>,
+}
+
 /// Represents some representative metadata of a *physical file*. Used as part
 /// of the "same logical song" heuristic.
 #[derive(Debug,Clone,Serialize,Deserialize,PartialEq)]
@@ -105,7 +119,18 @@ pub struct LogicalSong {
     id: SongID,
     user_metadata: BTreeMap<String, String>,
     physical_files: Vec<FileID>,
+    /// Which track of each physical file is this song. (0 = the whole file.)
+    /// Always the same length as `physical_files`.
+    physical_tracks: Vec<u32>,
     duration: u32, // (duration of last played back version)
+    /// Loops the user has saved for this song, by name.
+    practice_loops: BTreeMap<String, PracticeLoop>,
+    /// Where the user stopped hearing this song, if it's the kind of song
+    /// that picks up where it left off. See `playback::remembers_position`.
+    remembered_position: Option<f64>,
+    /// If this song is played from the network (e.g. internet radio) instead
+    /// of from a file, where from. Such a song has no physical files.
+    stream_url: Option<String>,
     // Not stored in database; populated as the database is loaded
     similarity_recs: Vec<SimilarityRec>,
 }
@@ -131,8 +156,9 @@ lazy_static! {
     static ref SONGS_BY_SONG_ID
         : RwLock<HashMap<SongID,LogicalSongRef>>
         = RwLock::new(HashMap::new());
+    /// Songs indexed by file ID *and track number*.
     static ref SONGS_BY_FILE_ID
-        : RwLock<HashMap<FileID,LogicalSongRef>>
+        : RwLock<HashMap<(FileID,u32),LogicalSongRef>>
         = RwLock::new(HashMap::new());
     static ref SONGS_BY_P_FILENAME
         : RwLock<HashMap<String,Vec<LogicalSongRef>>>
@@ -174,23 +200,50 @@ fn add_possibilities(songs: Option<&Vec<LogicalSongRef>>,
     }
 }
 
+/// Returns the "filename" to use in a `SimilarityRec` for the given track of
+/// the file at the given path. Tracks get their track number tacked on, so
+/// that tracks of the same file don't look alike just because they share a
+/// filename.
+fn similarity_filename(path: &Path, track: u32) -> String {
+    let filename = path.file_name().map(OsStr::to_string_lossy)
+        .map(Cow::into_owned).unwrap();
+    if track == 0 { filename }
+    else { format!("{}#{}", filename, track) }
+}
+
 /// Called by the appropriate routines in `physical` when a physical file is
-/// found. We will either match this file to a logical song already in our
-/// database, or make a new (fresly-imported) song.
+/// found. For each track of the file (usually just one, the whole file), we
+/// will either match it to a logical song already in our database, or make a
+/// new (fresly-imported) song.
 pub fn incorporate_physical(file_ref: PhysicalFileRef) {
     let file = file_ref.read().unwrap();
-    let duration = file.get_duration();
-    let absolute_path = file.get_absolute_paths().last().unwrap();
     let metadata = file.get_raw_metadata();
-    let similarity_rec = SimilarityRec::new(absolute_path.file_name()
-                                            .map(OsStr::to_string_lossy)
-                                            .map(Cow::into_owned)
-                                            .unwrap(),
+    let track_numbers = file.get_track_numbers();
+    for track in track_numbers {
+        if track == 0 {
+            incorporate_track(&file, track, &metadata);
+        }
+        else {
+            let mut metadata = metadata.clone();
+            file.apply_track_metadata(track, &mut metadata);
+            incorporate_track(&file, track, &metadata);
+        }
+    }
+}
+
+/// Incorporates one track of a physical file. See `incorporate_physical`.
+fn incorporate_track(file: &PhysicalFile, track: u32,
+                     metadata: &BTreeMap<String,String>) {
+    let duration = file.get_track_duration(track);
+    let absolute_path = file.get_absolute_paths().last().unwrap();
+    let similarity_rec = SimilarityRec::new(similarity_filename(absolute_path,
+                                                                track),
                                             duration,
-                                            &metadata);
+                                            metadata);
     let _lock = INCORPORATION_LOCK.lock().unwrap();
-    // physical file already incorporated? if so, nothing to do
-    if let Some(_) = SONGS_BY_FILE_ID.read().unwrap().get(file.get_id()) {
+    // track already incorporated? if so, nothing to do
+    if let Some(_) = SONGS_BY_FILE_ID.read().unwrap()
+        .get(&(*file.get_id(), track)) {
         info!("Same exact song! {:?}", metadata.get("title"));
         return
     }
@@ -222,16 +275,21 @@ pub fn incorporate_physical(file_ref: PhysicalFileRef) {
         info!("Existing song! score = {}, title = {:?}", possibility.1, possibility.0.read().unwrap().user_metadata.get("title"));
         let mut logical_song = possibility.0.write().unwrap();
         logical_song.physical_files.push(*file.get_id());
+        logical_song.physical_tracks.push(track);
+        SONGS_BY_FILE_ID.write().unwrap().insert((*file.get_id(), track),
+                                                 possibility.0.clone());
         if logical_song.similarity_recs.iter().find(|&x| x == &similarity_rec)
         .is_none() {
             logical_song.similarity_recs.push(similarity_rec);
             db::update_song_physical_files_and_similarity_recs
                 (logical_song.id, &logical_song.physical_files,
+                 &logical_song.physical_tracks,
                  &logical_song.similarity_recs);
         }
         else {
             db::update_song_physical_files
-                (logical_song.id, &logical_song.physical_files);
+                (logical_song.id, &logical_song.physical_files,
+                 &logical_song.physical_tracks);
         }
     }
     // TODO: soft matches
@@ -241,11 +299,15 @@ pub fn incorporate_physical(file_ref: PhysicalFileRef) {
             id: SongID::from_inner(0),
             user_metadata: BTreeMap::new(),
             physical_files: vec![*file.get_id()],
+            physical_tracks: vec![track],
             duration: similarity_rec.duration,
+            practice_loops: BTreeMap::new(),
+            remembered_position: None,
+            stream_url: None,
             similarity_recs: vec![similarity_rec.clone()],
         });
         let mut new_song = new_song_ref.write().unwrap();
-        if let Err(x) = new_song.import_metadata(&file, Some(&metadata)) {
+        if let Err(x) = new_song.import_metadata(file, track, Some(metadata)) {
             // TODO: error reporting, better
             error!("While importing metadata for song on initial scan: {}", x);
             warn!("Falling back to simple import.");
@@ -261,6 +323,7 @@ pub fn incorporate_physical(file_ref: PhysicalFileRef) {
         }
         let song_id = db::add_song(&new_song.user_metadata,
                                    &new_song.physical_files,
+                                   &new_song.physical_tracks,
                                    &new_song.similarity_recs,
                                    new_song.duration).unwrap(); // TODO: errors
         assert_ne!(song_id, NO_SONG_ID);
@@ -269,7 +332,8 @@ pub fn incorporate_physical(file_ref: PhysicalFileRef) {
         drop(new_song);
         LOGICAL_SONGS.write().unwrap().push(new_song_ref.clone());
         SONGS_BY_SONG_ID.write().unwrap().insert(song_id,new_song_ref.clone());
-        SONGS_BY_FILE_ID.write().unwrap().insert(*file.get_id(),new_song_ref.clone());
+        SONGS_BY_FILE_ID.write().unwrap().insert((*file.get_id(), track),
+                                                 new_song_ref.clone());
         SONGS_BY_P_FILENAME.write().unwrap().entry(similarity_rec.filename)
             .or_insert_with(Vec::new).push(new_song_ref.clone());
         if let Some(title) = similarity_rec.title.clone() {
@@ -288,6 +352,129 @@ pub fn incorporate_physical(file_ref: PhysicalFileRef) {
     }
 }
 
+/// Parses the `start_time` and `end_time` metadata in the given metadata.
+/// Invalid values are ignored. See `LogicalSong::get_trim`.
+fn parse_trim(metadata: &BTreeMap<String, String>) -> (f64, Option<f64>) {
+    let start: f64 = metadata.get("start_time")
+        .and_then(|x| x.parse().ok())
+        .filter(|x: &f64| x.is_finite() && *x > 0.0)
+        .unwrap_or(0.0);
+    let end: Option<f64> = metadata.get("end_time")
+        .and_then(|x| x.parse().ok())
+        .filter(|x: &f64| x.is_finite() && *x > start);
+    (start, end)
+}
+
+/// Applies the trim points in the given metadata to the given untrimmed
+/// duration.
+fn trimmed_duration(untrimmed: u32, metadata: &BTreeMap<String, String>)
+-> u32 {
+    let (start, end) = parse_trim(metadata);
+    let end = match end {
+        Some(x) => x.min(untrimmed as f64),
+        None => untrimmed as f64,
+    };
+    (end - start).round().max(0.0) as u32
+}
+
+/// Fetch the logical song that the given track of the given physical file
+/// belongs to.
+pub fn get_song_by_file_id(id: &FileID, track: u32) -> Option<LogicalSongRef> {
+    SONGS_BY_FILE_ID.read().unwrap().get(&(*id, track)).cloned()
+}
+
+/// Called after `physical::replace_file`. Every song that had a track of the
+/// old file gets the same track of the new one instead, so it keeps its
+/// identity. The similarity records that came from the old file are remade
+/// from the new one, since its tags are different now.
+pub fn replace_physical_file(old_id: &FileID, new_id: &FileID) {
+    let file_ref = physical::get_file_by_id(new_id);
+    let file = file_ref.as_ref().map(|x| x.read().unwrap());
+    let _lock = INCORPORATION_LOCK.lock().unwrap();
+    let mut songs_by_file_id = SONGS_BY_FILE_ID.write().unwrap();
+    let mut songs_by_p_filename = SONGS_BY_P_FILENAME.write().unwrap();
+    let mut songs_by_p_title = SONGS_BY_P_TITLE.write().unwrap();
+    let mut songs_by_p_artist = SONGS_BY_P_ARTIST.write().unwrap();
+    let mut songs_by_p_album = SONGS_BY_P_ALBUM.write().unwrap();
+    let keys: Vec<(FileID, u32)> = songs_by_file_id.keys()
+        .filter(|(id, _)| id == old_id).cloned().collect();
+    for key in keys {
+        let track = key.1;
+        let song_ref = songs_by_file_id.remove(&key).unwrap();
+        songs_by_file_id.insert((*new_id, track), song_ref.clone());
+        let mut song = song_ref.write().unwrap();
+        for id in song.physical_files.iter_mut() {
+            if id == old_id { *id = *new_id }
+        }
+        let new_file = match file.as_ref() {
+            Some(x) => x,
+            None => {
+                db::update_song_physical_files(song.id, &song.physical_files,
+                                               &song.physical_tracks);
+                continue
+            },
+        };
+        let metadata = new_file.get_raw_track_metadata(track);
+        let duration = new_file.get_track_duration(track);
+        for path in new_file.get_absolute_paths() {
+            let rec = SimilarityRec::new(similarity_filename(path, track),
+                                         duration, &metadata);
+            // (the old file's record is the one with the same filename)
+            match song.similarity_recs.iter()
+                .position(|x| x.filename == rec.filename) {
+                Some(n) => {
+                    let old = std::mem::replace(&mut song.similarity_recs[n],
+                                                rec.clone());
+                    let recs = &song.similarity_recs;
+                    unindex(&mut songs_by_p_title, old.title.as_ref(),
+                            recs.iter().any(|x| x.title == old.title),
+                            &song_ref);
+                    unindex(&mut songs_by_p_artist, old.artist.as_ref(),
+                            recs.iter().any(|x| x.artist == old.artist),
+                            &song_ref);
+                    unindex(&mut songs_by_p_album, old.album.as_ref(),
+                            recs.iter().any(|x| x.album == old.album),
+                            &song_ref);
+                },
+                None => {
+                    index(&mut songs_by_p_filename, Some(&rec.filename),
+                          &song_ref);
+                    song.similarity_recs.push(rec.clone());
+                },
+            }
+            index(&mut songs_by_p_title, rec.title.as_ref(), &song_ref);
+            index(&mut songs_by_p_artist, rec.artist.as_ref(), &song_ref);
+            index(&mut songs_by_p_album, rec.album.as_ref(), &song_ref);
+        }
+        db::update_song_physical_files_and_similarity_recs
+            (song.id, &song.physical_files, &song.physical_tracks,
+             &song.similarity_recs);
+    }
+    GENERATION.bump();
+}
+
+/// Puts a song in one of the similarity indexes under the given key, if it
+/// isn't already there.
+fn index(index: &mut HashMap<String, Vec<LogicalSongRef>>,
+         key: Option<&String>, song_ref: &LogicalSongRef) {
+    let key = match key { Some(x) => x, None => return };
+    let songs = index.entry(key.clone()).or_insert_with(Vec::new);
+    if !songs.contains(song_ref) { songs.push(song_ref.clone()) }
+}
+
+/// Takes a song out of one of the similarity indexes under the given key,
+/// unless `still_has` says that another of its records still has that key.
+fn unindex(index: &mut HashMap<String, Vec<LogicalSongRef>>,
+           key: Option<&String>, still_has: bool,
+           song_ref: &LogicalSongRef) {
+    let key = match key { Some(x) => x, None => return };
+    if still_has { return }
+    if let Some(songs) = index.get_mut(key) {
+        songs.retain(|x| x != song_ref);
+        if songs.is_empty() { index.remove(key); }
+    }
+}
+
 /// Fetch a logical song by its unique ID.
 pub fn get_song_by_song_id(id: SongID) -> Option<LogicalSongRef> {
     SONGS_BY_SONG_ID.read().unwrap().get(&id).map(LogicalSongRef::clone)
@@ -317,21 +504,60 @@ impl LogicalSong {
         &self.user_metadata
     }
     /// Tries to open a `PhysicalFile` of this song for decoding. Errors will
-    /// be logged.
-    pub fn open_stream(&self) -> Option<ffmpeg::AVFormat> {
-        for id in self.physical_files.iter() {
-            if let Some(x) = physical::open_stream(id) {
+    /// be logged. Returns the opened file, and the part of it that is this
+    /// song.
+    pub fn open_stream(&self) -> Option<(ffmpeg::AVFormat, physical::Span)> {
+        if let Some(url) = self.stream_url.as_ref() {
+            return match ffmpeg::AVFormat::open_url(url) {
+                Ok(x) => Some((x, physical::Span::WHOLE)),
+                Err(x) => {
+                    warn!("Unable to open {:?}: {:?}", url, x);
+                    None
+                },
+            }
+        }
+        for (id, &track) in self.physical_files.iter()
+            .zip(self.physical_tracks.iter()) {
+            if let Some(x) = physical::open_stream(id, track) {
                 return Some(x)
             }
         }
         None
     }
+    /// Returns true if this song is played from the network instead of from a
+    /// file. Such a song has no end (and no duration), and can't be sought.
+    pub fn is_stream(&self) -> bool { self.stream_url.is_some() }
+    /// Returns the URL this song is played from, if it's a network stream.
+    pub fn get_stream_url(&self) -> Option<&str> {
+        self.stream_url.as_ref().map(String::as_str)
+    }
     /// Gets the list of `PhysicalFile` IDs that this song is backed by.
     pub fn get_physical_files(&self) -> &[FileID] {
         &self.physical_files[..]
     }
+    /// Gets the track number of each of the `PhysicalFile`s returned by
+    /// `get_physical_files`. (0 = the whole file.)
+    pub fn get_physical_tracks(&self) -> &[u32] {
+        &self.physical_tracks[..]
+    }
     /// Returns the (estimated) duration of the song, in seconds.
     pub fn get_duration(&self) -> u32 { self.duration }
+    /// Returns the trim points of the song: where playback should start, and
+    /// where it should stop (if before the end), in seconds from the beginning
+    /// of the untrimmed song. These come from the `start_time` and `end_time`
+    /// metadata.
+    pub fn get_trim(&self) -> (f64, Option<f64>) {
+        parse_trim(&self.user_metadata)
+    }
+    /// Returns the duration of the song, in seconds, before any trim points
+    /// are applied. Returns `None` if we don't know where any of its physical
+    /// files are.
+    pub fn get_untrimmed_duration(&self) -> Option<u32> {
+        self.physical_files.iter().zip(self.physical_tracks.iter())
+            .filter_map(|(id, &track)| physical::get_file_by_id(id)
+                        .map(|x| x.read().unwrap().get_track_duration(track)))
+            .next()
+    }
     /// Updates the duration of the song. This can happen if different physical
     /// files of the song have different estimated durations because of codec
     /// differences, and a different one is chosen to be played...
@@ -345,12 +571,62 @@ impl LogicalSong {
             self.duration = nu;
         }
     }
+    /// Returns the loops the user has saved for this song, by name.
+    pub fn get_practice_loops(&self) -> &BTreeMap<String, PracticeLoop> {
+        &self.practice_loops
+    }
+    /// Saves (or, if `nu` is `None`, deletes) one of this song's practice
+    /// loops.
+    pub fn set_practice_loop(&mut self, name: &str,
+                             nu: Option<PracticeLoop>) {
+        let changed = match nu {
+            Some(nu) => self.practice_loops.insert(name.to_owned(), nu)
+                != Some(nu),
+            None => self.practice_loops.remove(name).is_some(),
+        };
+        if changed {
+            db::update_song_practice_loops(self.id, &self.practice_loops);
+        }
+    }
+    /// Returns true if this song's metadata says it should pick up where it
+    /// left off, e.g. because it's an audiobook. (Set `remember_position` to
+    /// anything but `0`, `no`, or `false`.)
+    pub fn wants_position_remembered(&self) -> bool {
+        match self.user_metadata.get("remember_position") {
+            None => false,
+            Some(x) => {
+                let x = x.trim().to_lowercase();
+                !(x.is_empty() || x == "0" || x == "no" || x == "false")
+            },
+        }
+    }
+    /// Returns where the user stopped hearing this song, in seconds, if it's
+    /// been remembered.
+    pub fn get_remembered_position(&self) -> Option<f64> {
+        self.remembered_position
+    }
+    pub fn set_remembered_position(&mut self, nu: Option<f64>) {
+        if self.remembered_position != nu {
+            db::update_song_remembered_position(self.id, nu);
+            self.remembered_position = nu;
+        }
+    }
     /// Change the metadata of the song. This is a kinda expensive operation.
     ///
     /// Returns true if the metadata actually changed, and therefore the data­
     /// base got updated.
     pub fn set_metadata(&mut self, mut new_meta: BTreeMap<String, String>)
     -> bool {
+        if parse_trim(&new_meta) != parse_trim(&self.user_metadata) {
+            // The trim points changed, so the duration did too.
+            if let Some(untrimmed) = self.get_untrimmed_duration() {
+                let nu = trimmed_duration(untrimmed, &new_meta);
+                if self.duration != nu {
+                    db::update_song_duration(self.id, nu);
+                    self.duration = nu;
+                }
+            }
+        }
         new_meta.insert("duration".to_owned(), format!("{}", self.duration));
         new_meta.insert("song_id".to_owned(), format!("{}", self.id));
         if self.user_metadata != new_meta {
@@ -458,21 +734,29 @@ impl LogicalSongRef {
 /// Called by the database as songs are loaded.
 pub fn add_song_from_db(id: SongID, user_metadata: BTreeMap<String, String>,
                         physical_files: Vec<FileID>,
+                        physical_tracks: Vec<u32>,
                         similarity_recs: Option<Vec<SimilarityRec>>,
-                        duration: u32) {
+                        duration: u32,
+                        practice_loops: BTreeMap<String, PracticeLoop>,
+                        remembered_position: Option<f64>,
+                        stream_url: Option<String>) {
     assert_ne!(id, NO_SONG_ID);
+    assert_eq!(physical_files.len(), physical_tracks.len());
     let neu_ref = LogicalSongRef::new(LogicalSong {
         similarity_recs: similarity_recs.unwrap_or_else(Vec::new),
-        id, user_metadata, physical_files, duration,
+        id, user_metadata, physical_files, physical_tracks, duration,
+        practice_loops, remembered_position, stream_url,
     });
     let neu = neu_ref.write().unwrap();
     LOGICAL_SONGS.write().unwrap().push(neu_ref.clone());
     SONGS_BY_SONG_ID.write().unwrap().insert(id, neu_ref.clone());
     let mut songs_by_file_id = SONGS_BY_FILE_ID.write().unwrap();
-    for id in neu.physical_files.iter() {
-        songs_by_file_id.insert(*id, neu_ref.clone());
+    for (id, &track) in neu.physical_files.iter()
+        .zip(neu.physical_tracks.iter()) {
+        songs_by_file_id.insert((*id, track), neu_ref.clone());
     }
-    if neu.similarity_recs.len() == 0 {
+    // (network streams have nothing to be similar to)
+    if neu.similarity_recs.len() == 0 && !neu.is_stream() {
         SONGS_WITH_NO_RECS.write().unwrap().push(neu_ref.clone());
     }
     else {
@@ -500,6 +784,36 @@ pub fn add_song_from_db(id: SongID, user_metadata: BTreeMap<String, String>,
     GENERATION.bump();
 }
 
+/// Makes a new song that is played from the given network URL (e.g. an
+/// internet radio station), with the given title.
+pub fn create_stream_song(url: &str, title: &str)
+-> anyhow::Result<LogicalSongRef> {
+    let url = url.trim();
+    if !(url.starts_with("http://") || url.starts_with("https://")) {
+        return Err(anyhow!("Only http:// and https:// URLs can be played"))
+    }
+    let title = match title.trim() {
+        "" => url,
+        x => x,
+    };
+    let mut user_metadata = BTreeMap::new();
+    user_metadata.insert("title".to_owned(), title.to_owned());
+    let id = db::add_stream_song(url, &user_metadata)?;
+    assert_ne!(id, NO_SONG_ID);
+    let neu_ref = LogicalSongRef::new(LogicalSong {
+        id, user_metadata: BTreeMap::new(), physical_files: Vec::new(),
+        physical_tracks: Vec::new(), duration: 0,
+        practice_loops: BTreeMap::new(), remembered_position: None,
+        stream_url: Some(url.to_owned()), similarity_recs: Vec::new(),
+    });
+    // (this fills in `duration` and `song_id`)
+    neu_ref.write().unwrap().set_metadata(user_metadata);
+    LOGICAL_SONGS.write().unwrap().push(neu_ref.clone());
+    SONGS_BY_SONG_ID.write().unwrap().insert(id, neu_ref.clone());
+    GENERATION.bump();
+    Ok(neu_ref)
+}
+
 lazy_static! {
     static ref SCRIPT_GENERATION: GenerationTracker = GenerationTracker::new();
     static ref IMPORT_SCRIPT_LOCK: Mutex<()> = Mutex::new(());
@@ -573,10 +887,10 @@ fn load_import_script(lua: &Lua) -> anyhow::Result<Function> {
 }
 
 impl LogicalSong {
-    /// Does a metadata import for this song using the given `PhysicalFile` and
-    /// returns the resulting metadata. (Use `import_metadata` if you want to
-    /// import directly.)
-    pub fn get_imported_metadata(&mut self, file: &PhysicalFile,
+    /// Does a metadata import for this song using the given track of the given
+    /// `PhysicalFile` and returns the resulting metadata. (Use
+    /// `import_metadata` if you want to import directly.)
+    pub fn get_imported_metadata(&mut self, file: &PhysicalFile, track: u32,
                                  metadata: Option<&BTreeMap<String,String>>)
     -> anyhow::Result<BTreeMap<String, String>> {
         let res = TLS.with(|cell| -> anyhow::Result<BTreeMap<String,String>> {
@@ -602,7 +916,7 @@ impl LogicalSong {
                 lua.create_table_from(metadata.iter().map(|(a,b)| (a.as_str(), b.as_str()))).anyhowify()?
             }
             else {
-                lua.create_table_from(file.get_raw_metadata().iter().map(|(a,b)| (a.as_str(), b.as_str()))).anyhowify()?
+                lua.create_table_from(file.get_raw_track_metadata(track).iter().map(|(a,b)| (a.as_str(), b.as_str()))).anyhowify()?
             };
             globals.raw_set("inmeta", inmeta).anyhowify()?;
             let outmeta = lua.create_table_from(self.user_metadata.iter().map(|(a,b)| (a.as_str(), b.as_str()))).anyhowify()?;
@@ -614,6 +928,11 @@ impl LogicalSong {
             let paths = lua.create_table_from(file.get_absolute_paths().iter().enumerate().map(|(i, x)| (i+1, x.to_string_lossy().into_owned()))).anyhowify()?;
             globals.raw_set("paths", paths).anyhowify()?;
             globals.raw_set("file_id", file.get_id().to_string()).anyhowify()?;
+            let span = file.get_track_span(track);
+            let track: Option<u32> = if track == 0 { None } else { Some(track) };
+            globals.raw_set("track", track).anyhowify()?;
+            globals.raw_set("track_start", track.map(|_| span.start)).anyhowify()?;
+            globals.raw_set("track_end", track.and(span.end)).anyhowify()?;
             let song_id: Option<i64> = if self.id == NO_SONG_ID { None }
             else { Some(self.id.inner.try_into().unwrap()) };
             globals.raw_set("song_id", song_id).anyhowify()?;
@@ -629,8 +948,9 @@ impl LogicalSong {
                     new_metadata.insert(k, v);
                 }
             }
+            let duration = trimmed_duration(file.get_track_duration(track.unwrap_or(0)), &new_metadata);
             new_metadata.insert("duration".to_owned(),
-                                format!("{}", file.get_duration()));
+                                format!("{}", duration));
             if let Some(song_id) = song_id {
                 new_metadata.insert("song_id".to_owned(),
                                     format!("{}", song_id));
@@ -647,10 +967,10 @@ impl LogicalSong {
     ///
     /// `metadata`: If you already know what the physical metadata is, pass it
     /// here.
-    pub fn import_metadata(&mut self, file: &PhysicalFile,
+    pub fn import_metadata(&mut self, file: &PhysicalFile, track: u32,
                            metadata: Option<&BTreeMap<String,String>>)
     -> anyhow::Result<bool> {
-        let new_metadata = self.get_imported_metadata(file, metadata)?;
+        let new_metadata = self.get_imported_metadata(file, track, metadata)?;
         if self.user_metadata != new_metadata {
             self.user_metadata = new_metadata;
             if self.id != NO_SONG_ID {
@@ -717,7 +1037,8 @@ pub fn maybe_recreate_recs() {
         let mut song = song_ref.write().unwrap();
         assert!(song.similarity_recs.is_empty());
         let mut neu_recs = Vec::with_capacity(song.physical_files.len());
-        for id in song.physical_files.iter() {
+        for (id, &track) in song.physical_files.iter()
+            .zip(song.physical_tracks.iter()) {
             let file_ref = match physical::get_file_by_id(id) {
                 Some(x) => x,
                 None => {
@@ -727,12 +1048,10 @@ pub fn maybe_recreate_recs() {
             };
             let file = file_ref.read().unwrap();
             for path in file.get_absolute_paths() {
-                let filename = path.file_name().map(OsStr::to_string_lossy)
-                    .unwrap();
-                let metadata = file.get_raw_metadata();
+                let metadata = file.get_raw_track_metadata(track);
                 let similarity_rec: SimilarityRec = SimilarityRec::new(
-                    filename.to_owned().into(),
-                    file.get_duration(),
+                    similarity_filename(path, track),
+                    file.get_track_duration(track),
                     &metadata
                 );
                 songs_by_p_filename.entry(similarity_rec.filename.clone())
@@ -13,6 +13,17 @@ mod ui;
 mod remote;
 mod errors;
 mod bufring;
+mod cue;
+mod dsp;
+mod stretch;
+mod output;
+mod alarm;
+mod limiter;
+mod feed;
+mod podcast;
+mod broadcast;
+mod sync;
+mod writeback;
 
 use reference::Reference;
 use generation::{GenerationTracker, GenerationValue, NOT_GENERATED};
@@ -38,5 +49,7 @@ fn main() {
     }
     db::open_database().unwrap();
     ffmpeg::init();
+    broadcast::restart();
+    playback::restore_state();
     ui::go();
 }
@@ -0,0 +1,66 @@
+//! This module contains the different places that audio can be sent. The
+//! playback thread doesn't care where its audio goes; it hands a callback to
+//! whichever backend the user chose, and the backend calls it whenever it
+//! wants more audio.
+
+use crate::*;
+use prefs::AudioBackend;
+
+mod pa;
+mod null;
+mod wav;
+#[cfg(feature = "pulse")]
+mod pulse;
+
+/// Called by an output stream whenever it wants more audio. The first
+/// parameter is the (interleaved) buffer to fill. The second is the time, in
+/// the stream's timebase, at which the first sample of that buffer will reach
+/// the user's ears.
+pub type OutputCallback = Box<dyn FnMut(&mut [f32], f64) + Send>;
+
+/// Somewhere audio can go.
+pub trait Backend {
+    /// Returns the sample rate that the output device would prefer. Only
+    /// consulted if we're resampling.
+    fn native_sample_rate(&mut self) -> anyhow::Result<f64>;
+    /// Opens a stream with the given sample format. The callback may be called
+    /// before the stream is started, to prime buffers.
+    fn open_stream(&mut self, sample_rate: f64, channel_count: i32,
+                   callback: OutputCallback)
+        -> anyhow::Result<Box<dyn Stream>>;
+}
+
+/// An open output stream. Dropping it stops it immediately, without waiting
+/// for queued audio to finish playing.
+pub trait Stream {
+    /// Starts the stream. Until this is called, the stream's clock doesn't
+    /// advance.
+    fn start(&mut self) -> anyhow::Result<()>;
+    /// Returns the current time in the stream's timebase. Compare with the
+    /// times passed to the `OutputCallback`.
+    fn time(&self) -> f64;
+    /// Tells the stream what song is now being heard, for backends that can
+    /// show that to the user (e.g. in a system mixer). The default does
+    /// nothing.
+    fn set_metadata(&mut self, _title: Option<&str>, _artist: Option<&str>) {}
+}
+
+/// Creates the given kind of backend. If `use_default_device` is true, the
+/// backend ignores the user's choice of device, and uses the default one.
+pub fn open_backend(which: AudioBackend, use_default_device: bool)
+    -> anyhow::Result<Box<dyn Backend>> {
+    Ok(match which {
+        AudioBackend::PortAudio =>
+            Box::new(pa::PortAudioBackend::new(use_default_device)?),
+        AudioBackend::Null =>
+            Box::new(null::NullBackend::new(prefs::get_null_output_speed())),
+        AudioBackend::File =>
+            Box::new(wav::WavBackend::new(prefs::get_null_output_speed())?),
+        #[cfg(feature = "pulse")]
+        AudioBackend::Pulse => Box::new(pulse::PulseBackend),
+        #[cfg(not(feature = "pulse"))]
+        AudioBackend::Pulse =>
+            return Err(anyhow::anyhow!("This copy of Tsong was built without \
+                                        PulseAudio support.")),
+    })
+}
@@ -0,0 +1,210 @@
+//! Output to nowhere. Audio is consumed at the rate a real device would
+//! consume it (or faster, if `null_output_speed` says so) and then thrown
+//! away. Useful for headless machines, and for testing playback without a
+//! sound card.
+//!
+//! The `paced_stream` here is also the heart of the file backend.
+
+use crate::*;
+use super::{Backend, Stream, OutputCallback};
+
+use std::{
+    sync::{Arc, atomic::{AtomicBool, Ordering}},
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+use anyhow::anyhow;
+
+/// The sample rate we claim to prefer, if asked.
+pub const NATIVE_SAMPLE_RATE: f64 = 44100.0;
+
+/// The longest we'll sleep at one time, in (real) seconds. Keeps us
+/// responsive to being dropped.
+const MAX_SLEEP: f64 = 0.05;
+
+/// Something that wants to see every buffer that gets "played".
+pub type Tap = Box<dyn FnMut(&[f32]) + Send>;
+
+pub struct NullBackend {
+    /// How many times faster than real time to consume audio.
+    speed: f64,
+}
+
+impl NullBackend {
+    pub fn new(speed: f64) -> NullBackend {
+        NullBackend { speed }
+    }
+}
+
+impl Backend for NullBackend {
+    fn native_sample_rate(&mut self) -> anyhow::Result<f64> {
+        Ok(NATIVE_SAMPLE_RATE)
+    }
+    fn open_stream(&mut self, sample_rate: f64, channel_count: i32,
+                   callback: OutputCallback)
+        -> anyhow::Result<Box<dyn Stream>> {
+        Ok(Box::new(paced_stream(sample_rate, channel_count, self.speed,
+                                 playback::get_effective_latency(),
+                                 callback, None)?))
+    }
+}
+
+/// A stream whose clock is driven by a thread, rather than by a sound card.
+pub struct PacedStream {
+    sample_rate: f64,
+    channel_count: i32,
+    speed: f64,
+    /// How much audio, in seconds, each callback fills.
+    latency: f64,
+    /// These are taken when the stream starts.
+    callback: Option<OutputCallback>,
+    tap: Option<Tap>,
+    /// When the stream started, in real time. `None` until it does.
+    epoch: Option<Instant>,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+/// Makes a `PacedStream`, whose clock runs `speed` times faster than real
+/// time, and which asks for `latency` seconds of audio at a time. Each buffer
+/// is passed to `tap`, if there is one, after the callback fills it.
+pub fn paced_stream(sample_rate: f64, channel_count: i32, speed: f64,
+                    latency: f64, callback: OutputCallback, tap: Option<Tap>)
+    -> anyhow::Result<PacedStream> {
+    if sample_rate.is_nan() || sample_rate < 1.0 || channel_count <= 0 {
+        return Err(anyhow!("Invalid sample format: {}Hz, {} channels",
+                           sample_rate, channel_count))
+    }
+    if speed.is_nan() || speed <= 0.0 {
+        return Err(anyhow!("Invalid output speed: {}", speed))
+    }
+    Ok(PacedStream {
+        sample_rate, channel_count, speed, latency,
+        callback: Some(callback), tap,
+        epoch: None,
+        stop: Arc::new(AtomicBool::new(false)),
+        thread: None,
+    })
+}
+
+impl Stream for PacedStream {
+    fn start(&mut self) -> anyhow::Result<()> {
+        if self.epoch.is_some() { return Ok(()) }
+        let mut callback = self.callback.take().unwrap();
+        let mut tap = self.tap.take();
+        let epoch = Instant::now();
+        let speed = self.speed;
+        let stop = self.stop.clone();
+        // We pretend that the device has a buffer the size of our desired
+        // latency, and that we refill it every time it's played.
+        let buffer_frames = (self.latency * self.sample_rate).ceil()
+            .max(1.0) as usize;
+        let buffer_length = buffer_frames as f64 / self.sample_rate;
+        let mut buffer = vec![0.0; buffer_frames
+                              * self.channel_count as usize];
+        self.epoch = Some(epoch);
+        self.thread = Some(std::thread::Builder::new()
+                           .name("Paced Output".to_owned())
+                           .spawn(move || {
+            // stream time at which the next buffer will be heard
+            let mut next_heard = buffer_length;
+            while !stop.load(Ordering::Relaxed) {
+                // Wait until the previous buffer starts "playing".
+                let due = next_heard - buffer_length;
+                let now = epoch.elapsed().as_secs_f64() * speed;
+                if now < due {
+                    let wait = ((due - now) / speed).min(MAX_SLEEP);
+                    std::thread::sleep(Duration::from_secs_f64(wait));
+                    continue
+                }
+                callback(&mut buffer[..], next_heard);
+                if let Some(tap) = tap.as_mut() { tap(&buffer[..]) }
+                next_heard += buffer_length;
+            }
+        })?);
+        Ok(())
+    }
+    fn time(&self) -> f64 {
+        match self.epoch {
+            Some(x) => x.elapsed().as_secs_f64() * self.speed,
+            None => 0.0,
+        }
+    }
+}
+
+impl Drop for PacedStream {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Mutex, mpsc};
+
+    #[test]
+    fn rejects_bad_formats() {
+        let callback = || -> OutputCallback { Box::new(|_, _| ()) };
+        assert!(paced_stream(0.0, 2, 1.0, 0.1, callback(), None).is_err());
+        assert!(paced_stream(44100.0, 0, 1.0, 0.1, callback(), None).is_err());
+        assert!(paced_stream(44100.0, 2, 0.0, 0.1, callback(), None).is_err());
+        assert!(paced_stream(44100.0, 2, 1.0, 0.1, callback(), None).is_ok());
+    }
+
+    #[test]
+    fn clock_waits_for_start() {
+        let mut stream = paced_stream(1000.0, 1, 100.0, 0.1,
+                                      Box::new(|_, _| ()), None).unwrap();
+        std::thread::sleep(Duration::from_millis(20));
+        assert_eq!(stream.time(), 0.0);
+        stream.start().unwrap();
+        std::thread::sleep(Duration::from_millis(20));
+        // 20ms at 100x is two seconds
+        assert!(stream.time() >= 2.0);
+    }
+
+    #[test]
+    fn buffers_follow_latency_and_speed() {
+        let (tx, rx) = mpsc::channel();
+        let tx = Mutex::new(tx);
+        let callback = Box::new(move |buffer: &mut [f32], heard: f64| {
+            for (n, sample) in buffer.iter_mut().enumerate() {
+                *sample = n as f32;
+            }
+            let _ = tx.lock().unwrap().send((buffer.len(), heard));
+        });
+        let tapped = Arc::new(Mutex::new(Vec::new()));
+        let tap_tapped = tapped.clone();
+        let tap = Box::new(move |buffer: &[f32]| {
+            tap_tapped.lock().unwrap().push(buffer.to_vec());
+        });
+        let mut stream = paced_stream(1000.0, 2, 50.0, 0.25, callback,
+                                      Some(tap)).unwrap();
+        stream.start().unwrap();
+        let start = Instant::now();
+        let mut calls = Vec::new();
+        while calls.len() < 8 {
+            calls.push(rx.recv_timeout(Duration::from_secs(5)).unwrap());
+        }
+        // 8 buffers of a quarter second each, at 50x, take about 40ms. (The
+        // first one doesn't wait at all.)
+        assert!(start.elapsed() >= Duration::from_millis(30));
+        drop(stream);
+        for (n, &(len, heard)) in calls.iter().enumerate() {
+            // 250 frames, two channels each
+            assert_eq!(len, 500);
+            assert!((heard - (n + 1) as f64 * 0.25).abs() < 1e-9);
+        }
+        let tapped = tapped.lock().unwrap();
+        assert!(tapped.len() >= 8);
+        for buffer in tapped.iter() {
+            assert_eq!(buffer.len(), 500);
+            assert_eq!(buffer[499], 499.0);
+        }
+    }
+}
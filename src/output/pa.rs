@@ -0,0 +1,137 @@
+//! Output through PortAudio, to whatever host API and device the user chose
+//! in the settings.
+
+use crate::*;
+use super::{Backend, Stream, OutputCallback};
+
+use log::warn;
+use std::{
+    sync::{Arc, atomic::{AtomicBool, Ordering}},
+    time::Instant,
+};
+
+use portaudio::{
+    stream::{Parameters, OutputSettings, OutputCallbackArgs},
+    DeviceIndex,
+    NonBlocking,
+    Output,
+    PortAudio,
+    StreamCallbackResult,
+};
+use anyhow::anyhow;
+
+pub struct PortAudioBackend {
+    pa: PortAudio,
+    /// If true, use the chosen host API's default device, no matter which
+    /// device the user chose.
+    use_default_device: bool,
+}
+
+impl PortAudioBackend {
+    pub fn new(use_default_device: bool)
+        -> anyhow::Result<PortAudioBackend> {
+        // (PortAudio only looks for devices when it's initialized, so making a
+        // new backend is also how we notice devices coming and going.)
+        let pa = PortAudio::new()
+            .or_else(|x| Err(anyhow!("Could not initialize PortAudio: {}",
+                                     x)))?;
+        Ok(PortAudioBackend { pa, use_default_device })
+    }
+    /// Figures out which device the user wants us to use.
+    fn chosen_device(&self) -> anyhow::Result<DeviceIndex> {
+        let pa = &self.pa;
+        let hostapi_index = prefs::get_chosen_audio_api(pa);
+        let device_index = if self.use_default_device { None }
+        else {
+            prefs::get_chosen_audio_device_for_api(pa, hostapi_index)
+        };
+        Ok(match device_index {
+            Some(x) => pa.api_device_index_to_device_index
+                (hostapi_index, x as i32)
+                .or_else(|x| Err(anyhow!("Error finding a device by index: {}",
+                                         x)))?,
+            None => match pa.host_api_info(hostapi_index)
+                .and_then(|x| x.default_output_device) {
+                    Some(x) => x,
+                    None => pa.default_output_device()
+                        .or_else(|_| Err(anyhow!("No default output \
+                                                  device?")))?
+                }
+        })
+    }
+}
+
+impl Backend for PortAudioBackend {
+    fn native_sample_rate(&mut self) -> anyhow::Result<f64> {
+        let info = self.pa.device_info(self.chosen_device()?)?;
+        if info.default_sample_rate < 1.0 { Ok(44100.0) }
+        else { Ok(info.default_sample_rate) }
+    }
+    fn open_stream(&mut self, sample_rate: f64, channel_count: i32,
+                   mut callback: OutputCallback)
+        -> anyhow::Result<Box<dyn Stream>> {
+        let device_index = self.chosen_device()?;
+        let parameters = Parameters::new(device_index,
+                                         channel_count,
+                                         true, // interleaved
+                                         playback::get_effective_latency());
+        let flags = portaudio::stream_flags
+            ::PA_PRIME_OUTPUT_BUFFERS_USING_STREAM_CALLBACK;
+        let settings = OutputSettings::with_flags(parameters, sample_rate,
+                                                  0, flags);
+        let broken_time = Arc::new(AtomicBool::new(false));
+        let epoch = Instant::now();
+        let callback_broken_time = broken_time.clone();
+        let stream = self.pa.open_non_blocking_stream(settings, move |args: OutputCallbackArgs<f32>| {
+            let OutputCallbackArgs { buffer, time, .. } = args;
+            let now = if time.current == 0.0 && time.buffer_dac == 0.0 {
+                let was_broken = callback_broken_time
+                    .swap(true, Ordering::Release);
+                let true_now = epoch.elapsed().as_secs_f64();
+                if !was_broken {
+                    warn!("Stream time is broken on this driver! Using the \
+                           wall-clock hack!");
+                    true_now // don't add latency, we're hopefully priming
+                }
+                else {
+                    true_now + playback::get_effective_latency()
+                }
+            }
+            else {
+                time.buffer_dac
+            };
+            callback(buffer, now);
+            // some PA backends are buggy (including the one that ends up
+            // talking to the "other" PA) and will drop buffers if we use
+            // ::Complete.
+            StreamCallbackResult::Continue
+        }).or_else(|x| Err(anyhow!("Unable to open audio stream: {}", x)))?;
+        Ok(Box::new(PortAudioStream { stream, broken_time, epoch }))
+    }
+}
+
+struct PortAudioStream {
+    stream: portaudio::Stream<NonBlocking, Output<f32>>,
+    /// Set by the callback if the driver doesn't give us usable stream times.
+    broken_time: Arc<AtomicBool>,
+    /// used if `broken_time` is true
+    epoch: Instant,
+}
+
+impl Stream for PortAudioStream {
+    fn start(&mut self) -> anyhow::Result<()> {
+        self.stream.start()
+            .or_else(|x| Err(anyhow!("Unable to start audio stream: {}", x)))
+    }
+    fn time(&self) -> f64 {
+        if self.broken_time.load(Ordering::Acquire) {
+            self.epoch.elapsed().as_secs_f64()
+        } else { self.stream.time() }
+    }
+}
+
+impl Drop for PortAudioStream {
+    fn drop(&mut self) {
+        let _ = self.stream.abort();
+    }
+}
@@ -0,0 +1,244 @@
+//! Output through the PulseAudio protocol, which PipeWire also speaks. This
+//! gets us per-application volume, a stream name and song metadata in the
+//! mixer, and streams that follow the server's default sink around (we never
+//! ask for a particular sink, so the server is free to move us).
+//!
+//! Everything here runs on PulseAudio's threaded mainloop. Any call into the
+//! context or stream from outside a callback must hold the mainloop lock.
+
+use crate::*;
+use super::{Backend, Stream, OutputCallback};
+
+use std::{
+    cell::RefCell,
+    rc::Rc,
+};
+
+use libpulse_binding as pulse;
+use pulse::{
+    context::{self, Context},
+    def::BufferAttr,
+    mainloop::threaded::Mainloop,
+    proplist::{self, Proplist, properties},
+    sample::{Format, Spec},
+    stream::{self, SeekMode},
+};
+use anyhow::anyhow;
+
+/// The sample rate we claim to prefer, if asked. The server will resample to
+/// whatever the sink actually wants, and it's better at it than guessing.
+const NATIVE_SAMPLE_RATE: f64 = 48000.0;
+
+pub struct PulseBackend;
+
+impl Backend for PulseBackend {
+    fn native_sample_rate(&mut self) -> anyhow::Result<f64> {
+        Ok(NATIVE_SAMPLE_RATE)
+    }
+    fn open_stream(&mut self, sample_rate: f64, channel_count: i32,
+                   callback: OutputCallback)
+        -> anyhow::Result<Box<dyn Stream>> {
+        Ok(Box::new(PulseStream::open(sample_rate, channel_count, callback)?))
+    }
+}
+
+struct PulseStream {
+    mainloop: Rc<RefCell<Mainloop>>,
+    context: Rc<RefCell<Context>>,
+    stream: Rc<RefCell<stream::Stream>>,
+}
+
+fn make_proplist() -> anyhow::Result<Proplist> {
+    let mut proplist = Proplist::new()
+        .ok_or_else(|| anyhow!("Unable to create a PulseAudio proplist"))?;
+    let _ = proplist.set_str(properties::APPLICATION_NAME, "Tsong");
+    let _ = proplist.set_str(properties::APPLICATION_ID, "name.bizna.tsong");
+    let _ = proplist.set_str(properties::APPLICATION_ICON_NAME, "tsong");
+    let _ = proplist.set_str(properties::MEDIA_ROLE, "music");
+    Ok(proplist)
+}
+
+impl PulseStream {
+    fn open(sample_rate: f64, channel_count: i32, mut callback: OutputCallback)
+        -> anyhow::Result<PulseStream> {
+        let spec = Spec {
+            format: Format::FLOAT32NE,
+            channels: channel_count as u8,
+            rate: sample_rate.round() as u32,
+        };
+        if channel_count <= 0 || !spec.is_valid() {
+            return Err(anyhow!("PulseAudio can't play {}Hz audio with {} \
+                                channels", sample_rate, channel_count))
+        }
+        let mut proplist = make_proplist()?;
+        let mainloop = Rc::new(RefCell::new(
+            Mainloop::new().ok_or_else(|| anyhow!("Unable to create a \
+                                                   PulseAudio mainloop"))?));
+        let context = Rc::new(RefCell::new(
+            Context::new_with_proplist(&*mainloop.borrow(), "Tsong",
+                                       &proplist)
+                .ok_or_else(|| anyhow!("Unable to create a PulseAudio \
+                                        context"))?));
+        {
+            let mainloop_ref = mainloop.clone();
+            context.borrow_mut().set_state_callback(Some(Box::new(move || {
+                unsafe { (*mainloop_ref.as_ptr()).signal(false); }
+            })));
+        }
+        context.borrow_mut().connect(None, context::FlagSet::NOFLAGS, None)
+            .map_err(|x| anyhow!("Unable to connect to PulseAudio: {}", x))?;
+        mainloop.borrow_mut().lock();
+        // From here until we unlock, every early return needs to unlock too,
+        // so do the rest in a closure.
+        let res = (|| -> anyhow::Result<Rc<RefCell<stream::Stream>>> {
+            mainloop.borrow_mut().start()
+                .map_err(|x| anyhow!("Unable to start PulseAudio mainloop: \
+                                      {}", x))?;
+            loop {
+                match context.borrow().get_state() {
+                    context::State::Ready => break,
+                    context::State::Failed | context::State::Terminated =>
+                        return Err(anyhow!("Unable to connect to \
+                                            PulseAudio")),
+                    _ => mainloop.borrow_mut().wait(),
+                }
+            }
+            let stream = Rc::new(RefCell::new(
+                stream::Stream::new_with_proplist(&mut context.borrow_mut(),
+                                                  "Music", &spec, None,
+                                                  &mut proplist)
+                    .ok_or_else(|| anyhow!("Unable to create a PulseAudio \
+                                            stream"))?));
+            {
+                let mainloop_ref = mainloop.clone();
+                stream.borrow_mut().set_state_callback(Some(Box::new(move || {
+                    unsafe { (*mainloop_ref.as_ptr()).signal(false); }
+                })));
+            }
+            // Position, in frames, of the next frame we'll write. The
+            // stream's clock is in the same units (once divided by the
+            // sample rate).
+            let mut written_frames: u64 = 0;
+            let mut buffer: Vec<f32> = Vec::new();
+            let frame_bytes = channel_count as usize
+                * std::mem::size_of::<f32>();
+            {
+                let stream_ref = stream.clone();
+                stream.borrow_mut().set_write_callback(Some(Box::new(move |nbytes: usize| {
+                    let frames = nbytes / frame_bytes;
+                    if frames == 0 { return }
+                    buffer.resize(frames * channel_count as usize, 0.0);
+                    callback(&mut buffer[..],
+                             written_frames as f64 / sample_rate);
+                    written_frames += frames as u64;
+                    let bytes = unsafe {
+                        std::slice::from_raw_parts(buffer.as_ptr() as *const u8,
+                                                   frames * frame_bytes)
+                    };
+                    // We're inside a callback on the mainloop thread, so the
+                    // lock is held, but the `RefCell` might not be free.
+                    let stream = unsafe { &mut *stream_ref.as_ptr() };
+                    let _ = stream.write(bytes, None, 0, SeekMode::Relative);
+                })));
+            }
+            let bytes_per_second = spec.rate as f64 * frame_bytes as f64;
+            let attr = BufferAttr {
+                maxlength: u32::MAX,
+                tlength: (playback::get_effective_latency()
+                          * bytes_per_second) as u32,
+                prebuf: u32::MAX,
+                minreq: u32::MAX,
+                fragsize: u32::MAX,
+            };
+            // No device name, so that the server picks (and keeps picking)
+            // the default sink for us.
+            stream.borrow_mut().connect_playback(None, Some(&attr),
+                                                 stream::FlagSet::START_CORKED
+                                                 | stream::FlagSet::INTERPOLATE_TIMING
+                                                 | stream::FlagSet::AUTO_TIMING_UPDATE
+                                                 | stream::FlagSet::ADJUST_LATENCY,
+                                                 None, None)
+                .map_err(|x| anyhow!("Unable to open PulseAudio stream: {}",
+                                     x))?;
+            loop {
+                match stream.borrow().get_state() {
+                    stream::State::Ready => break,
+                    stream::State::Failed | stream::State::Terminated =>
+                        return Err(anyhow!("Unable to open PulseAudio \
+                                            stream")),
+                    _ => mainloop.borrow_mut().wait(),
+                }
+            }
+            Ok(stream)
+        })();
+        mainloop.borrow_mut().unlock();
+        match res {
+            Ok(stream) => Ok(PulseStream { mainloop, context, stream }),
+            Err(x) => {
+                mainloop.borrow_mut().stop();
+                Err(x)
+            },
+        }
+    }
+}
+
+impl Stream for PulseStream {
+    fn start(&mut self) -> anyhow::Result<()> {
+        self.mainloop.borrow_mut().lock();
+        let _ = self.stream.borrow_mut().uncork(None);
+        self.mainloop.borrow_mut().unlock();
+        Ok(())
+    }
+    fn time(&self) -> f64 {
+        self.mainloop.borrow_mut().lock();
+        let ret = match self.stream.borrow().get_time() {
+            Ok(Some(x)) => x.0 as f64 / 1000000.0,
+            _ => 0.0,
+        };
+        self.mainloop.borrow_mut().unlock();
+        ret
+    }
+    fn set_metadata(&mut self, title: Option<&str>, artist: Option<&str>) {
+        let mut proplist = match make_proplist() {
+            Ok(x) => x,
+            Err(_) => return,
+        };
+        let name = match (title, artist) {
+            (Some(title), Some(artist)) => format!("{} - {}", artist, title),
+            (Some(title), None) => title.to_owned(),
+            _ => "Music".to_owned(),
+        };
+        let _ = proplist.set_str(properties::MEDIA_NAME, &name);
+        if let Some(title) = title {
+            let _ = proplist.set_str(properties::MEDIA_TITLE, title);
+        }
+        if let Some(artist) = artist {
+            let _ = proplist.set_str(properties::MEDIA_ARTIST, artist);
+        }
+        self.mainloop.borrow_mut().lock();
+        let _ = self.stream.borrow_mut()
+            .update_proplist(proplist::UpdateMode::Replace, &mut proplist,
+                             None);
+        self.mainloop.borrow_mut().unlock();
+    }
+}
+
+impl Drop for PulseStream {
+    fn drop(&mut self) {
+        self.mainloop.borrow_mut().lock();
+        {
+            let mut stream = self.stream.borrow_mut();
+            // (this also breaks the reference cycle through the callback)
+            stream.set_write_callback(None);
+            stream.set_state_callback(None);
+            let _ = stream.disconnect();
+        }
+        {
+            let mut context = self.context.borrow_mut();
+            context.set_state_callback(None);
+            context.disconnect();
+        }
+        self.mainloop.borrow_mut().unlock();
+        self.mainloop.borrow_mut().stop();
+    }
+}
@@ -0,0 +1,174 @@
+//! Output to a WAV file. The file gets exactly what would have been heard,
+//! silence and all, paced the same way as the null backend.
+//!
+//! Pausing and resuming keeps appending to the same file. If the sample format
+//! changes, the current file is finished and a new one is started next to it,
+//! with a number on the end of its name.
+
+use crate::*;
+use super::{Backend, Stream, OutputCallback, null};
+
+use log::error;
+use std::{
+    fs::File,
+    io::{BufWriter, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use anyhow::anyhow;
+
+/// Where in the file the RIFF chunk's size goes.
+const RIFF_SIZE_OFFSET: u64 = 4;
+/// Where in the file the `fact` chunk's sample count goes.
+const FACT_COUNT_OFFSET: u64 = 46;
+/// Where in the file the `data` chunk's size goes.
+const DATA_SIZE_OFFSET: u64 = 54;
+/// How big the header is. The RIFF chunk's size is this, minus 8, plus the
+/// size of the data.
+const HEADER_SIZE: u64 = 58;
+
+/// Writes a 32-bit float WAV file.
+struct WavWriter {
+    file: BufWriter<File>,
+    sample_rate: f64,
+    channel_count: i32,
+    data_bytes: u64,
+}
+
+impl WavWriter {
+    fn create(path: &Path, sample_rate: f64, channel_count: i32)
+        -> anyhow::Result<WavWriter> {
+        let mut file = BufWriter::new(File::create(path)?);
+        let rate = sample_rate.round() as u32;
+        let channels = channel_count as u16;
+        let block_align = channels * 4;
+        file.write_all(b"RIFF")?;
+        file.write_all(&((HEADER_SIZE - 8) as u32).to_le_bytes())?;
+        file.write_all(b"WAVE")?;
+        file.write_all(b"fmt ")?;
+        file.write_all(&18u32.to_le_bytes())?;
+        file.write_all(&3u16.to_le_bytes())?; // WAVE_FORMAT_IEEE_FLOAT
+        file.write_all(&channels.to_le_bytes())?;
+        file.write_all(&rate.to_le_bytes())?;
+        file.write_all(&(rate * block_align as u32).to_le_bytes())?;
+        file.write_all(&block_align.to_le_bytes())?;
+        file.write_all(&32u16.to_le_bytes())?; // bits per sample
+        file.write_all(&0u16.to_le_bytes())?; // no extension
+        file.write_all(b"fact")?;
+        file.write_all(&4u32.to_le_bytes())?;
+        file.write_all(&0u32.to_le_bytes())?;
+        file.write_all(b"data")?;
+        file.write_all(&0u32.to_le_bytes())?;
+        Ok(WavWriter { file, sample_rate, channel_count, data_bytes: 0 })
+    }
+    fn write(&mut self, samples: &[f32]) -> std::io::Result<()> {
+        for sample in samples.iter() {
+            self.file.write_all(&sample.to_le_bytes())?;
+        }
+        self.data_bytes += samples.len() as u64 * 4;
+        Ok(())
+    }
+    /// Fills in the sizes in the header, so that the file is valid up to the
+    /// current point.
+    fn update_header(&mut self) -> std::io::Result<()> {
+        // (a WAV file can't be bigger than 4GiB, but let's not panic over it)
+        let data_bytes = self.data_bytes.min(u32::MAX as u64 - HEADER_SIZE);
+        let frames = data_bytes / (self.channel_count as u64 * 4);
+        self.file.seek(SeekFrom::Start(RIFF_SIZE_OFFSET))?;
+        self.file.write_all(&((HEADER_SIZE - 8 + data_bytes) as u32)
+                            .to_le_bytes())?;
+        self.file.seek(SeekFrom::Start(FACT_COUNT_OFFSET))?;
+        self.file.write_all(&(frames as u32).to_le_bytes())?;
+        self.file.seek(SeekFrom::Start(DATA_SIZE_OFFSET))?;
+        self.file.write_all(&(data_bytes as u32).to_le_bytes())?;
+        self.file.seek(SeekFrom::End(0))?;
+        self.file.flush()
+    }
+}
+
+impl Drop for WavWriter {
+    fn drop(&mut self) {
+        if let Err(x) = self.update_header() {
+            error!("Unable to finish writing WAV file: {}", x);
+        }
+    }
+}
+
+pub struct WavBackend {
+    path: PathBuf,
+    /// How many files we've started so far.
+    file_count: u32,
+    writer: Arc<Mutex<Option<WavWriter>>>,
+    /// How many times faster than real time to write audio.
+    speed: f64,
+}
+
+impl WavBackend {
+    pub fn new(speed: f64) -> anyhow::Result<WavBackend> {
+        let path = prefs::get_output_file()
+            .ok_or_else(|| anyhow!("The file audio backend is selected, but \
+                                    no output file is set."))?;
+        Ok(WavBackend {
+            path, file_count: 0, writer: Arc::new(Mutex::new(None)), speed,
+        })
+    }
+    /// Returns the path that the next file should be written to.
+    fn next_path(&mut self) -> PathBuf {
+        self.file_count += 1;
+        if self.file_count == 1 { return self.path.clone() }
+        let stem = self.path.file_stem()
+            .map(|x| x.to_string_lossy().into_owned())
+            .unwrap_or_else(String::new);
+        let name = match self.path.extension() {
+            Some(ext) => format!("{}-{}.{}", stem, self.file_count,
+                                 ext.to_string_lossy()),
+            None => format!("{}-{}", stem, self.file_count),
+        };
+        self.path.with_file_name(name)
+    }
+}
+
+impl Backend for WavBackend {
+    fn native_sample_rate(&mut self) -> anyhow::Result<f64> {
+        Ok(null::NATIVE_SAMPLE_RATE)
+    }
+    fn open_stream(&mut self, sample_rate: f64, channel_count: i32,
+                   callback: OutputCallback)
+        -> anyhow::Result<Box<dyn Stream>> {
+        {
+            let mut writer = self.writer.lock().unwrap();
+            let reusable = match writer.as_ref() {
+                Some(x) => x.sample_rate == sample_rate
+                    && x.channel_count == channel_count,
+                None => false,
+            };
+            if !reusable {
+                // finish the old one before starting the new one
+                *writer = None;
+                let path = self.next_path();
+                *writer = Some(WavWriter::create(&path, sample_rate,
+                                                 channel_count)?);
+            }
+        }
+        let writer = self.writer.clone();
+        let tap = Box::new(move |buffer: &[f32]| {
+            let mut writer = writer.lock().unwrap();
+            let res = match writer.as_mut() {
+                Some(x) => x.write(buffer).and_then(|_| x.update_header()),
+                None => return,
+            };
+            if let Err(x) = res {
+                error!("Unable to write to WAV file: {}", x);
+                errors::from("Audio Output",
+                             format!("Unable to write to WAV file: {}", x));
+                // don't keep trying
+                *writer = None;
+            }
+        });
+        Ok(Box::new(null::paced_stream(sample_rate, channel_count,
+                                       self.speed,
+                                       playback::get_effective_latency(),
+                                       callback, Some(tap))?))
+    }
+}
@@ -19,6 +19,7 @@ use std::{
     sync::RwLock,
 };
 use anyhow::anyhow;
+use serde::{Serialize,Deserialize};
 
 use lsx::{
     sha256,
@@ -78,9 +79,40 @@ impl FileID {
     }
 }
 
-/// A *physical file* is a file on the disk. It contains (from our perspective)
-/// exactly one *logical song*. Different encodings, etc. of the same logical
-/// song correspond to different physical files.
+/// One of several songs contained in the same physical file, as delimited by
+/// a cue sheet or by chapters in the container.
+#[derive(Clone,Debug,Serialize,Deserialize,PartialEq)]
+pub struct Track {
+    /// Where the track starts, in seconds from the beginning of the file.
+    pub start: f64,
+    /// Where the track ends, in seconds from the beginning of the file. `None`
+    /// means it goes until the end of the file.
+    pub end: Option<f64>,
+    /// Metadata from the cue sheet or chapter, which takes precedence over the
+    /// file's own metadata.
+    pub metadata: BTreeMap<String, String>,
+}
+
+/// The part of a physical file that should actually be played, in seconds from
+/// the beginning of the file.
+#[derive(Clone,Copy,Debug,Default,PartialEq)]
+pub struct Span {
+    pub start: f64,
+    pub end: Option<f64>,
+}
+
+impl Span {
+    /// A span covering the whole file.
+    pub const WHOLE: Span = Span { start: 0.0, end: None };
+}
+
+/// A *physical file* is a file on the disk. Usually, it contains (from our
+/// perspective) exactly one *logical song*. Different encodings, etc. of the
+/// same logical song correspond to different physical files.
+///
+/// Some files (whole-album rips with a cue sheet, containers with chapters)
+/// contain several *tracks*, each of which is its own logical song. Tracks
+/// are numbered from 1. Track 0 always means the whole file.
 #[derive(Debug)]
 pub struct PhysicalFile {
     // Serialized in database
@@ -96,6 +128,10 @@ pub struct PhysicalFile {
     /// as a shortcut (in combination with size) to prevent having to rescan
     /// every file on every startup.
     relative_paths: Vec<String>,
+    /// The tracks this file is divided into, or an empty list if it's a single
+    /// song. `None` if this file was scanned before we knew about tracks, and
+    /// we haven't checked it yet.
+    tracks: Option<Vec<Track>>,
     // Not serialized in database
     /// Raw metadata, exactly as returned by FFMPEG.
     raw_meta: AtomicTake<BTreeMap<String,String>>,
@@ -140,6 +176,91 @@ impl PhysicalFile {
     pub fn get_duration(&self) -> u32 {
         self.duration
     }
+    /// Returns the file's size, in bytes.
+    pub fn get_size(&self) -> u64 {
+        self.size
+    }
+    /// Returns the tracks this file is divided into. Empty if the file is a
+    /// single song.
+    pub fn get_tracks(&self) -> &[Track] {
+        match self.tracks.as_ref() {
+            Some(x) => &x[..],
+            None => &[],
+        }
+    }
+    /// Returns the part of the file corresponding to the given track number.
+    /// Track 0, and any track that doesn't exist, is the whole file.
+    pub fn get_track_span(&self, track: u32) -> Span {
+        match (track as usize).checked_sub(1)
+            .and_then(|x| self.get_tracks().get(x)) {
+                Some(x) => Span { start: x.start, end: x.end },
+                None => Span::WHOLE,
+            }
+    }
+    /// Returns the (approximate) duration of the given track number, in
+    /// seconds.
+    pub fn get_track_duration(&self, track: u32) -> u32 {
+        let span = self.get_track_span(track);
+        let end = span.end.unwrap_or(self.duration as f64);
+        (end - span.start).round().max(0.0) as u32
+    }
+    /// Puts the metadata of the given track number (from the cue sheet or
+    /// chapter) on top of the given raw metadata of the file.
+    pub fn apply_track_metadata(&self, track: u32,
+                                metadata: &mut BTreeMap<String, String>) {
+        if let Some(x) = (track as usize).checked_sub(1)
+            .and_then(|x| self.get_tracks().get(x)) {
+                for (k, v) in x.metadata.iter() {
+                    metadata.insert(k.clone(), v.clone());
+                }
+            }
+    }
+    /// Reads the raw metadata for the given track number: the metadata of the
+    /// file, with the metadata of the track on top of it. Same caveats as
+    /// `get_raw_metadata`.
+    pub fn get_raw_track_metadata(&self, track: u32)
+    -> BTreeMap<String, String> {
+        let mut ret = self.get_raw_metadata();
+        self.apply_track_metadata(track, &mut ret);
+        ret
+    }
+    /// Returns the track numbers that should become logical songs: 1 through
+    /// N if the file is divided into tracks, or just 0 otherwise.
+    pub fn get_track_numbers(&self) -> Vec<u32> {
+        match self.get_tracks().len() {
+            0 => vec![0],
+            x => (1 ..= x as u32).collect(),
+        }
+    }
+}
+
+/// Why a file that the scanner found is not a song.
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub enum NonMusicKind {
+    /// FFMPEG could open it, but it has no audio stream. (Images, etc.)
+    NoAudio,
+    /// It has an audio stream, but it also has a video stream, and the user
+    /// doesn't want videos treated as songs.
+    Video,
+    /// FFMPEG didn't recognize it at all. (Documents, etc.)
+    Unrecognized,
+}
+
+impl NonMusicKind {
+    pub fn to_db_value(&self) -> i8 {
+        match self {
+            NonMusicKind::NoAudio => 0,
+            NonMusicKind::Video => 1,
+            NonMusicKind::Unrecognized => 2,
+        }
+    }
+    pub fn from_db_value(n: i64) -> NonMusicKind {
+        match n {
+            1 => NonMusicKind::Video,
+            2 => NonMusicKind::Unrecognized,
+            _ => NonMusicKind::NoAudio,
+        }
+    }
 }
 
 lazy_static! {
@@ -153,11 +274,17 @@ lazy_static! {
     static ref FILES_BY_RELATIVE_PATH
         : RwLock<HashMap<String, Vec<PhysicalFileRef>>>
         = RwLock::new(HashMap::new());
+    /// Files that we've found, opened, and decided weren't songs. Keyed by
+    /// absolute path. Values are size, mtime, and why it's not a song.
+    static ref NON_MUSIC_FILES
+        : RwLock<HashMap<String, (u64, u64, NonMusicKind)>>
+        = RwLock::new(HashMap::new());
 }
 
 /// Called by the database during initial database load.
 pub fn add_file_from_db(id: FileID, size: u64, duration: u32,
-                        relative_paths: Vec<String>) {
+                        relative_paths: Vec<String>,
+                        tracks: Option<Vec<Track>>) {
     let mut physical_files = PHYSICAL_FILES.write().unwrap();
     let mut files_by_relative_path = FILES_BY_RELATIVE_PATH.write().unwrap();
     let neu_ref = match physical_files.entry(id) {
@@ -169,7 +296,7 @@ pub fn add_file_from_db(id: FileID, size: u64, duration: u32,
         Entry::Vacant(ent) => {
             let record = PhysicalFileRef::new(PhysicalFile {
                 id, size, raw_meta: AtomicTake::empty(), duration,
-                relative_paths, absolute_paths: vec![],
+                relative_paths, tracks, absolute_paths: vec![],
             });
             ent.insert(record.clone());
             record
@@ -192,6 +319,49 @@ pub fn add_file_from_db(id: FileID, size: u64, duration: u32,
     }
 }
 
+/// Called by the database during initial database load.
+pub fn add_non_music_file_from_db(absolute_path: String, size: u64, mtime: u64,
+                                  kind: NonMusicKind) {
+    NON_MUSIC_FILES.write().unwrap().insert(absolute_path, (size,mtime,kind));
+}
+
+/// Called by the scanner when it finds a file that it doesn't recognize as a
+/// song. Returns true if we've already looked at this file and found that it
+/// isn't a song, and it doesn't appear to have changed since then.
+pub fn is_known_non_music_file(size: u64, mtime: u64, absolute_path: &Path)
+    -> bool {
+    let absolute_path = absolute_path.to_string_lossy();
+    match NON_MUSIC_FILES.read().unwrap().get(absolute_path.as_ref()) {
+        Some(&(known_size, known_mtime, kind))
+            if known_size == size && known_mtime == mtime => {
+                // If it was only skipped for being a video, and the user now
+                // wants videos, we'll have to look again.
+                kind != NonMusicKind::Video || !prefs::get_play_video_files()
+            },
+        _ => false,
+    }
+}
+
+/// Called by the scanner when it has opened a file and determined that it
+/// isn't a song, so that we won't waste time opening it again next time.
+pub fn saw_non_music_file(size: u64, mtime: u64, absolute_path: &Path,
+                          kind: NonMusicKind) {
+    let absolute_path = absolute_path.to_string_lossy().into_owned();
+    db::add_non_music_file(&absolute_path, size, mtime, kind);
+    NON_MUSIC_FILES.write().unwrap().insert(absolute_path, (size,mtime,kind));
+}
+
+/// Called by the scanner when it has opened a file and determined that it
+/// *is* a song, in case it previously wasn't.
+pub fn forget_non_music_file(absolute_path: &Path) {
+    let absolute_path = absolute_path.to_string_lossy();
+    let removed = NON_MUSIC_FILES.write().unwrap()
+        .remove(absolute_path.as_ref()).is_some();
+    if removed {
+        db::delete_non_music_file(&absolute_path);
+    }
+}
+
 /// Called by the scanner when it first finds a file. Will return its file ID
 /// if the file is already in our database, or `None` if it must be deeply
 /// scanned.
@@ -224,7 +394,7 @@ pub fn saw_file(size: u64, _mtime: u64,
 /// matches what we already have, and throws an error if it doesn't.
 pub fn scanned_file(id: &FileID, size: u64, _mtime: u64, duration: u32,
                     relative_path: &str, absolute_path: &Path,
-                    raw_meta: BTreeMap<String,String>)
+                    raw_meta: BTreeMap<String,String>, tracks: Vec<Track>)
     -> anyhow::Result<()> {
     // Use writer locks because we're *fairly* sure we're gonna have to write
     // something...
@@ -266,12 +436,14 @@ pub fn scanned_file(id: &FileID, size: u64, _mtime: u64, duration: u32,
                     id: *id, size, duration,
                     raw_meta: AtomicTake::new(raw_meta),
                     relative_paths: vec![relative_path.to_owned()],
+                    tracks: Some(tracks),
                     absolute_paths: vec![absolute_path.to_owned()],
                 });
                 ent.insert(record_ref.clone());
                 let record = record_ref.read().unwrap();
                 db::add_file(&record.id, record.size,
-                             record.duration, &record.relative_paths);
+                             record.duration, &record.relative_paths,
+                             record.get_tracks());
                 drop(record);
                 record_ref
             },
@@ -295,13 +467,45 @@ pub fn scanned_file(id: &FileID, size: u64, _mtime: u64, duration: u32,
     Ok(())
 }
 
-/// Tries to open this `PhysicalFile` for decoding. Errors will be logged.
-pub fn open_stream(id: &FileID) -> Option<ffmpeg::AVFormat> {
+/// Returns true if the given file was scanned before we knew about tracks,
+/// and we haven't yet checked whether it has a cue sheet or chapters.
+pub fn needs_track_check(id: &FileID) -> bool {
+    match PHYSICAL_FILES.read().unwrap().get(id) {
+        Some(x) => x.read().unwrap().tracks.is_none(),
+        None => false,
+    }
+}
+
+/// Called by the scanner when it has checked an already-known file for a cue
+/// sheet or chapters. If it turns out to have tracks, new logical songs will
+/// be made for them. (Whatever logical song already had the whole file keeps
+/// it.)
+pub fn checked_file_tracks(id: &FileID, tracks: Vec<Track>) {
+    let record = match PHYSICAL_FILES.read().unwrap().get(id) {
+        Some(x) => x.clone(),
+        None => return,
+    };
+    {
+        let mut record = record.write().unwrap();
+        db::update_file_tracks(id, &tracks);
+        record.tracks = Some(tracks);
+    }
+    if !record.read().unwrap().get_tracks().is_empty() {
+        logical::incorporate_physical(record);
+    }
+}
+
+/// Tries to open the given track of this `PhysicalFile` for decoding. Errors
+/// will be logged. Returns the opened file, and the part of it that should be
+/// played.
+pub fn open_stream(id: &FileID, track: u32)
+-> Option<(ffmpeg::AVFormat, Span)> {
     let files = PHYSICAL_FILES.read().unwrap();
     let file = files.get(id)?.read().unwrap();
+    let span = file.get_track_span(track);
     for path in file.absolute_paths.iter() {
         match ffmpeg::AVFormat::open_input(&path) {
-            Ok(x) => return Some(x),
+            Ok(x) => return Some((x, span)),
             Err(x) => {
                 warn!("Unable to open {:?}: {:?}", path, x);
                 continue
@@ -315,7 +519,58 @@ pub fn get_file_by_id(id: &FileID) -> Option<PhysicalFileRef> {
     PHYSICAL_FILES.read().unwrap().get(id).cloned()
 }
 
-fn try_read_metadata(path: &Path) -> anyhow::Result<BTreeMap<String,String>> {
+/// Called when a file's contents are being deliberately changed in place (see
+/// `writeback`), giving it a new ID. The new file takes over everything we
+/// knew about the old one, except for its size and duration. Call
+/// `logical::replace_physical_file` afterward, so that its songs follow it.
+///
+/// Once we know the replacement can go ahead, `put_in_place` is called (with
+/// the library locked) to actually put the new file where the old one was. If
+/// anything fails before then, or `put_in_place` itself fails, nothing is
+/// changed.
+pub fn replace_file<F>(old_id: &FileID, new_id: FileID, size: u64,
+                       duration: u32, put_in_place: F)
+    -> anyhow::Result<PhysicalFileRef>
+where F: FnOnce() -> anyhow::Result<()> {
+    let mut physical_files = PHYSICAL_FILES.write().unwrap();
+    let mut files_by_relative_path = FILES_BY_RELATIVE_PATH.write().unwrap();
+    if physical_files.contains_key(&new_id) {
+        return Err(anyhow!("The new file would be identical to another file \
+                            that's already in the library"))
+    }
+    if !physical_files.contains_key(old_id) {
+        return Err(anyhow!("The old file is no longer in the library"))
+    }
+    put_in_place()?;
+    let old_ref = physical_files.remove(old_id).unwrap();
+    let (relative_paths, tracks, absolute_paths) = {
+        let old = old_ref.read().unwrap();
+        (old.relative_paths.clone(), old.tracks.clone(),
+         old.absolute_paths.clone())
+    };
+    let new_ref = PhysicalFileRef::new(PhysicalFile {
+        id: new_id, size, duration, raw_meta: AtomicTake::empty(),
+        relative_paths, tracks, absolute_paths,
+    });
+    physical_files.insert(new_id, new_ref.clone());
+    let new = new_ref.read().unwrap();
+    for path in new.relative_paths.iter() {
+        if let Some(files) = files_by_relative_path.get_mut(path) {
+            for file in files.iter_mut() {
+                if *file == old_ref { *file = new_ref.clone() }
+            }
+        }
+    }
+    db::replace_file(old_id, &new.id, new.size, new.duration,
+                     &new.relative_paths, new.get_tracks());
+    drop(new);
+    Ok(new_ref)
+}
+
+/// Reads the metadata of the file at the given path, exactly as returned by
+/// FFMPEG.
+pub fn try_read_metadata(path: &Path)
+-> anyhow::Result<BTreeMap<String,String>> {
     let mut avf = ffmpeg::AVFormat::open_input(&path)?;
     avf.find_stream_info()?;
     let best_stream_id = match avf.find_best_stream()? {
@@ -1,25 +1,22 @@
 //! This module handles the current playback state; playing/paused, current
 //! song, current playlist, etc. It also manages the actual playback device(s),
-//! opening and closing and starting and stopping the stream.
+//! opening and closing and starting and stopping the stream. (The details of
+//! talking to a particular kind of device are in the `output` module.)
 
 use crate::*;
 
 use log::{warn, error};
 use std::{
     collections::VecDeque,
-    sync::{Arc, Mutex, atomic::Ordering},
-    sync::mpsc::{Sender, Receiver, channel},
-    time::Instant,
+    sync::{Arc, Mutex},
+    sync::mpsc::{Sender, Receiver, RecvTimeoutError, channel},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
-use portaudio::{
-    stream::{Parameters, OutputSettings, OutputCallbackArgs},
-    PortAudio,
-    StreamCallbackResult,
-};
 use lazy_static::lazy_static;
 use anyhow::anyhow;
 use libsoxr::Soxr;
+use limiter::Limiter;
 
 /// Internal state used when resampling audio. Wraps `libsoxr`.
 struct ResampleState {
@@ -30,12 +27,14 @@ struct ResampleState {
 }
 
 trait ResampleStateOptionImplHack {
-    fn output(&mut self, native_sample_rate: Option<f64>, frame: AudioFrame)
+    fn output(&mut self, native_sample_rate: Option<f64>, frame: AudioFrame,
+              chain: &mut dsp::Chain, eq: Option<&dsp::EqPreset>)
         -> anyhow::Result<()>;
 }
 
 impl ResampleStateOptionImplHack for Option<ResampleState> {
-    fn output(&mut self, native_sample_rate: Option<f64>, frame: AudioFrame)
+    fn output(&mut self, native_sample_rate: Option<f64>, frame: AudioFrame,
+              chain: &mut dsp::Chain, eq: Option<&dsp::EqPreset>)
         -> anyhow::Result<()> {
         if let Some(native_sample_rate) = native_sample_rate {
             let need_recreate = match self {
@@ -51,14 +50,15 @@ impl ResampleStateOptionImplHack for Option<ResampleState> {
                     let (_, out_floats) = me.soxr.process::<f32,f32>
                         (None, &mut buf[..])?;
                     buf.resize(out_floats * me.channel_count as usize, 0.0);
-                    FRAME_QUEUE.lock().unwrap().push_back(AudioFrame {
+                    queue_frame(AudioFrame {
                         song_id: frame.song_id,
                         time: frame.time,
                         sample_rate: native_sample_rate,
                         channel_count: me.channel_count,
+                        time_scale: frame.time_scale,
                         data: buf,
                         consumed: 0,
-                    });
+                    }, chain, eq);
                 }
                 if native_sample_rate == frame.sample_rate {
                     *self = None;
@@ -98,19 +98,70 @@ impl ResampleStateOptionImplHack for Option<ResampleState> {
                 frame.data = buf;
                 frame.data.resize(buf_pos, 0.0);
                 frame.sample_rate = native_sample_rate;
-                FRAME_QUEUE.lock().unwrap().push_back(frame);
+                queue_frame(frame, chain, eq);
             }
             else {
-                FRAME_QUEUE.lock().unwrap().push_back(frame);
+                queue_frame(frame, chain, eq);
             }
         }
         else {
-            FRAME_QUEUE.lock().unwrap().push_back(frame);
+            queue_frame(frame, chain, eq);
         }
         Ok(())
     }
 }
 
+/// Time-stretches a chunk of decoded audio according to the tempo, resamples
+/// it if the tempo (or the output device) calls for that, and queues the
+/// result. If `chunk` is `None`, flushes whatever audio the stretcher is
+/// holding back instead.
+fn output_stretched(chunk: Option<stretch::Chunk>, tempo: Tempo,
+                    native_sample_rate: Option<f64>,
+                    resample_state: &mut Option<ResampleState>,
+                    stretcher: &mut stretch::Stretcher,
+                    chain: &mut dsp::Chain, eq: Option<&dsp::EqPreset>)
+    -> anyhow::Result<()> {
+    let (stretch_factor, shift) = tempo.get_factors();
+    let mut res: anyhow::Result<()> = Ok(());
+    let mut output = |chunk: stretch::Chunk| {
+        if res.is_err() { return }
+        // Resampling audio as if its sample rate were `shift` times higher
+        // makes it play `shift` times faster, and higher. If we're shifting,
+        // we have to resample even if the user didn't ask us to.
+        let target_rate = native_sample_rate
+            .or(Some(chunk.sample_rate).filter(|_| shift != 1.0));
+        res = resample_state.output(target_rate, AudioFrame {
+            song_id: chunk.song_id,
+            time: chunk.time,
+            sample_rate: chunk.sample_rate * shift,
+            channel_count: chunk.channel_count,
+            time_scale: chunk.time_scale * shift,
+            data: chunk.data,
+            consumed: 0,
+        }, chain, eq);
+    };
+    match chunk {
+        Some(chunk) => stretcher.process(stretch_factor, chunk, &mut output),
+        None => stretcher.flush(&mut output),
+    }
+    res
+}
+
+/// Remixes a frame to the output channel layout, runs it through the DSP
+/// chain, and then queues it for playback.
+fn queue_frame(mut frame: AudioFrame, chain: &mut dsp::Chain,
+               eq: Option<&dsp::EqPreset>) {
+    if let Some((channel_count, data))
+        = dsp::map_channels(frame.channel_count, &frame.data[..]) {
+            frame.channel_count = channel_count;
+            bufring::finished_with_buf(std::mem::replace(&mut frame.data,
+                                                         data));
+        }
+    chain.process(eq, frame.sample_rate, frame.channel_count,
+                  &mut frame.data[..]);
+    FRAME_QUEUE.lock().unwrap().push_back(frame);
+}
+
 /// A chunk of audio, ready to be sent to the sound card.
 struct AudioFrame {
     song_id: SongID,
@@ -118,6 +169,9 @@ struct AudioFrame {
     time: f64,
     sample_rate: f64,
     channel_count: i32,
+    /// how many seconds of the song pass for each second of this frame
+    /// (not 1 if the playback speed has been changed)
+    time_scale: f64,
     data: Vec<f32>, // hooray! lots of copying!
     /// number of indices within data that have been consumed
     consumed: usize,
@@ -155,21 +209,298 @@ pub enum PlaybackCommand {
     /// the playlist, or we're not near the beginning of a song, starts the
     /// current song over. If playback is currently not active, acts as if we
     /// paused at the beginning of whatever song gets picked.
-    Prev
+    Prev,
+    /// Play part of a song, and then stop. The times are in seconds from the
+    /// beginning of the song, *ignoring* its `start_time` and `end_time`, so
+    /// that new trim points can be tried out before they're applied.
+    Preview(LogicalSongRef, f64, Option<f64>),
+    /// The user changed the audio output settings (backend, device, latency,
+    /// etc.). If playback is active, the stream is closed and reopened with
+    /// the new settings, continuing from the point the user last heard.
+    ReopenOutput,
+    /// Change the playback speed, clamped within `MIN_SPEED` and `MAX_SPEED`.
+    /// 1.0 is normal speed.
+    SetSpeed(f64),
+    /// Shift the pitch by the given number of semitones (clamped within
+    /// `MAX_PITCH_SHIFT` either way), without changing the speed.
+    SetPitchShift(f64),
+    /// Choose whether changing the speed should leave the pitch alone (true)
+    /// or change it along with the speed, like a tape (false).
+    SetPreservePitch(bool),
+    /// Change the A–B loop.
+    Loop(LoopCommand),
+    /// Start (or, if `None`, cancel) the sleep timer.
+    SetSleepTimer(Option<SleepTimer>),
+    /// Choose (or, if `None`, cancel) a point at which playback should stop
+    /// on its own.
+    SetStopAfter(Option<StopAfter>),
+    /// Bring the volume up from silence over the given number of seconds,
+    /// starting now, so that (for instance) an alarm doesn't go off at full
+    /// blast.
+    FadeIn(f64),
 }
 use PlaybackCommand::*;
 
+/// Things that can be done to the A–B loop. See `PlaybackCommand::Loop`.
+#[derive(Debug)]
+pub enum LoopCommand {
+    /// Start a new loop at the point the user is hearing right now. It won't
+    /// do anything until its end is marked.
+    MarkStart,
+    /// End the loop at the point the user is hearing right now, and start
+    /// looping. If no start was marked (in this song, before this point), the
+    /// loop starts at the beginning of the song.
+    MarkEnd,
+    /// Loop the given part of the song the user is hearing, or (if `None`)
+    /// stop looping.
+    Set(Option<logical::PracticeLoop>),
+    /// Change how many times the loop is played before playback carries on
+    /// with the rest of the song. `None` = forever.
+    SetRepeats(Option<u32>),
+}
+
+/// A part of one song that is being played over and over.
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub struct AbLoop {
+    pub song_id: SongID,
+    /// Where the loop starts, in seconds from the beginning of the song.
+    pub start: f64,
+    /// Where the loop ends. `None` if only the start has been marked so far.
+    pub end: Option<f64>,
+    /// How many times to play the loop. `None` = forever.
+    pub repeats: Option<u32>,
+    /// How many times the loop has been played all the way through.
+    pub times_played: u32,
+}
+
+impl AbLoop {
+    /// Returns the loop points if, when playback reaches the end of this
+    /// loop, it should jump back to the start.
+    fn get_active_points(&self) -> Option<(f64, f64)> {
+        let more = self.repeats.map(|n| self.times_played + 1 < n)
+            .unwrap_or(true);
+        match self.end {
+            Some(end) if more && end > self.start => Some((self.start, end)),
+            _ => None,
+        }
+    }
+}
+
+/// How long the sleep timer takes to fade out, in seconds, if it fades out.
+const SLEEP_FADE_LENGTH: f32 = 30.0;
+
+/// A point in time at which playback stops, so that the user can fall asleep
+/// to it.
+#[derive(Clone,Copy,Debug)]
+pub struct SleepTimer {
+    /// When playback stops.
+    pub deadline: Instant,
+    /// If true, the volume fades out over the last `SLEEP_FADE_LENGTH`
+    /// seconds before the deadline.
+    pub fade_out: bool,
+}
+
+impl SleepTimer {
+    pub fn new(duration: Duration, fade_out: bool) -> SleepTimer {
+        SleepTimer { deadline: Instant::now() + duration, fade_out }
+    }
+    fn is_expired(&self) -> bool {
+        Instant::now() >= self.deadline
+    }
+    /// Returns how loud (as an amplitude) the audio should be at the given
+    /// time.
+    fn get_volume_at(&self, when: Instant) -> f32 {
+        if !self.fade_out { return 1.0 }
+        let left = self.deadline.saturating_duration_since(when)
+            .as_secs_f32();
+        let volume = (left / SLEEP_FADE_LENGTH).min(1.0);
+        volume * volume
+    }
+}
+
+/// A ramp from silence up to the normal volume. See `PlaybackCommand::FadeIn`.
+#[derive(Clone,Copy,Debug)]
+struct VolumeRamp {
+    start: Instant,
+    /// How long the ramp lasts, in seconds.
+    length: f32,
+}
+
+impl VolumeRamp {
+    fn new(length: f64) -> VolumeRamp {
+        VolumeRamp { start: Instant::now(), length: length.max(0.0) as f32 }
+    }
+    /// Returns how loud (as an amplitude) the audio should be at the given
+    /// time.
+    fn get_volume_at(&self, when: Instant) -> f32 {
+        if self.length <= 0.0 { return 1.0 }
+        let elapsed = when.saturating_duration_since(self.start)
+            .as_secs_f32();
+        let volume = (elapsed / self.length).min(1.0);
+        volume * volume
+    }
+}
+
+/// Points at which playback can stop on its own. Each one only happens once.
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub enum StopAfter {
+    /// Stop at the end of the song being played.
+    Song,
+    /// Stop when the next song would be from a different album than the one
+    /// being played.
+    Album,
+    /// Pause at the end of the playlist, with the first song ready to go,
+    /// instead of stopping or looping.
+    Playlist,
+}
+
+/// How many frames of audio from just past the end of an A–B loop are faded
+/// out over the start of the loop when it repeats. (The same length as the
+/// fade-in that `AVFormat::seek_to_time` does.)
+const LOOP_CROSSFADE_FRAMES: usize = 1000;
+
+/// A short fade that the callback applies when the user pauses, stops,
+/// resumes, or changes songs, so that the audio doesn't cut off (or start)
+/// abruptly. It's counted in frames, so it comes out the same no matter how
+/// the stream sizes its buffers.
+#[derive(Clone,Copy,Debug)]
+struct TransportFade {
+    /// True if fading in, false if fading out.
+    fading_in: bool,
+    /// How many frames the fade lasts.
+    length: usize,
+    /// How many frames of the fade have been played so far.
+    done: usize,
+    /// If fading out, the time (in the stream's timebase) at which the user
+    /// will hear the end of the fade, once the callback has gotten that far.
+    silent_at: Option<f64>,
+}
+
+impl TransportFade {
+    fn new(fading_in: bool, length: f64, sample_rate: f64) -> TransportFade {
+        TransportFade {
+            fading_in, done: 0, silent_at: None,
+            length: (length * sample_rate).round().max(1.0) as usize,
+        }
+    }
+    fn is_finished(&self) -> bool { self.done >= self.length }
+    /// Returns how loud (as an amplitude) the next frame should be, and
+    /// advances by one frame.
+    fn next_volume(&mut self) -> f32 {
+        let pos = self.done.min(self.length) as f32 / self.length as f32;
+        self.done = self.done.saturating_add(1);
+        let volume = if self.fading_in { pos } else { 1.0 - pos };
+        volume * volume
+    }
+}
+
+/// Why the playback thread has to throw away its audio backend.
+#[derive(Debug)]
+enum OutputChange {
+    /// The user changed the audio output settings.
+    Reconfigured,
+    /// The audio device stopped asking for audio. (Probably unplugged.)
+    DeviceLost,
+    /// We raised the latency because of underruns. The stream has to be
+    /// reopened, but the backend can stay.
+    LatencyRaised,
+    /// The user changed something (the speed, the pitch, an A–B loop...)
+    /// that affects how audio is decoded. Audio that was already decoded has
+    /// to be thrown away, but the backend can stay.
+    DecodingChanged,
+}
+
+/// The lowest permitted playback speed.
+pub const MIN_SPEED: f64 = 0.5;
+/// The highest permitted playback speed.
+pub const MAX_SPEED: f64 = 2.0;
+/// The furthest the pitch can be shifted, up or down, in semitones.
+pub const MAX_PITCH_SHIFT: f64 = 12.0;
+
+/// How fast, and at what pitch, songs are played.
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub struct Tempo {
+    /// 1.0 is normal speed, 2.0 is twice as fast, etc.
+    pub speed: f64,
+    /// How many semitones to shift the pitch, on top of whatever the speed
+    /// does to it.
+    pub pitch_shift: f64,
+    /// If true, the speed doesn't affect the pitch.
+    pub preserve_pitch: bool,
+}
+
+impl Default for Tempo {
+    fn default() -> Tempo {
+        Tempo { speed: 1.0, pitch_shift: 0.0, preserve_pitch: true }
+    }
+}
+
+impl Tempo {
+    /// Returns true if songs are played exactly as they are.
+    pub fn is_normal(&self) -> bool {
+        self.speed == 1.0 && self.pitch_shift == 0.0
+    }
+    /// Returns how much the audio must be time-stretched (without changing
+    /// pitch) and how much it must then be sped up by resampling (changing
+    /// pitch) to get this tempo.
+    fn get_factors(&self) -> (f64, f64) {
+        let mut pitch = 2f64.powf(self.pitch_shift / 12.0);
+        if !self.preserve_pitch { pitch *= self.speed }
+        (self.speed / pitch, pitch)
+    }
+    /// Changes the speed. Returns true if anything changed.
+    fn set_speed(&mut self, speed: f64) -> bool {
+        let nu = speed.max(MIN_SPEED).min(MAX_SPEED);
+        let changed = self.speed != nu;
+        self.speed = nu;
+        changed
+    }
+    /// Changes the pitch shift. Returns true if anything changed.
+    fn set_pitch_shift(&mut self, pitch_shift: f64) -> bool {
+        let nu = pitch_shift.max(-MAX_PITCH_SHIFT).min(MAX_PITCH_SHIFT);
+        let changed = self.pitch_shift != nu;
+        self.pitch_shift = nu;
+        changed
+    }
+    /// Changes whether the speed affects the pitch. Returns true if anything
+    /// changed that would affect the audio.
+    fn set_preserve_pitch(&mut self, preserve_pitch: bool) -> bool {
+        let changed = self.preserve_pitch != preserve_pitch
+            && self.speed != 1.0;
+        self.preserve_pitch = preserve_pitch;
+        changed
+    }
+}
+
+/// Statistics about the underruns that have happened this session, and what
+/// we've done about them.
+#[derive(Clone,Debug,Default)]
+pub struct UnderrunStats {
+    /// How many times the audio has dropped out.
+    pub count: u32,
+    /// How much silence the dropouts added up to, in seconds.
+    pub total_silence: f64,
+    /// How many seconds we've added to the decode-ahead.
+    pub extra_decode_ahead: f64,
+    /// How many seconds we've added to the latency.
+    pub extra_latency: f64,
+}
+
 /// CallbackReports are tied to time stamps in the stream timebase. Each one
 /// indicates that what the user is hearing will match the given report at that
 /// time.
 #[derive(Debug)]
 enum CallbackReport {
-    /// User is hearing the given point in time of the given song.
-    SongPlaying { song_id: SongID, time: f64 },
+    /// User is hearing the given point in time of the given song, and each
+    /// second they hear covers `time_scale` seconds of the song.
+    SongPlaying { song_id: SongID, time: f64, time_scale: f64 },
     /// User has heard the end of playback, and the stream should be closed.
     PlaybackFinished,
     /// A sample format change is needed, and the stream should be closed.
     SampleFormatChanged,
+    /// We ran out of audio while playback was still going on, and the user is
+    /// hearing the given number of seconds of silence instead.
+    Underrun { silence: f64 },
 }
 use CallbackReport::*;
 
@@ -200,8 +531,89 @@ impl PlaybackStatus {
     pub fn is_playing(&self) -> bool {
         *self == PlaybackStatus::Playing
     }
+    pub fn to_db_value(&self) -> i8 {
+        match self {
+            PlaybackStatus::Stopped => 0,
+            PlaybackStatus::Paused => 1,
+            PlaybackStatus::Playing => 2,
+        }
+    }
+    pub fn from_db_value(n: i64) -> PlaybackStatus {
+        match n {
+            1 => PlaybackStatus::Paused,
+            2 => PlaybackStatus::Playing,
+            _ => PlaybackStatus::Stopped, // be tolerant
+        }
+    }
 }
 
+/// The playback state, as saved in the database so that it can be restored
+/// the next time Tsong starts.
+#[derive(Clone,Debug,PartialEq)]
+pub struct SavedState {
+    pub playlist_id: Option<PlaylistID>,
+    /// The song the user was hearing, if any.
+    pub song_id: Option<SongID>,
+    /// The point in `song_id` the user was hearing, in seconds.
+    pub position: f64,
+    pub status: PlaybackStatus,
+    /// The exact order of the playlist, if it was shuffled.
+    pub shuffle_order: Option<Vec<SongID>>,
+}
+
+/// If the user stops hearing a song whose position is remembered within this
+/// many seconds of its end, they finished it, and it will start over next
+/// time...
+const FINISHED_LEEWAY: f64 = 5.0;
+/// ...and if they stop within this many seconds of its beginning, there's
+/// nothing worth remembering.
+const MIN_REMEMBERED_POSITION: f64 = 5.0;
+
+/// Returns true if the given song should pick up where it left off, either
+/// because its metadata asks for that or because the playlist it's being
+/// played from does. (A network stream never can.)
+fn remembers_position(song: &LogicalSong, playlist: Option<&PlaylistRef>)
+-> bool {
+    if song.is_stream() { return false }
+    song.wants_position_remembered()
+        || playlist.map(|x| x.read().unwrap().get_remember_positions())
+        .unwrap_or(false)
+}
+
+/// Notes that the user stopped hearing the given song at the given point, if
+/// it's a song whose position is remembered. (A podcast episode that was
+/// heard to the end also gets marked played.)
+fn note_position(song_ref: &LogicalSongRef, time: f64,
+                 playlist: Option<&PlaylistRef>) {
+    podcast::note_heard(song_ref, time, FINISHED_LEEWAY);
+    let mut song = song_ref.write().unwrap();
+    if !remembers_position(&song, playlist) { return }
+    let finished = time + FINISHED_LEEWAY >= song.get_duration() as f64;
+    song.set_remembered_position
+        (if finished || time < MIN_REMEMBERED_POSITION { None }
+         else { Some(time) });
+}
+
+/// Notes where the user is in the song they're hearing, if it's a song whose
+/// position is remembered, so that not much is lost if Tsong doesn't get to
+/// exit cleanly.
+fn note_active_position() {
+    let state = STATE.lock().unwrap();
+    let active_song = match state.status {
+        PlaybackStatus::Stopped => None,
+        _ => state.active_song.clone(),
+    };
+    let future_playlist = state.future_playlist.clone();
+    drop(state);
+    if let Some((song, time)) = active_song {
+        note_position(&song, time, future_playlist.as_ref());
+    }
+}
+
+/// The playback state is saved at least this often while Tsong is running,
+/// so that not much is lost if it doesn't get to exit cleanly.
+const STATE_SAVE_INTERVAL: Duration = Duration::from_secs(15);
+
 #[derive(Default)]
 struct InternalState {
     /// The song that the user is *currently hearing*, and the timestamp within
@@ -212,6 +624,13 @@ struct InternalState {
     future_song: Option<LogicalSongRef>,
     /// The FFMPEG input stream corresponding to `future_song`.
     future_stream: Option<ffmpeg::AVFormat>,
+    /// Where `future_song` is within `future_stream`. Only meaningful while
+    /// `future_stream` is open.
+    future_placement: Placement,
+    /// If set, the next time this song's stream is opened, only the given part
+    /// of it will be played, and then playback will stop. Times are in seconds
+    /// from the beginning of the untrimmed song.
+    preview: Option<(LogicalSongRef, f64, Option<f64>)>,
     /// The playlist from which the *next* song will be drawn.
     future_playlist: Option<PlaylistRef>,
     /// The playback thread will update this to reflect the current playback
@@ -220,8 +639,51 @@ struct InternalState {
     /// Whether we are currently muted. When we're muted, we pretend our volume
     /// is set to zero.
     muted: bool,
+    /// How fast, and at what pitch, to play.
+    tempo: Tempo,
+    /// The A–B loop, if any. Only takes effect while its song is playing.
+    ab_loop: Option<AbLoop>,
+    /// Audio from just past the end of the A–B loop, and its channel count,
+    /// waiting to be mixed into the audio from the start of the loop.
+    loop_seam: Option<(i32, Vec<f32>)>,
+    sleep_timer: Option<SleepTimer>,
+    fade_in: Option<VolumeRamp>,
+    stop_after: Option<StopAfter>,
+    /// If set, when the user hears the end of playback, we pause with this
+    /// song ready to go instead of stopping. (See `StopAfter::Playlist`.)
+    pause_on: Option<LogicalSongRef>,
+    /// Titles sent by network streams, in the order we decoded them: which
+    /// song, the time in that song from which the title applies, and the
+    /// title. See `get_stream_title`.
+    stream_titles: VecDeque<(SongID, f64, String)>,
+}
+
+/// Where a song is within an open stream. All times are in seconds from the
+/// beginning of the file.
+#[derive(Clone,Copy,Debug,Default)]
+struct Placement {
+    /// The part of the file that should actually be played.
+    span: physical::Span,
+    /// The point in the file that corresponds to time zero in the song, i.e.
+    /// the start of the track plus the song's `start_time`.
+    origin: f64,
+    /// Where the song's track starts, before any trimming. Loop points are
+    /// relative to this.
+    track_start: f64,
+    /// True if we're playing a preview, and should stop at the end of `span`.
+    previewing: bool,
 }
 
+/// Every this many underruns, we raise the latency as well as the
+/// decode-ahead.
+const UNDERRUNS_PER_LATENCY_RAISE: u32 = 2;
+
+/// If the audio callback hasn't run for this many times the desired latency,
+/// we assume the device has gone away...
+const STALL_LATENCIES: f64 = 4.0;
+/// ...unless that's less than this many seconds.
+const MIN_STALL_TIME: f64 = 2.0;
+
 lazy_static! {
     // We can't have an `RwLock` here, because `RwLock` doesn't grant Sync (as
     // multiple readers could read simultaneously) and `AVFormat` isn't Sync.
@@ -236,10 +698,18 @@ lazy_static! {
         = Mutex::new(VecDeque::new());
     static ref CURRENT_AUDIO_FORMAT: Mutex<(f64, i32)>
         = Mutex::new(Default::default());
-    static ref BROKEN_STREAM_TIME: std::sync::atomic::AtomicBool
-        = Default::default();
-    /// used if `BROKEN_STREAM_TIME` is true
-    static ref BROKEN_EPOCH: Instant = Instant::now();
+    static ref UNDERRUNS: Mutex<UnderrunStats>
+        = Mutex::new(Default::default());
+    /// The transport fade in progress, if any. A finished fade-out stays here,
+    /// keeping the stream silent, until the next stream fades back in.
+    static ref TRANSPORT_FADE: Mutex<Option<TransportFade>>
+        = Mutex::new(None);
+    /// The limiter for the current stream.
+    static ref LIMITER: Mutex<Option<Limiter>>
+        = Mutex::new(None);
+    /// The last playback state we saved, and when we saved it.
+    static ref LAST_SAVED_STATE: Mutex<Option<(Instant, SavedState)>>
+        = Mutex::new(None);
 }
 
 /// Selects a different playlist to be active, without changing the active
@@ -293,41 +763,261 @@ pub fn send_command(wat: PlaybackCommand) {
     }
 }
 
-fn send_callback_report(when: f64, wat: CallbackReport) {
-    REPORT_QUEUE.lock().unwrap().push_back((when, wat));
+/// Returns the current playback speed and pitch.
+pub fn get_tempo() -> Tempo {
+    STATE.lock().unwrap().tempo
+}
+
+/// If the user is hearing a network stream, returns the title of whatever
+/// they're hearing on it (if the stream told us).
+pub fn get_stream_title() -> Option<String> {
+    let mut state = STATE.lock().unwrap();
+    let (song_id, time) = match state.active_song.as_ref() {
+        Some((song, time)) => (song.read().unwrap().get_id(), *time),
+        None => return None,
+    };
+    let titles = &mut state.stream_titles;
+    let heard = titles.iter().rposition(|x| x.0 == song_id && x.1 <= time)?;
+    // anything before that, the user is done hearing
+    titles.drain(..heard);
+    titles.front().map(|x| x.2.clone())
+}
+
+/// Returns how much the limiter has turned the audio down, at most, since the
+/// last time this was called, in decibels. 0.0 means it hasn't had to.
+pub fn take_limiter_reduction() -> f64 {
+    match LIMITER.lock().unwrap().as_mut() {
+        Some(limiter) => -20.0 * (limiter.take_lowest_gain() as f64).log10(),
+        None => 0.0,
+    }
 }
 
-fn playback_callback(args: OutputCallbackArgs<f32>) -> StreamCallbackResult {
-    // destructure parameters
-    let OutputCallbackArgs {
-        buffer,
-        time,
-        ..
-    } = args;
-    let mut now = if time.current == 0.0 && time.buffer_dac == 0.0 {
-        let was_broken = BROKEN_STREAM_TIME.swap(true, Ordering::Release);
-        let true_now = BROKEN_EPOCH.elapsed().as_secs_f64();
-        if !was_broken {
-            warn!("Stream time is broken on this driver! Using the wall-clock \
-                   hack!");
-            true_now // don't add latency, we're hopefully priming buffers
+/// Returns the sleep timer, if there is one.
+pub fn get_sleep_timer() -> Option<SleepTimer> {
+    STATE.lock().unwrap().sleep_timer
+}
+
+/// Returns the point at which playback will stop on its own, if any.
+pub fn get_stop_after() -> Option<StopAfter> {
+    STATE.lock().unwrap().stop_after
+}
+
+/// Returns the A–B loop, if there is one.
+pub fn get_ab_loop() -> Option<AbLoop> {
+    STATE.lock().unwrap().ab_loop
+}
+
+/// Returns the playback state as it should be saved.
+fn get_state_to_save() -> SavedState {
+    let state = STATE.lock().unwrap();
+    let future_playlist = state.future_playlist.clone();
+    let active_song = match state.status {
+        PlaybackStatus::Stopped => None,
+        _ => state.active_song.clone(),
+    };
+    let status = state.status;
+    // (playlists lock the state while holding their own lock, so we can't
+    // read them while we hold it)
+    drop(state);
+    let (song_id, position) = match active_song {
+        Some((song, time)) => (Some(song.read().unwrap().get_id()), time),
+        None => (None, 0.0),
+    };
+    let (playlist_id, shuffle_order) = match future_playlist {
+        Some(playlist) => {
+            let playlist = playlist.read().unwrap();
+            (Some(playlist.get_id()), playlist.get_shuffle_order())
+        },
+        None => (None, None),
+    };
+    SavedState { playlist_id, song_id, position, status, shuffle_order }
+}
+
+/// Saves the playback state to the database, so that it can be restored the
+/// next time Tsong starts.
+pub fn save_state() {
+    note_active_position();
+    let saved = get_state_to_save();
+    db::update_playback_state(&saved);
+    *LAST_SAVED_STATE.lock().unwrap() = Some((Instant::now(), saved));
+}
+
+/// Saves the playback state, if it's been a while since the last time and it
+/// has changed since then. Call this every so often.
+pub fn maybe_save_state() {
+    let last_saved = LAST_SAVED_STATE.lock().unwrap();
+    if let Some((when, _)) = last_saved.as_ref() {
+        if when.elapsed() < STATE_SAVE_INTERVAL { return }
+    }
+    drop(last_saved);
+    note_active_position();
+    let saved = get_state_to_save();
+    let mut last_saved = LAST_SAVED_STATE.lock().unwrap();
+    if last_saved.as_ref().map(|(_, x)| x) != Some(&saved) {
+        db::update_playback_state(&saved);
+    }
+    *last_saved = Some((Instant::now(), saved));
+}
+
+/// Restores the playback state saved by a previous session, if there is one.
+/// If a song was playing, it comes back paused, at the point the user last
+/// heard. Call this once, at startup, after the database is loaded.
+pub fn restore_state() {
+    let saved = match db::get_playback_state() {
+        Some(x) => x,
+        None => return,
+    };
+    let playlist = saved.playlist_id.and_then(playlist::get_playlist_by_id);
+    if let (Some(playlist), Some(order))
+    = (playlist.as_ref(), saved.shuffle_order.as_ref()) {
+        let mut playlist = playlist.write().unwrap();
+        if playlist.is_shuffled() {
+            playlist.restore_shuffle_order(order.clone());
         }
-        else {
-            true_now + prefs::get_desired_latency()
+    }
+    // (a song can't be played without a playlist to carry on with, so if the
+    // playlist was deleted, start out stopped)
+    let song = match saved.status {
+        PlaybackStatus::Stopped => None,
+        _ => saved.song_id.filter(|_| playlist.is_some())
+            .and_then(logical::get_song_by_song_id),
+    };
+    let mut state = STATE.lock().unwrap();
+    state.future_playlist = playlist;
+    if let Some(song) = song {
+        state.status = PlaybackStatus::Paused;
+        state.future_song = Some(song.clone());
+        state.future_stream = None;
+        state.active_song = Some((song, saved.position));
+        if let Err(x) = state.reset_to_heard_point() {
+            error!("While restoring playback state: {}", x);
         }
     }
+    drop(state);
+    *LAST_SAVED_STATE.lock().unwrap() = Some((Instant::now(), saved));
+}
+
+/// Returns statistics about the underruns that have happened this session.
+pub fn get_underrun_stats() -> UnderrunStats {
+    UNDERRUNS.lock().unwrap().clone()
+}
+
+/// Returns the latency we're actually using: the user's desired latency, plus
+/// however much we've had to add because of underruns.
+pub fn get_effective_latency() -> f64 {
+    let extra = UNDERRUNS.lock().unwrap().extra_latency;
+    (prefs::get_desired_latency() + extra).min(prefs::MAX_DESIRED_LATENCY)
+}
+
+/// Returns the decode-ahead we're actually using: the user's decode-ahead,
+/// plus however much we've had to add because of underruns.
+pub fn get_effective_decode_ahead() -> f64 {
+    // (don't hold the lock while `get_effective_latency` takes it again)
+    let extra = UNDERRUNS.lock().unwrap().extra_decode_ahead;
+    (prefs::get_decode_ahead() + extra)
+        .max(get_effective_latency() * 3.0)
+        .min(prefs::MAX_DECODE_AHEAD)
+}
+
+/// Records an underrun, tells the user about it, and buffers more for the rest
+/// of the session. Returns true if the latency was raised, in which case the
+/// stream must be reopened.
+fn note_underrun(state: &InternalState, silence: f64) -> bool {
+    let decode_ahead = get_effective_decode_ahead();
+    let latency = get_effective_latency();
+    let mut stats = UNDERRUNS.lock().unwrap();
+    stats.count += 1;
+    stats.total_silence += silence;
+    stats.extra_decode_ahead += (prefs::MAX_DECODE_AHEAD - decode_ahead)
+        .min(decode_ahead * 0.5).max(0.0);
+    let raise_latency = stats.count % UNDERRUNS_PER_LATENCY_RAISE == 0
+        && latency < prefs::MAX_DESIRED_LATENCY;
+    if raise_latency {
+        stats.extra_latency += (prefs::MAX_DESIRED_LATENCY - latency)
+            .min(latency * 0.5);
+    }
+    drop(stats);
+    let wat = match state.active_song.as_ref() {
+        Some((song, time)) => {
+            let time = time.max(0.0) as u32;
+            format!("[{}] Audio dropped out at {}:{:02} into {:?}.",
+                    wall_clock_time(), time / 60, time % 60,
+                    song.read().unwrap().get_metadata().get("title")
+                    .map(String::as_str).unwrap_or("(untitled)"))
+        },
+        None => format!("[{}] Audio dropped out.", wall_clock_time()),
+    };
+    let wat = if raise_latency {
+        format!("{} Now decoding {:.1} seconds ahead, with {:.2} seconds of \
+                 latency.", wat, get_effective_decode_ahead(),
+                get_effective_latency())
+    }
     else {
-        time.buffer_dac
+        format!("{} Now decoding {:.1} seconds ahead.", wat,
+                get_effective_decode_ahead())
     };
-    let volume = if STATE.lock().unwrap().muted { 0.0 }
+    warn!("{}", wat);
+    errors::from("Underruns", wat);
+    raise_latency
+}
+
+/// Returns the current wall-clock time as `HH:MM:SS`, in local time if we can
+/// find out what that is.
+fn wall_clock_time() -> String {
+    let secs = SystemTime::now().duration_since(UNIX_EPOCH)
+        .map(|x| x.as_secs()).unwrap_or(0);
+    if let Some(local) = alarm::local_time(secs as i64) {
+        return format!("{:02}:{:02}:{:02}",
+                       local.hour, local.minute, local.second)
+    }
+    let secs = secs % 86400;
+    format!("{:02}:{:02}:{:02} UTC", secs / 3600, secs / 60 % 60, secs % 60)
+}
+
+fn send_callback_report(when: f64, wat: CallbackReport) {
+    REPORT_QUEUE.lock().unwrap().push_back((when, wat));
+}
+
+/// Fills an output buffer with queued audio. `now` is the time, in the
+/// stream's timebase, at which the first sample of `buffer` will be heard.
+fn playback_callback(buffer: &mut [f32], now: f64) {
+    let mut limiter = LIMITER.lock().unwrap();
+    // (the limiter delays everything a little)
+    let now = now + limiter.as_ref().map(Limiter::get_delay).unwrap_or(0.0);
+    let start_time = now;
+    let mut now = now;
+    let (muted, sleep_timer, fade_in) = {
+        let state = STATE.lock().unwrap();
+        (state.muted, state.sleep_timer, state.fade_in)
+    };
+    let volume = if muted { 0.0 }
     else {
         let volume = prefs::get_volume() as f32 / 100.0;
         volume * volume
     };
-    let mut rem = buffer;
+    let mut rem = &mut *buffer;
     let mut queue = FRAME_QUEUE.lock().unwrap();
     let current_audio_format = *CURRENT_AUDIO_FORMAT.lock().unwrap();
     let (sample_rate, channel_count) = current_audio_format;
+    // If the sleep timer is fading out, or we're fading in, the volume changes
+    // smoothly over the course of the buffer.
+    let total_len = rem.len();
+    let fade_volume_at = |when: Instant| {
+        sleep_timer.map(|x| x.get_volume_at(when)).unwrap_or(1.0)
+            * fade_in.map(|x| x.get_volume_at(when)).unwrap_or(1.0)
+    };
+    let (fade_start, fade_end) = if sleep_timer.is_some() || fade_in.is_some() {
+        let start = Instant::now();
+        let end = start + Duration::from_secs_f64
+            ((total_len / channel_count.max(1) as usize) as f64
+             / sample_rate);
+        (fade_volume_at(start), fade_volume_at(end))
+    }
+    else { (1.0, 1.0) };
+    let volume_at = |rem_len: usize| {
+        let pos = (total_len - rem_len) as f32 / total_len.max(1) as f32;
+        volume * (fade_start + (fade_end - fade_start) * pos)
+    };
     while rem.len() > 0 {
         let next_el = match queue.get_mut(0) {
             None => break,
@@ -337,15 +1027,23 @@ fn playback_callback(args: OutputCallbackArgs<f32>) -> StreamCallbackResult {
             break
         }
         let next_data = &next_el.data[next_el.consumed..];
-        send_callback_report(now, SongPlaying { song_id: next_el.song_id, time: next_el.time + (next_el.consumed / channel_count as usize) as f64 / sample_rate});
+        send_callback_report(now, SongPlaying { song_id: next_el.song_id, time: next_el.time + (next_el.consumed / channel_count as usize) as f64 / sample_rate * next_el.time_scale, time_scale: next_el.time_scale });
         if next_data.len() > rem.len() {
-            copy_with_volume(rem, &next_data[..rem.len()], volume);
+            let start_volume = volume_at(rem.len());
+            let end_volume = volume_at(0);
+            copy_with_volume(rem, &next_data[..rem.len()],
+                             channel_count as usize, start_volume,
+                             end_volume);
             now += (rem.len() / channel_count as usize) as f64 / sample_rate;
             next_el.consumed += rem.len();
             rem = &mut [];
         }
         else {
-            copy_with_volume(&mut rem[..next_data.len()], next_data, volume);
+            let start_volume = volume_at(rem.len());
+            let end_volume = volume_at(rem.len() - next_data.len());
+            copy_with_volume(&mut rem[..next_data.len()], next_data,
+                             channel_count as usize, start_volume,
+                             end_volume);
             now += (next_data.len() / channel_count as usize) as f64 / sample_rate;
             rem = &mut rem[next_data.len()..];
             queue.pop_front();
@@ -353,7 +1051,30 @@ fn playback_callback(args: OutputCallbackArgs<f32>) -> StreamCallbackResult {
     }
     // fill rest with zeroes
     // (slice::fill isn't stable yet)
+    let missing = rem.len();
     for el in rem.iter_mut() { *el = 0.0; }
+    // keep anything the volume or EQ made too loud from clipping
+    if let Some(limiter) = limiter.as_mut() {
+        limiter.process(buffer);
+    }
+    drop(limiter);
+    // apply any transport fade, one frame at a time
+    let mut transport_fade = TRANSPORT_FADE.lock().unwrap();
+    if let Some(fade) = transport_fade.as_mut() {
+        let frames = buffer.chunks_mut(channel_count.max(1) as usize);
+        for (n, frame) in frames.enumerate() {
+            if !fade.fading_in && fade.silent_at.is_none()
+            && fade.is_finished() {
+                fade.silent_at = Some(start_time + n as f64 / sample_rate);
+            }
+            let volume = fade.next_volume();
+            for el in frame.iter_mut() { *el *= volume; }
+        }
+        if fade.fading_in && fade.is_finished() {
+            *transport_fade = None;
+        }
+    }
+    drop(transport_fade);
     // so. why did we stop?
     match queue.get(0) {
         None => {
@@ -364,15 +1085,23 @@ fn playback_callback(args: OutputCallbackArgs<f32>) -> StreamCallbackResult {
             // will acquire the queue lock while holding the state lock, if we
             // try to do the reverse we could end up with deadlock.
             // If we couldn't get the lock, assume that playback is ongoing.
-            // We'll play some extra silence, but that's okay.
+            // We'll play some extra silence, but that's okay. (We won't call
+            // it an underrun, though, since we don't know that it is one.)
             let playback_over = STATE.try_lock().map(|x| {
                 x.status != PlaybackStatus::Playing
                     || x.future_song.is_none()
-            }).unwrap_or(false);
-            if playback_over {
-                send_callback_report(now, PlaybackFinished);
+            }).ok();
+            match playback_over {
+                Some(true) => send_callback_report(now, PlaybackFinished),
+                Some(false) if missing > 0 => {
+                    // The playback thread didn't keep up.
+                    send_callback_report(now, Underrun {
+                        silence: (missing / channel_count as usize) as f64
+                            / sample_rate
+                    });
+                },
+                _ => (),
             }
-            // TODO: underrun detection
         },
         Some(x) => {
             if (x.sample_rate, x.channel_count) != current_audio_format {
@@ -381,30 +1110,99 @@ fn playback_callback(args: OutputCallbackArgs<f32>) -> StreamCallbackResult {
             // otherwise, nothing to report
         },
     };
+    drop(queue);
+    // let anyone listening to the broadcast hear what we just heard
+    broadcast::feed(buffer, sample_rate, channel_count);
     let _ = PLAYBACK_CONTROL_TX.lock().unwrap().as_ref().unwrap()
         .send(PlaybackThreadMessage::CallbackRan);
-    // some PA backends are buggy (including the one that ends up talking to
-    // the "other" PA) and will drop buffers if we use ::Complete.
-    StreamCallbackResult::Continue
 }
 
-fn copy_with_volume(dst: &mut[f32], src: &[f32], volume: f32) {
+/// If transport fades are on, fades out whatever the user is hearing, and
+/// waits until they've heard the end of the fade.
+fn fade_out_transport(stream: &dyn output::Stream) {
+    if !prefs::get_transport_fades() { return }
+    let length = prefs::get_transport_fade_length();
+    let (sample_rate, _) = *CURRENT_AUDIO_FORMAT.lock().unwrap();
+    *TRANSPORT_FADE.lock().unwrap()
+        = Some(TransportFade::new(false, length, sample_rate));
+    // (if the callback stops running, don't wait forever)
+    let deadline = Instant::now() + Duration::from_secs_f64
+        (length + get_effective_latency() * STALL_LATENCIES);
+    while Instant::now() < deadline {
+        let silent_at = TRANSPORT_FADE.lock().unwrap()
+            .and_then(|x| x.silent_at);
+        if silent_at.map(|x| stream.time() >= x).unwrap_or(false) { break }
+        std::thread::sleep(Duration::from_millis(5));
+    }
+}
+
+/// Mixes audio from just past the end of an A–B loop into the audio from the
+/// start of the loop, fading it out as the start (which `seek_to_time` has
+/// already faded in) fades in, so that there's no click at the seam.
+fn mix_loop_seam(data: &mut [f32], tail: &[f32], channel_count: i32) {
+    let channel_count = channel_count as usize;
+    let frames = (tail.len() / channel_count).max(1) as f32;
+    for (n, (dst, src)) in data.chunks_exact_mut(channel_count)
+        .zip(tail.chunks_exact(channel_count)).enumerate() {
+            let volume = 1.0 - n as f32 / frames;
+            for (dst, src) in dst.iter_mut().zip(src.iter()) {
+                *dst += src * volume;
+            }
+        }
+}
+
+/// Copies audio, scaling it by a volume that goes smoothly from `volume` at
+/// the start to `end_volume` at the end.
+fn copy_with_volume(dst: &mut[f32], src: &[f32], channel_count: usize,
+                    volume: f32, end_volume: f32) {
     assert_eq!(dst.len(), src.len());
-    for n in 0 .. src.len() {
-        dst[n] = src[n] * volume;
+    if volume == end_volume {
+        for n in 0 .. src.len() {
+            dst[n] = src[n] * volume;
+        }
+        return
     }
+    let channel_count = channel_count.max(1);
+    let frames = (src.len() / channel_count).max(1) as f32;
+    for (n, (dst, src)) in dst.chunks_mut(channel_count)
+        .zip(src.chunks(channel_count)).enumerate() {
+            let volume = volume + (end_volume - volume) * n as f32 / frames;
+            for (dst, src) in dst.iter_mut().zip(src.iter()) {
+                *dst = src * volume;
+            }
+        }
 }
 
 fn playback_thread(state: Arc<Mutex<InternalState>>,
                    playback_control_rx: Receiver<PlaybackThreadMessage>) {
-    let pa = PortAudio::new().expect("Could not initialize PortAudio");
+    // The backend we're currently using, and which kind it is. We hang on to
+    // it between streams, and only replace it if the user picks a different
+    // kind.
+    let mut backend: Option<(prefs::AudioBackend, Box<dyn output::Backend>)>
+        = None;
+    // True if we lost the device the user chose, and are using the default
+    // device instead until the user changes the settings.
+    let mut using_default_device = false;
     loop {
         while state.lock().unwrap().status != PlaybackStatus::Playing {
             match playback_control_rx.recv() {
                 Err(_) => return, // bye bye...
                 Ok(PlaybackThreadMessage::Command(cmd)) => {
+                    // (nothing has been decoded, so nothing needs decoding
+                    // again)
+                    let cmd = match state.lock().unwrap().handle_command(cmd) {
+                        Ok(_) => continue,
+                        Err(cmd) => cmd,
+                    };
                     match cmd {
                         Pause | Stop => (), // nothing to do
+                        ReopenOutput => {
+                            // Nothing is open, but a new backend might see
+                            // devices the old one didn't.
+                            backend = None;
+                            using_default_device = false;
+                            errors::reset_from("Audio Output");
+                        },
                         Play(Some(song)) => {
                             // Play the CHOSEN SONG.
                             let mut state = state.lock().unwrap();
@@ -413,6 +1211,14 @@ fn playback_thread(state: Arc<Mutex<InternalState>>,
                             state.future_song = Some(song.clone());
                             state.active_song = Some((song, 0.0));
                         },
+                        Preview(song, start, end) => {
+                            let mut state = state.lock().unwrap();
+                            state.status = PlaybackStatus::Playing;
+                            state.future_stream = None;
+                            state.future_song = Some(song.clone());
+                            state.preview = Some((song.clone(), start, end));
+                            state.active_song = Some((song, 0.0));
+                        },
                         Play(None) => {
                             // Play the CURRENT SONG, if there is one.
                             // Otherwise, play the FIRST SONG.
@@ -456,17 +1262,18 @@ fn playback_thread(state: Arc<Mutex<InternalState>>,
                             }
                             state.future_stream = None;
                         },
+                        _ => (), // (handled above)
                     }
                 },
                 Ok(_) => (), // still not playing!
             }
         }
         while state.lock().unwrap().status == PlaybackStatus::Playing {
-            // One of three things has happened:
+            // One of these things has happened:
             // - We're starting playback from nothing. Make a new stream.
             // - Sample rate changed during playback. Make a new stream.
             // - User requested that a different song be played.
-            // - User changed the audio device. (TODO)
+            // - User changed the audio device, or the device went away.
             // - We hit the end of the playlist and looping isn't enabled.
             //   Finish up. (This might be handled elsewhere?)
             // But before we can do anything else, there might be some more
@@ -478,6 +1285,13 @@ fn playback_thread(state: Arc<Mutex<InternalState>>,
                     // this shouldn't happen but is harmless
                     PlaybackThreadMessage::CallbackRan => (),
                     PlaybackThreadMessage::Command(cmd) => {
+                        // (no audio has been decoded yet, so there's nothing
+                        // to throw away)
+                        let cmd = match state.lock().unwrap()
+                            .handle_command(cmd) {
+                                Ok(_) => continue,
+                                Err(cmd) => cmd,
+                            };
                         match cmd {
                             Stop => {
                                 let mut state = state.lock().unwrap();
@@ -496,7 +1310,18 @@ fn playback_thread(state: Arc<Mutex<InternalState>>,
                                 state.future_song = Some(song);
                                 state.future_stream = None;
                             },
+                            Preview(song, start, end) => {
+                                let mut state = state.lock().unwrap();
+                                state.future_song = Some(song.clone());
+                                state.future_stream = None;
+                                state.preview = Some((song, start, end));
+                            },
                             Play(None) => (), // nothing to do
+                            ReopenOutput => {
+                                backend = None;
+                                using_default_device = false;
+                                errors::reset_from("Audio Output");
+                            },
                             Next => {
                                 let mut state = state.lock().unwrap();
                                 state.next_song();
@@ -515,15 +1340,64 @@ fn playback_thread(state: Arc<Mutex<InternalState>>,
                                         state.prev_song();
                                     },
                                 }
-                            }
+                            },
+                            _ => (), // (handled above)
                         }
                     }
                 }
             }
             errors::reset_from("Playback Thread");
-            match playback_thread_inner_loop(&pa, &state,
-                                             &playback_control_rx) {
-                Ok(_) => (),
+            let wanted_backend = prefs::get_audio_backend();
+            if backend.as_ref().map(|x| x.0) != Some(wanted_backend) {
+                // get rid of the old one first
+                backend = None;
+                match output::open_backend(wanted_backend,
+                                           using_default_device) {
+                    Ok(x) => backend = Some((wanted_backend, x)),
+                    Err(x) => {
+                        error!("while opening audio backend: {}", x);
+                        errors::from("Playback Thread", x.to_string());
+                        state.lock().unwrap().status = PlaybackStatus::Paused;
+                        continue
+                    },
+                }
+            }
+            match playback_thread_inner_loop(&mut *backend.as_mut().unwrap().1,
+                                             &state, &playback_control_rx) {
+                Ok(None) => (),
+                Ok(Some(change)) => {
+                    match change {
+                        // same backend, new stream, nothing else to do
+                        OutputChange::LatencyRaised
+                            | OutputChange::DecodingChanged => (),
+                        OutputChange::Reconfigured => {
+                            backend = None;
+                            using_default_device = false;
+                            errors::reset_from("Audio Output");
+                        },
+                        OutputChange::DeviceLost => {
+                            backend = None;
+                            error!("audio device stopped responding");
+                            if prefs::get_fall_back_to_default_device() {
+                                errors::from("Audio Output",
+                                             "The audio device stopped \
+                                              responding. Switched to the \
+                                              default device.".to_owned());
+                                using_default_device = true;
+                            }
+                            else {
+                                errors::from("Audio Output",
+                                             "The audio device stopped \
+                                              responding. Playback has been \
+                                              paused.".to_owned());
+                                let mut state = state.lock().unwrap();
+                                if state.status == PlaybackStatus::Playing {
+                                    state.status = PlaybackStatus::Paused;
+                                }
+                            }
+                        },
+                    }
+                },
                 Err(x) => {
                     error!("in playback thread: {}", x);
                     errors::from("Playback Thread", x.to_string());
@@ -541,45 +1415,33 @@ fn playback_thread(state: Arc<Mutex<InternalState>>,
 }
 
 /// Inner loop of the playback thread. Convenient way to pass any API errors
-/// upward and handle them.
-fn playback_thread_inner_loop(pa: &PortAudio,
+/// upward and handle them. Returns `Some` if the audio backend needs to be
+/// replaced; if so, the state has already been reset to the heard point.
+fn playback_thread_inner_loop(backend: &mut dyn output::Backend,
                               state: &Arc<Mutex<InternalState>>,
                               playback_control_rx:
                               &Receiver<PlaybackThreadMessage>)
-    -> anyhow::Result<()> {
+    -> anyhow::Result<Option<OutputChange>> {
     // we assume that playback is happening... if it's not, go away
     if state.lock().unwrap().status != PlaybackStatus::Playing {
-        return Ok(())
+        return Ok(None)
     }
     // Time to open a new stream...
-    let hostapi_index = prefs::get_chosen_audio_api(&pa);
-    let device_index = prefs::get_chosen_audio_device_for_api
-        (&pa, hostapi_index);
-    let device_index = match device_index {
-        Some(x) => pa.api_device_index_to_device_index
-            (hostapi_index, x as i32)
-            .or_else(|x| Err(anyhow!("Error finding a device by index: {}", x)))?,
-        None => match pa.host_api_info(hostapi_index)
-            .and_then(|x| x.default_output_device) {
-                Some(x) => x,
-                None => pa.default_output_device()
-                    .or_else(|_| Err(anyhow!("No default output device?")))?
-            }
-    };
     let native_sample_rate = if prefs::get_resample_audio() {
-        let info = pa.device_info(device_index)?;
-        if info.default_sample_rate < 1.0 { Some(44100.0) }
-        else { Some(info.default_sample_rate) }
+        Some(backend.native_sample_rate()?)
     } else { None };
     let mut resample_state = None;
+    let mut chain = dsp::Chain::new();
+    let mut stretcher = stretch::Stretcher::new();
     let (sample_rate, channel_count) = {
-        decode_some_frames(&state, native_sample_rate, &mut resample_state);
+        decode_some_frames(&state, native_sample_rate, &mut resample_state,
+                           &mut stretcher, &mut chain);
         // double lock in the common case :(
         match FRAME_QUEUE.lock().unwrap().get(0) {
             None => {
                 state.lock().unwrap().status
                     = PlaybackStatus::Stopped;
-                return Ok(())
+                return Ok(None)
             },
             Some(ref x) =>
                 (x.sample_rate, x.channel_count),
@@ -587,24 +1449,36 @@ fn playback_thread_inner_loop(pa: &PortAudio,
     };
     *CURRENT_AUDIO_FORMAT.lock().unwrap()
         = (sample_rate, channel_count);
-    let parameters = Parameters::new(device_index,
-                                     channel_count,
-                                     true, // interleaved
-                                     prefs::get_desired_latency());
-    let flags = portaudio::stream_flags
-        ::PA_PRIME_OUTPUT_BUFFERS_USING_STREAM_CALLBACK;
-    let settings = OutputSettings::with_flags(parameters, sample_rate,
-                                              0, flags);
-    let mut stream = pa.open_non_blocking_stream(settings,
-                                                 playback_callback)
-        .or_else(|x| Err(anyhow!("Unable to open audio stream: {}", x)))?;
+    // If the last stream faded out, this one fades in.
+    {
+        let mut transport_fade = TRANSPORT_FADE.lock().unwrap();
+        *transport_fade = match *transport_fade {
+            Some(fade) if !fade.fading_in =>
+                Some(TransportFade::new(true,
+                                        prefs::get_transport_fade_length(),
+                                        sample_rate)),
+            _ => None,
+        };
+    }
+    *LIMITER.lock().unwrap() = Some(Limiter::new(sample_rate, channel_count));
+    let mut stream = backend.open_stream(sample_rate, channel_count,
+                                         Box::new(playback_callback))?;
     // just in case...
     REPORT_QUEUE.lock().unwrap().clear();
-    BROKEN_STREAM_TIME.store(false, Ordering::Relaxed);
-    decode_some_frames(&state, native_sample_rate, &mut resample_state);
-    stream.start()
-        .or_else(|x| Err(anyhow!("Unable to start audio stream: {}", x)))?;
+    decode_some_frames(&state, native_sample_rate, &mut resample_state,
+                       &mut stretcher, &mut chain);
+    stream.start()?;
     let mut sample_rate_changing = false;
+    let mut output_change = None;
+    // If the callback doesn't run for this long, the device is gone.
+    let stall_timeout = Duration::from_secs_f64
+        ((get_effective_latency() * STALL_LATENCIES).max(MIN_STALL_TIME));
+    // The song we last told the stream about.
+    let mut announced_song: Option<SongID> = None;
+    // True if the last report was an underrun. (The callback will report an
+    // underrun every time it runs until the audio comes back, but it's all
+    // one dropout as far as the user is concerned.)
+    let mut in_underrun = false;
     'alive_loop: while state.lock().unwrap().status == PlaybackStatus::Playing {
         let mut got_message = false;
         // process at least one message. once at least one message has
@@ -612,13 +1486,33 @@ fn playback_thread_inner_loop(pa: &PortAudio,
         // periodic tasks.
         while let Some(message) =
             if got_message { playback_control_rx.try_recv().ok() }
-        else { playback_control_rx.recv().ok() } {
+        else {
+            match playback_control_rx.recv_timeout(stall_timeout) {
+                Ok(x) => Some(x),
+                Err(RecvTimeoutError::Timeout) => {
+                    output_change = Some(OutputChange::DeviceLost);
+                    break 'alive_loop;
+                },
+                Err(RecvTimeoutError::Disconnected) => None,
+            }
+        } {
             got_message = true;
             match message {
                 PlaybackThreadMessage::CallbackRan => (),
                 PlaybackThreadMessage::Command(cmd) => {
+                    let handled = state.lock().unwrap().handle_command(cmd);
+                    let cmd = match handled {
+                        Ok(false) => continue,
+                        Ok(true) => {
+                            output_change
+                                = Some(OutputChange::DecodingChanged);
+                            break 'alive_loop;
+                        },
+                        Err(cmd) => cmd,
+                    };
                     match cmd {
                         Stop => {
+                            fade_out_transport(&*stream);
                             let mut state = state.lock().unwrap();
                             state.status = PlaybackStatus::Stopped;
                             state.future_song = None;
@@ -626,18 +1520,33 @@ fn playback_thread_inner_loop(pa: &PortAudio,
                             break 'alive_loop;
                         },
                         Pause => {
+                            fade_out_transport(&*stream);
                             let mut state = state.lock().unwrap();
                             state.status = PlaybackStatus::Paused;
                             break 'alive_loop;
                         },
                         Play(Some(song)) => {
+                            fade_out_transport(&*stream);
                             let mut state = state.lock().unwrap();
                             state.future_song = Some(song);
                             state.future_stream = None;
                             break 'alive_loop;
                         },
+                        Preview(song, start, end) => {
+                            fade_out_transport(&*stream);
+                            let mut state = state.lock().unwrap();
+                            state.future_song = Some(song.clone());
+                            state.future_stream = None;
+                            state.preview = Some((song, start, end));
+                            break 'alive_loop;
+                        },
                         Play(None) => (), // nothing to do
+                        ReopenOutput => {
+                            output_change = Some(OutputChange::Reconfigured);
+                            break 'alive_loop;
+                        },
                         Next => {
+                            fade_out_transport(&*stream);
                             let mut state = state.lock().unwrap();
                             // play the next song, AS THE USER HEARS
                             state.future_stream = None;
@@ -646,6 +1555,7 @@ fn playback_thread_inner_loop(pa: &PortAudio,
                             break 'alive_loop;
                         },
                         Prev => {
+                            fade_out_transport(&*stream);
                             let mut state = state.lock().unwrap();
                             state.future_stream = None;
                             match state.active_song.as_mut() {
@@ -661,29 +1571,46 @@ fn playback_thread_inner_loop(pa: &PortAudio,
                             }
                             break 'alive_loop;
                         },
+                        _ => (), // (handled above)
                     }
                 },
             }
         }
         // Now run any necessary periodic tasks, such as updating the
         // current time and song that we report.
-        let now = if BROKEN_STREAM_TIME.load(Ordering::Acquire) {
-            BROKEN_EPOCH.elapsed().as_secs_f64()
-        } else { stream.time() };
+        {
+            let mut state = state.lock().unwrap();
+            if state.sleep_timer.map(|x| x.is_expired()).unwrap_or(false) {
+                // Good night!
+                state.sleep_timer = None;
+                state.status = PlaybackStatus::Stopped;
+                state.future_song = None;
+                state.future_stream = None;
+                break 'alive_loop;
+            }
+        }
+        let now = stream.time();
         // temporarily take the report queue lock and...
         let mut report_queue = REPORT_QUEUE.lock().unwrap();
         while report_queue.get(0).map(|x| x.0 <= now).unwrap_or(false){
             let (report_time, el) = report_queue.pop_front().unwrap();
             match el {
-                SongPlaying { song_id, time: songtime } => {
+                SongPlaying { song_id, time: songtime, time_scale } => {
+                    in_underrun = false;
                     let mut state = state.lock().unwrap();
                     let change_song = match &state.active_song {
                         &Some(ref x) => x.0.read().unwrap()
                             .get_id() != song_id,
                         &None => true
                     };
-                    let songtime = songtime + (now - report_time);
+                    let songtime = songtime
+                        + (now - report_time) * time_scale;
                     if change_song {
+                        if let Some((old_song, old_time))
+                            = state.active_song.clone() {
+                                note_position(&old_song, old_time,
+                                              state.future_playlist.as_ref());
+                            }
                         state.active_song = Some((logical::get_song_by_song_id(song_id).ok_or_else(|| anyhow!("Playback changed to a song not in the database!"))?, songtime));
                     }
                     else {
@@ -694,10 +1621,38 @@ fn playback_thread_inner_loop(pa: &PortAudio,
                     sample_rate_changing = true;
                     break 'alive_loop;
                 },
+                Underrun { silence } => {
+                    if in_underrun {
+                        UNDERRUNS.lock().unwrap().total_silence += silence;
+                    }
+                    else {
+                        in_underrun = true;
+                        let state = state.lock().unwrap();
+                        if note_underrun(&state, silence) {
+                            output_change = Some(OutputChange::LatencyRaised);
+                            break 'alive_loop;
+                        }
+                    }
+                },
                 PlaybackFinished => {
                     let mut state = state.lock().unwrap();
                     if state.status == PlaybackStatus::Playing {
-                        state.status = PlaybackStatus::Stopped;
+                        state.stop_after = None;
+                        match state.pause_on.take() {
+                            Some(song) => {
+                                if let Some((old_song, old_time))
+                                    = state.active_song.clone() {
+                                        note_position(&old_song, old_time,
+                                                      state.future_playlist
+                                                      .as_ref());
+                                    }
+                                state.status = PlaybackStatus::Paused;
+                                state.future_song = Some(song.clone());
+                                state.future_stream = None;
+                                state.active_song = Some((song, 0.0));
+                            },
+                            None => state.status = PlaybackStatus::Stopped,
+                        }
                     }
                     break 'alive_loop;
                 },
@@ -705,12 +1660,28 @@ fn playback_thread_inner_loop(pa: &PortAudio,
         }
         // release the lock...
         drop(report_queue);
+        // Let the stream (and the broadcast) know if the user started hearing
+        // a different song.
+        let active_song = state.lock().unwrap().active_song.as_ref()
+            .map(|x| x.0.clone());
+        if let Some(song) = active_song {
+            let song = song.read().unwrap();
+            if announced_song != Some(song.get_id()) {
+                announced_song = Some(song.get_id());
+                let metadata = song.get_metadata();
+                let title = metadata.get("title").map(String::as_str);
+                let artist = metadata.get("artist").map(String::as_str);
+                stream.set_metadata(title, artist);
+                broadcast::set_metadata(title, artist);
+            }
+        }
         // ...so that we're not holding it during the (expensive)
         // decoding step
-        decode_some_frames(&state, native_sample_rate, &mut resample_state);
+        decode_some_frames(&state, native_sample_rate, &mut resample_state,
+                           &mut stretcher, &mut chain);
     }
     // Clean up!
-    let _ = stream.abort();
+    drop(stream);
     // Any reports after we decided to kill the stream are of no
     // consequence.
     REPORT_QUEUE.lock().unwrap().clear();
@@ -719,7 +1690,13 @@ fn playback_thread_inner_loop(pa: &PortAudio,
     if !sample_rate_changing { FRAME_QUEUE.lock().unwrap().clear() }
     let mut state = state.lock().unwrap();
     match state.status {
-        PlaybackStatus::Playing => (),
+        PlaybackStatus::Playing => {
+            if output_change.is_some() {
+                // Pick up where the user left off, on whatever stream we
+                // open next.
+                state.reset_to_heard_point()?;
+            }
+        },
         PlaybackStatus::Paused => {
             // Whatever song the user was hearing when they hit pause,
             // that's where we paused.
@@ -727,19 +1704,24 @@ fn playback_thread_inner_loop(pa: &PortAudio,
         },
         PlaybackStatus::Stopped => {
             // There is no longer an active song.
+            if let Some((song, time)) = state.active_song.clone() {
+                note_position(&song, time, state.future_playlist.as_ref());
+            }
             state.future_song = None;
             state.active_song = None;
         },
     }
-    Ok(())
+    Ok(output_change)
 }
 
 /// Wrapper that repeatedly calls `state.decode_some_frames()` until enough
 /// samples are queued.
 fn decode_some_frames(state: &Arc<Mutex<InternalState>>,
                       native_sample_rate: Option<f64>,
-                      resample_state: &mut Option<ResampleState>) {
-    let decode_ahead = prefs::get_decode_ahead();
+                      resample_state: &mut Option<ResampleState>,
+                      stretcher: &mut stretch::Stretcher,
+                      chain: &mut dsp::Chain) {
+    let decode_ahead = get_effective_decode_ahead();
     // briefly hold the lock to figure out how many frames are queued up
     let mut decoded = FRAME_QUEUE.lock().unwrap().iter()
         .fold(0.0, |total, el| total + ((el.data.len() - el.consumed)
@@ -753,7 +1735,8 @@ fn decode_some_frames(state: &Arc<Mutex<InternalState>>,
         if state.future_song.is_none() { break }
         decoded += state.decode_some_frames(decode_ahead - decoded,
                                             native_sample_rate,
-                                            resample_state);
+                                            resample_state, stretcher,
+                                            chain);
     }
 }
 
@@ -762,7 +1745,27 @@ impl InternalState {
     fn check_stream(&mut self) -> anyhow::Result<()> {
         if self.future_stream.is_some() { return Ok(()) }
         if let Some(future_song) = self.future_song.as_ref() {
-            self.future_stream = future_song.read().unwrap().open_stream();
+            let (opened, (start_time, end_time), is_stream)
+                = { let song = future_song.read().unwrap();
+                    (song.open_stream(), song.get_trim(), song.is_stream()) };
+            // (a network stream starts over with whatever it's playing now)
+            let song_id = future_song.read().unwrap().get_id();
+            self.stream_titles.retain(|x| x.0 != song_id);
+            let track = match opened {
+                Some((stream, track)) => {
+                    self.future_stream = Some(stream);
+                    track
+                },
+                None => {
+                    self.future_stream = None;
+                    physical::Span::WHOLE
+                },
+            };
+            let preview = match self.preview.take() {
+                Some((song, start, end)) if &song == future_song
+                    => Some((start, end)),
+                _ => None,
+            };
             if let Some(ref mut stream) = self.future_stream {
                 stream.find_stream_info()?;
                 // TODO: don't panic!
@@ -771,7 +1774,61 @@ impl InternalState {
                     Some(x) => stream.open_stream(x)?,
                     None => return Err(anyhow!("Is this not a music file?")),
                 };
-                future_song.set_duration(durr);
+                // Where the track ends, untrimmed, and then trimmed
+                let track_end = track.end
+                    .unwrap_or(durr as f64).max(track.start);
+                let trimmed_end = match end_time {
+                    Some(x) => track_end.min(track.start + x),
+                    None => track_end,
+                };
+                let origin = (track.start + start_time).min(trimmed_end);
+                let span = match preview {
+                    Some((start, end)) => physical::Span {
+                        start: (track.start + start).min(track_end),
+                        end: Some(match end {
+                            Some(x) => track_end.min(track.start + x),
+                            None => track_end,
+                        }),
+                    },
+                    None => physical::Span {
+                        start: origin,
+                        end: if trimmed_end < track_end
+                            || track.end.is_some() { Some(trimmed_end) }
+                        else { None },
+                    },
+                };
+                self.future_placement = Placement {
+                    span, origin, track_start: track.start,
+                    previewing: preview.is_some(),
+                };
+                // (a network stream's "duration" is just however much of it
+                // was buffered when we opened it, and would be written to the
+                // database anew every time we did)
+                if !is_stream {
+                    future_song.set_duration((trimmed_end - origin).round()
+                                             as u32);
+                }
+                // If this song picks up where it left off, and we aren't
+                // already playing it (e.g. starting it over), pick up.
+                let resume_point = if preview.is_some()
+                    || self.active_song.as_ref().map(|x| &x.0)
+                    == Some(future_song) { None }
+                else {
+                    let song = future_song.read().unwrap();
+                    if remembers_position(&song,
+                                          self.future_playlist.as_ref()) {
+                        song.get_remembered_position()
+                    } else { None }
+                };
+                let resume_point = resume_point.map(|x| origin + x)
+                    .filter(|&x| x > span.start
+                            && span.end.map(|end| x < end).unwrap_or(true));
+                if let Some(resume_point) = resume_point {
+                    stream.seek_to_time(resume_point);
+                }
+                else if span.start > 0.0 {
+                    stream.seek_to_time(span.start);
+                }
                 Ok(())
             }
             else {
@@ -826,6 +1883,48 @@ impl InternalState {
         self.future_song = next_index.and_then(|x| songs.get(x)).cloned();
         self.future_stream = None;
     }
+    /// Called when we've decoded all of a song. Goes to the next song, unless
+    /// the user asked for playback to stop here.
+    fn song_ended(&mut self) {
+        self.pause_on = None;
+        let album = |song: Option<&LogicalSongRef>| {
+            song.and_then(|x| x.read().unwrap().get_metadata().get("album")
+                          .cloned())
+        };
+        let ended_album = album(self.future_song.as_ref());
+        let at_end_of_playlist = match (self.future_playlist.as_ref(),
+                                        self.future_song.as_ref()) {
+            (Some(playlist), Some(song)) => {
+                let playlist = playlist.maybe_refreshed();
+                let songs = playlist.get_songs();
+                songs.iter().position(|x| x == song)
+                    .map(|x| x + 1 >= songs.len()).unwrap_or(false)
+            },
+            _ => false,
+        };
+        self.next_song();
+        let stop = match self.stop_after {
+            None => false,
+            Some(StopAfter::Song) => true,
+            Some(StopAfter::Album) =>
+                album(self.future_song.as_ref()) != ended_album,
+            Some(StopAfter::Playlist) => at_end_of_playlist,
+        };
+        if !stop { return }
+        if self.stop_after == Some(StopAfter::Playlist) {
+            // (if the playlist doesn't loop, there's no next song, so start
+            // it over)
+            self.pause_on = self.future_song.clone().or_else(|| {
+                self.future_playlist.as_ref().and_then(|x| {
+                    x.maybe_refreshed().get_songs().get(0).cloned()
+                })
+            });
+        }
+        // (`stop_after` is cleared when the user actually hears the end, in
+        // case we have to decode this part over again before then)
+        self.future_song = None;
+        self.future_stream = None;
+    }
     /// Goes to the previous song in the playlist, or start the current one
     /// over if we're at the beginning of the shuffle *or* if this song is not
     /// in the active playlist.
@@ -861,7 +1960,9 @@ impl InternalState {
     /// Returns the number of seconds of audio decoded.
     pub fn decode_some_frames(&mut self, secs: f64,
                               native_sample_rate: Option<f64>,
-                              resample_state: &mut Option<ResampleState>)
+                              resample_state: &mut Option<ResampleState>,
+                              stretcher: &mut stretch::Stretcher,
+                              chain: &mut dsp::Chain)
     -> f64 {
         if !self.future_song.is_none() {
             match self.check_stream() {
@@ -877,87 +1978,273 @@ impl InternalState {
         }
         if !self.future_song.is_none() {
             let song_id = self.future_song.as_ref().unwrap().read().unwrap().get_id();
+            let is_stream = self.future_song.as_ref().unwrap().read().unwrap()
+                .is_stream();
+            let eq = dsp::get_eq_for_song(self.future_song.as_ref().unwrap()
+                                          .read().unwrap().get_metadata());
+            // Times coming out of the stream are relative to the start of the
+            // file, not to the start of the song.
+            let placement = self.future_placement;
+            let span = placement.span;
+            let tempo = self.tempo;
             if let Some(ref mut av) = self.future_stream {
                 let mut decoded_so_far = 0.0;
                 while decoded_so_far < secs && !self.future_song.is_none() {
-                    let looping = self.future_playlist.as_ref().unwrap().read()
+                    let looping = !placement.previewing
+                        && self.future_playlist.as_ref().unwrap().read()
                         .unwrap().get_playmode() == Playmode::LoopOne;
-                    let loop_spot: Option<f64> =
-                        if looping {
-                            self.future_song.as_ref()
-                                .and_then(|x| x.read().unwrap().get_metadata()
-                                          .get("loop_end").map(String::as_str)
-                                          .and_then(|x| str::parse(x).ok()))
-                        } else { None };
-                    // true if we have encountered the loop spot
+                    // An A–B loop takes precedence over the song's own loop
+                    // points.
+                    let ab_points = self.ab_loop
+                        .filter(|x| x.song_id == song_id
+                                && !placement.previewing)
+                        .and_then(|x| x.get_active_points());
+                    let (loop_start, loop_end) = if let Some((start, end))
+                        = ab_points {
+                            // (A–B loop points are in song time, but loop
+                            // points are relative to the start of the track)
+                            let offset = placement.origin
+                                - placement.track_start;
+                            (start + offset, Some(end + offset))
+                        }
+                    else if looping {
+                        let song = self.future_song.as_ref().unwrap()
+                            .read().unwrap();
+                        let metadata = song.get_metadata();
+                        let loop_start: f64 = metadata.get("loop_start")
+                            .and_then(|x| str::parse(x).ok())
+                            .unwrap_or(0.0);
+                        let loop_end: Option<f64> = metadata.get("loop_end")
+                            .and_then(|x| str::parse(x).ok())
+                            // a loop that ends before it starts isn't a loop
+                            .filter(|&x| x > loop_start);
+                        (loop_start, loop_end)
+                    } else { (0.0, None) };
+                    // Where to stop decoding, in file time: the loop end if
+                    // there is one (and it isn't trimmed off), or the end of
+                    // the song if it doesn't go all the way to the end of the
+                    // file.
+                    let loop_stop = loop_end
+                        .map(|x| x + placement.track_start)
+                        .filter(|&x| span.end.map(|y| x <= y).unwrap_or(true));
+                    let stop_spot = loop_stop.or(span.end);
+                    let crossfade = ab_points.is_some() && loop_stop.is_some();
+                    let mut seam = self.loop_seam.take();
+                    // true if we have encountered the stop spot
                     let mut endut = false;
+                    // song time of the last audio we decoded
+                    let mut decoded_time = None;
                     let more_left = av.decode_some(|start_time, sample_rate, channel_count, mut data| {
                         if endut { return }
                         assert!(data.len() > 0);
                         assert!(channel_count > 0 && channel_count < 32);
-                        if let Some(loop_spot) = loop_spot {
-                            if loop_spot >= start_time {
-                                let samples_in_frame =
-                                    data.len() / channel_count as usize;
-                                let duration = (samples_in_frame as f64)
-                                    / sample_rate;
-                                if loop_spot < start_time + duration {
-                                    endut = true;
-                                    let end_sample = (loop_spot - start_time)
-                                        * sample_rate;
-                                    let floored_end_sample =end_sample.floor();
-                                    let end_sample =
-                                        if end_sample == floored_end_sample {
-                                            floored_end_sample - 1.0
-                                        } else { floored_end_sample } as usize;
-                                    let end_index = end_sample
-                                        * channel_count as usize;
-                                    assert!(end_index <= data.len());
-                                    data.resize(end_index, 0.0);
+                        if let Some((seam_channels, tail)) = seam.take() {
+                            if seam_channels == channel_count {
+                                mix_loop_seam(&mut data, &tail,
+                                              channel_count);
+                            }
+                            bufring::finished_with_buf(tail);
+                        }
+                        if let Some(stop_spot) = stop_spot {
+                            if start_time >= stop_spot {
+                                endut = true;
+                                return
+                            }
+                            let samples_in_frame =
+                                data.len() / channel_count as usize;
+                            let duration = (samples_in_frame as f64)
+                                / sample_rate;
+                            if stop_spot < start_time + duration {
+                                endut = true;
+                                let end_sample = (stop_spot - start_time)
+                                    * sample_rate;
+                                let floored_end_sample =end_sample.floor();
+                                let end_sample =
+                                    if end_sample == floored_end_sample {
+                                        floored_end_sample - 1.0
+                                    } else { floored_end_sample } as usize;
+                                let end_index = end_sample
+                                    * channel_count as usize;
+                                assert!(end_index <= data.len());
+                                if crossfade {
+                                    let tail_end = (end_index
+                                                    + LOOP_CROSSFADE_FRAMES
+                                                    * channel_count as usize)
+                                        .min(data.len());
+                                    let mut tail = bufring::get_buf();
+                                    tail.extend_from_slice
+                                        (&data[end_index .. tail_end]);
+                                    seam = Some((channel_count, tail));
                                 }
+                                data.resize(end_index, 0.0);
+                                if data.is_empty() { return }
                             }
                         }
+                        // (in output time, which is what the decode-ahead is
+                        // measured in)
                         decoded_so_far += (data.len() / channel_count as usize)
-                            as f64 / sample_rate as f64;
+                            as f64 / sample_rate as f64 / tempo.speed;
+                        decoded_time = Some(start_time - placement.origin);
                         let res =
-                            resample_state.output(native_sample_rate,
-                                                  AudioFrame {
-                                                      song_id, consumed: 0,
-                                                      time: start_time,
-                                                      sample_rate: sample_rate,
-                                                      channel_count, data,
-                                                  });
+                            output_stretched(Some(stretch::Chunk {
+                                song_id,
+                                time: start_time - placement.origin,
+                                time_scale: 1.0,
+                                sample_rate, channel_count, data,
+                            }), tempo, native_sample_rate, resample_state,
+                                             stretcher, chain, eq.as_ref());
                         match res {
                             Ok(_) => (),
                             Err(x) => error!("Error resampling audio: {}", x),
                         }
                     });
-                    if endut {
-                        let loop_spot: f64 =
-                            self.future_song.as_ref()
-                            .and_then(|x| x.read().unwrap().get_metadata()
-                                      .get("loop_start").map(String::as_str)
-                                      .and_then(|x| str::parse(x).ok()))
-                            .unwrap_or(0.0);
-                        av.seek_to_time(loop_spot);
+                    self.loop_seam = seam;
+                    if is_stream {
+                        if let Some(title) = av.read_stream_title() {
+                            let changed = self.stream_titles.back()
+                                .map(|x| x.0 != song_id || x.2 != title)
+                                .unwrap_or(true);
+                            if changed {
+                                self.stream_titles.push_back
+                                    ((song_id, decoded_time.unwrap_or(0.0),
+                                      title));
+                            }
+                        }
+                    }
+                    if endut && loop_stop.is_some() {
+                        if ab_points.is_some() {
+                            if let Some(ab_loop) = self.ab_loop.as_mut() {
+                                ab_loop.times_played += 1;
+                            }
+                        }
+                        av.seek_to_time(loop_start + placement.track_start);
                     }
-                    else if !more_left {
-                        if looping {
-                            av.seek_to_time(0.0);
+                    else if endut || !more_left {
+                        if placement.previewing {
+                            // That's all we wanted to hear.
+                            self.future_song = None;
+                            self.future_stream = None;
+                            break
                         }
+                        else if looping
+                            && self.stop_after != Some(StopAfter::Song) {
+                                av.seek_to_time(span.start);
+                            }
                         else {
-                            self.next_song();
+                            self.song_ended();
                             break
                         }
                     }
                 }
+                if self.future_song.is_none() {
+                    // Playback is ending. Don't leave the end of the last
+                    // song stuck in the stretcher.
+                    if let Err(x) = output_stretched(None, tempo,
+                                                     native_sample_rate,
+                                                     resample_state,
+                                                     stretcher, chain,
+                                                     eq.as_ref()) {
+                        error!("Error resampling audio: {}", x);
+                    }
+                }
                 return decoded_so_far
             }
         }
         return 0.0
     }
+    /// Carries out the commands that only change how we decode, which are
+    /// handled the same way whether or not anything is playing: speed, pitch,
+    /// loops, the sleep timer, stop-after, and fading in. Returns true if
+    /// audio that has already been decoded may no longer be what the user
+    /// should hear. Any other command is handed back, for the caller to deal
+    /// with.
+    fn handle_command(&mut self, cmd: PlaybackCommand)
+    -> Result<bool, PlaybackCommand> {
+        Ok(match cmd {
+            SetSpeed(speed) => self.tempo.set_speed(speed),
+            SetPitchShift(shift) => self.tempo.set_pitch_shift(shift),
+            SetPreservePitch(preserve) =>
+                self.tempo.set_preserve_pitch(preserve),
+            Loop(cmd) => self.handle_loop_command(cmd),
+            SetSleepTimer(timer) => {
+                self.sleep_timer = timer;
+                false
+            },
+            FadeIn(length) => {
+                self.fade_in = Some(VolumeRamp::new(length));
+                false
+            },
+            SetStopAfter(stop_after) => {
+                self.stop_after = stop_after;
+                // If we've already decoded past the end of the song the user
+                // is hearing, decide again.
+                let heard = self.active_song.as_ref().map(|x| &x.0);
+                heard != self.future_song.as_ref()
+            },
+            cmd => return Err(cmd),
+        })
+    }
+    /// Carries out a `LoopCommand`. Returns true if audio that has already
+    /// been decoded may no longer be what the user should hear.
+    fn handle_loop_command(&mut self, cmd: LoopCommand) -> bool {
+        let was_looping = self.ab_loop
+            .and_then(|x| x.get_active_points()).is_some();
+        let repeats = self.ab_loop.and_then(|x| x.repeats);
+        let heard = self.active_song.as_ref()
+            .map(|(song, time)| (song.read().unwrap().get_id(), *time));
+        match cmd {
+            LoopCommand::MarkStart => {
+                let (song_id, start) = match heard {
+                    Some(x) => x,
+                    None => return false,
+                };
+                self.ab_loop = Some(AbLoop {
+                    song_id, start, end: None, repeats, times_played: 0,
+                });
+                was_looping
+            },
+            LoopCommand::MarkEnd => {
+                let (song_id, end) = match heard {
+                    Some(x) => x,
+                    None => return false,
+                };
+                let start = self.ab_loop
+                    .filter(|x| x.song_id == song_id && x.start < end)
+                    .map(|x| x.start).unwrap_or(0.0);
+                // (the user just heard the loop, so that counts as once)
+                self.ab_loop = Some(AbLoop {
+                    song_id, start, end: Some(end), repeats, times_played: 1,
+                });
+                true
+            },
+            LoopCommand::Set(Some(nu)) => {
+                let song_id = match heard {
+                    Some((song_id, _)) => song_id,
+                    None => return false,
+                };
+                self.ab_loop = Some(AbLoop {
+                    song_id, start: nu.start, end: Some(nu.end),
+                    repeats: nu.repeats, times_played: 0,
+                });
+                true
+            },
+            LoopCommand::Set(None) => {
+                self.ab_loop = None;
+                was_looping
+            },
+            LoopCommand::SetRepeats(nu) => {
+                match self.ab_loop.as_mut() {
+                    Some(x) => x.repeats = nu,
+                    None => return false,
+                }
+                was_looping || self.ab_loop
+                    .and_then(|x| x.get_active_points()).is_some()
+            },
+        }
+    }
     fn reset_to_heard_point(&mut self) -> anyhow::Result<()> {
         FRAME_QUEUE.lock().unwrap().clear();
+        self.loop_seam = None;
         let (cur_song, timestamp) = self.active_song.as_ref().map(|(x,y)| (x.clone(), *y)).ok_or_else(|| anyhow!("Resetting to heard point but there's no heard song?"))?;
         if Some(&cur_song) != self.future_song.as_ref() {
             self.future_song = Some(cur_song);
@@ -965,7 +2252,8 @@ impl InternalState {
         }
         if self.check_stream().is_ok() {
             if let Some(stream) = self.future_stream.as_mut() {
-                stream.seek_to_time(timestamp);
+                stream.seek_to_time(timestamp
+                                    + self.future_placement.origin);
             }
         }
         Ok(())
@@ -994,3 +2282,234 @@ pub fn volume_to_db(level: i32) -> f64 {
     asq.log10() * 10.0
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        collections::BTreeMap,
+        f64::consts::PI,
+        fs,
+        path::{Path, PathBuf},
+        sync::atomic::{AtomicU64, Ordering},
+    };
+
+    /// How many times faster than real time the tests play.
+    const SPEED: f64 = 20.0;
+    /// Sample rate of the songs the tests play.
+    const RATE: u32 = 8000;
+
+    lazy_static! {
+        /// There's only one playback thread, so the tests take turns.
+        static ref TEST_LOCK: Mutex<()> = Mutex::new(());
+    }
+    /// Source of song, file, and playlist IDs that nothing else is using.
+    static NEXT_ID: AtomicU64 = AtomicU64::new(1_000_000);
+
+    /// Takes the test lock, and sets up to play through the null backend as
+    /// fast as is reasonable.
+    fn setup() -> std::sync::MutexGuard<'static, ()> {
+        // (a test that failed while holding the lock poisons it)
+        let lock = TEST_LOCK.lock().unwrap_or_else(|x| x.into_inner());
+        prefs::set_audio_backend(prefs::AudioBackend::Null);
+        prefs::set_null_output_speed(SPEED);
+        prefs::set_transport_fades(false);
+        lock
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir()
+            .join(format!("tsong-playback-test-{}-{}", name,
+                          std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Writes a mono, 16-bit WAV file of a quiet tone. Returns its size.
+    fn write_tone(path: &Path, seconds: u32) -> u64 {
+        let frames = RATE * seconds;
+        let mut data = Vec::with_capacity(44 + frames as usize * 2);
+        data.extend_from_slice(b"RIFF");
+        data.extend_from_slice(&(36 + frames * 2).to_le_bytes());
+        data.extend_from_slice(b"WAVEfmt ");
+        data.extend_from_slice(&16u32.to_le_bytes());
+        data.extend_from_slice(&1u16.to_le_bytes()); // PCM
+        data.extend_from_slice(&1u16.to_le_bytes()); // mono
+        data.extend_from_slice(&RATE.to_le_bytes());
+        data.extend_from_slice(&(RATE * 2).to_le_bytes());
+        data.extend_from_slice(&2u16.to_le_bytes()); // block align
+        data.extend_from_slice(&16u16.to_le_bytes()); // bits per sample
+        data.extend_from_slice(b"data");
+        data.extend_from_slice(&(frames * 2).to_le_bytes());
+        for n in 0 .. frames {
+            let x = (n as f64 * 440.0 * 2.0 * PI / RATE as f64).sin();
+            data.extend_from_slice(&((x * 1000.0) as i16).to_le_bytes());
+        }
+        fs::write(path, &data).unwrap();
+        data.len() as u64
+    }
+
+    /// Makes a song, `seconds` long, out of a new file in `dir`.
+    fn make_song(dir: &Path, seconds: u32, metadata: &[(&str, &str)])
+        -> LogicalSongRef {
+        let n = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        let name = format!("{}.wav", n);
+        let path = dir.join(&name);
+        let size = write_tone(&path, seconds);
+        let mut hash = [0u8; physical::ID_SIZE];
+        hash[..8].copy_from_slice(&n.to_le_bytes());
+        let file_id = FileID::from_hash(&hash);
+        let relative_path = format!("tsong-playback-test/{}", name);
+        physical::add_file_from_db(file_id, size, seconds,
+                                   vec![relative_path.clone()],
+                                   Some(vec![]));
+        assert_eq!(physical::saw_file(size, 0, &relative_path, &path),
+                   Some(file_id));
+        let mut user_metadata: BTreeMap<String, String> = metadata.iter()
+            .map(|(k, v)| (k.to_string(), v.to_string())).collect();
+        // (the default sort order puts the songs in the order they were made)
+        user_metadata.insert("title".to_owned(), name);
+        let song_id = SongID::from_inner(n);
+        logical::add_song_from_db(song_id, user_metadata, vec![file_id],
+                                  vec![0], None, seconds, BTreeMap::new(),
+                                  None, None);
+        logical::get_song_by_song_id(song_id).unwrap()
+    }
+
+    fn make_playlist(songs: &[&LogicalSongRef], playmode: Playmode)
+        -> PlaylistRef {
+        let n = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        let song_ids = songs.iter()
+            .map(|x| x.read().unwrap().get_id()).collect();
+        playlist::add_playlist_from_db(PlaylistID::from_inner(n), None, n,
+                                       format!("Test {}", n), String::new(),
+                                       false, playmode, song_ids,
+                                       playlist::DEFAULT_COLUMNS.clone(),
+                                       playlist::DEFAULT_SORT_ORDER.clone(),
+                                       false, false,
+                                       playlist::DEFAULT_ALBUM_KEY.clone())
+    }
+
+    /// Plays `first` from `playlist`, and watches what the user would hear
+    /// until playback stops or `enough` says we've heard enough. Returns what
+    /// was heard, one entry each time we looked. Stops playback before
+    /// returning.
+    fn listen(playlist: &PlaylistRef, first: &LogicalSongRef,
+              enough: impl Fn(&[(SongID, f64)]) -> bool)
+        -> Vec<(SongID, f64)> {
+        set_future_playlist(Some(playlist.clone()));
+        send_command(Play(Some(first.clone())));
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while get_playback_status() != PlaybackStatus::Playing {
+            assert!(Instant::now() < deadline, "playback never started");
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        let mut heard = Vec::new();
+        loop {
+            assert!(Instant::now() < deadline, "heard too little: {:?}",
+                    heard);
+            let (status, active_song) = get_status_and_active_song();
+            if status != PlaybackStatus::Playing { break }
+            if let Some((song, time)) = active_song {
+                heard.push((song.read().unwrap().get_id(), time));
+            }
+            if enough(&heard) { break }
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        send_command(Stop);
+        while get_playback_status() != PlaybackStatus::Stopped {
+            assert!(Instant::now() < deadline, "playback never stopped");
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        set_future_playlist(None);
+        heard
+    }
+
+    /// Returns the songs that were heard, in order.
+    fn songs_heard(heard: &[(SongID, f64)]) -> Vec<SongID> {
+        let mut ret: Vec<SongID> = Vec::new();
+        for &(id, _) in heard {
+            if ret.last() != Some(&id) { ret.push(id) }
+        }
+        ret
+    }
+
+    /// Returns how many times the time heard jumped backwards within a song.
+    fn wraps(heard: &[(SongID, f64)]) -> usize {
+        heard.windows(2)
+            .filter(|x| x[0].0 == x[1].0 && x[1].1 < x[0].1 - 0.25)
+            .count()
+    }
+
+    fn id(song: &LogicalSongRef) -> SongID { song.read().unwrap().get_id() }
+
+    #[test]
+    fn end_stops_after_last_song() {
+        let _lock = setup();
+        let dir = temp_dir("end");
+        let a = make_song(&dir, 1, &[]);
+        // loop points only count in LoopOne
+        let b = make_song(&dir, 2, &[("loop_start", "0.5"),
+                                      ("loop_end", "1.0")]);
+        let c = make_song(&dir, 1, &[]);
+        let playlist = make_playlist(&[&a, &b, &c], Playmode::End);
+        let heard = listen(&playlist, &a, |_| false);
+        assert_eq!(songs_heard(&heard), vec![id(&a), id(&b), id(&c)]);
+        assert_eq!(wraps(&heard), 0);
+        let furthest_b = heard.iter().filter(|x| x.0 == id(&b))
+            .map(|x| x.1).fold(0.0, f64::max);
+        assert!(furthest_b > 1.5, "only heard b up to {}", furthest_b);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn loop_goes_back_to_first_song() {
+        let _lock = setup();
+        let dir = temp_dir("loop");
+        let a = make_song(&dir, 1, &[]);
+        let b = make_song(&dir, 1, &[]);
+        let playlist = make_playlist(&[&a, &b], Playmode::Loop);
+        let heard = listen(&playlist, &a,
+                           |heard| songs_heard(heard).len() >= 5);
+        assert_eq!(songs_heard(&heard),
+                   vec![id(&a), id(&b), id(&a), id(&b), id(&a)]);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn loop_one_repeats_song() {
+        let _lock = setup();
+        let dir = temp_dir("loop-one");
+        let a = make_song(&dir, 1, &[]);
+        let b = make_song(&dir, 1, &[]);
+        let playlist = make_playlist(&[&a, &b], Playmode::LoopOne);
+        let heard = listen(&playlist, &a, |heard| wraps(heard) >= 3);
+        assert_eq!(songs_heard(&heard), vec![id(&a)]);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn loop_one_obeys_loop_points() {
+        let _lock = setup();
+        let dir = temp_dir("loop-points");
+        let a = make_song(&dir, 3, &[("loop_start", "1.0"),
+                                      ("loop_end", "2.0")]);
+        let b = make_song(&dir, 1, &[]);
+        let playlist = make_playlist(&[&a, &b], Playmode::LoopOne);
+        let heard = listen(&playlist, &a, |heard| wraps(heard) >= 3);
+        assert_eq!(songs_heard(&heard), vec![id(&a)]);
+        // (the time we see can run a little past where the audio is, until
+        // the playback thread catches up with the output)
+        let slack = 0.3;
+        let first_wrap = heard.windows(2)
+            .position(|x| x[1].1 < x[0].1 - 0.25).unwrap() + 1;
+        for &(_, time) in heard.iter() {
+            assert!(time < 2.0 + slack, "heard past loop end: {}", time);
+        }
+        for &(_, time) in heard[first_wrap ..].iter() {
+            assert!(time > 1.0 - slack, "heard before loop start: {}", time);
+        }
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -130,8 +130,16 @@ pub struct Playlist {
     sort_order: Vec<(String,bool)>,
     /// True if shuffled, false if sorted.
     shuffled: bool,
+    /// True if shuffling should shuffle whole albums, keeping each album's
+    /// songs together and in order.
+    shuffle_albums: bool,
+    /// The tags whose values, together, say which album a song is on.
+    album_key: Vec<String>,
     /// Playback mode (whether and how to loop).
     playmode: Playmode,
+    /// True if every song played from this playlist should pick up where it
+    /// left off.
+    remember_positions: bool,
     // not serialized in database
     /// The logical song generation last time we got refreshed.
     library_generation: GenerationValue,
@@ -143,6 +151,9 @@ pub struct Playlist {
     sorted_songs: Vec<LogicalSongRef>,
     /// References to child playlists
     children: Vec<PlaylistRef>,
+    /// A shuffled order saved from a previous session, to be used instead of
+    /// a fresh shuffle the next time this playlist is shuffled.
+    saved_order: Option<Vec<SongID>>,
 }
 
 const PLAYLIST_CODE_LIBRARY: &str = include_str!("lua/playlist_lib.lua");
@@ -171,6 +182,9 @@ lazy_static! {
             Column{tag:"album".to_owned(),
                    width:DEFAULT_COLUMN_WIDTH}
         ];
+    pub static ref DEFAULT_ALBUM_KEY
+        : Vec<String>
+        = vec!["album".to_owned(), "album_artist".to_owned()];
     pub static ref DEFAULT_SORT_ORDER
         : Vec<(String,bool)>
         = vec![
@@ -193,7 +207,6 @@ impl Playlist {
     /// Checks the validity of the given rule code. Returns:
     /// - `Err("...")` → the rule code is invalid and we made no change
     /// - `Ok(...)` → the rule code is valid and we made the change
-    #[allow(dead_code)]
     pub fn set_rule_code(&mut self, neu: String) -> Result<(), String> {
         self.refresh_with_code(Some(&neu))?;
         self.rule_code = neu;
@@ -254,6 +267,15 @@ impl Playlist {
         self.playmode = nu;
         db::update_playlist_playmode(self.id, nu)
     }
+    /// Returns true if songs played from this playlist pick up where they
+    /// left off.
+    pub fn get_remember_positions(&self) -> bool { self.remember_positions }
+    pub fn set_remember_positions(&mut self, nu: bool) {
+        if self.remember_positions != nu {
+            self.remember_positions = nu;
+            db::update_playlist_remember_positions(self.id, nu);
+        }
+    }
     pub fn bump_playmode(&mut self) -> Playmode {
         let nu = self.playmode.bump();
         self.set_playmode(nu);
@@ -302,6 +324,36 @@ impl Playlist {
             self.resort(false);
         }
     }
+    /// Turns on shuffle mode, if it wasn't already on, and shuffles the
+    /// playlist anew, without regard for the active song.
+    pub fn reshuffle(&mut self) {
+        if !self.shuffled {
+            self.shuffled = true;
+            db::update_playlist_shuffled(self.id, self.shuffled);
+        }
+        self.saved_order = None;
+        self.resort(true);
+    }
+    /// Returns true if shuffling shuffles whole albums instead of songs.
+    pub fn get_shuffle_albums(&self) -> bool { self.shuffle_albums }
+    /// Returns the tags that say which album a song is on, for the purposes
+    /// of album shuffle.
+    pub fn get_album_key(&self) -> &[String] { &self.album_key[..] }
+    /// Changes whether shuffling shuffles whole albums, and what counts as an
+    /// album. If the playlist is shuffled, it gets reshuffled.
+    pub fn set_album_shuffle(&mut self, shuffle_albums: bool,
+                             album_key: Vec<String>) {
+        let album_key = if album_key.is_empty() { DEFAULT_ALBUM_KEY.clone() }
+        else { album_key };
+        if self.shuffle_albums != shuffle_albums
+        || self.album_key != album_key {
+            self.shuffle_albums = shuffle_albums;
+            self.album_key = album_key;
+            db::update_playlist_album_shuffle(self.id, self.shuffle_albums,
+                                              &self.album_key);
+            if self.shuffled { self.resort(false); }
+        }
+    }
     /// Returns true if the playlist is shuffled, false if it is sorted.
     pub fn is_shuffled(&self) -> bool {
         self.shuffled
@@ -403,6 +455,26 @@ impl Playlist {
     pub fn get_songs(&self) -> &[LogicalSongRef] {
         &self.sorted_songs[..]
     }
+    /// Returns the order the songs are shuffled in, or `None` if the playlist
+    /// isn't shuffled.
+    pub fn get_shuffle_order(&self) -> Option<Vec<SongID>> {
+        if !self.shuffled { return None }
+        match self.saved_order.as_ref() {
+            // (we haven't been refreshed since the order was restored)
+            Some(x) => Some(x.clone()),
+            None => Some(self.sorted_songs.iter()
+                         .map(|x| x.read().unwrap().get_id()).collect()),
+        }
+    }
+    /// Arranges for the next shuffle to put the songs in the given order,
+    /// instead of a random one. Any songs that aren't in the order will be
+    /// shuffled in after the ones that are.
+    pub fn restore_shuffle_order(&mut self, order: Vec<SongID>) {
+        self.saved_order = Some(order);
+        if !self.unsorted_songs.is_empty() {
+            self.resort(true);
+        }
+    }
     /// Sort (or shuffle) this playlist.
     ///
     /// Returns true if the order of the playlist's contents changed as a
@@ -412,9 +484,26 @@ impl Playlist {
     /// put the currently playing song at the beginning of the shuffle.
     pub fn resort(&mut self, ignore_active_song: bool) -> bool {
         let mut newly_sorted_songs = self.unsorted_songs.clone();
-        if self.shuffled {
+        if self.shuffled && self.shuffle_albums {
+            newly_sorted_songs = self.shuffle_by_album(newly_sorted_songs,
+                                                       ignore_active_song);
+        }
+        else if self.shuffled {
             let mut rng = thread_rng();
-            if newly_sorted_songs.len() > 1 {
+            let saved_order = if newly_sorted_songs.len() > 1 {
+                self.saved_order.take()
+            } else { None };
+            if let Some(saved_order) = saved_order {
+                let positions: HashMap<SongID, usize> = saved_order.iter()
+                    .enumerate().map(|(n, id)| (*id, n)).collect();
+                // (the sort is stable, so the new songs stay shuffled)
+                newly_sorted_songs.shuffle(&mut rng);
+                newly_sorted_songs.sort_by_key(|x| {
+                    positions.get(&x.read().unwrap().get_id()).cloned()
+                        .unwrap_or(usize::MAX)
+                });
+            }
+            else if newly_sorted_songs.len() > 1 {
                 // if a song is currently playing, and it's in this playlist,
                 // put it first.
                 let active_song = if ignore_active_song { None }
@@ -465,6 +554,88 @@ impl Playlist {
             false
         }
     }
+    /// Shuffles the given songs a whole album at a time. Songs are grouped by
+    /// `album_key`, the groups are shuffled, and each group is put in disc
+    /// and track order. Songs with none of the `album_key` tags are each
+    /// their own group.
+    ///
+    /// `ignore_active_song` has the same meaning as in `resort`, except that
+    /// it's the active song's whole album that goes first. If it's true, and
+    /// we're reshuffling after the end of the playlist, we avoid starting
+    /// with the album that just finished.
+    fn shuffle_by_album(&mut self, songs: Vec<LogicalSongRef>,
+                        ignore_active_song: bool) -> Vec<LogicalSongRef> {
+        let mut rng = thread_rng();
+        let mut groups: Vec<Vec<LogicalSongRef>> = Vec::new();
+        let mut group_indices: HashMap<Vec<String>, usize> = HashMap::new();
+        for song_ref in songs.into_iter() {
+            let song = song_ref.read().unwrap();
+            let metadata = song.get_metadata();
+            let key: Vec<String> = self.album_key.iter()
+                .map(|tag| metadata.get(tag).cloned().unwrap_or_default())
+                .collect();
+            drop(song);
+            if key.iter().all(String::is_empty) {
+                groups.push(vec![song_ref]);
+                continue
+            }
+            let index = *group_indices.entry(key).or_insert_with(|| {
+                groups.push(Vec::new());
+                groups.len() - 1
+            });
+            groups[index].push(song_ref);
+        }
+        for group in groups.iter_mut() {
+            group.sort_by(|a, b| {
+                let a = a.read().unwrap();
+                let b = b.read().unwrap();
+                for key in &["disc#", "track#"] {
+                    let a_value = a.get_metadata().get(*key)
+                        .map(String::as_str).unwrap_or("");
+                    let b_value = b.get_metadata().get(*key)
+                        .map(String::as_str).unwrap_or("");
+                    let ordering = compare_str(a_value, b_value);
+                    if ordering != Ordering::Equal { return ordering }
+                }
+                a.get_id().cmp(&b.get_id())
+            });
+        }
+        groups.shuffle(&mut rng);
+        let saved_order = if groups.len() > 1 { self.saved_order.take() }
+        else { None };
+        if let Some(saved_order) = saved_order {
+            let positions: HashMap<SongID, usize> = saved_order.iter()
+                .enumerate().map(|(n, id)| (*id, n)).collect();
+            // (the sort is stable, so the new albums stay shuffled)
+            groups.sort_by_key(|group| {
+                group.iter()
+                    .filter_map(|x| positions.get(&x.read().unwrap().get_id())
+                                .cloned())
+                    .min().unwrap_or(usize::MAX)
+            });
+        }
+        else if groups.len() > 1 {
+            let first_group_n = if ignore_active_song {
+                // don't start over with the album that just finished
+                match self.sorted_songs.last() {
+                    Some(last_song) if groups[0].contains(last_song) =>
+                        rng.gen_range(1 .. groups.len()),
+                    _ => 0,
+                }
+            }
+            else {
+                match playback::get_active_song() {
+                    Some((song_ref, _)) => groups.iter()
+                        .position(|x| x.contains(&song_ref)).unwrap_or(0),
+                    None => 0,
+                }
+            };
+            if first_group_n != 0 {
+                groups.swap(0, first_group_n);
+            }
+        }
+        groups.into_iter().flatten().collect()
+    }
 }
 
 pub fn create_new_playlist() -> anyhow::Result<PlaylistRef> {
@@ -480,7 +651,8 @@ pub fn create_new_playlist() -> anyhow::Result<PlaylistRef> {
     Ok(add_playlist_from_db(new_id, None, new_order, new_playlist_name,
                             String::new(), false, Playmode::End, Vec::new(),
                             DEFAULT_COLUMNS.clone(),
-                            DEFAULT_SORT_ORDER.clone()))
+                            DEFAULT_SORT_ORDER.clone(), false, false,
+                            DEFAULT_ALBUM_KEY.clone()))
 }
 
 /// Add a new playlist, loaded from the database. You will need to call
@@ -491,15 +663,19 @@ pub fn add_playlist_from_db(id: PlaylistID, parent_id: Option<PlaylistID>,
                             shuffled: bool, playmode: Playmode,
                             manually_added_ids: Vec<SongID>,
                             columns: Vec<Column>,
-                            sort_order: Vec<(String,bool)>)
+                            sort_order: Vec<(String,bool)>,
+                            remember_positions: bool,
+                            shuffle_albums: bool,
+                            album_key: Vec<String>)
     -> PlaylistRef {
     let ret = PlaylistRef::new(
         Playlist { id, parent_id, parent_order, name, rule_code,
                    manually_added_ids, columns, sort_order, shuffled, playmode,
+                   remember_positions, shuffle_albums, album_key,
                    library_generation: NOT_GENERATED,
                    self_generation: GenerationTracker::new(),
                    unsorted_songs: Vec::new(), sorted_songs: Vec::new(),
-                   children: Vec::new() }
+                   children: Vec::new(), saved_order: None }
     );
     if parent_id.is_none() {
         TOP_LEVEL_PLAYLISTS.write().unwrap().push(ret.clone());
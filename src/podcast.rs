@@ -0,0 +1,885 @@
+//! This module handles podcasts: feeds that we check every so often, whose
+//! episodes we download into a directory of our own.
+//!
+//! That directory is scanned like any other music location (see
+//! `scan::get_scan_paths`), so downloaded episodes become ordinary songs.
+//! Once they have, `apply_episode_metadata` gives them metadata from the feed.
+//! Each podcast gets a playlist of its own, whose rule picks out its episodes
+//! by their `podcast_id`.
+//!
+//! Feeds are checked by whatever UI is running (see `poll`), in a background
+//! thread.
+
+use crate::*;
+
+use log::{error, info, warn};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::{self, File},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    sync::{atomic::{AtomicBool, Ordering}, RwLock},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::anyhow;
+use lazy_static::lazy_static;
+
+/// Podcasts are checked for new episodes this often, in seconds.
+const REFRESH_INTERVAL: i64 = 6 * 60 * 60;
+
+/// How many of the newest episodes a new subscription keeps downloaded.
+const DEFAULT_KEEP_EPISODES: u32 = 3;
+
+/// Episodes whose feed doesn't say otherwise get this extension.
+const DEFAULT_EXTENSION: &str = "mp3";
+
+/// Something that can download things over HTTP. Everything in this module
+/// that touches the network goes through one of these, so that it can be
+/// pointed at something other than the real internet.
+pub trait HttpClient {
+    /// Downloads whatever is at the given URL, writing it to `out` as it
+    /// arrives.
+    fn fetch(&self, url: &str, out: &mut dyn Write) -> anyhow::Result<()>;
+}
+
+/// Downloads using FFMPEG's own HTTP client.
+pub struct FfmpegHttp;
+
+impl HttpClient for FfmpegHttp {
+    fn fetch(&self, url: &str, out: &mut dyn Write) -> anyhow::Result<()> {
+        ffmpeg::read_url(url, out)
+    }
+}
+
+/// Downloads by running a command (like `curl -sfL`), with the URL added as
+/// its last argument, and reading what it writes to standard output.
+pub struct CommandHttp {
+    pub command: String,
+}
+
+impl HttpClient for CommandHttp {
+    fn fetch(&self, url: &str, out: &mut dyn Write) -> anyhow::Result<()> {
+        let mut words = self.command.split_whitespace();
+        let program = words.next()
+            .ok_or_else(|| anyhow!("The HTTP command is empty"))?;
+        let mut child = Command::new(program).args(words).arg(url)
+            .stdin(Stdio::null()).stdout(Stdio::piped()).spawn()?;
+        let copied = io::copy(child.stdout.as_mut().unwrap(), out);
+        let status = child.wait()?;
+        copied?;
+        if !status.success() {
+            return Err(anyhow!("{} failed ({})", program, status))
+        }
+        Ok(())
+    }
+}
+
+/// Returns the HTTP client the user has chosen. (See
+/// `prefs::get_http_command`.)
+pub fn get_http_client() -> Box<dyn HttpClient + Send> {
+    match prefs::get_http_command() {
+        Some(command) => Box::new(CommandHttp { command }),
+        None => Box::new(FfmpegHttp),
+    }
+}
+
+#[derive(Clone,Debug,PartialEq)]
+pub struct Podcast {
+    pub id: u64,
+    /// Where the feed is.
+    pub url: String,
+    /// The feed's title, or its URL if we haven't read it yet.
+    pub title: String,
+    /// The playlist that holds this podcast's episodes.
+    pub playlist_id: Option<PlaylistID>,
+    /// How many of the newest episodes to keep downloaded. `None` means all
+    /// of them.
+    pub keep_episodes: Option<u32>,
+    /// Whether to delete episodes once they've been played.
+    pub delete_played: bool,
+    /// When the feed was last checked, in seconds since the UNIX epoch.
+    pub last_refreshed: Option<i64>,
+}
+
+#[derive(Clone,Debug,PartialEq)]
+pub struct Episode {
+    pub podcast_id: u64,
+    /// What the feed calls this episode. Unique within a podcast.
+    pub guid: String,
+    /// Where this episode was in the feed the last time we checked it. 0 is
+    /// the newest.
+    pub position: u32,
+    /// Where to download the episode's audio from.
+    pub url: String,
+    /// Metadata from the feed, ready to be given to the episode's song.
+    pub metadata: BTreeMap<String, String>,
+    /// Where the episode was downloaded to, if it's downloaded.
+    pub path: Option<PathBuf>,
+    /// The downloaded file's ID, if it's downloaded.
+    pub file_id: Option<FileID>,
+    pub played: bool,
+}
+
+impl Episode {
+    pub fn get_title(&self) -> &str {
+        self.metadata.get("title").map(String::as_str).unwrap_or(&self.guid)
+    }
+    pub fn is_downloaded(&self) -> bool { self.path.is_some() }
+}
+
+lazy_static! {
+    // Deadlock avoidance lexical order:
+    // - `PODCASTS` lock
+    // - `EPISODES` lock
+    // Neither is held while locking a song.
+    static ref PODCASTS: RwLock<Vec<Podcast>> = RwLock::new(Vec::new());
+    static ref EPISODES: RwLock<Vec<Episode>> = RwLock::new(Vec::new());
+}
+
+/// True while the refresh thread is running.
+static REFRESHING: AtomicBool = AtomicBool::new(false);
+/// Set by the refresh thread when it's done. See `poll`.
+static REFRESHED: AtomicBool = AtomicBool::new(false);
+
+fn now() -> i64 {
+    SystemTime::now().duration_since(UNIX_EPOCH)
+        .map(|x| x.as_secs() as i64).unwrap_or(0)
+}
+
+/// Called by the database when loading podcasts.
+pub fn add_podcast_from_db(podcast: Podcast) {
+    PODCASTS.write().unwrap().push(podcast);
+}
+
+/// Called by the database when loading podcast episodes.
+pub fn add_episode_from_db(episode: Episode) {
+    EPISODES.write().unwrap().push(episode);
+}
+
+/// Returns every podcast, in the order they were subscribed to.
+pub fn get_podcasts() -> Vec<Podcast> {
+    PODCASTS.read().unwrap().clone()
+}
+
+/// Returns true if there are any podcasts at all.
+pub fn have_podcasts() -> bool {
+    !PODCASTS.read().unwrap().is_empty()
+}
+
+/// Returns the given podcast's episodes, newest first.
+pub fn get_episodes(podcast_id: u64) -> Vec<Episode> {
+    let mut ret: Vec<Episode> = EPISODES.read().unwrap().iter()
+        .filter(|x| x.podcast_id == podcast_id).cloned().collect();
+    ret.sort_by_key(|x| x.position);
+    ret
+}
+
+/// Subscribes to the feed at the given URL. A playlist is made for it right
+/// away; its episodes arrive the next time `poll` is called.
+pub fn subscribe(url: &str) -> anyhow::Result<(Podcast, PlaylistRef)> {
+    let url = url.trim();
+    if url.is_empty() {
+        return Err(anyhow!("No URL was given"))
+    }
+    if PODCASTS.read().unwrap().iter().any(|x| x.url == url) {
+        return Err(anyhow!("Already subscribed to that podcast"))
+    }
+    let mut podcast = Podcast {
+        id: 0, url: url.to_owned(), title: url.to_owned(), playlist_id: None,
+        keep_episodes: Some(DEFAULT_KEEP_EPISODES), delete_played: false,
+        last_refreshed: None,
+    };
+    podcast.id = db::create_podcast(&podcast)?;
+    let playlist_ref = playlist::create_new_playlist()?;
+    {
+        let mut playlist = playlist_ref.write().unwrap();
+        playlist.set_name(podcast.title.clone());
+        if let Err(x) = playlist.set_rule_code(format!("podcast_id == \"{}\"",
+                                                       podcast.id)) {
+            warn!("Couldn't set podcast playlist's rule: {}", x);
+        }
+        playlist.set_remember_positions(true);
+        podcast.playlist_id = Some(playlist.get_id());
+    }
+    db::update_podcast(&podcast);
+    PODCASTS.write().unwrap().push(podcast.clone());
+    Ok((podcast, playlist_ref))
+}
+
+/// Replaces the podcast with the same id. Its downloads will be brought in
+/// line with its new settings the next time it's refreshed.
+pub fn update_podcast(podcast: &Podcast) {
+    let mut podcasts = PODCASTS.write().unwrap();
+    if let Some(old) = podcasts.iter_mut().find(|x| x.id == podcast.id) {
+        db::update_podcast(podcast);
+        *old = podcast.clone();
+    }
+}
+
+/// Forgets a podcast, and deletes all of its downloaded episodes. Its
+/// playlist is left for the caller to deal with.
+pub fn unsubscribe(id: u64) {
+    PODCASTS.write().unwrap().retain(|x| x.id != id);
+    for episode in get_episodes(id).iter() {
+        delete_download(episode);
+        forget_download(episode);
+    }
+    EPISODES.write().unwrap().retain(|x| x.podcast_id != id);
+    db::delete_podcast(id);
+}
+
+/// Marks an episode as played or unplayed. (This is also done automatically,
+/// when an episode is heard to the end.)
+pub fn set_played(podcast_id: u64, guid: &str, played: bool) {
+    let episode = {
+        let mut episodes = EPISODES.write().unwrap();
+        let episode = match episodes.iter_mut()
+            .find(|x| x.podcast_id == podcast_id && x.guid == guid) {
+                Some(x) => x,
+                None => return,
+            };
+        if episode.played == played { return }
+        episode.played = played;
+        db::put_episode(episode);
+        episode.clone()
+    };
+    for song_ref in get_songs_for_episode(&episode) {
+        let mut song = song_ref.write().unwrap();
+        let mut metadata = song.get_metadata().clone();
+        metadata.insert("played".to_owned(), played_value(played));
+        song.set_metadata(metadata);
+    }
+}
+
+/// Called by playback when it's done with a song (or periodically while
+/// playing it). If the song is a podcast episode, and it was heard to within
+/// `leeway` seconds of the end, marks it played.
+pub fn note_heard(song_ref: &LogicalSongRef, time: f64, leeway: f64) {
+    let song = song_ref.read().unwrap();
+    if time + leeway < song.get_duration() as f64 { return }
+    let metadata = song.get_metadata();
+    if metadata.get("played").map(String::as_str) == Some("yes") { return }
+    let podcast_id = match metadata.get("podcast_id")
+        .and_then(|x| x.parse().ok()) {
+            Some(x) => x,
+            None => return,
+        };
+    let guid = match metadata.get("podcast_guid") {
+        Some(x) => x.clone(),
+        None => return,
+    };
+    drop(song);
+    set_played(podcast_id, &guid, true);
+}
+
+fn played_value(played: bool) -> String {
+    (if played { "yes" } else { "no" }).to_owned()
+}
+
+/// Returns the songs that a downloaded episode became. (Usually one, but an
+/// episode with chapters is split into several.)
+fn get_songs_for_episode(episode: &Episode) -> Vec<LogicalSongRef> {
+    let file_id = match episode.file_id.as_ref() {
+        Some(x) => x,
+        None => return Vec::new(),
+    };
+    let tracks = match physical::get_file_by_id(file_id) {
+        Some(x) => x.read().unwrap().get_track_numbers(),
+        None => return Vec::new(),
+    };
+    tracks.into_iter()
+        .filter_map(|track| logical::get_song_by_file_id(file_id, track))
+        .collect()
+}
+
+/// Gives every downloaded episode's songs the episode's metadata from the
+/// feed, if they don't already have it. Call after a scan.
+///
+/// Also renames the playlists of podcasts whose titles we've just learned.
+pub fn apply_episode_metadata() {
+    let podcasts = get_podcasts();
+    let episodes = EPISODES.read().unwrap().clone();
+    for episode in episodes.iter() {
+        let podcast_id = episode.podcast_id.to_string();
+        for song_ref in get_songs_for_episode(episode) {
+            let mut song = song_ref.write().unwrap();
+            let old = song.get_metadata();
+            if old.get("podcast_id") == Some(&podcast_id)
+            && old.get("podcast_guid") == Some(&episode.guid) {
+                continue
+            }
+            let mut metadata = old.clone();
+            for (k, v) in episode.metadata.iter() {
+                metadata.insert(k.clone(), v.clone());
+            }
+            metadata.insert("podcast_id".to_owned(), podcast_id.clone());
+            metadata.insert("podcast_guid".to_owned(), episode.guid.clone());
+            metadata.insert("played".to_owned(),
+                            played_value(episode.played));
+            song.set_metadata(metadata);
+        }
+    }
+    for podcast in podcasts.iter() {
+        let playlist_ref = match podcast.playlist_id
+            .and_then(playlist::get_playlist_by_id) {
+                Some(x) => x,
+                None => continue,
+            };
+        let mut playlist = playlist_ref.write().unwrap();
+        if playlist.get_name() == podcast.url && podcast.title != podcast.url {
+            playlist.set_name(podcast.title.clone());
+        }
+    }
+}
+
+/// Called periodically by the UI. Starts refreshing any podcasts that are due
+/// for it in the background. Returns true if a refresh has finished since the
+/// last call, in which case the caller should scan the podcast directory and
+/// then call `apply_episode_metadata`.
+pub fn poll() -> bool {
+    let now = now();
+    let due: Vec<u64> = PODCASTS.read().unwrap().iter()
+        .filter(|x| match x.last_refreshed {
+            Some(then) => now - then >= REFRESH_INTERVAL,
+            None => true,
+        }).map(|x| x.id).collect();
+    if !due.is_empty() {
+        start_refresh(due);
+    }
+    REFRESHED.swap(false, Ordering::SeqCst)
+}
+
+/// Starts refreshing the given podcasts in the background, unless a refresh
+/// is already in progress. Returns true if it started.
+pub fn start_refresh(ids: Vec<u64>) -> bool {
+    if REFRESHING.compare_exchange(false, true, Ordering::SeqCst,
+                                   Ordering::SeqCst).is_err() {
+        return false
+    }
+    let client = get_http_client();
+    let spawned = std::thread::Builder::new()
+        .name("podcast refresh thread".to_owned())
+        .spawn(move || {
+            for id in ids.into_iter() {
+                if let Err(x) = refresh_podcast(id, &*client) {
+                    report_failure(id, &format!("{:#}", x));
+                }
+            }
+            REFRESHED.store(true, Ordering::SeqCst);
+            REFRESHING.store(false, Ordering::SeqCst);
+        });
+    if let Err(x) = spawned {
+        error!("Unable to spawn podcast refresh thread: {:?}", x);
+        REFRESHING.store(false, Ordering::SeqCst);
+        return false
+    }
+    true
+}
+
+/// Returns true while podcasts are being refreshed.
+pub fn is_refreshing() -> bool {
+    REFRESHING.load(Ordering::SeqCst)
+}
+
+/// Checks a podcast's feed for new episodes, then downloads and deletes
+/// episodes as its settings call for.
+pub fn refresh_podcast<H: HttpClient + ?Sized>(id: u64, client: &H)
+-> anyhow::Result<()> {
+    // (The user can change a podcast's settings while we're in the middle of
+    // this, so we only ever change the fields we're responsible for.)
+    let url = {
+        let mut podcasts = PODCASTS.write().unwrap();
+        let podcast = match podcasts.iter_mut().find(|x| x.id == id) {
+            Some(x) => x,
+            None => return Ok(()),
+        };
+        // Even if this fails, don't try again until the next interval.
+        podcast.last_refreshed = Some(now());
+        db::update_podcast(podcast);
+        podcast.url.clone()
+    };
+    info!("Refreshing podcast {:?}", url);
+    let mut buf = Vec::new();
+    client.fetch(&url, &mut buf)?;
+    let feed = feed::parse(&buf[..])?;
+    let podcast = {
+        let mut podcasts = PODCASTS.write().unwrap();
+        let podcast = match podcasts.iter_mut().find(|x| x.id == id) {
+            Some(x) => x,
+            None => return Ok(()),
+        };
+        if let Some(title) = feed.title.as_ref() {
+            if title != &podcast.title {
+                podcast.title = title.clone();
+                db::update_podcast(podcast);
+            }
+        }
+        podcast.clone()
+    };
+    merge_episodes(&podcast, &feed);
+    let (wanted, unwanted) = plan_downloads(&podcast);
+    for episode in unwanted.iter() {
+        delete_download(episode);
+        forget_download(episode);
+    }
+    let dir = prefs::get_podcast_directory().join(podcast.id.to_string());
+    let mut errors = Vec::new();
+    for episode in wanted.iter() {
+        if let Err(x) = download_episode(episode, &dir, client) {
+            errors.push(format!("{}: {:#}", episode.get_title(), x));
+        }
+    }
+    if errors.is_empty() { Ok(()) }
+    else { Err(anyhow!("Couldn't download some episodes:\n{}",
+                       errors.join("\n"))) }
+}
+
+/// Makes metadata for an episode's song, out of what the feed says about it.
+fn make_episode_metadata(podcast: &Podcast, feed: &feed::Feed,
+                         item: &feed::FeedEpisode)
+-> BTreeMap<String, String> {
+    let mut ret = BTreeMap::new();
+    ret.insert("album".to_owned(), podcast.title.clone());
+    ret.insert("genre".to_owned(), "Podcast".to_owned());
+    if let Some(title) = item.title.as_ref() {
+        ret.insert("title".to_owned(), title.clone());
+    }
+    if let Some(author) = item.author.as_ref().or(feed.author.as_ref()) {
+        ret.insert("artist".to_owned(), author.clone());
+    }
+    if let Some(date) = item.date.as_ref() {
+        ret.insert("date".to_owned(), date.clone());
+        // RSS dates look like "Tue, 10 Jun 2003 04:00:00 GMT", and Atom
+        // dates look like "2003-06-10T04:00:00Z"
+        let year = date.split(|c: char| !c.is_ascii_digit())
+            .find(|x| x.len() == 4);
+        if let Some(year) = year {
+            ret.insert("year".to_owned(), year.to_owned());
+        }
+    }
+    if let Some(description) = item.description.as_ref() {
+        ret.insert("comment".to_owned(), description.clone());
+    }
+    if let Some(number) = item.number.as_ref() {
+        ret.insert("track#".to_owned(), number.clone());
+    }
+    ret
+}
+
+/// Brings our list of a podcast's episodes up to date with its feed. New
+/// episodes are added, and known ones get their place and metadata updated.
+/// Episodes that have dropped off the feed are kept, after all the others.
+fn merge_episodes(podcast: &Podcast, feed: &feed::Feed) {
+    let mut positions = HashMap::new();
+    for (n, item) in feed.episodes.iter().enumerate() {
+        // (if a guid appears twice, the first one wins)
+        positions.entry(item.guid.as_str()).or_insert((n as u32, item));
+    }
+    let mut episodes = EPISODES.write().unwrap();
+    let mut gone: Vec<&mut Episode> = Vec::new();
+    for episode in episodes.iter_mut()
+        .filter(|x| x.podcast_id == podcast.id) {
+        match positions.remove(episode.guid.as_str()) {
+            Some((position, item)) => {
+                let metadata = make_episode_metadata(podcast, feed, item);
+                if episode.position != position || episode.url != item.url
+                || episode.metadata != metadata {
+                    episode.position = position;
+                    episode.url = item.url.clone();
+                    episode.metadata = metadata;
+                    db::put_episode(episode);
+                }
+            },
+            None => gone.push(episode),
+        }
+    }
+    gone.sort_by_key(|x| x.position);
+    let base = feed.episodes.len() as u32;
+    for (n, episode) in gone.into_iter().enumerate() {
+        if episode.position != base + n as u32 {
+            episode.position = base + n as u32;
+            db::put_episode(episode);
+        }
+    }
+    let mut new: Vec<(u32, &feed::FeedEpisode)> = positions.into_iter()
+        .map(|(_, x)| x).collect();
+    new.sort_by_key(|x| x.0);
+    for (position, item) in new.into_iter() {
+        let episode = Episode {
+            podcast_id: podcast.id, guid: item.guid.clone(), position,
+            url: item.url.clone(),
+            metadata: make_episode_metadata(podcast, feed, item),
+            path: None, file_id: None, played: false,
+        };
+        db::put_episode(&episode);
+        episodes.push(episode);
+    }
+}
+
+/// Works out which of a podcast's episodes should be downloaded but aren't
+/// (first list), and which are downloaded but shouldn't be (second list).
+fn plan_downloads(podcast: &Podcast) -> (Vec<Episode>, Vec<Episode>) {
+    let episodes = get_episodes(podcast.id);
+    let keep = podcast.keep_episodes.map(|x| x as usize)
+        .unwrap_or(usize::MAX);
+    let mut wanted = Vec::new();
+    let mut unwanted = Vec::new();
+    let mut kept = 0;
+    for episode in episodes.into_iter() {
+        let want = kept < keep && !(podcast.delete_played && episode.played);
+        if want { kept += 1 }
+        match (want, episode.is_downloaded()) {
+            (true, false) => wanted.push(episode),
+            (false, true) => unwanted.push(episode),
+            _ => (),
+        }
+    }
+    (wanted, unwanted)
+}
+
+/// Turns an episode's title into something that can safely be a filename.
+fn sanitize_file_name(name: &str) -> String {
+    let ret: String = name.chars().take(80)
+        .map(|c| if c.is_alphanumeric() || " -_,()".contains(c) { c }
+             else { '_' })
+        .collect();
+    let ret = ret.trim();
+    if ret.is_empty() { "Episode".to_owned() }
+    else { ret.to_owned() }
+}
+
+/// Guesses an episode's file extension from its URL.
+fn guess_extension(url: &str) -> &str {
+    let path = url.split(|c| c == '?' || c == '#').next().unwrap_or(url);
+    let last = path.rsplit('/').next().unwrap_or(path);
+    match last.rfind('.').map(|x| &last[x+1..]) {
+        Some(x) if !x.is_empty() && x.len() <= 5
+            && x.chars().all(|c| c.is_ascii_alphanumeric()) => x,
+        _ => DEFAULT_EXTENSION,
+    }
+}
+
+/// Downloads an episode into the given directory. It's downloaded under a
+/// hidden name first, so that a scan won't find a partial file.
+fn download_episode<H: HttpClient + ?Sized>(episode: &Episode, dir: &Path,
+                                            client: &H)
+-> anyhow::Result<()> {
+    fs::create_dir_all(dir)?;
+    let stem = sanitize_file_name(episode.get_title());
+    let extension = guess_extension(&episode.url);
+    let mut path = dir.join(format!("{}.{}", stem, extension));
+    let mut n = 2;
+    while path.exists() {
+        path = dir.join(format!("{} ({}).{}", stem, n, extension));
+        n += 1;
+    }
+    let temp_path = dir.join(format!(".{}.part",
+                                     path.file_name().unwrap()
+                                     .to_string_lossy()));
+    info!("Downloading {:?} to {:?}", episode.url, path);
+    let result = File::create(&temp_path).map_err(anyhow::Error::from)
+        .and_then(|mut file| {
+            client.fetch(&episode.url, &mut file)?;
+            file.flush()?;
+            Ok(())
+        });
+    if let Err(x) = result {
+        let _ = fs::remove_file(&temp_path);
+        return Err(x)
+    }
+    fs::rename(&temp_path, &path)?;
+    let file_id = FileID::from_file(File::open(&path)?)?;
+    let mut episodes = EPISODES.write().unwrap();
+    match episodes.iter_mut().find(|x| x.podcast_id == episode.podcast_id
+                                   && x.guid == episode.guid) {
+        Some(x) => {
+            x.path = Some(path);
+            x.file_id = Some(file_id);
+            db::put_episode(x);
+        },
+        None => {
+            // we were unsubscribed while downloading
+            let _ = fs::remove_file(&path);
+        },
+    }
+    Ok(())
+}
+
+/// Called when a downloaded episode's file has been changed in place (see
+/// `writeback`), so that we can still find its songs.
+pub fn replaced_file(old_id: &FileID, new_id: &FileID) {
+    let mut episodes = EPISODES.write().unwrap();
+    for episode in episodes.iter_mut()
+        .filter(|x| x.file_id.as_ref() == Some(old_id)) {
+        episode.file_id = Some(*new_id);
+        db::put_episode(episode);
+    }
+}
+
+/// Deletes an episode's downloaded file, if it has one.
+fn delete_download(episode: &Episode) {
+    if let Some(path) = episode.path.as_ref() {
+        info!("Deleting podcast episode {:?}", path);
+        match fs::remove_file(path) {
+            Err(x) if x.kind() != io::ErrorKind::NotFound =>
+                warn!("Couldn't delete {:?}: {}", path, x),
+            _ => (),
+        }
+    }
+}
+
+/// Records that an episode is no longer downloaded, and takes its songs out
+/// of its podcast's playlist.
+fn forget_download(episode: &Episode) {
+    for song_ref in get_songs_for_episode(episode) {
+        let mut song = song_ref.write().unwrap();
+        let mut metadata = song.get_metadata().clone();
+        if metadata.remove("podcast_id").is_some() {
+            song.set_metadata(metadata);
+        }
+    }
+    let mut episodes = EPISODES.write().unwrap();
+    if let Some(x) = episodes.iter_mut().find(|x| {
+        x.podcast_id == episode.podcast_id && x.guid == episode.guid
+    }) {
+        x.path = None;
+        x.file_id = None;
+        db::put_episode(x);
+    }
+}
+
+/// Reports that a podcast couldn't be refreshed.
+fn report_failure(id: u64, why: &str) {
+    let title = PODCASTS.read().unwrap().iter().find(|x| x.id == id)
+        .map(|x| x.title.clone()).unwrap_or_else(|| id.to_string());
+    let wat = format!("Couldn't refresh the podcast {:?}: {}", title, why);
+    error!("{}", wat);
+    errors::from("Podcasts", wat);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+
+    /// Serves canned responses instead of going to the internet, and
+    /// remembers what was asked for.
+    #[derive(Default)]
+    struct FakeHttp {
+        responses: HashMap<String, Vec<u8>>,
+        fetched: RefCell<Vec<String>>,
+    }
+
+    impl FakeHttp {
+        fn serve(&mut self, url: &str, body: &[u8]) {
+            self.responses.insert(url.to_owned(), body.to_vec());
+        }
+    }
+
+    impl HttpClient for FakeHttp {
+        fn fetch(&self, url: &str, out: &mut dyn Write)
+        -> anyhow::Result<()> {
+            self.fetched.borrow_mut().push(url.to_owned());
+            match self.responses.get(url) {
+                Some(x) => Ok(out.write_all(x)?),
+                None => Err(anyhow!("404 Not Found")),
+            }
+        }
+    }
+
+    // (every test uses its own podcast IDs, since the episode list is shared)
+
+    fn make_podcast(id: u64) -> Podcast {
+        Podcast {
+            id, url: format!("http://example.com/{}/feed.xml", id),
+            title: format!("Podcast {}", id), playlist_id: None,
+            keep_episodes: None, delete_played: false, last_refreshed: None,
+        }
+    }
+
+    fn item(guid: &str, title: &str) -> feed::FeedEpisode {
+        feed::FeedEpisode {
+            guid: guid.to_owned(),
+            url: format!("http://example.com/{}.mp3", guid),
+            title: Some(title.to_owned()),
+            ..Default::default()
+        }
+    }
+
+    fn guids(episodes: &[Episode]) -> Vec<&str> {
+        episodes.iter().map(|x| x.guid.as_str()).collect()
+    }
+
+    #[test]
+    fn merge_order() {
+        db::open_test_database();
+        let podcast = make_podcast(101);
+        let mut feed = feed::Feed {
+            episodes: vec![item("c", "C"), item("b", "B"), item("a", "A")],
+            ..Default::default()
+        };
+        merge_episodes(&podcast, &feed);
+        let episodes = get_episodes(podcast.id);
+        assert_eq!(guids(&episodes), ["c", "b", "a"]);
+        assert_eq!(episodes.iter().map(|x| x.position).collect::<Vec<_>>(),
+                   [0, 1, 2]);
+        assert_eq!(episodes[0].get_title(), "C");
+        assert_eq!(episodes[0].metadata.get("album").map(String::as_str),
+                   Some("Podcast 101"));
+        // A new episode, a retitled one, a repeated guid, and one that
+        // dropped off the feed.
+        let mut repeat = item("d", "D again");
+        repeat.url = "http://example.com/elsewhere.mp3".to_owned();
+        feed.episodes = vec![item("d", "D"), item("c", "C, revised"),
+                             repeat, item("a", "A")];
+        merge_episodes(&podcast, &feed);
+        let episodes = get_episodes(podcast.id);
+        assert_eq!(guids(&episodes), ["d", "c", "a", "b"]);
+        assert_eq!(episodes.iter().map(|x| x.position).collect::<Vec<_>>(),
+                   [0, 1, 3, 4]);
+        assert_eq!(episodes[0].get_title(), "D");
+        assert_eq!(episodes[0].url, "http://example.com/d.mp3");
+        assert_eq!(episodes[1].get_title(), "C, revised");
+    }
+
+    #[test]
+    fn plan() {
+        let mut podcast = make_podcast(102);
+        for n in 0 .. 5 {
+            add_episode_from_db(Episode {
+                podcast_id: podcast.id, guid: format!("e{}", n), position: n,
+                url: format!("http://example.com/e{}.mp3", n),
+                metadata: BTreeMap::new(),
+                path: if n % 2 == 1 || n == 4 {
+                    Some(PathBuf::from(format!("e{}.mp3", n)))
+                } else { None },
+                file_id: None,
+                played: n == 1,
+            });
+        }
+        let plan = |podcast: &Podcast| {
+            let (wanted, unwanted) = plan_downloads(podcast);
+            (guids(&wanted).into_iter().map(str::to_owned)
+             .collect::<Vec<_>>(),
+             guids(&unwanted).into_iter().map(str::to_owned)
+             .collect::<Vec<_>>())
+        };
+        assert_eq!(plan(&podcast), (vec!["e0".to_owned(), "e2".to_owned()],
+                                    vec![]));
+        podcast.keep_episodes = Some(2);
+        assert_eq!(plan(&podcast), (vec!["e0".to_owned()],
+                                    vec!["e3".to_owned(), "e4".to_owned()]));
+        podcast.delete_played = true;
+        assert_eq!(plan(&podcast), (vec!["e0".to_owned(), "e2".to_owned()],
+                                    vec!["e1".to_owned(), "e3".to_owned(),
+                                         "e4".to_owned()]));
+        podcast.keep_episodes = Some(0);
+        assert_eq!(plan(&podcast), (vec![],
+                                    vec!["e1".to_owned(), "e3".to_owned(),
+                                         "e4".to_owned()]));
+    }
+
+    #[test]
+    fn extensions() {
+        assert_eq!(guess_extension("http://example.com/a.ogg"), "ogg");
+        assert_eq!(guess_extension("http://example.com/a.M4A?x=y.z#w.v"),
+                   "M4A");
+        assert_eq!(guess_extension("http://example.com/a.opus#t=10"), "opus");
+        assert_eq!(guess_extension("http://example.com/a"), "mp3");
+        assert_eq!(guess_extension("http://example.com/a."), "mp3");
+        assert_eq!(guess_extension("http://example.com/a.mp3/"), "mp3");
+        assert_eq!(guess_extension("http://example.com/dir.d/a"), "mp3");
+        assert_eq!(guess_extension("http://example.com/a.toolong"), "mp3");
+        assert_eq!(guess_extension("http://example.com/a.m-4"), "mp3");
+    }
+
+    #[test]
+    fn file_names() {
+        assert_eq!(sanitize_file_name("Episode 1: Begin?"),
+                   "Episode 1_ Begin_");
+        assert_eq!(sanitize_file_name("../../etc/passwd"),
+                   "______etc_passwd");
+        assert_eq!(sanitize_file_name("  Café (Live), Part-2_b  "),
+                   "Café (Live), Part-2_b");
+        assert_eq!(sanitize_file_name(""), "Episode");
+        assert_eq!(sanitize_file_name("   "), "Episode");
+        assert_eq!(sanitize_file_name(&"x".repeat(100)), "x".repeat(80));
+    }
+
+    #[test]
+    fn refresh() {
+        db::open_test_database();
+        let dir = std::env::temp_dir()
+            .join(format!("tsong-podcast-test-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        prefs::set_podcast_directory(Some(dir.to_str().unwrap()));
+        add_podcast_from_db(Podcast { keep_episodes: Some(2),
+                                      ..make_podcast(103) });
+        let podcast = make_podcast(103);
+        let feed_url = podcast.url.clone();
+        let mut http = FakeHttp::default();
+        http.serve(&feed_url, br#"<rss><channel><title>The Show</title>
+            <item><title>Three: The End</title><guid>3</guid>
+              <enclosure url="http://example.com/3.ogg"/></item>
+            <item><title>Two</title><guid>2</guid>
+              <enclosure url="http://example.com/2"/></item>
+            <item><title>One</title><guid>1</guid>
+              <enclosure url="http://example.com/1.mp3"/></item>
+            </channel></rss>"#);
+        http.serve("http://example.com/3.ogg", b"three");
+        http.serve("http://example.com/2", b"two");
+        http.serve("http://example.com/1.mp3", b"one");
+        refresh_podcast(podcast.id, &http).unwrap();
+        assert_eq!(*http.fetched.borrow(),
+                   [feed_url.as_str(), "http://example.com/3.ogg",
+                    "http://example.com/2"]);
+        let refreshed = get_podcasts().into_iter()
+            .find(|x| x.id == podcast.id).unwrap();
+        assert_eq!(refreshed.title, "The Show");
+        assert!(refreshed.last_refreshed.is_some());
+        let podcast_dir = dir.join("103");
+        let episodes = get_episodes(podcast.id);
+        assert_eq!(guids(&episodes), ["3", "2", "1"]);
+        assert_eq!(episodes[0].metadata.get("album").map(String::as_str),
+                   Some("The Show"));
+        assert_eq!(episodes[0].path.as_ref(),
+                   Some(&podcast_dir.join("Three_ The End.ogg")));
+        assert_eq!(episodes[0].file_id,
+                   Some(FileID::from_file(&b"three"[..]).unwrap()));
+        assert_eq!(episodes[1].path.as_ref(),
+                   Some(&podcast_dir.join("Two.mp3")));
+        assert_eq!(fs::read(podcast_dir.join("Two.mp3")).unwrap(), b"two");
+        assert!(!episodes[2].is_downloaded());
+        // A new episode that won't download, and a played one that should be
+        // deleted.
+        set_played(podcast.id, "3", true);
+        let mut podcast = refreshed;
+        podcast.delete_played = true;
+        update_podcast(&podcast);
+        http.serve(&feed_url, br#"<rss><channel><title>The Show</title>
+            <item><title>Four</title><guid>4</guid>
+              <enclosure url="http://example.com/4.mp3"/></item>
+            <item><title>Three: The End</title><guid>3</guid>
+              <enclosure url="http://example.com/3.ogg"/></item>
+            <item><title>Two</title><guid>2</guid>
+              <enclosure url="http://example.com/2"/></item>
+            </channel></rss>"#);
+        let err = refresh_podcast(podcast.id, &http).unwrap_err();
+        assert!(err.to_string().contains("Four: 404 Not Found"), "{}", err);
+        let episodes = get_episodes(podcast.id);
+        assert_eq!(guids(&episodes), ["4", "3", "2", "1"]);
+        assert!(!episodes[0].is_downloaded());
+        assert!(!episodes[1].is_downloaded());
+        assert!(episodes[1].played);
+        assert!(episodes[2].is_downloaded());
+        assert!(!episodes[3].is_downloaded());
+        // the failed download and the played episode leave nothing behind
+        let mut left: Vec<_> = fs::read_dir(&podcast_dir).unwrap()
+            .map(|x| x.unwrap().file_name()).collect();
+        left.sort();
+        assert_eq!(left, ["Two.mp3"]);
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -5,12 +5,15 @@
 use log::trace;
 use lazy_static::lazy_static;
 use crate::config;
+use crate::dsp::{self, ChannelLayout, EqPreset};
 use toml::Value;
 use serde::Deserialize;
 
 use std::{
+    collections::BTreeMap,
     convert::TryInto,
     io::{Read, Write},
+    path::PathBuf,
     sync::RwLock,
 };
 
@@ -19,6 +22,77 @@ use portaudio::{
     PortAudio,
 };
 
+/// Glob patterns that control which files under a particular music location
+/// get scanned. Patterns are matched against the path relative to the music
+/// location. `*` matches across directory separators, so `*.mid` matches MIDI
+/// files at any depth.
+#[derive(Clone,Debug,Default,Deserialize,PartialEq)]
+pub struct ScanPatterns {
+    /// If non-empty, only files matching at least one of these patterns will
+    /// be scanned.
+    #[serde(default)]
+    pub include: Vec<String>,
+    /// Files (and directories) matching any of these patterns will be skipped.
+    #[serde(default)]
+    pub exclude: Vec<String>,
+}
+
+/// Where audio goes.
+#[derive(Clone,Copy,Debug,Deserialize,PartialEq,Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum AudioBackend {
+    /// To a sound card, through PortAudio.
+    PortAudio,
+    /// Nowhere. Audio is consumed in real (or accelerated) time, and then
+    /// discarded.
+    Null,
+    /// To a WAV file, paced the same way as `Null`.
+    File,
+    /// To a PulseAudio (or PipeWire) server, natively. Only available if we
+    /// were built with the `pulse` feature.
+    Pulse,
+}
+
+impl Default for AudioBackend {
+    fn default() -> AudioBackend { AudioBackend::PortAudio }
+}
+
+impl AudioBackend {
+    /// The name of this backend, as it appears in the preferences file.
+    pub fn get_name(&self) -> &'static str {
+        match self {
+            AudioBackend::PortAudio => "portaudio",
+            AudioBackend::Null => "null",
+            AudioBackend::File => "file",
+            AudioBackend::Pulse => "pulse",
+        }
+    }
+}
+
+/// What we encode the HTTP broadcast as.
+#[derive(Clone,Copy,Debug,Deserialize,PartialEq,Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum BroadcastFormat {
+    /// Opus, in an Ogg container.
+    Opus,
+    /// MP3. Only available if FFMPEG was built with LAME.
+    Mp3,
+}
+
+impl Default for BroadcastFormat {
+    fn default() -> BroadcastFormat { BroadcastFormat::Opus }
+}
+
+impl BroadcastFormat {
+    /// The name of this format, as it appears in the preferences file.
+    pub fn get_name(&self) -> &'static str {
+        match self {
+            BroadcastFormat::Opus => "opus",
+            BroadcastFormat::Mp3 => "mp3",
+        }
+    }
+}
+
 #[derive(Debug,Deserialize)]
 pub struct Preferences {
     #[serde(default = "get_standard_volume")]
@@ -27,12 +101,40 @@ pub struct Preferences {
     show_decibels_on_volume_slider: bool,
     #[serde(default)]
     music_paths: Vec<String>,
+    #[serde(default = "get_standard_follow_symlinks")]
+    follow_symlinks: bool,
+    #[serde(default = "get_standard_scan_exclude")]
+    scan_exclude: Vec<String>,
+    #[serde(default)]
+    play_video_files: bool,
     #[serde(default = "get_standard_desired_latency")]
     desired_latency: f64,
     #[serde(default = "get_standard_decode_ahead")]
     decode_ahead: f64,
     #[serde(default)]
     resample_audio: bool,
+    #[serde(default)]
+    audio_backend: AudioBackend,
+    #[serde(default = "get_standard_null_output_speed")]
+    null_output_speed: f64,
+    #[serde(default)]
+    output_file: Option<String>,
+    #[serde(default)]
+    http_command: Option<String>,
+    #[serde(default)]
+    podcast_directory: Option<String>,
+    #[serde(default)]
+    broadcast: bool,
+    #[serde(default = "get_standard_broadcast_port")]
+    broadcast_port: u16,
+    #[serde(default)]
+    broadcast_format: BroadcastFormat,
+    #[serde(default)]
+    broadcast_all_interfaces: bool,
+    #[serde(default = "get_standard_broadcast_max_listeners")]
+    broadcast_max_listeners: u32,
+    #[serde(default = "get_standard_fall_back_to_default_device")]
+    fall_back_to_default_device: bool,
     // these two must both match in order for the choice to be considered valid
     #[serde(default)]
     audio_api_index: Option<u32>,
@@ -43,6 +145,26 @@ pub struct Preferences {
     audio_dev_index: Option<u32>,
     #[serde(default)]
     audio_dev_name: Option<String>,
+    #[serde(default)]
+    channel_layout: ChannelLayout,
+    #[serde(default)]
+    balance: f64,
+    #[serde(default)]
+    swap_channels: bool,
+    #[serde(default)]
+    eq_enabled: bool,
+    #[serde(default = "get_standard_eq_preset")]
+    eq_preset: String,
+    #[serde(default = "get_standard_transport_fades")]
+    transport_fades: bool,
+    #[serde(default = "get_standard_transport_fade_length")]
+    transport_fade_length: f64,
+    // must come last when writing, since these are TOML tables
+    #[serde(default)]
+    scan_patterns: BTreeMap<String, ScanPatterns>,
+    /// Presets made by the user. (The built-in ones are in `dsp`.)
+    #[serde(default)]
+    eq_presets: BTreeMap<String, EqPreset>,
 }
 
 const PREFS_FILE_NAME: &str = "Tsong.toml";
@@ -56,6 +178,22 @@ pub const MAX_VOLUME: i32 = 200;
 
 fn get_standard_volume() -> i32 { STANDARD_VOLUME }
 
+fn get_standard_follow_symlinks() -> bool { true }
+
+fn get_standard_fall_back_to_default_device() -> bool { true }
+
+fn get_standard_eq_preset() -> String { dsp::FLAT_PRESET.to_owned() }
+
+/// Files that are skipped under every music location, unless the user says
+/// otherwise. (These used to be hard-coded into the scanner.)
+pub const STANDARD_SCAN_EXCLUDE: &[&str] = &[
+    "*.xml", "*.itl", "*.itdb", "*.m3u", "*.itc",
+];
+
+fn get_standard_scan_exclude() -> Vec<String> {
+    STANDARD_SCAN_EXCLUDE.iter().map(|x| x.to_string()).collect()
+}
+
 /// The lowest permitted target latency.
 pub const MIN_DESIRED_LATENCY: f64 = 0.01;
 /// The standard target latency.
@@ -74,17 +212,86 @@ pub const MAX_DECODE_AHEAD: f64 = 35.0;
 
 fn get_standard_decode_ahead() -> f64 { STANDARD_DECODE_AHEAD }
 
+/// The lowest permitted speed for the null and file audio backends.
+pub const MIN_NULL_OUTPUT_SPEED: f64 = 1.0;
+/// The standard speed for the null and file audio backends. (Real time.)
+pub const STANDARD_NULL_OUTPUT_SPEED: f64 = 1.0;
+/// The highest permitted speed for the null and file audio backends.
+pub const MAX_NULL_OUTPUT_SPEED: f64 = 100.0;
+
+fn get_standard_null_output_speed() -> f64 { STANDARD_NULL_OUTPUT_SPEED }
+
+/// The standard TCP port for the HTTP broadcast.
+pub const STANDARD_BROADCAST_PORT: u16 = 8000;
+
+fn get_standard_broadcast_port() -> u16 { STANDARD_BROADCAST_PORT }
+
+/// The fewest listeners the HTTP broadcast can be limited to.
+pub const MIN_BROADCAST_MAX_LISTENERS: u32 = 1;
+/// The standard limit on how many listeners the HTTP broadcast takes at once.
+pub const STANDARD_BROADCAST_MAX_LISTENERS: u32 = 8;
+/// The most listeners the HTTP broadcast can be allowed to take at once.
+pub const MAX_BROADCAST_MAX_LISTENERS: u32 = 64;
+
+fn get_standard_broadcast_max_listeners() -> u32 {
+    STANDARD_BROADCAST_MAX_LISTENERS
+}
+
+fn get_standard_transport_fades() -> bool { true }
+
+/// The shortest permitted transport fade, in seconds.
+pub const MIN_TRANSPORT_FADE_LENGTH: f64 = 0.05;
+/// The standard length of a transport fade, in seconds.
+pub const STANDARD_TRANSPORT_FADE_LENGTH: f64 = 0.15;
+/// The longest permitted transport fade, in seconds.
+pub const MAX_TRANSPORT_FADE_LENGTH: f64 = 0.5;
+
+fn get_standard_transport_fade_length() -> f64 {
+    STANDARD_TRANSPORT_FADE_LENGTH
+}
+
+/// The leftmost permitted balance. (Right channel silent.)
+pub const MIN_BALANCE: f64 = -1.0;
+/// The standard balance. (Centered.)
+pub const STANDARD_BALANCE: f64 = 0.0;
+/// The rightmost permitted balance. (Left channel silent.)
+pub const MAX_BALANCE: f64 = 1.0;
+
 impl Default for Preferences {
     fn default() -> Self {
         Preferences {
             volume: STANDARD_VOLUME,
             show_decibels_on_volume_slider: false,
             music_paths: Vec::new(),
+            follow_symlinks: get_standard_follow_symlinks(),
+            scan_exclude: get_standard_scan_exclude(),
+            play_video_files: false,
             desired_latency: STANDARD_DESIRED_LATENCY,
             decode_ahead: STANDARD_DECODE_AHEAD,
             resample_audio: false,
+            audio_backend: AudioBackend::PortAudio,
+            null_output_speed: STANDARD_NULL_OUTPUT_SPEED,
+            output_file: None,
+            http_command: None,
+            podcast_directory: None,
+            broadcast: false,
+            broadcast_port: STANDARD_BROADCAST_PORT,
+            broadcast_format: BroadcastFormat::default(),
+            broadcast_all_interfaces: false,
+            broadcast_max_listeners: STANDARD_BROADCAST_MAX_LISTENERS,
+            fall_back_to_default_device:
+                get_standard_fall_back_to_default_device(),
             audio_api_index: None, audio_api_name: None,
             audio_dev_index: None, audio_dev_name: None,
+            channel_layout: ChannelLayout::default(),
+            balance: STANDARD_BALANCE,
+            swap_channels: false,
+            eq_enabled: false,
+            eq_preset: get_standard_eq_preset(),
+            transport_fades: get_standard_transport_fades(),
+            transport_fade_length: STANDARD_TRANSPORT_FADE_LENGTH,
+            scan_patterns: BTreeMap::new(),
+            eq_presets: BTreeMap::new(),
         }
     }
 }
@@ -114,6 +321,11 @@ pub fn read() -> anyhow::Result<()> {
         .min(MAX_DESIRED_LATENCY);
     prefs.decode_ahead = prefs.decode_ahead.max(MIN_DECODE_AHEAD)
         .min(MAX_DECODE_AHEAD);
+    prefs.null_output_speed = prefs.null_output_speed
+        .max(MIN_NULL_OUTPUT_SPEED).min(MAX_NULL_OUTPUT_SPEED);
+    prefs.balance = prefs.balance.max(MIN_BALANCE).min(MAX_BALANCE);
+    prefs.broadcast_max_listeners = prefs.broadcast_max_listeners
+        .max(MIN_BROADCAST_MAX_LISTENERS).min(MAX_BROADCAST_MAX_LISTENERS);
     Ok(())
 }
 
@@ -134,7 +346,45 @@ pub fn write() -> anyhow::Result<()> {
         writeln!(f, "  {},", Value::String(music_path.to_string()))?;
     }
     writeln!(f, "]")?;
+    writeln!(f, "follow_symlinks = {}", prefs.follow_symlinks)?;
+    write_string_array(&mut *f, "scan_exclude", &prefs.scan_exclude)?;
+    writeln!(f, "play_video_files = {}", prefs.play_video_files)?;
     writeln!(f, "resample_audio = {}", prefs.resample_audio)?;
+    writeln!(f, "fall_back_to_default_device = {}",
+             prefs.fall_back_to_default_device)?;
+    writeln!(f, "audio_backend = {}",
+             Value::String(prefs.audio_backend.get_name().to_owned()))?;
+    writeln!(f, "null_output_speed = {}",
+             Value::Float(prefs.null_output_speed))?;
+    if let Some(output_file) = prefs.output_file.as_ref() {
+        writeln!(f, "output_file = {}",
+                 Value::String(output_file.to_string()))?;
+    }
+    if let Some(http_command) = prefs.http_command.as_ref() {
+        writeln!(f, "http_command = {}",
+                 Value::String(http_command.to_string()))?;
+    }
+    if let Some(podcast_directory) = prefs.podcast_directory.as_ref() {
+        writeln!(f, "podcast_directory = {}",
+                 Value::String(podcast_directory.to_string()))?;
+    }
+    writeln!(f, "broadcast = {}", prefs.broadcast)?;
+    writeln!(f, "broadcast_port = {}", prefs.broadcast_port)?;
+    writeln!(f, "broadcast_format = {}",
+             Value::String(prefs.broadcast_format.get_name().to_owned()))?;
+    writeln!(f, "broadcast_all_interfaces = {}",
+             prefs.broadcast_all_interfaces)?;
+    writeln!(f, "broadcast_max_listeners = {}",
+             prefs.broadcast_max_listeners)?;
+    writeln!(f, "channel_layout = {}",
+             Value::String(prefs.channel_layout.get_name().to_owned()))?;
+    writeln!(f, "balance = {}", Value::Float(prefs.balance))?;
+    writeln!(f, "swap_channels = {}", prefs.swap_channels)?;
+    writeln!(f, "eq_enabled = {}", prefs.eq_enabled)?;
+    writeln!(f, "eq_preset = {}", Value::String(prefs.eq_preset.clone()))?;
+    writeln!(f, "transport_fades = {}", prefs.transport_fades)?;
+    writeln!(f, "transport_fade_length = {}",
+             Value::Float(prefs.transport_fade_length))?;
     match (prefs.audio_api_index, prefs.audio_api_name.as_ref()) {
         (Some(index), Some(name)) => {
             write!(f, "\n\
@@ -157,9 +407,42 @@ pub fn write() -> anyhow::Result<()> {
         }
         _ => (),
     }
+    for (music_path, patterns) in prefs.scan_patterns.iter() {
+        if patterns.include.is_empty() && patterns.exclude.is_empty() {
+            continue
+        }
+        writeln!(f, "\n[scan_patterns.{}]",
+                 Value::String(music_path.to_string()))?;
+        write_string_array(&mut *f, "include", &patterns.include)?;
+        write_string_array(&mut *f, "exclude", &patterns.exclude)?;
+    }
+    for (name, preset) in prefs.eq_presets.iter() {
+        writeln!(f, "\n[eq_presets.{}]", Value::String(name.to_string()))?;
+        writeln!(f, "preamp = {}", Value::Float(preset.preamp))?;
+        writeln!(f, "graphic = [{}]", preset.graphic.iter()
+                 .map(|&x| Value::Float(x).to_string())
+                 .collect::<Vec<_>>().join(", "))?;
+        writeln!(f, "parametric = [")?;
+        for band in preset.parametric.iter() {
+            writeln!(f, "  {{kind = {}, frequency = {}, gain = {}, q = {}}},",
+                     Value::String(band.kind.get_name().to_owned()),
+                     Value::Float(band.frequency), Value::Float(band.gain),
+                     Value::Float(band.q))?;
+        }
+        writeln!(f, "]")?;
+    }
     f.finish()
 }
 
+fn write_string_array<W: Write>(f: &mut W, key: &str, values: &[String])
+    -> std::io::Result<()> {
+    writeln!(f, "{} = [", key)?;
+    for value in values.iter() {
+        writeln!(f, "  {},", Value::String(value.to_string()))?;
+    }
+    writeln!(f, "]")
+}
+
 /// Returns the current setting of the volume slider, bound by `MIN_VOLUME`
 /// and `MAX_VOLUME`.
 pub fn get_volume() -> i32 {
@@ -209,6 +492,205 @@ pub fn set_resample_audio(nu: bool) -> bool {
     } else { false }
 }
 
+/// Returns true if, when the chosen audio device goes away during playback,
+/// we should carry on with the default device.
+pub fn get_fall_back_to_default_device() -> bool {
+    PREFERENCES.read().unwrap().fall_back_to_default_device
+}
+
+/// Alters whether we should fall back to the default audio device.
+///
+/// Returns true if playback should be restarted as a result of this change.
+/// (Currently always returns false.)
+pub fn set_fall_back_to_default_device(nu: bool) -> bool {
+    let mut prefs = PREFERENCES.write().unwrap();
+    prefs.fall_back_to_default_device = nu;
+    false
+}
+
+/// Returns the audio backend the user wants to use.
+pub fn get_audio_backend() -> AudioBackend {
+    PREFERENCES.read().unwrap().audio_backend
+}
+
+/// Alters which audio backend the user wants to use.
+///
+/// Returns true if playback should be restarted as a result of this change.
+pub fn set_audio_backend(nu: AudioBackend) -> bool {
+    let mut prefs = PREFERENCES.write().unwrap();
+    if prefs.audio_backend != nu {
+        prefs.audio_backend = nu;
+        true
+    } else { false }
+}
+
+/// Returns how many times faster than real time the null and file audio
+/// backends should consume audio.
+pub fn get_null_output_speed() -> f64 {
+    PREFERENCES.read().unwrap().null_output_speed
+}
+
+/// Alters the speed of the null and file audio backends, clamping it within
+/// `MIN_NULL_OUTPUT_SPEED` and `MAX_NULL_OUTPUT_SPEED`.
+///
+/// Returns true if playback should be restarted as a result of this change.
+pub fn set_null_output_speed(null_output_speed: f64) -> bool {
+    let mut prefs = PREFERENCES.write().unwrap();
+    let nu = null_output_speed.max(MIN_NULL_OUTPUT_SPEED)
+        .min(MAX_NULL_OUTPUT_SPEED);
+    if prefs.null_output_speed != nu {
+        prefs.null_output_speed = nu;
+        true
+    } else { false }
+}
+
+/// Returns the path that the file audio backend should write to, if one has
+/// been set.
+pub fn get_output_file() -> Option<PathBuf> {
+    PREFERENCES.read().unwrap().output_file.as_ref().map(PathBuf::from)
+}
+
+/// Alters the path that the file audio backend should write to.
+///
+/// Returns true if playback should be restarted as a result of this change.
+pub fn set_output_file(nu: Option<&str>) -> bool {
+    let mut prefs = PREFERENCES.write().unwrap();
+    let nu = nu.map(str::to_owned);
+    if prefs.output_file != nu {
+        prefs.output_file = nu;
+        true
+    } else { false }
+}
+
+/// Returns the command used to download things over HTTP (podcast feeds and
+/// episodes), if one has been set. The URL is added as the last argument, and
+/// the command should write what it downloads to standard output. If none
+/// has been set, FFMPEG's own HTTP client is used.
+pub fn get_http_command() -> Option<String> {
+    PREFERENCES.read().unwrap().http_command.clone()
+}
+
+/// Alters the command used to download things over HTTP.
+pub fn set_http_command(nu: Option<&str>) -> bool {
+    let mut prefs = PREFERENCES.write().unwrap();
+    let nu = nu.map(str::trim).filter(|x| !x.is_empty()).map(str::to_owned);
+    if prefs.http_command != nu {
+        prefs.http_command = nu;
+        true
+    } else { false }
+}
+
+/// Returns the directory that podcast episodes are downloaded into. Unless
+/// the user says otherwise, this is the `Podcasts` directory next to the
+/// database.
+pub fn get_podcast_directory() -> PathBuf {
+    match PREFERENCES.read().unwrap().podcast_directory.as_ref() {
+        Some(x) => PathBuf::from(x),
+        None => config::get_config_file_path("Podcasts"),
+    }
+}
+
+/// Alters the directory that podcast episodes are downloaded into.
+pub fn set_podcast_directory(nu: Option<&str>) -> bool {
+    let mut prefs = PREFERENCES.write().unwrap();
+    let nu = nu.map(str::to_owned);
+    if prefs.podcast_directory != nu {
+        prefs.podcast_directory = nu;
+        true
+    } else { false }
+}
+
+/// Returns true if whatever we're playing should also be broadcast over HTTP.
+pub fn get_broadcast() -> bool {
+    PREFERENCES.read().unwrap().broadcast
+}
+
+/// Alters whether we broadcast over HTTP.
+///
+/// Returns true if the broadcast should be restarted as a result of this
+/// change.
+pub fn set_broadcast(nu: bool) -> bool {
+    let mut prefs = PREFERENCES.write().unwrap();
+    if prefs.broadcast != nu {
+        prefs.broadcast = nu;
+        true
+    } else { false }
+}
+
+/// Returns the TCP port that the HTTP broadcast listens on.
+pub fn get_broadcast_port() -> u16 {
+    PREFERENCES.read().unwrap().broadcast_port
+}
+
+/// Alters the TCP port that the HTTP broadcast listens on.
+///
+/// Returns true if the broadcast should be restarted as a result of this
+/// change.
+pub fn set_broadcast_port(nu: u16) -> bool {
+    let mut prefs = PREFERENCES.write().unwrap();
+    if prefs.broadcast_port != nu {
+        prefs.broadcast_port = nu;
+        true
+    } else { false }
+}
+
+/// Returns what the HTTP broadcast is encoded as.
+pub fn get_broadcast_format() -> BroadcastFormat {
+    PREFERENCES.read().unwrap().broadcast_format
+}
+
+/// Alters what the HTTP broadcast is encoded as.
+///
+/// Returns true if the broadcast should be restarted as a result of this
+/// change.
+pub fn set_broadcast_format(nu: BroadcastFormat) -> bool {
+    let mut prefs = PREFERENCES.write().unwrap();
+    if prefs.broadcast_format != nu {
+        prefs.broadcast_format = nu;
+        true
+    } else { false }
+}
+
+/// Returns true if the HTTP broadcast should listen on every network
+/// interface, so that other computers can listen in. If false, it only
+/// listens on the loopback interface.
+pub fn get_broadcast_all_interfaces() -> bool {
+    PREFERENCES.read().unwrap().broadcast_all_interfaces
+}
+
+/// Alters whether the HTTP broadcast listens on every network interface.
+///
+/// Returns true if the broadcast should be restarted as a result of this
+/// change.
+pub fn set_broadcast_all_interfaces(nu: bool) -> bool {
+    let mut prefs = PREFERENCES.write().unwrap();
+    if prefs.broadcast_all_interfaces != nu {
+        prefs.broadcast_all_interfaces = nu;
+        true
+    } else { false }
+}
+
+/// Returns how many listeners the HTTP broadcast takes at once, bound by
+/// `MIN_BROADCAST_MAX_LISTENERS` and `MAX_BROADCAST_MAX_LISTENERS`.
+pub fn get_broadcast_max_listeners() -> u32 {
+    PREFERENCES.read().unwrap().broadcast_max_listeners
+}
+
+/// Alters how many listeners the HTTP broadcast takes at once, clamping it
+/// within `MIN_BROADCAST_MAX_LISTENERS` and `MAX_BROADCAST_MAX_LISTENERS`.
+///
+/// Returns true if the broadcast should be restarted as a result of this
+/// change.
+pub fn set_broadcast_max_listeners(nu: u32) -> bool {
+    let mut prefs = PREFERENCES.write().unwrap();
+    let nu = nu.max(MIN_BROADCAST_MAX_LISTENERS)
+        .min(MAX_BROADCAST_MAX_LISTENERS);
+    if prefs.broadcast_max_listeners != nu {
+        prefs.broadcast_max_listeners = nu;
+        true
+    } else { false }
+}
+
 /// Returns the current target audio latency, in seconds.
 pub fn get_desired_latency() -> f64 {
     PREFERENCES.read().unwrap().desired_latency
@@ -256,6 +738,214 @@ pub fn set_music_paths(music_paths: Vec<String>) {
     PREFERENCES.write().unwrap().music_paths = music_paths
 }
 
+/// Returns true if the scanner should descend into symlinked directories and
+/// scan symlinked files.
+pub fn get_follow_symlinks() -> bool {
+    PREFERENCES.read().unwrap().follow_symlinks
+}
+
+/// Alters whether the scanner should follow symlinks.
+///
+/// Returns true if playback should be restarted as a result of this change.
+/// (Currently always returns false.)
+pub fn set_follow_symlinks(nu: bool) -> bool {
+    PREFERENCES.write().unwrap().follow_symlinks = nu;
+    false
+}
+
+/// Returns true if files with both audio and video (music videos, concert
+/// recordings...) should be treated as songs.
+pub fn get_play_video_files() -> bool {
+    PREFERENCES.read().unwrap().play_video_files
+}
+
+/// Alters whether files with video should be treated as songs. Files that
+/// have already been scanned are not affected.
+///
+/// Returns true if playback should be restarted as a result of this change.
+/// (Currently always returns false.)
+pub fn set_play_video_files(nu: bool) -> bool {
+    PREFERENCES.write().unwrap().play_video_files = nu;
+    false
+}
+
+/// Returns a copy of the list of patterns that are excluded from scanning
+/// under every music path.
+pub fn get_scan_exclude() -> Vec<String> {
+    PREFERENCES.read().unwrap().scan_exclude.clone()
+}
+
+/// Replaces the list of patterns that are excluded from scanning under every
+/// music path.
+pub fn set_scan_exclude(scan_exclude: Vec<String>) {
+    PREFERENCES.write().unwrap().scan_exclude = scan_exclude
+}
+
+/// Returns the include/exclude patterns for the given music path. (Empty if
+/// none have been set.)
+pub fn get_scan_patterns(music_path: &str) -> ScanPatterns {
+    PREFERENCES.read().unwrap().scan_patterns.get(music_path).cloned()
+        .unwrap_or_default()
+}
+
+/// Replaces the include/exclude patterns for the given music path.
+pub fn set_scan_patterns(music_path: &str, patterns: ScanPatterns) {
+    let mut prefs = PREFERENCES.write().unwrap();
+    if patterns == ScanPatterns::default() {
+        prefs.scan_patterns.remove(music_path);
+    }
+    else {
+        prefs.scan_patterns.insert(music_path.to_owned(), patterns);
+    }
+}
+
+/// Returns the channel layout that audio should be remixed to.
+pub fn get_channel_layout() -> ChannelLayout {
+    PREFERENCES.read().unwrap().channel_layout
+}
+
+/// Alters the channel layout that audio should be remixed to.
+///
+/// Returns true if playback should be restarted as a result of this change.
+pub fn set_channel_layout(nu: ChannelLayout) -> bool {
+    let mut prefs = PREFERENCES.write().unwrap();
+    if prefs.channel_layout != nu {
+        prefs.channel_layout = nu;
+        true
+    } else { false }
+}
+
+/// Returns true if playback should fade out (and back in) when the user
+/// pauses, stops, resumes, or changes songs.
+pub fn get_transport_fades() -> bool {
+    PREFERENCES.read().unwrap().transport_fades
+}
+
+/// Alters whether playback fades when the user pauses, stops, resumes, or
+/// changes songs.
+///
+/// Returns true if playback should be restarted as a result of this change.
+/// (Currently always returns false.)
+pub fn set_transport_fades(nu: bool) -> bool {
+    PREFERENCES.write().unwrap().transport_fades = nu;
+    false
+}
+
+/// Returns how long the fades in `get_transport_fades` take, in seconds,
+/// bound by `MIN_TRANSPORT_FADE_LENGTH` and `MAX_TRANSPORT_FADE_LENGTH`.
+pub fn get_transport_fade_length() -> f64 {
+    PREFERENCES.read().unwrap().transport_fade_length
+        .max(MIN_TRANSPORT_FADE_LENGTH).min(MAX_TRANSPORT_FADE_LENGTH)
+}
+
+/// Alters how long the fades in `get_transport_fades` take, clamping it
+/// within `MIN_TRANSPORT_FADE_LENGTH` and `MAX_TRANSPORT_FADE_LENGTH`.
+///
+/// Returns true if playback should be restarted as a result of this change.
+/// (Currently always returns false.)
+pub fn set_transport_fade_length(length: f64) -> bool {
+    let mut prefs = PREFERENCES.write().unwrap();
+    prefs.transport_fade_length = length.max(MIN_TRANSPORT_FADE_LENGTH)
+        .min(MAX_TRANSPORT_FADE_LENGTH);
+    false
+}
+
+/// Returns the left/right balance, from `MIN_BALANCE` (all the way left) to
+/// `MAX_BALANCE` (all the way right).
+pub fn get_balance() -> f64 {
+    PREFERENCES.read().unwrap().balance
+}
+
+/// Alters the left/right balance, clamping it within `MIN_BALANCE` and
+/// `MAX_BALANCE`.
+///
+/// Returns true if playback should be restarted as a result of this change.
+pub fn set_balance(balance: f64) -> bool {
+    let mut prefs = PREFERENCES.write().unwrap();
+    let nu = balance.max(MIN_BALANCE).min(MAX_BALANCE);
+    if prefs.balance != nu {
+        prefs.balance = nu;
+        true
+    } else { false }
+}
+
+/// Returns true if the left and right channels should be swapped.
+pub fn get_swap_channels() -> bool {
+    PREFERENCES.read().unwrap().swap_channels
+}
+
+/// Alters whether the left and right channels should be swapped.
+///
+/// Returns true if playback should be restarted as a result of this change.
+pub fn set_swap_channels(nu: bool) -> bool {
+    let mut prefs = PREFERENCES.write().unwrap();
+    if prefs.swap_channels != nu {
+        prefs.swap_channels = nu;
+        true
+    } else { false }
+}
+
+/// Returns true if the user wants the equalizer applied to every song. (Songs
+/// with an `eq_preset` tag get their preset either way.)
+pub fn get_eq_enabled() -> bool {
+    PREFERENCES.read().unwrap().eq_enabled
+}
+
+/// Alters whether the equalizer is applied to every song.
+///
+/// Returns true if playback should be restarted as a result of this change.
+pub fn set_eq_enabled(nu: bool) -> bool {
+    let mut prefs = PREFERENCES.write().unwrap();
+    if prefs.eq_enabled != nu {
+        prefs.eq_enabled = nu;
+        true
+    } else { false }
+}
+
+/// Returns the name of the equalizer preset the user chose.
+pub fn get_chosen_eq_preset() -> String {
+    PREFERENCES.read().unwrap().eq_preset.clone()
+}
+
+/// Alters which equalizer preset the user chose.
+///
+/// Returns true if playback should be restarted as a result of this change.
+pub fn set_chosen_eq_preset(nu: &str) -> bool {
+    let mut prefs = PREFERENCES.write().unwrap();
+    if prefs.eq_preset != nu {
+        prefs.eq_preset = nu.to_owned();
+        true
+    } else { false }
+}
+
+/// Returns the equalizer preset with the given name, if there is one. The
+/// user's presets take precedence over the built-in ones.
+pub fn get_eq_preset(name: &str) -> Option<EqPreset> {
+    let prefs = PREFERENCES.read().unwrap();
+    prefs.eq_presets.get(name).cloned()
+        .or_else(|| dsp::get_builtin_presets().remove(name))
+}
+
+/// Returns the names of all the equalizer presets, built-in and user-made.
+pub fn get_eq_preset_names() -> Vec<String> {
+    let mut ret = dsp::get_builtin_presets();
+    for (name, preset) in PREFERENCES.read().unwrap().eq_presets.iter() {
+        ret.insert(name.clone(), preset.clone());
+    }
+    ret.into_iter().map(|(name, _)| name).collect()
+}
+
+/// Creates or replaces one of the user's equalizer presets.
+///
+/// Returns true if playback should be restarted as a result of this change.
+pub fn set_eq_preset(name: &str, preset: EqPreset) -> bool {
+    let mut prefs = PREFERENCES.write().unwrap();
+    if prefs.eq_presets.get(name) != Some(&preset) {
+        prefs.eq_presets.insert(name.to_owned(), preset);
+        true
+    } else { false }
+}
+
 /// Returns the `HostApiIndex` of the audio host API chosen by the user, or of
 /// the default host API if the user hasn't made a choice or if the user's
 /// choice could not be found.
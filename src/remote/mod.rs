@@ -24,11 +24,14 @@ impl Remote {
             mpris: mpris::MprisRemote::new(target)
         }
     }
-    pub fn set_now_playing(&self, song: Option<&LogicalSongRef>) {
+    /// `stream_title` is the title of whatever a network stream is playing,
+    /// if the song is one and we know.
+    pub fn set_now_playing(&self, song: Option<&LogicalSongRef>,
+                           stream_title: Option<&str>) {
         #[cfg(not(feature="mpris"))]
-        let _ = song;
+        let _ = (song, stream_title);
         #[cfg(feature="mpris")]
-        self.mpris.set_now_playing(song);
+        self.mpris.set_now_playing(song, stream_title);
     }
     pub fn set_play_pos(&self, pos: f64) {
         #[cfg(not(feature="mpris"))]
@@ -72,7 +75,8 @@ pub trait RemoteTarget {
 }
 
 trait RemoteSource {
-    fn set_now_playing(&self, _song: Option<&LogicalSongRef>);
+    fn set_now_playing(&self, _song: Option<&LogicalSongRef>,
+                       _stream_title: Option<&str>);
     fn set_play_pos(&self, _pos: f64);
     fn set_is_shuffled(&self, _is_shuffled: bool);
     fn set_cur_playmode(&self, _playmode: Playmode);
@@ -91,7 +91,8 @@ impl super::RemoteSource for MprisRemote {
     fn set_cur_playmode(&self, playmode: Playmode) {
         self.mpris_player.set_loop_status(playmode.into());
     }
-    fn set_now_playing(&self, song_ref: Option<&LogicalSongRef>) {
+    fn set_now_playing(&self, song_ref: Option<&LogicalSongRef>,
+                       stream_title: Option<&str>) {
         let mut mpris_metadata = mpris_player::Metadata {
             length: None,
             art_url: None,
@@ -107,8 +108,11 @@ impl super::RemoteSource for MprisRemote {
         };
         if let Some(song_ref) = song_ref {
             let song = song_ref.read().unwrap();
-            mpris_metadata.length = Some(song.get_duration() as i64
-                                         * 1000000);
+            if !song.is_stream() {
+                mpris_metadata.length = Some(song.get_duration() as i64
+                                             * 1000000);
+            }
+            mpris_metadata.url = song.get_stream_url().map(str::to_owned);
             let song_metadata = song.get_metadata();
             mpris_metadata.album = song_metadata.get("album")
                 .map(|x| x.to_owned());
@@ -125,6 +129,11 @@ impl super::RemoteSource for MprisRemote {
                 .and_then(|x| x.parse().ok());
             mpris_metadata.disc_number = song_metadata.get("disc#")
                 .and_then(|x| x.parse().ok());
+            if let Some(stream_title) = stream_title {
+                // Show what's on, and (in place of an album) what it's on.
+                mpris_metadata.album = mpris_metadata.title.take();
+                mpris_metadata.title = Some(stream_title.to_owned());
+            }
         }
         self.mpris_player.set_metadata(mpris_metadata);
     }
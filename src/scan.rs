@@ -2,25 +2,63 @@
 //! songs, recognizing known song files and identifying unknown ones.
 
 use anyhow::anyhow;
+use glob::Pattern;
 use std::{
-    collections::VecDeque,
+    collections::{HashSet, VecDeque},
     ffi::OsStr,
     fs,
     path::{Path, PathBuf},
     rc::Rc,
-    sync::{atomic::{AtomicU32, Ordering}, Arc, mpsc},
+    sync::{atomic::{AtomicBool, AtomicU32, Ordering}, Arc, Mutex, mpsc},
     thread,
+    time::{Duration, Instant},
 };
 
 use crate::*;
+use physical::NonMusicKind;
+
+/// A snapshot of how far along the current scan is.
+#[derive(Clone,Debug,Default)]
+pub struct ScanProgress {
+    /// The directory currently being searched, or containing the file
+    /// currently being scanned.
+    pub current_dir: Option<PathBuf>,
+    /// Number of files found so far, known or not.
+    pub files_discovered: u64,
+    /// Number of files we've found that we didn't recognize, and therefore
+    /// have to open and checksum.
+    pub files_to_hash: u64,
+    /// Number of those files we've finished with.
+    pub files_hashed: u64,
+    /// Total size of the files we have to open and checksum.
+    pub bytes_to_hash: u64,
+    /// Total size of the files we've finished with.
+    pub bytes_hashed: u64,
+    /// True until we've finished searching directories. Until then, the
+    /// totals above may still grow, and `eta` will be `None`.
+    pub searching: bool,
+    /// True if the scan has been paused with `ScanThread::set_paused`.
+    pub paused: bool,
+    /// Our best guess as to how much longer the scan will take.
+    pub eta: Option<Duration>,
+}
+
+/// State shared between the `ScanThread` and the thread it encapsulates.
+#[derive(Default)]
+struct ScanShared {
+    // Incremented by `rescan`. Decremented by the scan thread.
+    scans_left: AtomicU32,
+    cancel: AtomicBool,
+    pause: AtomicBool,
+    progress: Mutex<Option<ScanProgress>>,
+}
 
 /// Encapsulates the communication channels to and from the search thread.
 pub struct ScanThread {
     rescan_request_tx: mpsc::Sender<Vec<String>>,
     scan_result_rx: mpsc::Receiver<anyhow::Result<()>>,
-    // Incremented by `rescan`. Decremented by the scan thread.
     // Oh boy we made it an arc...
-    scans_left: Arc<AtomicU32>,
+    shared: Arc<ScanShared>,
 }
 
 impl ScanThread {
@@ -28,22 +66,43 @@ impl ScanThread {
     pub fn new() -> ScanThread {
         let (rescan_request_tx, rescan_request_rx) = mpsc::channel();
         let (scan_result_tx, scan_result_rx) = mpsc::channel();
-        let scans_left: Arc<AtomicU32> = Arc::new(0.into());
-        let scans_left_clone = scans_left.clone();
+        let shared: Arc<ScanShared> = Arc::new(Default::default());
+        let shared_clone = shared.clone();
         thread::Builder::new().name("song scan thread".to_owned())
             .spawn(move || search_thread_body(rescan_request_rx,
                                               scan_result_tx,
-                                              scans_left_clone))
+                                              shared_clone))
             .expect("Unable to spawn song scan thread");
-        ScanThread { rescan_request_tx, scan_result_rx, scans_left }
+        ScanThread { rescan_request_tx, scan_result_rx, shared }
     }
     /// Initiates a scan of the given music directories.
     pub fn rescan(&mut self, dirs: Vec<String>) -> anyhow::Result<()> {
         // set scanning to true BEFORE sending!
-        self.scans_left.fetch_add(1, Ordering::SeqCst);
+        self.shared.scans_left.fetch_add(1, Ordering::SeqCst);
         self.rescan_request_tx.send(dirs)?;
         Ok(())
     }
+    /// Stops the current scan, and any scans that are queued up behind it.
+    /// The scan thread finishes with the file it's currently working on, so
+    /// that nothing is left half-incorporated.
+    pub fn cancel(&mut self) {
+        if self.shared.scans_left.load(Ordering::SeqCst) != 0 {
+            self.shared.cancel.store(true, Ordering::SeqCst);
+        }
+    }
+    /// Pauses or unpauses scanning. As with `cancel`, a pause takes effect
+    /// between files.
+    pub fn set_paused(&mut self, paused: bool) {
+        self.shared.pause.store(paused, Ordering::SeqCst);
+    }
+    /// Returns true if scanning is paused.
+    pub fn is_paused(&self) -> bool {
+        self.shared.pause.load(Ordering::SeqCst)
+    }
+    /// Returns the progress of the scan currently in progress, if any.
+    pub fn get_progress(&self) -> Option<ScanProgress> {
+        self.shared.progress.lock().unwrap().clone()
+    }
     /// Returns a scan result, blocking if necessary. Returns:
     /// - `Err(...)` → The scanning thread crashed
     /// - `Ok(None)` → Scanning is complete
@@ -54,7 +113,7 @@ impl ScanThread {
     #[allow(dead_code)]
     pub fn get_result_blocking(&mut self)
     -> anyhow::Result<Option<anyhow::Result<()>>> {
-        if self.scans_left.load(Ordering::SeqCst) == 0 { Ok(None) }
+        if self.shared.scans_left.load(Ordering::SeqCst) == 0 { Ok(None) }
         else {
             // if we fetched it and it wasn't zero, then—since we are the
             // sole consumer of this queue—we will DEFINITELY get at least
@@ -75,7 +134,9 @@ impl ScanThread {
     ///   particular file, but the scan is continuing onward.
     pub fn get_result_nonblocking(&mut self)
     -> anyhow::Result<(bool, Option<anyhow::Result<()>>)> {
-        if self.scans_left.load(Ordering::SeqCst) == 0 { Ok((true, None)) }
+        if self.shared.scans_left.load(Ordering::SeqCst) == 0 {
+            Ok((true, None))
+        }
         else {
             // if we fetched it and it wasn't zero, then—since we are the
             // sole consumer of this queue—we will DEFINITELY get at least
@@ -89,12 +150,46 @@ impl ScanThread {
     }
 }
 
-fn interrogate_file(ent: &fs::DirEntry, fs_metadata: &fs::Metadata,
-                    size: u64, prefix: &Path)
-    -> anyhow::Result<()> {
-    let absolute_path = ent.path();
+/// Returns every directory that a full scan should search: the user's music
+/// locations, plus the podcast directory if there are any podcasts.
+pub fn get_scan_paths() -> Vec<String> {
+    let mut ret = prefs::get_music_paths();
+    if podcast::have_podcasts() {
+        let podcast_dir = prefs::get_podcast_directory();
+        if podcast_dir.is_dir() {
+            ret.push(podcast_dir.to_string_lossy().into_owned());
+        }
+    }
+    ret
+}
+
+/// A file that we found during the search, but didn't recognize. (Or did
+/// recognize, but still need to check for a cue sheet or chapters.)
+struct PendingFile {
+    absolute_path: PathBuf,
+    relative_path: String,
+    size: u64,
+    mtime: u64,
+    /// If we already know this file, its ID.
+    known_id: Option<FileID>,
+}
+
+impl PendingFile {
+    /// How many bytes we'll have to read to checksum this file.
+    fn bytes_to_hash(&self) -> u64 {
+        if self.known_id.is_some() { 0 } else { self.size }
+    }
+}
+
+/// Checks whether we already know about a file we just found. If we do,
+/// returns `Ok(None)`. If we don't, returns the information we'll need to
+/// scan it properly later.
+fn discover_file(absolute_path: PathBuf, fs_metadata: &fs::Metadata,
+                 prefix: &Path)
+    -> anyhow::Result<Option<PendingFile>> {
     let relative_path: String = absolute_path.strip_prefix(prefix).unwrap()
         .to_string_lossy().into_owned();
+    let size = fs_metadata.len();
     let mtime = match fs_metadata.modified() {
         // Only returns an error if the local OS doesn't support mtimes. I
         // doubt Tsong would otherwise function on such an OS, but just in
@@ -102,123 +197,402 @@ fn interrogate_file(ent: &fs::DirEntry, fs_metadata: &fs::Metadata,
         Err(_) => 456,
         Ok(x) => x.duration_since(std::time::SystemTime::UNIX_EPOCH)?.as_secs(),
     };
-    if let Some(_) = physical::saw_file(size, mtime,
-                                        &relative_path, &absolute_path) {
-        // It hasn't changed since the last time we saw it.
-        return Ok(())
+    if let Some(id) = physical::saw_file(size, mtime,
+                                         &relative_path, &absolute_path) {
+        // It hasn't changed since the last time we saw it. But it might be
+        // from before we knew about cue sheets and chapters.
+        if physical::needs_track_check(&id) {
+            return Ok(Some(PendingFile { absolute_path, relative_path, size,
+                                         mtime, known_id: Some(id) }))
+        }
+        return Ok(None)
+    }
+    if physical::is_known_non_music_file(size, mtime, &absolute_path) {
+        // We already looked at it, and it isn't a song.
+        return Ok(None)
+    }
+    Ok(Some(PendingFile { absolute_path, relative_path, size, mtime,
+                          known_id: None }))
+}
+
+/// Figures out what tracks, if any, a file is divided into. A cue sheet takes
+/// precedence over chapters in the file itself.
+fn find_tracks(absolute_path: &Path, avf: &ffmpeg::AVFormat)
+    -> Vec<physical::Track> {
+    if let Some(tracks) = cue::find_tracks_for(absolute_path) {
+        return tracks
+    }
+    let chapters = avf.read_chapters();
+    if chapters.len() < 2 { return Vec::new() }
+    let chapter_count = chapters.len();
+    chapters.into_iter().enumerate().map(|(n, chapter)| {
+        let mut metadata = chapter.metadata;
+        metadata.insert("track".to_owned(),
+                        format!("{}/{}", n + 1, chapter_count));
+        physical::Track {
+            start: chapter.start,
+            // The last chapter goes until the end of the file, even if the
+            // file claims otherwise.
+            end: if n + 1 == chapter_count { None }
+            else { Some(chapter.end) },
+            metadata,
+        }
+    }).collect()
+}
+
+/// Checks a file we already know about for a cue sheet or chapters.
+fn check_known_file_tracks(id: &FileID, absolute_path: &Path)
+    -> anyhow::Result<()> {
+    let mut avf = ffmpeg::AVFormat::open_input(absolute_path)?;
+    avf.find_stream_info()?;
+    physical::checked_file_tracks(id, find_tracks(absolute_path, &avf));
+    Ok(())
+}
+
+fn interrogate_file(file: &PendingFile) -> anyhow::Result<()> {
+    let PendingFile { absolute_path, relative_path, size, mtime,
+                      known_id } = file;
+    if let Some(id) = known_id {
+        return check_known_file_tracks(id, absolute_path)
     }
     // Okay, so we don't believe we've seen this physical file before. We need
     // to open it, get metadata, checksum it, etc.
-    let mut avf = ffmpeg::AVFormat::open_input(&absolute_path)?;
+    let mut avf = match ffmpeg::AVFormat::open_input(&absolute_path) {
+        Ok(x) => x,
+        Err(x) if x.is::<ffmpeg::UnrecognizedFormat>() => {
+            physical::saw_non_music_file(*size, *mtime, &absolute_path,
+                                         NonMusicKind::Unrecognized);
+            return Ok(())
+        },
+        Err(x) => return Err(x),
+    };
     avf.find_stream_info()?;
     let best_stream_id = match avf.find_best_stream()? {
         Some(x) => x,
         None => {
-            // TODO: not a music file
+            physical::saw_non_music_file(*size, *mtime, &absolute_path,
+                                         NonMusicKind::NoAudio);
             return Ok(())
         }
     };
+    if !prefs::get_play_video_files() && avf.has_video_stream() {
+        physical::saw_non_music_file(*size, *mtime, &absolute_path,
+                                     NonMusicKind::Video);
+        return Ok(())
+    }
+    physical::forget_non_music_file(&absolute_path);
     let metadata = avf.read_metadata(Some(best_stream_id));
     let duration = avf.estimate_duration(best_stream_id);
+    let tracks = find_tracks(&absolute_path, &avf);
     // We've got the metadata from ffmpeg. We're pretty sure at this point that
     // it's a music file. (Or something we can play as one, at least.) Checksum
     // the whole file to get its file ID.
     let fileid = FileID::from_file(fs::File::open(&absolute_path)?)?;
-    physical::scanned_file(&fileid, size, mtime, duration, &relative_path,
-                           &absolute_path, metadata)?;
+    physical::scanned_file(&fileid, *size, *mtime, duration, &relative_path,
+                           &absolute_path, metadata, tracks)?;
     // Everything went okay. We scanned the file. We got its metadata. It has
     // been added to our physical file database.
     Ok(())
 }
 
+/// Compiled include/exclude patterns for one music location.
+struct ScanRoot {
+    prefix: PathBuf,
+    include: Vec<Pattern>,
+    exclude: Vec<Pattern>,
+}
+
+impl ScanRoot {
+    fn new(dir: String, global_exclude: &[String],
+           errors: &mut Vec<anyhow::Error>) -> ScanRoot {
+        let patterns = prefs::get_scan_patterns(&dir);
+        let mut compile = |src: &[String]| -> Vec<Pattern> {
+            src.iter().filter_map(|x| match Pattern::new(x) {
+                Ok(x) => Some(x),
+                Err(e) => {
+                    errors.push(anyhow!("Invalid scan pattern {:?} for {:?}: \
+                                         {}", x, dir, e));
+                    None
+                },
+            }).collect()
+        };
+        let include = compile(&patterns.include[..]);
+        let mut exclude = compile(global_exclude);
+        exclude.extend(compile(&patterns.exclude[..]));
+        ScanRoot { prefix: PathBuf::from(dir), include, exclude }
+    }
+    /// Returns true if the given path (which must be somewhere under our
+    /// prefix) should be skipped.
+    fn excludes(&self, path: &Path, is_dir: bool) -> bool {
+        let relative_path = match path.strip_prefix(&self.prefix) {
+            Ok(x) => x.to_string_lossy(),
+            Err(_) => return false,
+        };
+        if is_dir {
+            // Give a pattern like `*/Podcasts/*` a chance to prune the whole
+            // directory. (The leading slash lets it match a directory right
+            // at the top of the location, too.)
+            let relative_path = relative_path.into_owned() + "/";
+            let rooted_path = format!("/{}", relative_path);
+            self.exclude.iter().any(|x| x.matches(&relative_path)
+                                    || x.matches(&rooted_path))
+        }
+        else {
+            self.exclude.iter().any(|x| x.matches(&relative_path))
+                || (!self.include.is_empty()
+                    && !self.include.iter().any(|x| x.matches(&relative_path)))
+        }
+    }
+}
+
+/// Something that uniquely identifies a directory, so that we can avoid
+/// scanning it twice (and avoid going around in circles forever if there's a
+/// symlink loop).
+#[cfg(unix)]
+type DirIdentity = (u64, u64);
+
+#[cfg(unix)]
+fn get_dir_identity(_path: &Path, metadata: &fs::Metadata)
+    -> Option<DirIdentity> {
+    use std::os::unix::fs::MetadataExt;
+    Some((metadata.dev(), metadata.ino()))
+}
+
+#[cfg(not(unix))]
+type DirIdentity = PathBuf;
+
+#[cfg(not(unix))]
+fn get_dir_identity(path: &Path, _metadata: &fs::Metadata)
+    -> Option<DirIdentity> {
+    fs::canonicalize(path).ok()
+}
+
+impl ScanShared {
+    fn update_progress<F: FnOnce(&mut ScanProgress)>(&self, f: F) {
+        match self.progress.lock().unwrap().as_mut() {
+            Some(progress) => f(progress),
+            None => (),
+        }
+    }
+    /// Waits out any pause, then returns true if the scan should stop.
+    fn should_stop(&self) -> bool {
+        if self.pause.load(Ordering::SeqCst) {
+            self.update_progress(|x| x.paused = true);
+            while self.pause.load(Ordering::SeqCst)
+                && !self.cancel.load(Ordering::SeqCst) {
+                    thread::sleep(Duration::from_millis(100));
+                }
+            self.update_progress(|x| x.paused = false);
+        }
+        self.cancel.load(Ordering::SeqCst)
+    }
+}
+
 fn search_thread_body(rescan_request_rx: mpsc::Receiver<Vec<String>>,
                       scan_result_tx: mpsc::Sender<anyhow::Result<()>>,
-                      scans_left: Arc<AtomicU32>) {
+                      shared: Arc<ScanShared>) {
     while let Ok(dir_list) = rescan_request_rx.recv() {
-        let mut dir_queue: VecDeque<(PathBuf, Rc<PathBuf>)> = dir_list
-            .into_iter().map(PathBuf::from).map(|x| {
-                let y = x.clone();
-                (x, Rc::new(y))
-            }).collect();
-        while let Some((dir, prefix)) = dir_queue.pop_back() {
-            let read_dir_iterator = match fs::read_dir(&dir) {
+        *shared.progress.lock().unwrap() = Some(ScanProgress {
+            searching: true,
+            ..Default::default()
+        });
+        match search_dirs(dir_list, &scan_result_tx, &shared) {
+            Some(_) => (),
+            None => return, // we got dropped, oh well
+        }
+        logical::maybe_recreate_recs();
+        if shared.cancel.load(Ordering::SeqCst) {
+            // Throw away any scans that were queued up behind this one.
+            while let Ok(_) = rescan_request_rx.try_recv() {
+                shared.scans_left.fetch_sub(1, Ordering::SeqCst);
+            }
+            shared.cancel.store(false, Ordering::SeqCst);
+        }
+        *shared.progress.lock().unwrap() = None;
+        shared.scans_left.fetch_sub(1, Ordering::SeqCst);
+        match scan_result_tx.send(Ok(())) {
+            Ok(_) => (),
+            Err(_) => return, // we got dropped, oh well
+        }
+    }
+}
+
+/// Performs one whole scan. One thread searches all the directories, making
+/// note of any files we don't recognize, while this one scans each of those
+/// files as soon as it's found. (So new songs show up right away, but we can
+/// only give an ETA once the search is done and we know how much work is
+/// left.)
+///
+/// Returns `None` if the other end of `scan_result_tx` hung up.
+fn search_dirs(dir_list: Vec<String>,
+               scan_result_tx: &mpsc::Sender<anyhow::Result<()>>,
+               shared: &Arc<ScanShared>) -> Option<()> {
+    let (pending_tx, pending_rx) = mpsc::channel();
+    let walk_result_tx = scan_result_tx.clone();
+    let walk_shared = shared.clone();
+    let walker = thread::Builder::new().name("song search thread".to_owned())
+        .spawn(move || walk_dirs(dir_list, &pending_tx, &walk_result_tx,
+                                 &walk_shared))
+        .expect("Unable to spawn song search thread");
+    let ret = incorporate_files(pending_rx, scan_result_tx, shared);
+    // (if we stopped early, `pending_rx` is gone, and the search will stop as
+    // soon as it notices)
+    let walked = walker.join().ok().flatten();
+    ret.and(walked)
+}
+
+/// Searches the given directories, sending every file we don't recognize to
+/// `pending_tx` as soon as we find it.
+///
+/// Stops early, as a normal stop, if the scan is cancelled or the other end
+/// of `pending_tx` hangs up (which it does when it stops early itself).
+/// Returns `None` if the other end of `scan_result_tx` hung up.
+fn walk_dirs(dir_list: Vec<String>,
+             pending_tx: &mpsc::Sender<PendingFile>,
+             scan_result_tx: &mpsc::Sender<anyhow::Result<()>>,
+             shared: &ScanShared) -> Option<()> {
+    let follow_symlinks = prefs::get_follow_symlinks();
+    let global_exclude = prefs::get_scan_exclude();
+    let mut pattern_errors = Vec::new();
+    let mut dir_queue: VecDeque<(PathBuf, Rc<ScanRoot>)> = dir_list
+        .into_iter().map(|x| {
+            let root = ScanRoot::new(x, &global_exclude[..],
+                                     &mut pattern_errors);
+            (root.prefix.clone(), Rc::new(root))
+        }).collect();
+    for x in pattern_errors.into_iter() {
+        scan_result_tx.send(Err(x)).ok()?;
+    }
+    let mut visited_dirs: HashSet<DirIdentity> = HashSet::new();
+    while let Some((dir, root)) = dir_queue.pop_back() {
+        if shared.should_stop() { return Some(()) }
+        match dir.metadata().ok()
+            .and_then(|metadata| get_dir_identity(&dir, &metadata)) {
+                Some(identity) => {
+                    if !visited_dirs.insert(identity) {
+                        // We've been here before. Either a symlink loop, or
+                        // two paths to the same place.
+                        continue
+                    }
+                },
+                // If we can't get its metadata, `read_dir` is about to fail
+                // anyway, and report a better error than we could.
+                None => (),
+        }
+        shared.update_progress(|x| x.current_dir = Some(dir.clone()));
+        let read_dir_iterator = match fs::read_dir(&dir) {
+            Ok(x) => x,
+            Err(x) => {
+                let x = anyhow!(x)
+                    .context(format!("While opening directory {:?}", dir));
+                scan_result_tx.send(Err(x)).ok()?;
+                continue
+            },
+        };
+        for ent in read_dir_iterator {
+            let ent = match ent {
                 Ok(x) => x,
                 Err(x) => {
                     let x = anyhow!(x)
-                        .context(format!("While opening directory {:?}", dir));
-                    match scan_result_tx.send(Err(x)) {
-                        Ok(_) => (),
-                        Err(_) => return, // we got dropped, oh well
-                    }
+                        .context(format!("While iterating directory {:?}",
+                                         dir));
+                    scan_result_tx.send(Err(x)).ok()?;
                     continue
                 },
             };
-            for ent in read_dir_iterator {
-                let ent = match ent {
-                    Ok(x) => x,
-                    Err(x) => {
-                        let x = anyhow!(x)
-                            .context(format!("While iterating directory {:?}",
-                                             dir));
-                        match scan_result_tx.send(Err(x)) {
-                            Ok(_) => (),
-                            Err(_) => return, // we got dropped, oh well
-                        }
+            match ent.path().file_name().map(OsStr::to_string_lossy) {
+                Some(x) => if x.starts_with(".") || x.ends_with("\r")
+                    || (x.starts_with("iTunes Library ")
+                        && !x.contains("."))
+                    // cue sheets are read alongside the files they describe
+                    || x.to_lowercase().ends_with(".cue") {
                         continue
-                    },
-                };
-                match ent.path().file_name().map(OsStr::to_string_lossy) {
-                    Some(x) => if x.starts_with(".") || x.ends_with("\r")
-                        || x.ends_with(".xml") || x.ends_with(".itl")
-                        || x.ends_with(".itdb") || x.ends_with(".m3u")
-                        || x.ends_with(".itc")
-                        || (x.starts_with("iTunes Library ")
-                            && !x.contains(".")) {
-                            continue
-                    },
-                    None => continue,
-                }
-                let metadata = match ent.path().metadata() {
-                    Err(x) => {
-                        let x = anyhow!(x)
-                            .context(format!("While getting metadata for {:?}",
-                                             ent.path()));
-                        match scan_result_tx.send(Err(x)) {
-                            Ok(_) => (),
-                            Err(_) => return, // we got dropped, oh well
-                        }
-                        continue
-                    },
-                    Ok(x) => x,
-                };
-                let size = metadata.len();
-                if metadata.file_type().is_dir() {
-                    // TODO: check for loops
-                    dir_queue.push_back((ent.path(),
-                                         prefix.clone()));
+                },
+                None => continue,
+            }
+            let metadata = if follow_symlinks { ent.path().metadata() }
+            else { fs::symlink_metadata(ent.path()) };
+            let metadata = match metadata {
+                Err(x) => {
+                    let x = anyhow!(x)
+                        .context(format!("While getting metadata for {:?}",
+                                         ent.path()));
+                    scan_result_tx.send(Err(x)).ok()?;
                     continue
-                }
-                else {
-                    match interrogate_file(&ent, &metadata, size, &prefix) {
-                        Ok(_) => (),
-                        Err(x) => {
-                            let x = x.context(format!("While scanning {:?}",
-                                                      ent.path()));
-                            match scan_result_tx.send(Err(x)) {
-                                Ok(_) => (),
-                                Err(_) => return, // we got dropped, oh well
-                            }
-                            continue
-                        },
+                },
+                Ok(x) => x,
+            };
+            if metadata.file_type().is_symlink() {
+                // only possible if we're not following symlinks
+                continue
+            }
+            if metadata.file_type().is_dir() {
+                if root.excludes(&ent.path(), true) { continue }
+                dir_queue.push_back((ent.path(),
+                                     root.clone()));
+                continue
+            }
+            if root.excludes(&ent.path(), false) { continue }
+            shared.update_progress(|x| x.files_discovered += 1);
+            match discover_file(ent.path(), &metadata, &root.prefix) {
+                Ok(None) => (),
+                Ok(Some(pending)) => {
+                    shared.update_progress(|x| {
+                        x.files_to_hash += 1;
+                        x.bytes_to_hash += pending.bytes_to_hash();
+                    });
+                    if pending_tx.send(pending).is_err() {
+                        return Some(())
                     }
-                }
+                },
+                Err(x) => {
+                    let x = x.context(format!("While scanning {:?}",
+                                              ent.path()));
+                    scan_result_tx.send(Err(x)).ok()?;
+                },
             }
         }
-        logical::maybe_recreate_recs();
-        scans_left.fetch_sub(1, Ordering::SeqCst);
-        match scan_result_tx.send(Ok(())) {
+    }
+    shared.update_progress(|x| x.searching = false);
+    Some(())
+}
+
+/// Scans every file that `walk_dirs` sends us, until it's done searching.
+///
+/// Returns `None` if the other end of `scan_result_tx` hung up.
+fn incorporate_files(pending_rx: mpsc::Receiver<PendingFile>,
+                     scan_result_tx: &mpsc::Sender<anyhow::Result<()>>,
+                     shared: &ScanShared) -> Option<()> {
+    let mut bytes_hashed = 0u64;
+    let mut hash_time = Duration::from_secs(0);
+    for file in pending_rx.iter() {
+        if shared.should_stop() { return Some(()) }
+        shared.update_progress(|x| {
+            x.current_dir = file.absolute_path.parent().map(Path::to_owned)
+        });
+        let start = Instant::now();
+        let result = interrogate_file(&file);
+        // Only count time spent actually scanning, not time spent paused.
+        hash_time += start.elapsed();
+        bytes_hashed += file.bytes_to_hash();
+        shared.update_progress(|x| {
+            x.files_hashed += 1;
+            x.bytes_hashed = bytes_hashed;
+            // (while we're still searching, we don't know how much is left)
+            x.eta = if x.searching || bytes_hashed == 0 { None } else {
+                let bytes_left = x.bytes_to_hash.saturating_sub(bytes_hashed);
+                Some(hash_time.mul_f64(bytes_left as f64
+                                       / bytes_hashed as f64))
+            };
+        });
+        match result {
             Ok(_) => (),
-            Err(_) => return, // we got dropped, oh well
+            Err(x) => {
+                let x = x.context(format!("While scanning {:?}",
+                                          file.absolute_path));
+                scan_result_tx.send(Err(x)).ok()?;
+            },
         }
     }
+    Some(())
 }
@@ -1,10 +1,11 @@
-PRAGMA user_version = 3;
+PRAGMA user_version = 13;
 
 CREATE TABLE PhysicalFiles(
        id BINARY(16) PRIMARY KEY,
        size INTEGER NOT NULL,
        duration INTEGER NOT NULL,
-       relative_paths BLOB NOT NULL
+       relative_paths BLOB NOT NULL,
+       tracks BLOB
 );
 
 CREATE TABLE LogicalSongs(
@@ -12,7 +13,11 @@ CREATE TABLE LogicalSongs(
        user_metadata BLOB NOT NULL,
        physical_files BLOB NOT NULL,
        duration INTEGER,
-       similarity_recs BLOB
+       similarity_recs BLOB,
+       physical_tracks BLOB,
+       practice_loops BLOB,
+       remembered_position REAL,
+       stream_url BLOB
 );
 
 CREATE TABLE Playlists(
@@ -25,7 +30,79 @@ CREATE TABLE Playlists(
        columns BLOB,
        sort_order BLOB,
        shuffled BOOLEAN,
-       playmode TINYINT
+       playmode TINYINT,
+       remember_positions BOOLEAN,
+       shuffle_albums BOOLEAN,
+       album_key BLOB
+);
+
+-- There is only ever one row, with an id of 0.
+CREATE TABLE PlaybackState(
+       id INTEGER PRIMARY KEY,
+       playlist_id INTEGER,
+       song_id INTEGER,
+       position REAL,
+       status TINYINT,
+       shuffle_order BLOB
+);
+
+-- minute is minutes past local midnight. days is a bitmask, bit 0 = Sunday;
+-- if it's zero, the alarm only goes off once.
+CREATE TABLE Alarms(
+       id INTEGER PRIMARY KEY,
+       minute INTEGER NOT NULL,
+       days TINYINT NOT NULL,
+       playlist_id INTEGER NOT NULL,
+       shuffle BOOLEAN NOT NULL,
+       volume INTEGER,
+       ramp REAL NOT NULL,
+       enabled BOOLEAN NOT NULL,
+       last_fired INTEGER
+);
+
+CREATE TABLE NonMusicFiles(
+       absolute_path BLOB PRIMARY KEY,
+       size INTEGER NOT NULL,
+       mtime INTEGER NOT NULL,
+       kind TINYINT NOT NULL
+);
+
+-- keep_episodes is how many of the newest episodes to keep downloaded; NULL
+-- means all of them.
+CREATE TABLE Podcasts(
+       id INTEGER PRIMARY KEY,
+       url BLOB NOT NULL,
+       title BLOB NOT NULL,
+       playlist_id INTEGER,
+       keep_episodes INTEGER,
+       delete_played BOOLEAN NOT NULL,
+       last_refreshed INTEGER
+);
+-- position is where the episode was in the feed, 0 being the newest. metadata
+-- is a JSON object, taken from the feed. path and file_id are set while the
+-- episode is downloaded.
+CREATE TABLE PodcastEpisodes(
+       podcast_id INTEGER NOT NULL,
+       guid BLOB NOT NULL,
+       position INTEGER NOT NULL,
+       url BLOB NOT NULL,
+       metadata BLOB NOT NULL,
+       path BLOB,
+       file_id BINARY(16),
+       played BOOLEAN NOT NULL,
+       PRIMARY KEY(podcast_id, guid)
+);
+-- format is a `sync::SyncFormat` value. bit_rate is in kbit/s. playlist_ids
+-- is a JSON array of the playlists to sync.
+CREATE TABLE SyncTargets(
+       id INTEGER PRIMARY KEY,
+       name BLOB NOT NULL,
+       directory BLOB NOT NULL,
+       path_template BLOB NOT NULL,
+       format INTEGER NOT NULL,
+       bit_rate INTEGER NOT NULL,
+       playlist_ids BLOB NOT NULL,
+       last_synced INTEGER
 );
 
 INSERT INTO Playlists(parent_order, name, rule_code)
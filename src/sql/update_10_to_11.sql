@@ -0,0 +1,4 @@
+-- stream_url is set for songs that are played from the network (internet
+-- radio, etc.) instead of from files. Such songs have no physical files.
+ALTER TABLE LogicalSongs ADD COLUMN stream_url BLOB;
+PRAGMA user_version = 11;
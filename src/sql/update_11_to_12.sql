@@ -0,0 +1,26 @@
+-- keep_episodes is how many of the newest episodes to keep downloaded; NULL
+-- means all of them.
+CREATE TABLE Podcasts(
+       id INTEGER PRIMARY KEY,
+       url BLOB NOT NULL,
+       title BLOB NOT NULL,
+       playlist_id INTEGER,
+       keep_episodes INTEGER,
+       delete_played BOOLEAN NOT NULL,
+       last_refreshed INTEGER
+);
+-- position is where the episode was in the feed, 0 being the newest. metadata
+-- is a JSON object, taken from the feed. path and file_id are set while the
+-- episode is downloaded.
+CREATE TABLE PodcastEpisodes(
+       podcast_id INTEGER NOT NULL,
+       guid BLOB NOT NULL,
+       position INTEGER NOT NULL,
+       url BLOB NOT NULL,
+       metadata BLOB NOT NULL,
+       path BLOB,
+       file_id BINARY(16),
+       played BOOLEAN NOT NULL,
+       PRIMARY KEY(podcast_id, guid)
+);
+PRAGMA user_version = 12;
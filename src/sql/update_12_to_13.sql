@@ -0,0 +1,13 @@
+-- format is a `sync::SyncFormat` value. bit_rate is in kbit/s. playlist_ids
+-- is a JSON array of the playlists to sync.
+CREATE TABLE SyncTargets(
+       id INTEGER PRIMARY KEY,
+       name BLOB NOT NULL,
+       directory BLOB NOT NULL,
+       path_template BLOB NOT NULL,
+       format INTEGER NOT NULL,
+       bit_rate INTEGER NOT NULL,
+       playlist_ids BLOB NOT NULL,
+       last_synced INTEGER
+);
+PRAGMA user_version = 13;
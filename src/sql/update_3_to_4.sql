@@ -0,0 +1,7 @@
+CREATE TABLE NonMusicFiles(
+       absolute_path BLOB PRIMARY KEY,
+       size INTEGER NOT NULL,
+       mtime INTEGER NOT NULL,
+       kind TINYINT NOT NULL
+);
+PRAGMA user_version = 4;
@@ -0,0 +1,5 @@
+-- NULL means we haven't yet checked the file for a cue sheet or chapters.
+ALTER TABLE PhysicalFiles ADD COLUMN tracks BLOB;
+-- NULL means every physical file is played in its entirety.
+ALTER TABLE LogicalSongs ADD COLUMN physical_tracks BLOB;
+PRAGMA user_version = 5;
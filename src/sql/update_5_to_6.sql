@@ -0,0 +1,3 @@
+-- NULL means the song has no saved practice loops.
+ALTER TABLE LogicalSongs ADD COLUMN practice_loops BLOB;
+PRAGMA user_version = 6;
@@ -0,0 +1,10 @@
+-- There is only ever one row, with an id of 0.
+CREATE TABLE PlaybackState(
+       id INTEGER PRIMARY KEY,
+       playlist_id INTEGER,
+       song_id INTEGER,
+       position REAL,
+       status TINYINT,
+       shuffle_order BLOB
+);
+PRAGMA user_version = 7;
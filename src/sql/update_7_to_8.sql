@@ -0,0 +1,4 @@
+-- NULL means the song will start from the beginning next time.
+ALTER TABLE LogicalSongs ADD COLUMN remembered_position REAL;
+ALTER TABLE Playlists ADD COLUMN remember_positions BOOLEAN;
+PRAGMA user_version = 8;
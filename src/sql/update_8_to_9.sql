@@ -0,0 +1,14 @@
+-- minute is minutes past local midnight. days is a bitmask, bit 0 = Sunday;
+-- if it's zero, the alarm only goes off once.
+CREATE TABLE Alarms(
+       id INTEGER PRIMARY KEY,
+       minute INTEGER NOT NULL,
+       days TINYINT NOT NULL,
+       playlist_id INTEGER NOT NULL,
+       shuffle BOOLEAN NOT NULL,
+       volume INTEGER,
+       ramp REAL NOT NULL,
+       enabled BOOLEAN NOT NULL,
+       last_fired INTEGER
+);
+PRAGMA user_version = 9;
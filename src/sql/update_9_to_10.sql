@@ -0,0 +1,4 @@
+-- album_key is a JSON array of tags. NULL means the default.
+ALTER TABLE Playlists ADD COLUMN shuffle_albums BOOLEAN;
+ALTER TABLE Playlists ADD COLUMN album_key BLOB;
+PRAGMA user_version = 10;
@@ -0,0 +1,404 @@
+//! This module changes the tempo of audio without changing its pitch, using
+//! WSOLA (Waveform Similarity Overlap-Add). Audio is cut into overlapping
+//! windowed segments, and the segments are put back together further apart
+//! (to slow down) or closer together (to speed up). Each segment is nudged a
+//! little, to where it best lines up with the audio it's being overlapped
+//! with, so that the waveforms don't cancel each other out.
+//!
+//! Combined with resampling, this gives us both speed changes that keep the
+//! pitch and pitch changes that keep the speed. See `playback::Tempo`.
+
+use crate::*;
+
+use std::f64::consts::PI;
+
+/// Length of each segment, in seconds.
+const SEGMENT_LENGTH: f64 = 0.04;
+/// How far a segment may be nudged from where it "should" be, in seconds.
+const SEARCH_TOLERANCE: f64 = 0.01;
+/// Only every this many frames are looked at while searching. Plenty for
+/// finding a good match, and a lot cheaper.
+const SEARCH_STRIDE: usize = 4;
+
+/// Some audio going into, or coming out of, a `Stretcher`.
+pub struct Chunk {
+    pub song_id: SongID,
+    /// Time in seconds from the beginning of the song that this chunk starts
+    /// at.
+    pub time: f64,
+    /// How many seconds of the song pass for each second of this chunk.
+    pub time_scale: f64,
+    pub sample_rate: f64,
+    pub channel_count: i32,
+    pub data: Vec<f32>,
+}
+
+/// What the audio currently in a `Stretcher` looks like. If any of this
+/// changes, the `Stretcher` starts over.
+#[derive(Clone,Copy,PartialEq)]
+struct Format {
+    song_id: SongID,
+    sample_rate: f64,
+    channel_count: i32,
+    tempo: f64,
+}
+
+pub struct Stretcher {
+    format: Option<Format>,
+    /// Input that hasn't been completely used yet. Interleaved.
+    input: Vec<f32>,
+    /// The song time of the first frame in `input`.
+    input_time: f64,
+    /// The song time we expect the next input chunk to start at. If it starts
+    /// somewhere else, there was a seek (or a loop), and we start over.
+    expected_time: f64,
+    /// Where, in frames from the start of `input`, the next segment would be
+    /// taken from if we didn't nudge it.
+    position: f64,
+    /// Where, in frames from the start of `input`, the last segment was
+    /// actually taken from. `None` if we haven't taken one yet.
+    previous: Option<usize>,
+    /// The second half of the last segment, already windowed, waiting to be
+    /// overlapped with the first half of the next one.
+    overlap: Vec<f32>,
+    /// A Hann window one segment long. Two halves of it add up to one.
+    window: Vec<f32>,
+    /// Segment length and search tolerance, in frames.
+    segment: usize,
+    tolerance: usize,
+}
+
+impl Default for Stretcher {
+    fn default() -> Stretcher { Stretcher::new() }
+}
+
+impl Stretcher {
+    pub fn new() -> Stretcher {
+        Stretcher {
+            format: None,
+            input: Vec::new(),
+            input_time: 0.0,
+            expected_time: 0.0,
+            position: 0.0,
+            previous: None,
+            overlap: Vec::new(),
+            window: Vec::new(),
+            segment: 0,
+            tolerance: 0,
+        }
+    }
+    /// Changes the tempo of a chunk by the given factor (2.0 = twice as fast).
+    /// Output is passed to `out` as it becomes available, which might be
+    /// later; some input is held back so that the next segment can be lined
+    /// up with it. If the tempo is 1, audio passes straight through.
+    pub fn process(&mut self, tempo: f64, chunk: Chunk,
+                   out: &mut dyn FnMut(Chunk)) {
+        let format = Format {
+            song_id: chunk.song_id,
+            sample_rate: chunk.sample_rate,
+            channel_count: chunk.channel_count,
+            tempo,
+        };
+        // (allow a little slop, since times are floating point)
+        let contiguous = (chunk.time - self.expected_time).abs()
+            < 2.0 / chunk.sample_rate;
+        if self.format != Some(format) || !contiguous {
+            self.flush(out);
+        }
+        self.expected_time = chunk.time + (chunk.data.len()
+                                           / chunk.channel_count as usize)
+            as f64 / chunk.sample_rate;
+        if tempo == 1.0 {
+            out(chunk);
+            return
+        }
+        if self.format.is_none() {
+            self.start(format, chunk.time);
+        }
+        self.input.extend_from_slice(&chunk.data[..]);
+        bufring::finished_with_buf(chunk.data);
+        self.run(out);
+    }
+    /// Passes along any audio being held back, without stretching it, and
+    /// starts over.
+    pub fn flush(&mut self, out: &mut dyn FnMut(Chunk)) {
+        let format = match self.format.take() {
+            Some(x) => x,
+            None => return,
+        };
+        let channel_count = format.channel_count as usize;
+        // The overlap is the windowed start of the audio that naturally
+        // follows the last segment. The rest of the window's worth of that
+        // same audio completes it exactly.
+        let start = self.previous.map(|x| x + self.segment / 2).unwrap_or(0);
+        let start_index = start * channel_count;
+        if start_index < self.input.len() {
+            let mut data = bufring::get_buf();
+            data.extend_from_slice(&self.input[start_index..]);
+            out(Chunk {
+                song_id: format.song_id,
+                time: self.input_time + start as f64 / format.sample_rate,
+                time_scale: 1.0,
+                sample_rate: format.sample_rate,
+                channel_count: format.channel_count,
+                data,
+            });
+        }
+        self.input.clear();
+        self.overlap.clear();
+        self.previous = None;
+    }
+    fn start(&mut self, format: Format, time: f64) {
+        self.format = Some(format);
+        self.input_time = time;
+        self.position = 0.0;
+        self.previous = None;
+        self.input.clear();
+        self.overlap.clear();
+        // (always even, so that it splits into two equal halves)
+        self.segment = ((SEGMENT_LENGTH * format.sample_rate) as usize / 2)
+            .max(1) * 2;
+        self.tolerance = (SEARCH_TOLERANCE * format.sample_rate) as usize;
+        if self.window.len() != self.segment {
+            let len = self.segment as f64;
+            self.window = (0 .. self.segment).map(|n| {
+                (0.5 - 0.5 * (2.0 * PI * n as f64 / len).cos()) as f32
+            }).collect();
+        }
+    }
+    /// Makes as many segments as the buffered input allows.
+    fn run(&mut self, out: &mut dyn FnMut(Chunk)) {
+        let format = self.format.unwrap();
+        let channel_count = format.channel_count as usize;
+        let half = self.segment / 2;
+        let frames_in = |me: &Stretcher| me.input.len() / channel_count;
+        loop {
+            let nominal = self.position.round() as usize;
+            let chosen = match self.previous {
+                None => {
+                    if frames_in(self) < nominal + self.segment { break }
+                    // The overlap starts out as the windowed start of the
+                    // audio, so that the first half-segment comes out
+                    // untouched instead of fading in.
+                    self.overlap = self.input[nominal * channel_count
+                                              .. (nominal + half)
+                                              * channel_count].to_vec();
+                    for (n, frame) in self.overlap
+                        .chunks_exact_mut(channel_count).enumerate() {
+                            for sample in frame.iter_mut() {
+                                *sample *= self.window[half + n];
+                            }
+                        }
+                    nominal
+                },
+                Some(previous) => {
+                    let natural = previous + half;
+                    if frames_in(self) < (nominal + self.tolerance
+                                          + self.segment)
+                        .max(natural + half) { break }
+                    self.find_best_match(nominal, natural)
+                },
+            };
+            let mut data = bufring::get_buf();
+            data.reserve(half * channel_count);
+            for n in 0 .. half {
+                let w = self.window[n];
+                for c in 0 .. channel_count {
+                    data.push(self.overlap[n * channel_count + c]
+                              + w * self.input[(chosen + n) * channel_count
+                                               + c]);
+                }
+            }
+            for n in 0 .. half {
+                let w = self.window[half + n];
+                for c in 0 .. channel_count {
+                    self.overlap[n * channel_count + c]
+                        = w * self.input[(chosen + half + n) * channel_count
+                                         + c];
+                }
+            }
+            out(Chunk {
+                song_id: format.song_id,
+                time: self.input_time + self.position / format.sample_rate,
+                time_scale: format.tempo,
+                sample_rate: format.sample_rate,
+                channel_count: format.channel_count,
+                data,
+            });
+            self.previous = Some(chosen);
+            self.position += half as f64 * format.tempo;
+            self.discard_old_input();
+        }
+    }
+    /// Returns the start of the segment, within `tolerance` of `nominal`,
+    /// that looks most like the audio starting at `natural`.
+    fn find_best_match(&self, nominal: usize, natural: usize) -> usize {
+        let channel_count = self.format.unwrap().channel_count as usize;
+        let half = self.segment / 2;
+        let lo = nominal.saturating_sub(self.tolerance);
+        let hi = nominal + self.tolerance;
+        let mix = |frame: usize| -> f32 {
+            self.input[frame * channel_count .. (frame + 1) * channel_count]
+                .iter().sum()
+        };
+        let target: Vec<f32> = (0 .. half).step_by(SEARCH_STRIDE)
+            .map(|n| mix(natural + n)).collect();
+        let mut best = nominal;
+        let mut best_score = f32::NEG_INFINITY;
+        for candidate in lo ..= hi {
+            let score: f32 = (0 .. half).step_by(SEARCH_STRIDE)
+                .zip(target.iter())
+                .map(|(n, t)| mix(candidate + n) * t).sum();
+            if score > best_score {
+                best = candidate;
+                best_score = score;
+            }
+        }
+        best
+    }
+    /// Drops input we'll never look at again.
+    fn discard_old_input(&mut self) {
+        let format = self.format.unwrap();
+        let channel_count = format.channel_count as usize;
+        let previous = match self.previous {
+            Some(x) => x,
+            None => return,
+        };
+        let discard = previous
+            .min((self.position as usize).saturating_sub(self.tolerance));
+        if discard == 0 { return }
+        self.input.drain(.. discard * channel_count);
+        self.input_time += discard as f64 / format.sample_rate;
+        self.position -= discard as f64;
+        self.previous = Some(previous - discard);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: f64 = 48000.0;
+    const FREQUENCY: f64 = 1000.0;
+    const AMPLITUDE: f32 = 0.25;
+    /// How much audio `stretch` feeds in.
+    const SECONDS: usize = 1;
+
+    /// Feeds `SECONDS` of a stereo sine wave, in pieces, through a
+    /// `Stretcher` at the given tempo, and returns everything that comes out.
+    fn stretch(tempo: f64) -> Vec<Chunk> {
+        let song_id = SongID::from_inner(1);
+        let mut stretcher = Stretcher::new();
+        let mut ret = Vec::new();
+        let mut out = |chunk| ret.push(chunk);
+        let piece = 1000;
+        for start in (0 .. SAMPLE_RATE as usize * SECONDS).step_by(piece) {
+            let data = (start .. start + piece).flat_map(|n| {
+                let t = n as f64 / SAMPLE_RATE;
+                let x = (2.0 * PI * FREQUENCY * t).sin() as f32 * AMPLITUDE;
+                vec![x, -x]
+            }).collect();
+            stretcher.process(tempo, Chunk {
+                song_id, time: start as f64 / SAMPLE_RATE, time_scale: 1.0,
+                sample_rate: SAMPLE_RATE, channel_count: 2, data,
+            }, &mut out);
+        }
+        stretcher.flush(&mut out);
+        ret
+    }
+
+    fn frames(chunk: &Chunk) -> usize { chunk.data.len() / 2 }
+
+    /// Checks that the chunks pick up in song time where the ones before
+    /// them left off, and cover all of the input.
+    fn check_times(chunks: &[Chunk]) {
+        let mut time = 0.0;
+        let mut time_scale = chunks[0].time_scale;
+        for chunk in chunks.iter() {
+            // (the held-back audio carries on from the end of the audio the
+            // last segment was actually taken from, which is wherever it was
+            // nudged to, and half a segment long rather than half a segment
+            // times the tempo)
+            let mut slack = 1.0 / SAMPLE_RATE;
+            if chunk.time_scale != time_scale {
+                slack += SEARCH_TOLERANCE
+                    + SEGMENT_LENGTH / 2.0 * (1.0 - time_scale).abs();
+            }
+            assert!((chunk.time - time).abs() <= slack,
+                    "chunk at {}, expected {}", chunk.time, time);
+            time = chunk.time
+                + frames(chunk) as f64 * chunk.time_scale / SAMPLE_RATE;
+            time_scale = chunk.time_scale;
+        }
+        assert!((time - SECONDS as f64).abs() < 1.0 / SAMPLE_RATE);
+    }
+
+    #[test]
+    fn unity_passes_through() {
+        let chunks = stretch(1.0);
+        assert_eq!(chunks.len(), SECONDS * 48);
+        check_times(&chunks);
+        for chunk in chunks.iter() {
+            assert_eq!(chunk.time_scale, 1.0);
+            let start = (chunk.time * SAMPLE_RATE).round() as usize;
+            let t = start as f64 / SAMPLE_RATE;
+            let x = (2.0 * PI * FREQUENCY * t).sin() as f32 * AMPLITUDE;
+            assert_eq!(&chunk.data[..2], &[x, -x]);
+        }
+    }
+
+    #[test]
+    fn tempos() {
+        for &tempo in &[0.5, 1.25, 2.0] {
+            let chunks = stretch(tempo);
+            check_times(&chunks);
+            // Everything but the held-back tail gets stretched...
+            let stretched: Vec<&Chunk> = chunks.iter()
+                .filter(|x| x.time_scale == tempo).collect();
+            let song_frames = stretched.iter()
+                .map(|x| frames(x) as f64 * tempo).sum::<f64>();
+            assert!(song_frames > SAMPLE_RATE * (SECONDS as f64 - 0.1));
+            // ...without changing the pitch or the loudness. Count how often
+            // the left channel crosses zero going up, and how loud it is.
+            let left: Vec<f32> = stretched.iter()
+                .flat_map(|x| x.data.iter().step_by(2).cloned()).collect();
+            let crossings = left.windows(2)
+                .filter(|x| x[0] < 0.0 && x[1] >= 0.0).count();
+            let seconds = left.len() as f64 / SAMPLE_RATE;
+            let frequency = crossings as f64 / seconds;
+            assert!((frequency - FREQUENCY).abs() < FREQUENCY * 0.01,
+                    "at tempo {}, came out at {} Hz", tempo, frequency);
+            let rms = (left.iter().map(|x| x * x).sum::<f32>()
+                       / left.len() as f32).sqrt();
+            let expected = AMPLITUDE * std::f32::consts::FRAC_1_SQRT_2;
+            assert!((rms - expected).abs() < expected * 0.05,
+                    "at tempo {}, RMS was {} instead of {}",
+                    tempo, rms, expected);
+            // The right channel is still the opposite of the left.
+            for chunk in stretched.iter() {
+                for frame in chunk.data.chunks_exact(2) {
+                    assert_eq!(frame[0], -frame[1]);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn seeking_starts_over() {
+        let song_id = SongID::from_inner(1);
+        let mut stretcher = Stretcher::new();
+        let mut chunks = Vec::new();
+        for &time in &[0.0, 5.0] {
+            stretcher.process(2.0, Chunk {
+                song_id, time, time_scale: 1.0, sample_rate: SAMPLE_RATE,
+                channel_count: 1, data: vec![0.5; SAMPLE_RATE as usize],
+            }, &mut |chunk| chunks.push(chunk));
+        }
+        // The audio held back from before the seek came out unstretched,
+        // before anything from after it.
+        let seek = chunks.iter().position(|x| x.time >= 5.0).unwrap();
+        assert!(seek > 0);
+        assert_eq!(chunks[seek - 1].time_scale, 1.0);
+        assert!(chunks[.. seek].iter().all(|x| x.time < 1.0));
+    }
+}
@@ -0,0 +1,956 @@
+//! This module syncs playlists to a portable player (or to any other folder).
+//!
+//! A *sync target* is a directory, some playlists, and some settings. Syncing
+//! it copies the best physical file of every song in those playlists into the
+//! directory, at a path made from the song's metadata (see `expand_template`),
+//! transcoding it first if the target wants a different format or a lower bit
+//! rate. The copies get the user's metadata as their tags; the original files
+//! are never touched. Each playlist becomes an M3U playlist in the top of the
+//! directory.
+//!
+//! We keep a manifest of everything we've written in the directory itself
+//! (see `MANIFEST_NAME`). It lets later syncs skip files that haven't changed,
+//! and delete files that aren't wanted anymore. Files that aren't in the
+//! manifest are never deleted or overwritten.
+//!
+//! The songs to sync are gathered by the UI thread, in `start_sync`; all the
+//! file work happens in a background thread.
+
+use crate::*;
+use crate::ffmpeg::Encoder;
+
+use anyhow::anyhow;
+use lazy_static::lazy_static;
+use libsoxr::Soxr;
+use log::{error, info, warn};
+use serde::{Serialize, Deserialize};
+use serde_json as json;
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    fs::{self, File},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::{Mutex, RwLock, atomic::{AtomicBool, Ordering}},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// The name of the manifest we keep in each target directory.
+const MANIFEST_NAME: &str = ".tsong-sync.json";
+
+/// The path template new sync targets start out with.
+pub const DEFAULT_PATH_TEMPLATE: &str
+    = "{album_artist|artist}/{album}/{track} {title}";
+
+/// The bit rate new sync targets start out with, in kbit/s.
+pub const DEFAULT_BIT_RATE: u32 = 192;
+
+/// A lossy source file is copied as it is, instead of transcoded, if its bit
+/// rate is at most this much more than the target's.
+const BIT_RATE_LEEWAY: f64 = 1.1;
+
+/// The longest we let a single file or directory name get, in characters.
+/// (Some players choke on long names.)
+const MAX_NAME_LENGTH: usize = 100;
+
+/// What format the copies on a sync target are in.
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub enum SyncFormat {
+    /// Whatever format the original is in. (Songs that are only part of a
+    /// file have to be transcoded anyway, and become FLAC.)
+    Copy,
+    Mp3,
+    Aac,
+    Opus,
+    Flac,
+}
+
+impl SyncFormat {
+    pub const ALL: &'static [SyncFormat] = &[
+        SyncFormat::Copy, SyncFormat::Mp3, SyncFormat::Aac, SyncFormat::Opus,
+        SyncFormat::Flac,
+    ];
+    pub fn to_db_value(&self) -> i8 {
+        match self {
+            SyncFormat::Copy => 0,
+            SyncFormat::Mp3 => 1,
+            SyncFormat::Aac => 2,
+            SyncFormat::Opus => 3,
+            SyncFormat::Flac => 4,
+        }
+    }
+    pub fn from_db_value(n: i64) -> SyncFormat {
+        match n {
+            1 => SyncFormat::Mp3,
+            2 => SyncFormat::Aac,
+            3 => SyncFormat::Opus,
+            4 => SyncFormat::Flac,
+            _ => SyncFormat::Copy, // be tolerant
+        }
+    }
+    // TODO: i18n
+    pub fn get_name(&self) -> &'static str {
+        match self {
+            SyncFormat::Copy => "Original format",
+            SyncFormat::Mp3 => "MP3",
+            SyncFormat::Aac => "AAC",
+            SyncFormat::Opus => "Opus",
+            SyncFormat::Flac => "FLAC",
+        }
+    }
+    /// Returns true if the bit rate setting means anything for this format.
+    pub fn has_bit_rate(&self) -> bool {
+        match self {
+            SyncFormat::Copy | SyncFormat::Flac => false,
+            _ => true,
+        }
+    }
+    /// Returns the details of encoding into this format: the encoders to try,
+    /// the container to put the result in, the extension to give the file,
+    /// and the name of the codec (to compare with what a source file has).
+    /// `Copy` is encoded the same way as `Flac`.
+    fn get_details(&self)
+    -> (&'static [&'static str], &'static str, &'static str, &'static str) {
+        match self {
+            SyncFormat::Mp3 => (&["libmp3lame"][..], "mp3", "mp3", "mp3"),
+            SyncFormat::Aac
+                => (&["libfdk_aac", "aac"][..], "ipod", "m4a", "aac"),
+            SyncFormat::Opus
+                => (&["libopus", "opus"][..], "ogg", "opus", "opus"),
+            SyncFormat::Copy | SyncFormat::Flac
+                => (&["flac"][..], "flac", "flac", "flac"),
+        }
+    }
+}
+
+#[derive(Clone,Debug,PartialEq)]
+pub struct SyncTarget {
+    pub id: u64,
+    pub name: String,
+    /// Where the copies go.
+    pub directory: PathBuf,
+    /// Where each song goes within `directory`. See `expand_template`.
+    pub path_template: String,
+    pub format: SyncFormat,
+    /// In kbit/s. Ignored if the format doesn't have a bit rate.
+    pub bit_rate: u32,
+    /// The playlists whose songs are synced.
+    pub playlist_ids: Vec<PlaylistID>,
+    /// When the target was last synced, in seconds since the UNIX epoch.
+    pub last_synced: Option<i64>,
+}
+
+/// What the manifest remembers about each copy it lists.
+#[derive(Clone,Debug,PartialEq,Serialize,Deserialize)]
+struct ManifestEntry {
+    /// The physical file it was made from.
+    file_id: String,
+    /// The track of that file it was made from.
+    track: u32,
+    /// The song's trim points, at the time.
+    trim: (f64, Option<f64>),
+    /// The target's format and bit rate, at the time.
+    settings: String,
+    /// The tags it was given.
+    tags: BTreeMap<String, String>,
+}
+
+/// Everything we've written to a target directory. Paths are relative to the
+/// directory, with `/` between components.
+#[derive(Debug,Default,Serialize,Deserialize)]
+struct Manifest {
+    files: BTreeMap<String, ManifestEntry>,
+    playlists: BTreeSet<String>,
+}
+
+/// A song to be synced, as gathered by `start_sync`.
+struct PlannedSong {
+    id: SongID,
+    metadata: BTreeMap<String, String>,
+    files: Vec<(FileID, u32)>,
+    trim: (f64, Option<f64>),
+    duration: u32,
+}
+
+/// A playlist to be written, as gathered by `start_sync`.
+struct PlannedPlaylist {
+    name: String,
+    songs: Vec<SongID>,
+}
+
+/// The physical file (and track of it) that a song will be copied from.
+struct Source {
+    file_id: FileID,
+    track: u32,
+    path: PathBuf,
+    span: physical::Span,
+    /// Estimated from the file's size and duration, in bits per second.
+    bit_rate: i64,
+}
+
+/// How far along the sync in progress is.
+#[derive(Clone,Copy,Debug,Default,PartialEq)]
+pub struct SyncProgress {
+    pub target_id: u64,
+    pub songs_done: usize,
+    pub songs_total: usize,
+}
+
+lazy_static! {
+    static ref TARGETS: RwLock<Vec<SyncTarget>> = RwLock::new(Vec::new());
+    static ref PROGRESS: Mutex<SyncProgress>
+        = Mutex::new(SyncProgress::default());
+}
+
+/// True while the sync thread is running.
+static SYNCING: AtomicBool = AtomicBool::new(false);
+/// Set by the sync thread when it's done. See `poll`.
+static SYNCED: AtomicBool = AtomicBool::new(false);
+/// Set when the user wants the sync in progress to stop.
+static CANCEL: AtomicBool = AtomicBool::new(false);
+
+fn now() -> i64 {
+    SystemTime::now().duration_since(UNIX_EPOCH)
+        .map(|x| x.as_secs() as i64).unwrap_or(0)
+}
+
+/// Called by the database when loading sync targets.
+pub fn add_target_from_db(target: SyncTarget) {
+    TARGETS.write().unwrap().push(target);
+}
+
+/// Returns every sync target, in the order they were made.
+pub fn get_targets() -> Vec<SyncTarget> {
+    TARGETS.read().unwrap().clone()
+}
+
+/// Makes a new sync target, with default settings and no playlists.
+pub fn create_target(name: &str, directory: PathBuf)
+-> anyhow::Result<SyncTarget> {
+    let mut target = SyncTarget {
+        id: 0, name: name.to_owned(), directory,
+        path_template: DEFAULT_PATH_TEMPLATE.to_owned(),
+        format: SyncFormat::Copy, bit_rate: DEFAULT_BIT_RATE,
+        playlist_ids: Vec::new(), last_synced: None,
+    };
+    target.id = db::create_sync_target(&target)?;
+    TARGETS.write().unwrap().push(target.clone());
+    Ok(target)
+}
+
+/// Replaces the sync target with the same id.
+pub fn update_target(target: &SyncTarget) {
+    let mut targets = TARGETS.write().unwrap();
+    if let Some(old) = targets.iter_mut().find(|x| x.id == target.id) {
+        // (the sync thread is the only one who changes this)
+        let last_synced = old.last_synced;
+        *old = target.clone();
+        old.last_synced = last_synced;
+        db::update_sync_target(old);
+    }
+}
+
+/// Forgets a sync target. Whatever was already copied to it stays there.
+pub fn delete_target(id: u64) {
+    TARGETS.write().unwrap().retain(|x| x.id != id);
+    db::delete_sync_target(id);
+}
+
+/// Starts syncing the given target in the background. Must be called from
+/// the UI thread, since it refreshes the target's playlists.
+pub fn start_sync(id: u64) -> anyhow::Result<()> {
+    let target = get_targets().into_iter().find(|x| x.id == id)
+        .ok_or_else(|| anyhow!("No such sync target"))?;
+    if !target.directory.is_dir() {
+        return Err(anyhow!("The folder {:?} doesn't exist (is the player \
+                            plugged in?)", target.directory))
+    }
+    if target.playlist_ids.is_empty() {
+        return Err(anyhow!("No playlists are chosen to be synced"))
+    }
+    let mut songs = Vec::new();
+    let mut playlists = Vec::new();
+    let mut seen = HashSet::new();
+    for &playlist_id in target.playlist_ids.iter() {
+        let playlist_ref = match playlist::get_playlist_by_id(playlist_id) {
+            Some(x) => x,
+            None => continue,
+        };
+        let playlist = playlist_ref.maybe_refreshed();
+        let mut planned = PlannedPlaylist {
+            name: playlist.get_name().to_owned(),
+            songs: Vec::with_capacity(playlist.get_songs().len()),
+        };
+        for song_ref in playlist.get_songs().iter() {
+            let song = song_ref.read().unwrap();
+            if song.is_stream() { continue }
+            planned.songs.push(song.get_id());
+            if !seen.insert(song.get_id()) { continue }
+            songs.push(PlannedSong {
+                id: song.get_id(),
+                metadata: song.get_metadata().clone(),
+                files: song.get_physical_files().iter().cloned()
+                    .zip(song.get_physical_tracks().iter().cloned())
+                    .collect(),
+                trim: song.get_trim(),
+                duration: song.get_duration(),
+            });
+        }
+        playlists.push(planned);
+    }
+    if SYNCING.compare_exchange(false, true, Ordering::SeqCst,
+                                Ordering::SeqCst).is_err() {
+        return Err(anyhow!("A sync is already in progress"))
+    }
+    CANCEL.store(false, Ordering::SeqCst);
+    *PROGRESS.lock().unwrap() = SyncProgress {
+        target_id: id, songs_done: 0, songs_total: songs.len(),
+    };
+    errors::reset_from("Sync");
+    let spawned = std::thread::Builder::new()
+        .name("sync thread".to_owned())
+        .spawn(move || {
+            if let Err(x) = sync_target(&target, songs, playlists) {
+                let wat = format!("Couldn't sync {}: {:#}", target.name, x);
+                error!("{}", wat);
+                errors::from("Sync", wat);
+            }
+            SYNCED.store(true, Ordering::SeqCst);
+            SYNCING.store(false, Ordering::SeqCst);
+        });
+    if let Err(x) = spawned {
+        SYNCING.store(false, Ordering::SeqCst);
+        return Err(anyhow!("Unable to spawn sync thread: {:?}", x))
+    }
+    Ok(())
+}
+
+/// Asks the sync in progress (if any) to stop as soon as it can. Whatever it
+/// has already copied stays, and will be skipped next time.
+pub fn cancel_sync() {
+    CANCEL.store(true, Ordering::SeqCst);
+}
+
+/// Returns true while a sync is in progress.
+pub fn is_syncing() -> bool {
+    SYNCING.load(Ordering::SeqCst)
+}
+
+/// Returns how far along the sync in progress is, if there is one.
+pub fn get_progress() -> Option<SyncProgress> {
+    if is_syncing() { Some(*PROGRESS.lock().unwrap()) }
+    else { None }
+}
+
+/// Called periodically by the UI. Returns true if a sync has finished since
+/// the last call.
+pub fn poll() -> bool {
+    SYNCED.swap(false, Ordering::SeqCst)
+}
+
+fn is_cancelled() -> bool {
+    CANCEL.load(Ordering::SeqCst)
+}
+
+/// Does the actual syncing, in the sync thread.
+fn sync_target(target: &SyncTarget, songs: Vec<PlannedSong>,
+               playlists: Vec<PlannedPlaylist>) -> anyhow::Result<()> {
+    info!("Syncing {:?} to {:?}", target.name, target.directory);
+    let root = &target.directory;
+    let old_manifest = read_manifest(root)?;
+    let mut manifest = Manifest::default();
+    let settings = if target.format.has_bit_rate() {
+        format!("{}@{}", target.format.get_name(), target.bit_rate)
+    } else { target.format.get_name().to_owned() };
+    // (lowercase, because some players' filesystems don't care about case)
+    let mut used_paths = HashSet::new();
+    let mut song_paths = HashMap::new();
+    for (n, song) in songs.iter().enumerate() {
+        if is_cancelled() { break }
+        PROGRESS.lock().unwrap().songs_done = n;
+        let source = match choose_source(song) {
+            Some(x) => x,
+            None => {
+                let wat = format!("Couldn't find any file for {}",
+                                  describe_song(&song.metadata));
+                warn!("{}", wat);
+                errors::from("Sync", wat);
+                continue
+            },
+        };
+        let whole = source.track == 0 && song.trim == (0.0, None);
+        // (a song that's all of a file we know how to write goes to a target
+        // that takes any format as it is)
+        let container = match target.format {
+            SyncFormat::Copy if whole
+                => ffmpeg::guess_container(&source.path),
+            _ => None,
+        };
+        let extension = match container {
+            Some(_) => source.path.extension()
+                .map(|x| x.to_string_lossy().to_lowercase())
+                .unwrap_or_else(|| "flac".to_owned()),
+            None => target.format.get_details().2.to_owned(),
+        };
+        let rel_path = choose_path(&target.path_template, &song.metadata,
+                                   &extension, root, &old_manifest,
+                                   &mut used_paths);
+        let entry = ManifestEntry {
+            file_id: source.file_id.to_string(),
+            track: source.track,
+            trim: song.trim,
+            settings: settings.clone(),
+            tags: writeback::make_file_tags(&song.metadata),
+        };
+        let path = root.join(&rel_path);
+        if old_manifest.files.get(&rel_path) == Some(&entry) && path.exists() {
+            manifest.files.insert(rel_path.clone(), entry);
+            song_paths.insert(song.id, rel_path);
+            continue
+        }
+        info!("Syncing {:?}", rel_path);
+        match write_copy(target.format, target.bit_rate, &source, song,
+                         whole, container.as_ref().map(String::as_str),
+                         &path, &entry.tags) {
+            Ok(_) => {
+                manifest.files.insert(rel_path.clone(), entry);
+                song_paths.insert(song.id, rel_path);
+            },
+            Err(_) if is_cancelled() => break,
+            Err(x) => {
+                let wat = format!("Couldn't sync {:?}: {:#}", rel_path, x);
+                error!("{}", wat);
+                errors::from("Sync", wat);
+                // whatever was there before is still there
+                if let Some(old) = old_manifest.files.get(&rel_path) {
+                    manifest.files.insert(rel_path.clone(), old.clone());
+                }
+            },
+        }
+    }
+    if is_cancelled() {
+        // Remember everything that's there now, and leave the cleaning up
+        // for a sync that finishes.
+        info!("Sync of {:?} cancelled", target.name);
+        for (k, v) in old_manifest.files.into_iter() {
+            manifest.files.entry(k).or_insert(v);
+        }
+        manifest.playlists = old_manifest.playlists;
+        return write_manifest(root, &manifest)
+    }
+    PROGRESS.lock().unwrap().songs_done = songs.len();
+    let songs_by_id: HashMap<SongID, &PlannedSong>
+        = songs.iter().map(|x| (x.id, x)).collect();
+    for playlist in playlists.iter() {
+        let mut name = sanitize_component(&playlist.name);
+        name.push_str(".m3u8");
+        let mut n = 2;
+        // (as in `choose_path`, never overwrite a file we didn't write)
+        while manifest.playlists.contains(&name)
+        || manifest.files.contains_key(&name)
+        || (!old_manifest.playlists.contains(&name)
+            && root.join(&name).exists()) {
+            name = format!("{} ({}).m3u8",
+                           sanitize_component(&playlist.name), n);
+            n += 1;
+        }
+        let mut m3u = "#EXTM3U\n".to_owned();
+        for id in playlist.songs.iter() {
+            let (rel_path, song) = match (song_paths.get(id),
+                                          songs_by_id.get(id)) {
+                (Some(x), Some(y)) => (x, y),
+                _ => continue,
+            };
+            m3u.push_str(&format!("#EXTINF:{},{}\n{}\n", song.duration,
+                                  describe_song(&song.metadata), rel_path));
+        }
+        if let Err(x) = write_atomically(&root.join(&name),
+                                         m3u.as_bytes()) {
+            let wat = format!("Couldn't write playlist {:?}: {}", name, x);
+            error!("{}", wat);
+            errors::from("Sync", wat);
+            continue
+        }
+        manifest.playlists.insert(name);
+    }
+    // Now get rid of whatever we wrote before that isn't wanted anymore.
+    let stale = old_manifest.files.keys()
+        .filter(|x| !manifest.files.contains_key(*x))
+        .chain(old_manifest.playlists.iter()
+               .filter(|x| !manifest.playlists.contains(*x)));
+    for rel_path in stale {
+        info!("Deleting stale {:?}", rel_path);
+        let path = root.join(rel_path);
+        match fs::remove_file(&path) {
+            Err(x) if x.kind() != io::ErrorKind::NotFound =>
+                warn!("Couldn't delete {:?}: {}", path, x),
+            _ => remove_empty_parents(root, &path),
+        }
+    }
+    write_manifest(root, &manifest)?;
+    let mut targets = TARGETS.write().unwrap();
+    if let Some(target) = targets.iter_mut().find(|x| x.id == target.id) {
+        target.last_synced = Some(now());
+        db::update_sync_target(target);
+    }
+    info!("Done syncing {:?}", target.name);
+    Ok(())
+}
+
+/// Describes a song as "Artist - Title", for M3U playlists and errors.
+fn describe_song(metadata: &BTreeMap<String, String>) -> String {
+    let title = metadata.get("title").map(String::as_str)
+        .unwrap_or("Untitled");
+    match metadata.get("artist") {
+        Some(artist) => format!("{} - {}", artist, title),
+        None => title.to_owned(),
+    }
+}
+
+/// Picks the best physical file of a song that we can find right now: the
+/// one with the highest bit rate (which will be a lossless one, if there's
+/// one of those).
+fn choose_source(song: &PlannedSong) -> Option<Source> {
+    song.files.iter().filter_map(|&(file_id, track)| {
+        let file_ref = physical::get_file_by_id(&file_id)?;
+        let file = file_ref.read().unwrap();
+        let path = file.get_absolute_paths().iter()
+            .find(|x| x.is_file())?.clone();
+        let bit_rate = file.get_size() as i64 * 8
+            / file.get_duration().max(1) as i64;
+        Some(Source {
+            file_id, track, path, span: file.get_track_span(track), bit_rate,
+        })
+    }).max_by_key(|x| x.bit_rate)
+}
+
+/// Chooses where a song goes, relative to the target directory: the path
+/// template expanded with its metadata, with a number added if that path has
+/// already been used this sync, or if there's a file there that we didn't put
+/// there.
+fn choose_path(template: &str, metadata: &BTreeMap<String, String>,
+               extension: &str, root: &Path, old_manifest: &Manifest,
+               used_paths: &mut HashSet<String>) -> String {
+    let base = expand_template(template, metadata);
+    let mut rel_path = format!("{}.{}", base, extension);
+    let mut n = 2;
+    while used_paths.contains(&rel_path.to_lowercase())
+    || (!old_manifest.files.contains_key(&rel_path)
+        && root.join(&rel_path).exists()) {
+        rel_path = format!("{} ({}).{}", base, n, extension);
+        n += 1;
+    }
+    used_paths.insert(rel_path.to_lowercase());
+    rel_path
+}
+
+/// Expands a path template with a song's metadata. Each `{key}` in the
+/// template is replaced with that metadata (or, failing that, with the tag of
+/// that name that the copy will get), and `{key1|key2}` uses `key2` if there
+/// is no `key1`. `{track}` and `{disc}` leave off any "of N" part, and
+/// `{track}` is padded to two digits. Components that come out empty become
+/// "Unknown". Returns the path (without an extension), relative to the target
+/// directory, with `/` between components.
+fn expand_template(template: &str, metadata: &BTreeMap<String, String>)
+-> String {
+    let tags = writeback::make_file_tags(metadata);
+    let components: Vec<String> = template.split('/')
+        .filter(|x| !x.trim().is_empty())
+        .map(|component| {
+            let mut expanded = String::new();
+            let mut rest = component;
+            while let Some(start) = rest.find('{') {
+                let end = match rest[start..].find('}') {
+                    Some(x) => start + x,
+                    None => break,
+                };
+                expanded.push_str(&rest[..start]);
+                let value = rest[start+1 .. end].split('|')
+                    .map(str::trim)
+                    .find_map(|key| get_template_value(key, metadata)
+                              .or_else(|| get_template_value(key, &tags)));
+                if let Some(value) = value {
+                    expanded.push_str(&value);
+                }
+                rest = &rest[end+1 ..];
+            }
+            expanded.push_str(rest);
+            sanitize_component(&expanded)
+        }).collect();
+    if components.is_empty() { "Unknown".to_owned() }
+    else { components.join("/") }
+}
+
+/// Returns the value of a piece of metadata, as it should go in a path.
+fn get_template_value(key: &str, metadata: &BTreeMap<String, String>)
+-> Option<String> {
+    let value = metadata.get(key)?.trim();
+    let value = match key {
+        "track" | "disc" => value.split('/').next().unwrap_or("").trim(),
+        _ => value,
+    };
+    if value.is_empty() { return None }
+    // (slashes in metadata mustn't make new directories)
+    let value = value.replace(&['/', '\\'][..], "_");
+    match (key, value.parse::<u32>()) {
+        ("track", Ok(x)) => Some(format!("{:02}", x)),
+        _ => Some(value),
+    }
+}
+
+/// Makes a single file or directory name safe for any filesystem a portable
+/// player is likely to have.
+fn sanitize_component(name: &str) -> String {
+    const RESERVED: &[&str] = &[
+        "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5",
+        "COM6", "COM7", "COM8", "COM9", "LPT1", "LPT2", "LPT3", "LPT4",
+        "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+    ];
+    let ret: String = name.chars()
+        .map(|c| if c.is_control() || "<>:\"/\\|?*".contains(c) { '_' }
+             else { c })
+        .take(MAX_NAME_LENGTH).collect();
+    // (no hidden files, and FAT doesn't like trailing dots)
+    let ret = ret.trim().trim_start_matches('.')
+        .trim_end_matches(&['.', ' '][..]);
+    if ret.is_empty() { "Unknown".to_owned() }
+    else if RESERVED.contains(&ret.to_uppercase().as_str()) {
+        format!("{}_", ret)
+    }
+    else { ret.to_owned() }
+}
+
+/// Writes the copy of a song to `path`, by way of a temporary file in the
+/// same directory. If `container` is given, the source file is copied into
+/// it as it is. Otherwise, it's transcoded into the target's format (unless
+/// it's already in that format).
+fn write_copy(format: SyncFormat, bit_rate: u32, source: &Source,
+              song: &PlannedSong, whole: bool, container: Option<&str>,
+              path: &Path, tags: &BTreeMap<String, String>)
+-> anyhow::Result<()> {
+    let dir = path.parent().unwrap();
+    fs::create_dir_all(dir)?;
+    let temp_path = dir.join(format!(".{}.part",
+                                     path.file_name().unwrap()
+                                     .to_string_lossy()));
+    let result = match container {
+        Some(container) => ffmpeg::remux(&source.path, &temp_path, container,
+                                         tags),
+        None if whole && can_remux(format, bit_rate, source) =>
+            ffmpeg::remux(&source.path, &temp_path, format.get_details().1,
+                          tags)
+            .or_else(|x| {
+                // Some streams can't go in the container we want them in.
+                // Transcoding always works.
+                warn!("Couldn't remux {:?}, transcoding instead: {:#}",
+                      source.path, x);
+                transcode(format, bit_rate, source, song, &temp_path, tags)
+            }),
+        None => transcode(format, bit_rate, source, song, &temp_path, tags),
+    };
+    if let Err(x) = result {
+        let _ = fs::remove_file(&temp_path);
+        return Err(x)
+    }
+    fs::rename(&temp_path, path)?;
+    Ok(())
+}
+
+/// Returns true if a whole source file can go to the target as it is (with
+/// new tags), instead of being transcoded: if it's already in the target's
+/// format, at a bit rate not much higher.
+fn can_remux(format: SyncFormat, bit_rate: u32, source: &Source) -> bool {
+    let mut avf = match ffmpeg::AVFormat::open_input(&source.path) {
+        Ok(x) => x,
+        Err(_) => return false,
+    };
+    let stream = match avf.find_stream_info().ok()
+        .and_then(|_| avf.find_best_stream().ok().flatten()) {
+            Some(x) => x,
+            None => return false,
+        };
+    if avf.get_codec_name(stream) != format.get_details().3 { return false }
+    if !format.has_bit_rate() { return true }
+    let source_rate = avf.get_bit_rate(stream).unwrap_or(source.bit_rate);
+    source_rate as f64 <= bit_rate as f64 * 1000.0 * BIT_RATE_LEEWAY
+}
+
+/// Decodes the part of the source file that is the song, and encodes it into
+/// a new file at `path`.
+fn transcode(format: SyncFormat, bit_rate: u32, source: &Source,
+             song: &PlannedSong, path: &Path,
+             tags: &BTreeMap<String, String>) -> anyhow::Result<()> {
+    let (codec_names, container, _, _) = format.get_details();
+    let mut avf = ffmpeg::AVFormat::open_input(&source.path)?;
+    avf.find_stream_info()?;
+    let stream = avf.find_best_stream()?
+        .ok_or_else(|| anyhow!("Is this not a music file?"))?;
+    let durr = avf.open_stream(stream)?;
+    // (the same arithmetic as playback uses)
+    let (start_time, end_time) = song.trim;
+    let track = source.span;
+    let track_end = track.end.unwrap_or(durr as f64).max(track.start);
+    let trimmed_end = match end_time {
+        Some(x) => track_end.min(track.start + x),
+        None => track_end,
+    };
+    let start = (track.start + start_time).min(trimmed_end);
+    let end = if trimmed_end < track_end || track.end.is_some() {
+        Some(trimmed_end)
+    } else { None };
+    if start > 0.0 {
+        avf.seek_to_time(start);
+    }
+    // (encoder, its channel count)
+    let mut encoder: Option<(Encoder, usize)> = None;
+    // (input rate, resampler)
+    let mut resampler: Option<(f64, Soxr)> = None;
+    let mut decoded = Vec::new();
+    let mut mixed = Vec::new();
+    let mut resampled = Vec::new();
+    // (everything goes straight into the file)
+    let mut out = Vec::new();
+    let mut eof = false;
+    let mut done = false;
+    while !eof && !done {
+        if is_cancelled() { return Err(anyhow!("Cancelled")) }
+        eof = !avf.decode_some(|time, sample_rate, channel_count, buf| {
+            decoded.push((time, sample_rate, channel_count, buf))
+        });
+        for (time, sample_rate, channel_count, mut buf) in decoded.drain(..) {
+            if done || channel_count <= 0 {
+                bufring::finished_with_buf(buf);
+                continue
+            }
+            let channel_count = channel_count as usize;
+            if let Some(end) = end {
+                let max_frames = ((end - time) * sample_rate).round()
+                    .max(0.0) as usize;
+                if buf.len() / channel_count >= max_frames {
+                    buf.truncate(max_frames * channel_count);
+                    done = true;
+                }
+            }
+            if encoder.is_none() {
+                // Portable players want stereo, but FLAC may as well keep
+                // everything.
+                let out_channels = match format {
+                    SyncFormat::Copy | SyncFormat::Flac
+                        => channel_count.min(8),
+                    _ => channel_count.min(2),
+                };
+                encoder = Some((Encoder::create_file
+                                (path, codec_names, container, tags,
+                                 sample_rate as i32, out_channels as i32,
+                                 bit_rate as i64 * 1000)?,
+                                out_channels));
+            }
+            let (encoder, out_channels) = encoder.as_mut().unwrap();
+            remix(&buf, channel_count, *out_channels, &mut mixed);
+            bufring::finished_with_buf(buf);
+            let out_rate = encoder.get_sample_rate() as f64;
+            if sample_rate == out_rate {
+                encoder.encode(&mixed[..], &mut out)?;
+            }
+            else {
+                resample(&mut resampler, sample_rate, out_rate,
+                         *out_channels, Some(&mixed[..]), &mut resampled)?;
+                encoder.encode(&resampled[..], &mut out)?;
+            }
+        }
+    }
+    let (mut encoder, out_channels) = encoder
+        .ok_or_else(|| anyhow!("No audio could be decoded"))?;
+    if let Some(in_rate) = resampler.as_ref().map(|x| x.0) {
+        // get the last little bit out of the resampler
+        resample(&mut resampler, in_rate, encoder.get_sample_rate() as f64,
+                 out_channels, None, &mut resampled)?;
+        encoder.encode(&resampled[..], &mut out)?;
+    }
+    encoder.finish(&mut out)?;
+    Ok(())
+}
+
+/// Copies interleaved audio with `in_channels` channels into `out`, with
+/// `out_channels` channels. Mono goes to every channel; other channels that
+/// don't fit are left out.
+fn remix(data: &[f32], in_channels: usize, out_channels: usize,
+         out: &mut Vec<f32>) {
+    out.clear();
+    if in_channels == out_channels {
+        out.extend_from_slice(data);
+        return
+    }
+    for frame in data.chunks_exact(in_channels) {
+        for c in 0 .. out_channels {
+            out.push(frame[c.min(in_channels - 1)]);
+        }
+    }
+}
+
+/// Resamples some interleaved audio into `out`, (re)creating the resampler if
+/// the input rate has changed. If `data` is `None`, gets whatever the
+/// resampler is still holding on to instead.
+fn resample(resampler: &mut Option<(f64, Soxr)>, in_rate: f64, out_rate: f64,
+            channels: usize, data: Option<&[f32]>, out: &mut Vec<f32>)
+-> anyhow::Result<()> {
+    if resampler.as_ref().map(|x| x.0) != Some(in_rate) {
+        *resampler = Some((in_rate,
+                           Soxr::create(in_rate, out_rate, channels as u32,
+                                        None, None, None)?));
+    }
+    let soxr = &resampler.as_ref().unwrap().1;
+    out.clear();
+    let mut out_pos = 0;
+    match data {
+        Some(data) => {
+            out.resize((data.len() as f64 * out_rate / in_rate).ceil()
+                       as usize + 200, 0.0);
+            let mut rem = data;
+            while rem.len() > 0 {
+                let (in_frames, out_frames)
+                    = soxr.process(Some(rem), &mut out[out_pos..])?;
+                rem = &rem[in_frames * channels..];
+                out_pos += out_frames * channels;
+                if rem.len() > 0 {
+                    out.resize(out.len() * 2, 0.0);
+                }
+            }
+        },
+        None => loop {
+            out.resize(out_pos + 4096 * channels, 0.0);
+            let (_, out_frames) = soxr.process::<f32, f32>
+                (None, &mut out[out_pos..])?;
+            if out_frames == 0 { break }
+            out_pos += out_frames * channels;
+        },
+    }
+    out.truncate(out_pos);
+    Ok(())
+}
+
+/// Reads the manifest in a target directory. A directory we haven't synced to
+/// before has an empty one.
+fn read_manifest(root: &Path) -> anyhow::Result<Manifest> {
+    match File::open(root.join(MANIFEST_NAME)) {
+        Ok(file) => json::from_reader(io::BufReader::new(file))
+            .map_err(|x| anyhow!("The sync manifest is corrupted: {}", x)),
+        Err(x) if x.kind() == io::ErrorKind::NotFound
+            => Ok(Manifest::default()),
+        Err(x) => Err(x.into()),
+    }
+}
+
+fn write_manifest(root: &Path, manifest: &Manifest) -> anyhow::Result<()> {
+    write_atomically(&root.join(MANIFEST_NAME),
+                     json::to_string(manifest)?.as_bytes())
+}
+
+/// Writes a file by way of a temporary file in the same directory, so that
+/// nothing ever sees it half written.
+fn write_atomically(path: &Path, data: &[u8]) -> anyhow::Result<()> {
+    let temp_path = path.with_file_name(format!(".{}.part",
+                                                path.file_name().unwrap()
+                                                .to_string_lossy()));
+    let result = File::create(&temp_path).and_then(|mut file| {
+        file.write_all(data)?;
+        file.flush()
+    });
+    if let Err(x) = result {
+        let _ = fs::remove_file(&temp_path);
+        return Err(x.into())
+    }
+    fs::rename(&temp_path, path)?;
+    Ok(())
+}
+
+/// Removes the directories containing a file we just deleted, as long as
+/// they're empty, up to (but not including) the target directory.
+fn remove_empty_parents(root: &Path, path: &Path) {
+    let mut dir = path.parent();
+    while let Some(x) = dir {
+        if x == root || !x.starts_with(root) { break }
+        // (this fails if it isn't empty, which is what we want)
+        if fs::remove_dir(x).is_err() { break }
+        dir = x.parent();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn metadata(pairs: &[(&str, &str)]) -> BTreeMap<String, String> {
+        pairs.iter().map(|&(k, v)| (k.to_owned(), v.to_owned())).collect()
+    }
+
+    #[test]
+    fn templates() {
+        let song = metadata(&[("artist", "Some Band"), ("album", "Debut"),
+                              ("title", "Opener"), ("track#", "3"),
+                              ("#tracks", "12"), ("disc#", "1"),
+                              ("#discs", "2")]);
+        assert_eq!(expand_template(DEFAULT_PATH_TEMPLATE, &song),
+                   "Some Band/Debut/03 Opener");
+        assert_eq!(expand_template("{disc}-{track}", &song), "1-03");
+        let mut with_album_artist = song.clone();
+        with_album_artist.insert("album_artist".to_owned(),
+                                 "Various".to_owned());
+        assert_eq!(expand_template(DEFAULT_PATH_TEMPLATE, &with_album_artist),
+                   "Various/Debut/03 Opener");
+        // (missing metadata, and empty components)
+        assert_eq!(expand_template(DEFAULT_PATH_TEMPLATE, &BTreeMap::new()),
+                   "Unknown/Unknown/Unknown");
+        assert_eq!(expand_template("//{title}/", &song), "Opener");
+        assert_eq!(expand_template("", &song), "Unknown");
+        // (a brace that's never closed is left as it is)
+        assert_eq!(expand_template("{title} {album", &song),
+                   "Opener {album");
+    }
+
+    #[test]
+    fn unsafe_metadata() {
+        let song = metadata(&[("artist", "AC/DC"), ("title", "What?"),
+                              ("album", "...")]);
+        assert_eq!(expand_template("{artist}/{album}/{title}", &song),
+                   "AC_DC/Unknown/What_");
+        assert_eq!(sanitize_component("con"), "con_");
+        assert_eq!(sanitize_component(" .hidden. "), "hidden");
+        assert_eq!(sanitize_component("a\tb:c"), "a_b_c");
+        assert_eq!(sanitize_component(&"x".repeat(200)).len(),
+                   MAX_NAME_LENGTH);
+    }
+
+    #[test]
+    fn paths() {
+        let root = std::env::temp_dir()
+            .join(format!("tsong-sync-test-{}", std::process::id()));
+        fs::create_dir_all(root.join("Band")).unwrap();
+        fs::write(root.join("Band/Theirs.mp3"), b"").unwrap();
+        fs::write(root.join("Band/Ours.mp3"), b"").unwrap();
+        let mut old_manifest = Manifest::default();
+        old_manifest.files.insert("Band/Ours.mp3".to_owned(),
+                                  ManifestEntry {
+                                      file_id: String::new(), track: 0,
+                                      trim: (0.0, None),
+                                      settings: String::new(),
+                                      tags: BTreeMap::new(),
+                                  });
+        let mut used_paths = HashSet::new();
+        let mut choose = |title: &str| {
+            choose_path("{artist}/{title}",
+                        &metadata(&[("artist", "Band"), ("title", title)]),
+                        "mp3", &root, &old_manifest, &mut used_paths)
+        };
+        let ours = choose("Ours");
+        let theirs = choose("Theirs");
+        let new = choose("New");
+        let again = choose("new");
+        let ours_again = choose("Ours");
+        fs::remove_dir_all(&root).unwrap();
+        assert_eq!(ours, "Band/Ours.mp3");
+        assert_eq!(theirs, "Band/Theirs (2).mp3");
+        assert_eq!(new, "Band/New.mp3");
+        assert_eq!(again, "Band/new (2).mp3");
+        assert_eq!(ours_again, "Band/Ours (2).mp3");
+    }
+}
@@ -0,0 +1,327 @@
+use crate::*;
+use log::error;
+use gtk::{
+    prelude::*,
+    Align,
+    BoxBuilder,
+    ButtonBoxBuilder, ButtonBoxStyle,
+    Button, ButtonBuilder,
+    CellRendererText,
+    CheckButton,
+    ComboBoxText,
+    LabelBuilder,
+    ListStore,
+    Orientation,
+    PolicyType,
+    ScrolledWindowBuilder,
+    SeparatorBuilder,
+    SpinButton,
+    TreeView, TreeViewBuilder, TreeViewColumn,
+    Window, WindowBuilder, WindowType,
+};
+use glib::{
+    Type
+};
+use std::{
+    cell::RefCell,
+    rc::Rc,
+};
+use alarm::Alarm;
+
+const ID_COLUMN: u32 = 0;
+const TIME_COLUMN: u32 = 1;
+const DAYS_COLUMN: u32 = 2;
+const PLAYLIST_COLUMN: u32 = 3;
+const ENABLED_COLUMN: u32 = 4;
+
+pub struct Controller {
+    window: Window,
+    alarms_view: TreeView,
+    alarms_model: ListStore,
+    new_button: Button,
+    delete_button: Button,
+    save_button: Button,
+    form_box: gtk::Box,
+    enabled_box: CheckButton,
+    hour_spin: SpinButton,
+    minute_spin: SpinButton,
+    day_boxes: Vec<CheckButton>,
+    playlist_view: ComboBoxText,
+    shuffle_box: CheckButton,
+    set_volume_box: CheckButton,
+    volume_spin: SpinButton,
+    ramp_spin: SpinButton,
+}
+
+impl Controller {
+    pub fn new() -> Rc<RefCell<Controller>> {
+        // TODO: i18n
+        let window = WindowBuilder::new()
+            .name("alarms").type_(WindowType::Toplevel)
+            .title("Tsong - Alarms").build();
+        let big_box = BoxBuilder::new()
+            .name("alarms").spacing(4).orientation(Orientation::Vertical)
+            .build();
+        window.add(&big_box);
+        let alarms_window = ScrolledWindowBuilder::new()
+            .hscrollbar_policy(PolicyType::Automatic)
+            .vscrollbar_policy(PolicyType::Automatic)
+            .vexpand(true).min_content_height(100)
+            .build();
+        let alarms_view = TreeViewBuilder::new()
+            .tooltip_text("Alarms start a playlist at a particular time of \
+                           day. They only go off while Tsong is running.")
+            .headers_visible(true).build();
+        for &(title, column) in &[("Time", TIME_COLUMN),
+                                  ("Days", DAYS_COLUMN),
+                                  ("Playlist", PLAYLIST_COLUMN),
+                                  ("On", ENABLED_COLUMN)] {
+            let view_column = TreeViewColumn::new();
+            view_column.set_title(title);
+            view_column.set_expand(column == PLAYLIST_COLUMN);
+            let cell = CellRendererText::new();
+            view_column.pack_start(&cell, true);
+            view_column.add_attribute(&cell, "text", column as i32);
+            alarms_view.append_column(&view_column);
+        }
+        let alarms_model = ListStore::new(&[Type::U64, Type::String,
+                                            Type::String, Type::String,
+                                            Type::String]);
+        alarms_window.add(&alarms_view);
+        big_box.add(&alarms_window);
+        let list_button_box = ButtonBoxBuilder::new()
+            .layout_style(ButtonBoxStyle::Expand)
+            .build();
+        let new_button = ButtonBuilder::new()
+            .label("New Alarm").build();
+        list_button_box.add(&new_button);
+        let delete_button = ButtonBuilder::new()
+            .label("Delete Alarm").build();
+        list_button_box.add(&delete_button);
+        big_box.add(&list_button_box);
+        big_box.add(&SeparatorBuilder::new()
+                    .orientation(Orientation::Horizontal).build());
+        let form_box = BoxBuilder::new()
+            .spacing(4).orientation(Orientation::Vertical).build();
+        big_box.add(&form_box);
+        let enabled_box = CheckButton::with_label("Enabled");
+        form_box.add(&enabled_box);
+        let time_box = BoxBuilder::new()
+            .spacing(4).orientation(Orientation::Horizontal).build();
+        time_box.add(&LabelBuilder::new().label("Time:").build());
+        let hour_spin = SpinButton::with_range(0.0, 23.0, 1.0);
+        hour_spin.set_wrap(true);
+        time_box.add(&hour_spin);
+        time_box.add(&LabelBuilder::new().label(":").build());
+        let minute_spin = SpinButton::with_range(0.0, 59.0, 1.0);
+        minute_spin.set_wrap(true);
+        time_box.add(&minute_spin);
+        form_box.add(&time_box);
+        let days_box = BoxBuilder::new()
+            .spacing(4).orientation(Orientation::Horizontal)
+            .tooltip_text("If no days are checked, the alarm goes off once, \
+                           then turns itself off.")
+            .build();
+        let day_boxes: Vec<CheckButton> = alarm::DAY_NAMES.iter()
+            .map(|name| CheckButton::with_label(name)).collect();
+        for day_box in day_boxes.iter() {
+            days_box.add(day_box);
+        }
+        form_box.add(&days_box);
+        form_box.add(&LabelBuilder::new()
+                     .label("Playlist:").halign(Align::Start).build());
+        let playlist_view = ComboBoxText::new();
+        form_box.add(&playlist_view);
+        let shuffle_box = CheckButton::with_label("Shuffle the playlist");
+        form_box.add(&shuffle_box);
+        let volume_box = BoxBuilder::new()
+            .spacing(4).orientation(Orientation::Horizontal).build();
+        let set_volume_box = CheckButton::with_label("Set the volume to:");
+        volume_box.add(&set_volume_box);
+        let volume_spin = SpinButton::with_range(0.0, 200.0, 1.0);
+        volume_box.add(&volume_spin);
+        volume_box.add(&LabelBuilder::new().label("%").build());
+        form_box.add(&volume_box);
+        let ramp_box = BoxBuilder::new()
+            .spacing(4).orientation(Orientation::Horizontal)
+            .tooltip_text("How long it takes the volume to come up from \
+                           silence.")
+            .build();
+        ramp_box.add(&LabelBuilder::new().label("Fade in over").build());
+        let ramp_spin = SpinButton::with_range(0.0, 600.0, 5.0);
+        ramp_box.add(&ramp_spin);
+        ramp_box.add(&LabelBuilder::new().label("seconds").build());
+        form_box.add(&ramp_box);
+        let save_button = ButtonBuilder::new()
+            .label("Save Alarm").build();
+        form_box.add(&save_button);
+        let ret = Rc::new(RefCell::new(Controller {
+            window, alarms_view, alarms_model, new_button, delete_button,
+            save_button, form_box, enabled_box, hour_spin, minute_spin,
+            day_boxes, playlist_view, shuffle_box, set_volume_box,
+            volume_spin, ramp_spin,
+        }));
+        let this = ret.borrow();
+        this.window.connect_delete_event(move |window, _| {
+            window.hide_on_delete()
+        });
+        let controller = ret.clone();
+        this.new_button.connect_clicked(move |_| {
+            let _ = controller.try_borrow_mut()
+                .map(|mut x| x.clicked_new());
+        });
+        let controller = ret.clone();
+        this.delete_button.connect_clicked(move |_| {
+            let _ = controller.try_borrow_mut()
+                .map(|mut x| x.clicked_delete());
+        });
+        let controller = ret.clone();
+        this.save_button.connect_clicked(move |_| {
+            let _ = controller.try_borrow_mut()
+                .map(|mut x| x.clicked_save());
+        });
+        let controller = ret.clone();
+        this.alarms_view.connect_cursor_changed(move |_| {
+            let _ = controller.try_borrow_mut()
+                .map(|mut x| x.changed_selection());
+        });
+        drop(this);
+        ret
+    }
+    /// Returns the alarm whose row is selected, if any.
+    fn get_selected_alarm(&self) -> Option<Alarm> {
+        let path = self.alarms_view.get_cursor().0?;
+        let iter = self.alarms_model.get_iter(&path)?;
+        let id = self.alarms_model.get_value(&iter, ID_COLUMN as i32)
+            .get::<u64>().ok()??;
+        alarm::get_alarms().into_iter().find(|x| x.id == id)
+    }
+    /// Selects the row for the alarm with the given id.
+    fn select_alarm(&self, id: u64) {
+        let view = &self.alarms_view;
+        self.alarms_model.foreach(|model, path, iter| {
+            let found = model.get_value(iter, ID_COLUMN as i32)
+                .get::<u64>().ok().flatten();
+            if found == Some(id) {
+                view.set_cursor(path, None::<&TreeViewColumn>, false);
+                true
+            }
+            else { false }
+        });
+    }
+    fn populate_alarms(&mut self) {
+        self.alarms_model.clear();
+        for alarm in alarm::get_alarms().iter() {
+            let playlist_name = playlist::get_playlist_by_id(alarm.playlist_id)
+                .map(|x| x.read().unwrap().get_name().to_owned())
+                .unwrap_or_else(|| "(deleted playlist)".to_owned());
+            let enabled = if alarm.enabled { "Yes" } else { "No" };
+            self.alarms_model.insert_with_values
+                (None, &[ID_COLUMN, TIME_COLUMN, DAYS_COLUMN,
+                         PLAYLIST_COLUMN, ENABLED_COLUMN],
+                 &[&alarm.id, &alarm.get_time_string(),
+                   &alarm.get_days_string(), &playlist_name, &enabled]);
+        }
+        self.alarms_view.set_model(Some(&self.alarms_model));
+    }
+    fn populate_playlists(&mut self) {
+        fn add_playlists(view: &ComboBoxText, playlists: &[PlaylistRef],
+                         depth: usize) {
+            for playlist_ref in playlists.iter() {
+                let playlist = playlist_ref.read().unwrap();
+                let label = format!("{}{}", "    ".repeat(depth),
+                                    playlist.get_name());
+                view.append(Some(&playlist.get_id().as_inner().to_string()),
+                            &label);
+                add_playlists(view, playlist.get_children(), depth + 1);
+            }
+        }
+        self.playlist_view.remove_all();
+        let top_level = playlist::get_top_level_playlists().clone();
+        add_playlists(&self.playlist_view, &top_level[..], 0);
+    }
+    /// Fills in the form with the selected alarm's settings, or makes it
+    /// insensitive if none is selected.
+    fn changed_selection(&mut self) {
+        let alarm = self.get_selected_alarm();
+        self.delete_button.set_sensitive(alarm.is_some());
+        self.form_box.set_sensitive(alarm.is_some());
+        let alarm = match alarm {
+            Some(x) => x,
+            None => return,
+        };
+        self.enabled_box.set_active(alarm.enabled);
+        self.hour_spin.set_value((alarm.minute / 60) as f64);
+        self.minute_spin.set_value((alarm.minute % 60) as f64);
+        for (n, day_box) in self.day_boxes.iter().enumerate() {
+            day_box.set_active(alarm.goes_off_on(n as u32));
+        }
+        let playlist_id = alarm.playlist_id.as_inner().to_string();
+        if !self.playlist_view.set_active_id(Some(&playlist_id)) {
+            self.playlist_view.set_active(None);
+        }
+        self.shuffle_box.set_active(alarm.shuffle);
+        self.set_volume_box.set_active(alarm.volume.is_some());
+        self.volume_spin.set_value(alarm.volume
+                                   .unwrap_or_else(prefs::get_volume) as f64);
+        self.ramp_spin.set_value(alarm.ramp);
+    }
+    fn clicked_new(&mut self) -> Option<()> {
+        let playlist_ref = playback::get_future_playlist()
+            .or_else(|| playlist::get_top_level_playlists().get(0).cloned())?;
+        let playlist_id = playlist_ref.read().unwrap().get_id();
+        match alarm::create_alarm(playlist_id) {
+            Ok(alarm) => {
+                self.populate_alarms();
+                self.select_alarm(alarm.id);
+                self.changed_selection();
+            },
+            Err(x) => {
+                // TODO: Error dialog
+                error!("Couldn't create an alarm: {:?}", x);
+            },
+        }
+        None
+    }
+    fn clicked_delete(&mut self) -> Option<()> {
+        let alarm = self.get_selected_alarm()?;
+        alarm::delete_alarm(alarm.id);
+        self.populate_alarms();
+        self.changed_selection();
+        None
+    }
+    fn clicked_save(&mut self) -> Option<()> {
+        let mut alarm = self.get_selected_alarm()?;
+        alarm.enabled = self.enabled_box.get_active();
+        alarm.minute = self.hour_spin.get_value_as_int() as u32 * 60
+            + self.minute_spin.get_value_as_int() as u32;
+        alarm.days = self.day_boxes.iter().enumerate()
+            .filter(|(_, x)| x.get_active())
+            .fold(0, |days, (n, _)| days | (1 << n));
+        if let Some(id) = self.playlist_view.get_active_id()
+            .and_then(|x| x.as_str().parse().ok()) {
+            alarm.playlist_id = PlaylistID::from_inner(id);
+        }
+        alarm.shuffle = self.shuffle_box.get_active();
+        alarm.volume = if self.set_volume_box.get_active() {
+            Some(self.volume_spin.get_value_as_int())
+        } else { None };
+        alarm.ramp = self.ramp_spin.get_value();
+        alarm::update_alarm(&alarm);
+        self.populate_alarms();
+        self.select_alarm(alarm.id);
+        self.changed_selection();
+        None
+    }
+    pub fn show(&mut self) {
+        if !self.window.is_visible() {
+            self.populate_playlists();
+            self.populate_alarms();
+            self.window.show_all();
+            self.changed_selection();
+        }
+        else {
+            self.window.present();
+        }
+    }
+}
@@ -9,9 +9,11 @@ use gtk::{
     ButtonsType,
     CellRendererText,
     CellRendererToggle,
+    CheckButton, CheckButtonBuilder,
     DestDefaults,
-    DialogFlags,
+    Dialog, DialogFlags,
     Entry, EntryBuilder,
+    GridBuilder,
     LabelBuilder,
     ListStore,
     MessageDialog, MessageType,
@@ -23,6 +25,7 @@ use gtk::{
     SelectionMode,
     SeparatorBuilder,
     TargetEntry, TargetFlags,
+    TextViewBuilder,
     TreeStore,
     TreeView, TreeViewBuilder, TreeViewColumn, TreeIter, TreePath,
     TreeRowReference,
@@ -30,6 +33,7 @@ use gtk::{
     Window, WindowBuilder, WindowType,
 };
 use glib::{
+    source::timeout_add_local,
     Type,
 };
 use gdk::{
@@ -91,7 +95,12 @@ pub struct Controller {
     // meta_script_button: Button,
     reimport_all_meta_button: Button,
     reimport_selected_meta_button: Button,
+    write_back_button: Button,
     new_meta_button: Button,
+    trim_start_entry: Entry,
+    trim_end_entry: Entry,
+    preview_start_button: Button,
+    preview_end_button: Button,
     notebook: Notebook,
     playlist_notebook: Notebook,
     song_notebook: Notebook,
@@ -100,6 +109,9 @@ pub struct Controller {
     playlist_page: u32,
     song_page: u32,
     playlist_code: Entry,
+    remember_positions_box: CheckButton,
+    shuffle_albums_box: CheckButton,
+    album_key_entry: Entry,
     apply_button: Button,
     cancel_button: Button,
     revert_button: Button,
@@ -136,11 +148,65 @@ const FILE_FILE_ID_COLUMN: u32 = 4;
 // TODO: i18n
 const MULTIPLE_VALUES: &str = "(multiple values)";
 const DELETED_VALUE: &str = "(delete)";
+/// How many seconds of a song to play when previewing a trim point.
+const PREVIEW_LENGTH: f64 = 5.0;
+
 // Currently only used when a value is newly created and hasn't been filled in
 // yet. In future, may also be used for certain "privileged" keys like "title"
 // or "artist".
 const EMPTY_VALUE: &str = "";
 
+/// Parses a time in seconds, or in minutes and seconds separated by a colon.
+fn parse_time(text: &str) -> Option<f64> {
+    let ret = match text.find(':') {
+        Some(x) => {
+            let minutes: u32 = text[..x].parse().ok()?;
+            let seconds: f64 = text[x+1..].parse().ok()?;
+            if seconds >= 60.0 { return None }
+            minutes as f64 * 60.0 + seconds
+        },
+        None => text.parse().ok()?,
+    };
+    if ret.is_finite() && ret >= 0.0 { Some(ret) } else { None }
+}
+
+/// Shows the user what writing the selected songs' metadata into their files
+/// would change, and asks whether to go ahead. Returns true if they do.
+fn confirm_write_back(window: &Window, plan: &writeback::Plan) -> bool {
+    let dialog = Dialog::with_buttons(Some("Write to Files"), Some(window),
+                                      DialogFlags::MODAL,
+                                      &[("Cancel", ResponseType::Cancel)]);
+    let message = if plan.is_empty() {
+        "None of the selected songs' files need to be changed."
+    }
+    else {
+        let write_button = dialog.add_button("Write", ResponseType::Ok);
+        write_button.get_style_context().add_class("destructive-action");
+        "The selected songs' metadata will be written into their files, as \
+         shown below. Other tags in the files will be left alone. This can't \
+         be undone!"
+    };
+    let big_box = BoxBuilder::new()
+        .orientation(Orientation::Vertical).spacing(6).margin(8).build();
+    big_box.add(&LabelBuilder::new().label(message).wrap(true)
+                .halign(Align::Start).build());
+    let text_view = TextViewBuilder::new()
+        .editable(false).cursor_visible(false).monospace(true).build();
+    text_view.get_buffer().unwrap().set_text(&plan.describe());
+    let scroller = ScrolledWindowBuilder::new()
+        .hscrollbar_policy(PolicyType::Automatic)
+        .vscrollbar_policy(PolicyType::Automatic)
+        .min_content_width(500).min_content_height(300)
+        .vexpand(true).build();
+    scroller.add(&text_view);
+    big_box.add(&scroller);
+    dialog.get_content_area().add(&big_box);
+    dialog.show_all();
+    let result = dialog.run();
+    dialog.close();
+    result == ResponseType::Ok
+}
+
 impl Controller {
     pub fn new(parent: Weak<RefCell<super::Controller>>,
                song_meta_update_tx: mpsc::Sender<SongID>)
@@ -187,6 +253,11 @@ impl Controller {
             .orientation(Orientation::Vertical).spacing(4).build();
         let meta_page = song_notebook.append_page::<_, Widget>(&meta_box, None);
         song_notebook.set_tab_label_text(&meta_box, "Metadata");
+        let trim_box = BoxBuilder::new()
+            .name("song_trim")
+            .orientation(Orientation::Vertical).spacing(4).build();
+        song_notebook.append_page::<_, Widget>(&trim_box, None);
+        song_notebook.set_tab_label_text(&trim_box, "Trim");
         let files_box = BoxBuilder::new()
             .name("files")
             .orientation(Orientation::Vertical).spacing(4).build();
@@ -202,6 +273,33 @@ impl Controller {
             .tooltip_text(PLAYLIST_CODE_TOOLTIP)
             .build();
         rule_box.add(&playlist_code);
+        let remember_positions_box = CheckButtonBuilder::new()
+            .label("Songs pick up where they left off")
+            .tooltip_text("If checked, songs played from this playlist start \
+                           where you stopped hearing them last time, instead \
+                           of from the beginning. Good for audiobooks and \
+                           long mixes. (To do this for particular songs no \
+                           matter what playlist they're in, set their \
+                           remember_position metadata to 1.)")
+            .build();
+        rule_box.add(&remember_positions_box);
+        let shuffle_albums_box = CheckButtonBuilder::new()
+            .label("Shuffle whole albums")
+            .tooltip_text("If checked, shuffling this playlist shuffles the \
+                           order of its albums, but plays each album's songs \
+                           together, in disc and track order.")
+            .build();
+        rule_box.add(&shuffle_albums_box);
+        rule_box.add(&LabelBuilder::new()
+                        .label("Tags that make up an album:")
+                        .halign(Align::Start).build());
+        let album_key_entry = EntryBuilder::new().hexpand(true)
+            .placeholder_text(&playlist::DEFAULT_ALBUM_KEY.join(", "))
+            .tooltip_text("Comma-separated list of tags. Songs with the same \
+                           values for all of these tags are considered to be \
+                           on the same album.")
+            .build();
+        rule_box.add(&album_key_entry);
         // The columns
         let columns_window = ScrolledWindowBuilder::new()
             .name("columns")
@@ -322,6 +420,13 @@ impl Controller {
             .label("Re-import _Selected").use_underline(true).build();
         reimport_selected_meta_button.set_sensitive(false);
         metadata_button_box.add(&reimport_selected_meta_button);
+        let write_back_button = ButtonBuilder::new()
+            .tooltip_text("Write the selected songs' metadata into their \
+                           files, on top of the files' own tags. You'll be \
+                           shown exactly what will change first.")
+            .label("_Write to Files…").use_underline(true).build();
+        write_back_button.set_sensitive(false);
+        metadata_button_box.add(&write_back_button);
         let new_meta_button = ButtonBuilder::new()
             .tooltip_text("Add a new metadata tag to the selected songs' \
                            metadata.")
@@ -330,6 +435,42 @@ impl Controller {
         metadata_button_box.add(&new_meta_button);
         meta_box.add(&metadata_button_box);
         super::set_icon(&new_meta_button, "tsong-add");
+        // Trim points
+        trim_box.add(&LabelBuilder::new()
+                     .label("Playback of the song will start and stop at \
+                             these points. Times are in seconds, or \
+                             minutes:seconds, from the beginning of the \
+                             song.")
+                     .wrap(true).halign(Align::Start).build());
+        let trim_grid = GridBuilder::new()
+            .row_spacing(4).column_spacing(6).build();
+        trim_grid.attach(&LabelBuilder::new().label("Start:")
+                         .halign(Align::End).build(), 0, 0, 1, 1);
+        let trim_start_entry = EntryBuilder::new().hexpand(true)
+            .tooltip_text("Where playback of the song should start. Leave \
+                           empty to start at the beginning.")
+            .build();
+        trim_grid.attach(&trim_start_entry, 1, 0, 1, 1);
+        let preview_start_button = ButtonBuilder::new()
+            .tooltip_text("Play the first few seconds of the song, starting \
+                           from this point.")
+            .label("Preview").build();
+        preview_start_button.set_sensitive(false);
+        trim_grid.attach(&preview_start_button, 2, 0, 1, 1);
+        trim_grid.attach(&LabelBuilder::new().label("End:")
+                         .halign(Align::End).build(), 0, 1, 1, 1);
+        let trim_end_entry = EntryBuilder::new().hexpand(true)
+            .tooltip_text("Where playback of the song should stop. Leave \
+                           empty to play to the end.")
+            .build();
+        trim_grid.attach(&trim_end_entry, 1, 1, 1, 1);
+        let preview_end_button = ButtonBuilder::new()
+            .tooltip_text("Play the last few seconds of the song, ending at \
+                           this point.")
+            .label("Preview").build();
+        preview_end_button.set_sensitive(false);
+        trim_grid.attach(&preview_end_button, 2, 1, 1, 1);
+        trim_box.add(&trim_grid);
         // Song files
         let files_model = TreeStore::new(&FILES_COLUMN_TYPES[..]);
         let files_window = ScrolledWindowBuilder::new()
@@ -416,13 +557,17 @@ impl Controller {
             parent, columns_model: ListStore::new(&[Type::String, Type::U32]),
             delete_column_button, new_column_button, column_tag_column,
             delete_meta_button, reimport_all_meta_button,
-            reimport_selected_meta_button, new_meta_button,
+            reimport_selected_meta_button, write_back_button, new_meta_button,
+            trim_start_entry, trim_end_entry,
+            preview_start_button, preview_end_button,
             columns_view, apply_button, cancel_button, ok_button,
             revert_button, // meta_script_button,
             meta_key_cell, meta_value_cell, meta_key_column,meta_modified_cell,
             meta_orig: BTreeMap::new(),
             meta_edits: BTreeMap::new(), meta_renames: BTreeMap::new(),
-            column_tag_cell, playlist_code, active_playlist: None,
+            column_tag_cell, playlist_code, remember_positions_box,
+            shuffle_albums_box, album_key_entry,
+            active_playlist: None,
             metadata_model, metadata_view, files_model, files_view,
             script_in_progress: Arc::new(AtomicBool::new(false)),
             selected_songs: Vec::new(), me: None,
@@ -577,6 +722,60 @@ impl Controller {
                 .map(|mut x| x.reimport_all_meta());
         });
         let controller = ret.clone();
+        let window = this.window.clone();
+        this.write_back_button.connect_clicked(move |_| {
+            if controller.borrow().maybe_show_script_wait_dialog() {
+                return;
+            }
+            let dirty = {
+                let controller = controller.borrow();
+                !(controller.meta_renames.is_empty()
+                  && controller.meta_edits.is_empty())
+            };
+            if dirty {
+                let dialog = MessageDialog::new(Some(&window),
+                                                DialogFlags::MODAL,
+                                                MessageType::Error,
+                                                ButtonsType::Cancel,
+                                                "Please apply your changes \
+                                                 before writing metadata to \
+                                                 files.");
+                let _ = dialog.run();
+                dialog.close();
+                return;
+            }
+            let plan_rx = match controller.try_borrow_mut() {
+                Ok(mut x) => x.plan_write_back(),
+                Err(_) => return,
+            };
+            // (check back until the plan is made, and then ask)
+            let controller = controller.clone();
+            let window = window.clone();
+            timeout_add_local(100, move || {
+                if controller.try_borrow()
+                    .map(|x| x.script_is_in_progress()).unwrap_or(true) {
+                        return Continue(true)
+                    }
+                if let Ok(plan) = plan_rx.try_recv() {
+                    if confirm_write_back(&window, &plan) {
+                        let _ = controller.try_borrow_mut()
+                            .map(|mut x| x.write_back(plan));
+                    }
+                }
+                Continue(false)
+            });
+        });
+        let controller = ret.clone();
+        this.preview_start_button.connect_clicked(move |_| {
+            let _ = controller.try_borrow()
+                .map(|x| x.clicked_preview(false));
+        });
+        let controller = ret.clone();
+        this.preview_end_button.connect_clicked(move |_| {
+            let _ = controller.try_borrow()
+                .map(|x| x.clicked_preview(true));
+        });
+        let controller = ret.clone();
         this.delete_meta_button.connect_clicked(move |_| {
             let _ = controller.try_borrow_mut()
                 .map(|mut x| x.clicked_delete_meta());
@@ -614,9 +813,15 @@ impl Controller {
             }
             false
         });
+        self.collect_trim_edits()?;
+        let album_key: Vec<String> = self.album_key_entry.get_text()
+            .split(',').map(str::trim).filter(|x| !x.is_empty())
+            .map(str::to_owned).collect();
         let parent = self.parent.upgrade()?;
         parent.try_borrow_mut().ok()?
-            .edit_playlist(playlist_code, columns);
+            .edit_playlist(playlist_code, columns,
+                           self.remember_positions_box.get_active(),
+                           self.shuffle_albums_box.get_active(), album_key);
         if !self.meta_renames.is_empty() || !self.meta_edits.is_empty() {
             for song_ref in self.selected_songs.iter() {
                 self.apply_meta_edits(song_ref);
@@ -642,6 +847,8 @@ impl Controller {
         self.metadata_model.clear();
         self.files_model.clear();
         self.playlist_code.set_text("");
+        self.trim_start_entry.set_text("");
+        self.trim_end_entry.set_text("");
         self.meta_orig.clear();
         self.meta_renames.clear();
         self.meta_edits.clear();
@@ -684,7 +891,10 @@ impl Controller {
         }
         if self.window.is_visible() { self.populate_song() }
         self.reimport_all_meta_button.set_sensitive(self.selected_songs.len() !=0);
+        self.write_back_button.set_sensitive(self.selected_songs.len() != 0);
         self.new_meta_button.set_sensitive(self.selected_songs.len() != 0);
+        self.preview_start_button.set_sensitive(self.selected_songs.len()==1);
+        self.preview_end_button.set_sensitive(self.selected_songs.len() == 1);
         //self.meta_script_button.set_sensitive(self.selected_songs.len() != 0);
     }
     fn populate(&mut self) {
@@ -695,6 +905,16 @@ impl Controller {
         let playlist = playlist_ref.read().unwrap();
         self.playlist_code.set_text(playlist.get_rule_code());
         self.check_playlist_code();
+        self.remember_positions_box
+            .set_active(playlist.get_remember_positions());
+        self.shuffle_albums_box.set_active(playlist.get_shuffle_albums());
+        let album_key = playlist.get_album_key();
+        if album_key == &playlist::DEFAULT_ALBUM_KEY[..] {
+            self.album_key_entry.set_text("");
+        }
+        else {
+            self.album_key_entry.set_text(&album_key.join(", "));
+        }
         self.columns_model.clear();
         for column in playlist.get_columns() {
             self.columns_model.insert_with_values(None, &[0, 1],
@@ -740,9 +960,13 @@ impl Controller {
                  &[FILE_NAME_COLUMN, FILE_IS_SENSITIVE_COLUMN,
                    FILE_SONG_ID_COLUMN],
                  &[&format!("{}", *song), &false, &song_id_value]);
-            let files = song.get_physical_files();
-            for file_id in files {
+            let files = song.get_physical_files().iter()
+                .zip(song.get_physical_tracks().iter());
+            for (file_id, &track) in files {
                 let mut name = String::new();
+                if track != 0 {
+                    write!(name, "Track {} of:\n", track).unwrap();
+                }
                 match physical::get_file_by_id(file_id) {
                     None => write!(name, "MISSING FILE").unwrap(),
                     Some(file_ref) => {
@@ -803,6 +1027,90 @@ impl Controller {
                 },
             }
         }
+        for &(entry, key, placeholder)
+        in &[(&self.trim_start_entry, "start_time", "Beginning"),
+             (&self.trim_end_entry, "end_time", "End")] {
+            match self.meta_orig.get(key) {
+                Some(Some(x)) => entry.set_text(x),
+                Some(None) => {
+                    entry.set_text("");
+                    entry.set_placeholder_text(Some(MULTIPLE_VALUES));
+                },
+                None => {
+                    entry.set_text("");
+                    entry.set_placeholder_text(Some(placeholder));
+                },
+            }
+        }
+    }
+    /// Parses the contents of one of the trim point entries. Returns
+    /// `Ok(None)` if it's empty, or `Err(())` (after telling the user about
+    /// it) if it's not a valid time.
+    fn parse_trim_entry(&self, entry: &Entry) -> Result<Option<f64>, ()> {
+        let text = entry.get_text();
+        let text = text.trim();
+        if text.is_empty() { return Ok(None) }
+        match parse_time(text) {
+            Some(x) => Ok(Some(x)),
+            None => {
+                let dialog = MessageDialog::new(Some(&self.window),
+                                                DialogFlags::MODAL,
+                                                MessageType::Error,
+                                                ButtonsType::Ok,
+                                                "Please enter a time in \
+                                                 seconds (e.g. \"90.5\") or \
+                                                 minutes and seconds (e.g. \
+                                                 \"1:30.5\").");
+                dialog.run();
+                dialog.close();
+                entry.grab_focus();
+                Err(())
+            },
+        }
+    }
+    /// Turns any changes to the trim point entries into metadata edits.
+    /// Returns `None` if one of them isn't valid.
+    fn collect_trim_edits(&mut self) -> Option<()> {
+        let mut edits = Vec::with_capacity(2);
+        for &(entry, key) in &[(&self.trim_start_entry, "start_time"),
+                               (&self.trim_end_entry, "end_time")] {
+            let orig = match self.meta_orig.get(key) {
+                Some(Some(x)) => x.as_str(),
+                _ => "",
+            };
+            if entry.get_text().trim() == orig { continue }
+            let value = match self.parse_trim_entry(entry).ok()? {
+                Some(x) => format!("{}", x),
+                None => String::new(),
+            };
+            edits.push((key.to_owned(), value));
+        }
+        self.meta_edits.extend(edits);
+        Some(())
+    }
+    /// Plays a few seconds of the selected song, starting at the (possibly
+    /// not yet applied) start point, or ending at the end point.
+    fn clicked_preview(&self, at_end: bool) -> Option<()> {
+        if self.selected_songs.len() != 1 { return None }
+        let song_ref = self.selected_songs[0].clone();
+        let start = self.parse_trim_entry(&self.trim_start_entry).ok()?
+            .unwrap_or(0.0);
+        let end = self.parse_trim_entry(&self.trim_end_entry).ok()?;
+        let (start, end) = if at_end {
+            let end_point = match end {
+                Some(x) => x,
+                None => song_ref.read().unwrap().get_untrimmed_duration()
+                    .unwrap_or(0) as f64,
+            };
+            ((end_point - PREVIEW_LENGTH).max(start), end)
+        }
+        else {
+            let preview_end = start + PREVIEW_LENGTH;
+            (start, Some(end.map(|x| x.min(preview_end))
+                         .unwrap_or(preview_end)))
+        };
+        playback::send_command(PlaybackCommand::Preview(song_ref, start, end));
+        None
     }
     fn check_playlist_code(&self) -> Option<String> {
         let value = self.playlist_code.get_text();
@@ -1162,10 +1470,12 @@ impl Controller {
         self.kickoff_script(move || {
             for song_ref in selected_songs.iter() {
                 let mut song = song_ref.write().unwrap();
-                let file = match song.get_physical_files().iter()
-                    .filter_map(physical::get_file_by_id)
+                let (file, track) = match song.get_physical_files().iter()
+                    .zip(song.get_physical_tracks().iter())
+                    .filter_map(|(id, &track)| physical::get_file_by_id(id)
+                                .map(|file| (file, track)))
                     .next() {
-                        Some(file) => file,
+                        Some(x) => x,
                         None => {
                             drop(song);
                             warn!("Song {:?} couldn't be reimported \
@@ -1175,7 +1485,7 @@ impl Controller {
                         },
                     };
                 let file = file.read().unwrap();
-                match song.import_metadata(&*file, None) {
+                match song.import_metadata(&*file, track, None) {
                     Ok(false) => (),
                     Ok(true) => {
                         let _ = song_meta_update_tx.send(song.get_id());
@@ -1196,10 +1506,12 @@ impl Controller {
         self.kickoff_script(move || {
             for song_ref in selected_songs.iter() {
                 let mut song = song_ref.write().unwrap();
-                let file = match song.get_physical_files().iter()
-                    .filter_map(physical::get_file_by_id)
+                let (file, track) = match song.get_physical_files().iter()
+                    .zip(song.get_physical_tracks().iter())
+                    .filter_map(|(id, &track)| physical::get_file_by_id(id)
+                                .map(|file| (file, track)))
                     .next() {
-                        Some(file) => file,
+                        Some(x) => x,
                         None => {
                             drop(song);
                             warn!("Song {:?} couldn't be reimported \
@@ -1209,7 +1521,8 @@ impl Controller {
                         },
                     };
                 let file = file.read().unwrap();
-                let imported = match song.get_imported_metadata(&*file, None) {
+                let imported = match song.get_imported_metadata(&*file, track,
+                                                                None) {
                     Ok(x) => x,
                     Err(x) => {
                         drop(song);
@@ -1231,6 +1544,25 @@ impl Controller {
             }
         });
     }
+    /// Works out what writing back the selected songs' metadata would do, in
+    /// the background, since that reads every file. The plan is sent when
+    /// it's made, just before the script is done.
+    fn plan_write_back(&mut self) -> mpsc::Receiver<writeback::Plan> {
+        let selected_songs = self.selected_songs.clone();
+        let (plan_tx, plan_rx) = mpsc::channel();
+        self.kickoff_script(move || {
+            let _ = plan_tx.send(writeback::plan(&selected_songs));
+        });
+        plan_rx
+    }
+    fn write_back(&mut self, plan: writeback::Plan) {
+        let song_meta_update_tx = self.song_meta_update_tx.clone();
+        self.kickoff_script(move || {
+            for song_id in writeback::apply(&plan) {
+                let _ = song_meta_update_tx.send(song_id);
+            }
+        });
+    }
     fn maybe_show_script_wait_dialog(&self) -> bool {
         if !self.script_is_in_progress() { return false }
         let dialog = MessageDialog::new(Some(&self.window),
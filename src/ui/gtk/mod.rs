@@ -12,26 +12,34 @@ use gtk::{
     Button, ButtonBuilder, ButtonBoxBuilder, ButtonBoxStyle,
     ButtonsType,
     CellRendererText,
+    CheckButton,
+    CheckMenuItem,
+    ComboBoxText,
     Container,
     DestDefaults,
-    DialogFlags,
+    Dialog, DialogFlags,
     Entry,
     Grid, GridBuilder,
     IconSize, IconTheme,
     Image,
     Label, LabelBuilder,
     ListStore,
+    Menu, MenuButton, MenuButtonBuilder, MenuItem,
     MessageDialog, MessageType,
     Orientation,
     Overlay, OverlayBuilder,
     PolicyType,
+    Popover,
+    PositionType,
+    ProgressBar, ProgressBarBuilder,
     ReliefStyle,
     ResponseType,
     Scale, ScaleBuilder,
     ScrolledWindowBuilder,
     SelectionData,
     SelectionMode,
-    SeparatorBuilder,
+    SeparatorBuilder, SeparatorMenuItem,
+    SpinButton,
     Spinner, SpinnerBuilder,
     StyleContext,
     TargetEntry, TargetFlags,
@@ -63,18 +71,28 @@ use std::{
     convert::TryInto,
     rc::{Rc,Weak},
     sync::{RwLockReadGuard, mpsc},
+    time::{Duration, Instant},
 };
 use lazy_static::lazy_static;
 use anyhow::anyhow;
+use playback::{LoopCommand, SleepTimer, StopAfter};
+use logical::PracticeLoop;
 
 mod settings;
 mod edit;
 mod errors_window;
+mod alarms;
+mod podcasts;
+mod sync_window;
 mod scrp;
 use scrp::*;
 
 const INACTIVE_WEIGHT: u32 = 400; // normal weight
 const ACTIVE_WEIGHT: u32 = 800; // bold
+/// How much (in dB) the limiter has to turn the audio down before we show it.
+const LIMITER_INDICATOR_THRESHOLD: f64 = 0.1;
+/// How long to keep showing that the limiter turned the audio down.
+const LIMITER_INDICATOR_HOLD: Duration = Duration::from_secs(1);
 const TSONG_SONGS_MIMETYPE: &str = "application/x-tsong-songs";
 const TSONG_PLAYLISTS_MIMETYPE: &str = "application/x-tsong-playlists";
 const TSONG_SONGS_TYPE: u32 = 1;
@@ -114,12 +132,20 @@ pub struct Controller {
     rollup_grid: Grid,
     settings_button: ToggleButton,
     shuffle_button: ToggleButton,
+    sleep_button: MenuButton,
+    add_stream_button: Button,
+    podcasts_button: Button,
+    sync_button: Button,
     volume_scale: Scale,
     volume_label: Label,
     window: ApplicationWindow,
     playlist_generation: GenerationValue,
     errors_generation: GenerationValue,
     scan_spinner: Spinner,
+    scan_box: gtk::Box,
+    scan_progress: ProgressBar,
+    scan_pause_button: ToggleButton,
+    scan_cancel_button: Button,
     remote: Option<Remote>,
     remote_time: f64,
     last_active_playlist: Option<(TreeIter,PlaylistRef)>,
@@ -129,8 +155,15 @@ pub struct Controller {
     settings_controller: Option<Rc<RefCell<settings::Controller>>>,
     edit_controller: Option<Rc<RefCell<edit::Controller>>>,
     errors_controller: Option<Rc<RefCell<errors_window::Controller>>>,
+    alarms_controller: Option<Rc<RefCell<alarms::Controller>>>,
+    podcasts_controller: Option<Rc<RefCell<podcasts::Controller>>>,
+    sync_controller: Option<Rc<RefCell<sync_window::Controller>>>,
     periodic_timer: Option<SourceId>,
     volume_changed: bool,
+    /// Until when to show that the limiter is turning the audio down.
+    limiting_until: Option<Instant>,
+    /// The last network stream title we showed, if any.
+    last_stream_title: Option<String>,
     me: Option<Weak<RefCell<Controller>>>,
     song_meta_update_rx: mpsc::Receiver<SongID>,
 }
@@ -138,7 +171,7 @@ pub struct Controller {
 impl Controller {
     pub fn new(application: &Application) -> Rc<RefCell<Controller>> {
         let mut scan_thread = ScanThread::new();
-        scan_thread.rescan(prefs::get_music_paths())
+        scan_thread.rescan(scan::get_scan_paths())
             .expect("Couldn't start the initial music scan!");
         let icon_theme = IconTheme::get_default().unwrap();
         if let Ok(path) = std::env::var("TSONG_ICON_PATH") {
@@ -219,8 +252,10 @@ impl Controller {
             .restrict_to_fill_level(false)
             .adjustment(&Adjustment::new(prefs::get_volume() as f64,
                                          0.0, 200.0, 1.0, 10.0, 10.0))
-            .tooltip_text("Adjust playback volume. Note that volumes above \
-                           100% may result in distortion.")
+            .tooltip_text("Adjust playback volume. Volumes above 100% are \
+                           held back by a limiter to keep them from \
+                           distorting; the percentage is shown in bold while \
+                           that's happening.")
             .build();
         let volume_label = LabelBuilder::new()
             .halign(Align::Center).valign(Align::Center).build();
@@ -324,6 +359,38 @@ impl Controller {
                            song, loop an entire playlist, or never loop.")
             .name("playmode").build();
         playlist_control_box.pack_start(&playmode_button, false, false, 0);
+        // Button to change speed and pitch:
+        let tempo_button = MenuButtonBuilder::new()
+            .tooltip_text("Change the playback speed and pitch, e.g. for \
+                           transcribing or practicing along with a song.")
+            .name("tempo").build();
+        build_practice_popover(&tempo_button);
+        playlist_control_box.pack_start(&tempo_button, false, false, 0);
+        // Button for the sleep timer and friends:
+        let sleep_button = MenuButtonBuilder::new()
+            .tooltip_text("Stop playback after a while, or at the end of \
+                           this song, album, or playlist.")
+            .name("sleep").label("Sleep").build();
+        playlist_control_box.pack_start(&sleep_button, false, false, 0);
+        // Button to add an internet radio station (or similar):
+        let add_stream_button = ButtonBuilder::new()
+            .tooltip_text("Add an internet radio station, or anything else \
+                           that can be played from an http:// or https:// \
+                           URL, to this playlist.")
+            .name("add_stream").label("Add Stream").build();
+        playlist_control_box.pack_start(&add_stream_button, false, false, 0);
+        // Button to manage podcast subscriptions:
+        let podcasts_button = ButtonBuilder::new()
+            .tooltip_text("Subscribe to podcasts, and choose which of their \
+                           episodes are kept.")
+            .name("podcasts").label("Podcasts").build();
+        playlist_control_box.pack_start(&podcasts_button, false, false, 0);
+        // Button to sync playlists to a portable player:
+        let sync_button = ButtonBuilder::new()
+            .tooltip_text("Copy playlists to a phone, a portable player, or \
+                           any other folder, and keep them up to date.")
+            .name("sync").label("Sync").build();
+        playlist_control_box.pack_start(&sync_button, false, false, 0);
         // Button to edit playlist settings:
         let edit_button = ToggleButtonBuilder::new()
             .tooltip_text("Open a window where you can edit properties of \
@@ -332,6 +399,24 @@ impl Controller {
         playlist_control_box.pack_end(&edit_button, false, false, 0);
         below_playlist_box.pack_start(&playlist_control_box, false, false, 0);
         rollup_grid.attach(&below_playlist_box, 2, 1, 1, 1);
+        // Scan progress, only visible during a scan
+        let scan_box = BoxBuilder::new()
+            .name("scan_progress").orientation(Orientation::Horizontal)
+            .spacing(4).build();
+        let scan_progress = ProgressBarBuilder::new()
+            .show_text(true).hexpand(true).valign(Align::Center).build();
+        scan_box.pack_start(&scan_progress, true, true, 0);
+        let scan_pause_button = ToggleButtonBuilder::new()
+            .tooltip_text("Temporarily stop scanning for songs.")
+            .label("Pause").build();
+        scan_box.pack_start(&scan_pause_button, false, false, 0);
+        let scan_cancel_button = ButtonBuilder::new()
+            .tooltip_text("Stop scanning for songs. Songs that have already \
+                           been found will stay in the library, and the rest \
+                           will be found on the next scan.")
+            .label("Cancel").build();
+        scan_box.pack_start(&scan_cancel_button, false, false, 0);
+        rollup_grid.attach(&scan_box, 0, 2, 3, 1);
         outer_box.add(&rollup_grid);
         // done setting up the widgets, time to bind everything to the
         // controller
@@ -487,10 +572,13 @@ impl Controller {
         let (song_meta_update_tx, song_meta_update_rx) = mpsc::channel();
         let nu = Rc::new(RefCell::new(Controller {
             rollup_button, settings_button, prev_button, next_button,
-            shuffle_button, playmode_button, play_button, volume_scale,
+            shuffle_button, playmode_button, play_button, sleep_button,
+            add_stream_button, podcasts_button, sync_button,
+            volume_scale,
             volume_label, playlists_view, playlist_view,
             playlists_model, playlist_model, playlist_stats, osd,
             scan_spinner, scan_thread, rollup_grid, control_box,
+            scan_box, scan_progress, scan_pause_button, scan_cancel_button,
             new_playlist_button, delete_playlist_button,
             playlist_name_column, playlist_name_cell, window,
             edit_button, errors_button,
@@ -499,8 +587,11 @@ impl Controller {
             active_playlist: None, playlist_generation: Default::default(),
             errors_generation: Default::default(), errors_controller: None,
             last_built_playlist: None, me: None, settings_controller: None,
-            edit_controller: None, rolled_down_height: 400,
+            edit_controller: None, alarms_controller: None,
+            podcasts_controller: None, sync_controller: None,
+            rolled_down_height: 400,
             periodic_timer: None, volume_changed: false,
+            limiting_until: None, last_stream_title: None,
             song_meta_update_rx,
         }));
         // Throughout this application, we make use of a hack.
@@ -513,6 +604,11 @@ impl Controller {
         this.settings_controller = Some(settings::Controller::new(Rc::downgrade(&nu)));
         this.edit_controller = Some(edit::Controller::new(Rc::downgrade(&nu), song_meta_update_tx));
         this.errors_controller = Some(errors_window::Controller::new(Rc::downgrade(&nu)));
+        this.alarms_controller = Some(alarms::Controller::new());
+        this.podcasts_controller
+            = Some(podcasts::Controller::new(Rc::downgrade(&nu)));
+        this.sync_controller
+            = Some(sync_window::Controller::new(Rc::downgrade(&nu)));
         this.remote = Some(Remote::new(Rc::downgrade(&nu)));
         this.delete_playlist_button
             .set_sensitive(this.delete_playlist_button_should_be_sensitive());
@@ -529,6 +625,16 @@ impl Controller {
             playback::send_command(PlaybackCommand::Next)
         });
         let controller = nu.clone();
+        this.scan_pause_button.connect_toggled(move |button| {
+            let _ = controller.try_borrow_mut()
+                .map(|mut x| x.scan_thread.set_paused(button.get_active()));
+        });
+        let controller = nu.clone();
+        this.scan_cancel_button.connect_clicked(move |_| {
+            let _ = controller.try_borrow_mut()
+                .map(|mut x| x.clicked_scan_cancel());
+        });
+        let controller = nu.clone();
         this.rollup_button.connect_clicked(move |_| {
             let _ = controller.try_borrow_mut()
                 .map(|mut x| x.clicked_rollup());
@@ -616,6 +722,36 @@ context.drag_finish(res.0, res.1, time);
                 .map(|mut x| x.clicked_delete_playlist());
         });
         let controller = nu.clone();
+        let window = this.window.clone();
+        this.add_stream_button.connect_clicked(move |_| {
+            let (url, title) = match ask_for_stream(&window) {
+                Some(x) => x,
+                None => return,
+            };
+            let res = controller.try_borrow_mut()
+                .map(|mut x| x.add_stream(&url, &title));
+            if let Ok(Err(x)) = res {
+                let error = MessageDialog::new(Some(&window),
+                                               DialogFlags::MODAL,
+                                               MessageType::Error,
+                                               ButtonsType::Ok,
+                                               &format!("Couldn't add the \
+                                                         stream: {}", x));
+                error.run();
+                error.close();
+            }
+        });
+        let controller = nu.clone();
+        this.podcasts_button.connect_clicked(move |_| {
+            let _ = controller.try_borrow_mut()
+                .map(|mut x| x.clicked_podcasts());
+        });
+        let controller = nu.clone();
+        this.sync_button.connect_clicked(move |_| {
+            let _ = controller.try_borrow_mut()
+                .map(|mut x| x.clicked_sync());
+        });
+        let controller = nu.clone();
         this.window.connect_size_allocate(move |_, allocation| {
             let _ = controller.try_borrow_mut()
                 .map(|mut x| x.main_window_resized(allocation));
@@ -709,6 +845,21 @@ context.drag_finish(res.0, res.1, time);
                             .map(|mut x| x.remote_playmode());
                         return Inhibit(true)
                     },
+                    key::bracketleft => {
+                        playback::send_command(PlaybackCommand::Loop
+                                               (LoopCommand::MarkStart));
+                        return Inhibit(true)
+                    },
+                    key::bracketright => {
+                        playback::send_command(PlaybackCommand::Loop
+                                               (LoopCommand::MarkEnd));
+                        return Inhibit(true)
+                    },
+                    key::backslash => {
+                        playback::send_command(PlaybackCommand::Loop
+                                               (LoopCommand::Set(None)));
+                        return Inhibit(true)
+                    },
                     _ => ()
                 }
             }
@@ -752,12 +903,14 @@ context.drag_finish(res.0, res.1, time);
                 Inhibit(false)
             }
         });
+        build_sleep_menu(&this.sleep_button, &nu);
         this.activate_playlist_by_path(&TreePath::new_first());
         this.force_periodic();
         // okay, show the window and away we go
         this.window.show_all();
         // and now, this! (because show_all ruins it otherwise)
         this.errors_button.set_visible(false);
+        this.update_scan_status();
         drop(this);
         nu
     }
@@ -1005,6 +1158,10 @@ context.drag_finish(res.0, res.1, time);
         self.update_scan_status();
         self.update_errors();
         self.maybe_update_playlist();
+        self.check_alarms();
+        self.check_podcasts();
+        self.check_sync();
+        playback::maybe_save_state();
         if self.volume_changed {
             // TODO: do prefs updates in the background?
             match prefs::write() {
@@ -1087,8 +1244,60 @@ context.drag_finish(res.0, res.1, time);
         }
         playback::set_future_playlist(neu);
     }
+    /// Shows, on the volume label, whether the limiter has had to turn the
+    /// audio down recently. (If it didn't, that audio would have clipped.)
+    fn update_limiter_indicator(&mut self) {
+        let now = Instant::now();
+        if playback::take_limiter_reduction() > LIMITER_INDICATOR_THRESHOLD {
+            if self.limiting_until.is_none() {
+                self.volume_label.get_style_context().add_class("limiting");
+            }
+            self.limiting_until = Some(now + LIMITER_INDICATOR_HOLD);
+        }
+        else if self.limiting_until.map(|x| x <= now).unwrap_or(false) {
+            self.volume_label.get_style_context().remove_class("limiting");
+            self.limiting_until = None;
+        }
+    }
+    /// Shows what the sleep timer (or "stop after") is going to do, and when.
+    fn update_sleep_button(&self) {
+        // TODO: i18n
+        let label = match (playback::get_sleep_timer(),
+                           playback::get_stop_after()) {
+            (Some(timer), _) => {
+                let left = timer.deadline
+                    .saturating_duration_since(Instant::now()).as_secs();
+                format!("Sleep {}", pretty_duration(left as u32))
+            },
+            (None, Some(StopAfter::Song)) => "Stop after song".to_owned(),
+            (None, Some(StopAfter::Album)) => "Stop after album".to_owned(),
+            (None, Some(StopAfter::Playlist)) => "Pause at end".to_owned(),
+            (None, None) => "Sleep".to_owned(),
+        };
+        if self.sleep_button.get_label().as_ref().map(|x| x.as_str())
+            != Some(label.as_str()) {
+                self.sleep_button.set_label(&label);
+            }
+    }
+    /// Sets (or, if `minutes` is `None`, cancels) the sleep timer.
+    fn set_sleep_timer(&self, minutes: Option<f64>, fade_out: bool) {
+        let timer = minutes.map(|x| {
+            SleepTimer::new(Duration::from_secs_f64(x.max(0.0) * 60.0),
+                            fade_out)
+        });
+        playback::send_command(PlaybackCommand::SetSleepTimer(timer));
+        self.update_sleep_button();
+    }
+    /// Sets (or cancels) where playback should stop on its own.
+    fn set_stop_after(&self, nu: Option<StopAfter>) {
+        playback::send_command(PlaybackCommand::SetStopAfter(nu));
+        self.update_sleep_button();
+    }
     fn update_view(&mut self) {
+        self.update_sleep_button();
+        self.update_limiter_indicator();
         let (status, active_song) = playback::get_status_and_active_song();
+        let stream_title = playback::get_stream_title();
         if status.is_playing() {
             set_icon(&self.play_button, "tsong-pause");
         }
@@ -1107,20 +1316,34 @@ context.drag_finish(res.0, res.1, time);
                     self.remote_time = time;
                     self.remote.as_ref().unwrap().set_play_pos(time);
                 }
-                self.osd.set_label
-                    (&format!("{} - {}\n{} / {}",
-                              metadata.get("title").map(String::as_str)
-                              .unwrap_or("Unknown Title"),
-                              metadata.get("artist").map(String::as_str)
-                              .unwrap_or("Unknown Artist"),
-                              pretty_duration(time.floor() as u32),
-                              pretty_duration(song.get_duration())));
+                let title = metadata.get("title").map(String::as_str)
+                    .unwrap_or("Unknown Title");
+                if !song.is_stream() {
+                    self.osd.set_label
+                        (&format!("{} - {}\n{} / {}", title,
+                                  metadata.get("artist").map(String::as_str)
+                                  .unwrap_or("Unknown Artist"),
+                                  pretty_duration(time.floor() as u32),
+                                  pretty_duration(song.get_duration())));
+                }
+                else if let Some(stream_title) = stream_title.as_ref() {
+                    // (`title` is the station, here)
+                    self.osd.set_label
+                        (&format!("{}\n{} / {}", stream_title, title,
+                                  pretty_duration(time.floor() as u32)));
+                }
+                else {
+                    self.osd.set_label
+                        (&format!("{}\n{} / live", title,
+                                  pretty_duration(time.floor() as u32)));
+                }
                 drop(song);
                 Some(song_ref)
             },
         };
-        if self.last_active_song.as_ref().map(|x| &x.1)
-        != active_song.as_ref() {
+        let song_changed = self.last_active_song.as_ref().map(|x| &x.1)
+            != active_song.as_ref();
+        if song_changed {
             let playlist_model = self.playlist_model.as_ref().unwrap();
             match self.last_active_song.as_ref() {
                 Some((Some(iter), _)) => {
@@ -1162,9 +1385,14 @@ context.drag_finish(res.0, res.1, time);
                 },
                 None => (),
             }
-            // TODO: also do this if we edit the song's metadata while it's
-            // playing
-            self.remote.as_ref().unwrap().set_now_playing(active_song.as_ref());
+        }
+        // TODO: also do this if we edit the song's metadata while it's
+        // playing
+        if song_changed || stream_title != self.last_stream_title {
+            self.remote.as_ref().unwrap()
+                .set_now_playing(active_song.as_ref(),
+                                 stream_title.as_ref().map(String::as_str));
+            self.last_stream_title = stream_title;
         }
     }
     fn force_spinner_start(&self) {
@@ -1183,6 +1411,9 @@ context.drag_finish(res.0, res.1, time);
                 // (We would try updating the playlist here, except that that
                 // will already have happened, because `update_view()` is
                 // called before us)
+                podcast::apply_episode_metadata();
+                let _ = self.podcasts_controller.as_ref().unwrap()
+                    .try_borrow_mut().map(|mut x| x.podcasts_changed());
                 true
             },
             Ok((false, Some(Err(x)))) => {
@@ -1191,7 +1422,7 @@ context.drag_finish(res.0, res.1, time);
                 true
             },
         };
-        scan_in_progress
+        scan_in_progress || sync::is_syncing()
             || self.edit_controller.as_ref().unwrap().borrow()
             .script_is_in_progress()
     }
@@ -1202,6 +1433,60 @@ context.drag_finish(res.0, res.1, time);
         else {
             self.scan_spinner.stop();
         }
+        match self.scan_thread.get_progress() {
+            None => {
+                self.scan_box.set_visible(false);
+                self.scan_pause_button.set_active(false);
+            },
+            Some(progress) => {
+                self.scan_box.set_visible(true);
+                self.scan_pause_button.set_active(progress.paused
+                                                  || self.scan_thread
+                                                  .is_paused());
+                let dir = progress.current_dir.as_ref()
+                    .and_then(|x| x.file_name())
+                    .map(|x| x.to_string_lossy().into_owned())
+                    .unwrap_or_else(String::new);
+                // TODO: i18n, plurality
+                let text = if progress.searching {
+                    self.scan_progress.pulse();
+                    format!("Searching {}... ({} files found, {} new)",
+                            dir, progress.files_discovered,
+                            progress.files_to_hash)
+                }
+                else {
+                    self.scan_progress.set_fraction
+                        (if progress.bytes_to_hash == 0 { 1.0 }
+                         else { progress.bytes_hashed as f64
+                                / progress.bytes_to_hash as f64 });
+                    let mut text = format!("Scanning {}... ({} of {} new \
+                                            files, {} of {} MB)", dir,
+                                           progress.files_hashed,
+                                           progress.files_to_hash,
+                                           progress.bytes_hashed / 1000000,
+                                           progress.bytes_to_hash / 1000000);
+                    if let Some(eta) = progress.eta {
+                        text += &format!(", about {} left",
+                                         pretty_duration(eta.as_secs()
+                                                         .try_into()
+                                                         .unwrap_or(u32::MAX)));
+                    }
+                    text
+                };
+                if progress.paused {
+                    self.scan_progress.set_text(Some("Paused"));
+                }
+                else {
+                    self.scan_progress.set_text(Some(&text));
+                }
+            },
+        }
+    }
+    fn clicked_scan_cancel(&mut self) {
+        self.scan_thread.cancel();
+        // don't leave the next scan paused
+        self.scan_thread.set_paused(false);
+        self.force_periodic_soon();
     }
     fn update_errors(&mut self) -> Option<()> {
         if let Some((new_generation, errors)) = errors::if_newer_than(&self.errors_generation) {
@@ -1474,6 +1759,21 @@ context.drag_finish(res.0, res.1, time);
             }
             playlist::delete_playlist(playlist);
         }
+        self.rebuild_playlists_model();
+        None
+    }
+    /// Deletes a playlist that something other than the user (e.g. a podcast
+    /// going away) has decided should go.
+    fn forget_playlist(&mut self, playlist_ref: PlaylistRef) {
+        if Some(&playlist_ref) == self.active_playlist.as_ref() {
+            self.active_playlist = None;
+        }
+        playlist::delete_playlist(playlist_ref);
+        self.rebuild_playlists_model();
+    }
+    /// Rebuilds the list of playlists, after playlists have been added or
+    /// deleted.
+    fn rebuild_playlists_model(&mut self) {
         let expanded_playlist_ids = self.get_expanded_playlists();
         let (neu_model, _, neu_active_playlist) = build_playlists_model(&[]);
         self.playlists_model = neu_model;
@@ -1485,7 +1785,6 @@ context.drag_finish(res.0, res.1, time);
         }
         self.delete_playlist_button
             .set_sensitive(self.delete_playlist_button_should_be_sensitive());
-        None
     }
     fn clicked_rollup(&mut self) {
         let mut geom = Geometry {
@@ -1565,8 +1864,101 @@ context.drag_finish(res.0, res.1, time);
     fn closed_edit(&mut self) {
         self.edit_button.set_active(false);
     }
+    /// Makes a new song that plays from the given URL, and adds it to the
+    /// active playlist.
+    fn add_stream(&mut self, url: &str, title: &str) -> anyhow::Result<()> {
+        let song_id = logical::create_stream_song(url, title)?
+            .read().unwrap().get_id();
+        if let Some(playlist_ref) = self.active_playlist.as_ref() {
+            let mut playlist = playlist_ref.write().unwrap();
+            // (the new song has the highest ID, so the list stays sorted)
+            let mut songs = playlist.get_manual_songs().to_vec();
+            songs.push(song_id);
+            playlist.set_manual_songs(songs);
+        }
+        self.rebuild_playlist_view();
+        Ok(())
+    }
+    fn clicked_alarms(&mut self) -> Option<()> {
+        self.alarms_controller.as_ref().unwrap().try_borrow_mut().ok()?
+            .show();
+        None
+    }
+    fn clicked_podcasts(&mut self) -> Option<()> {
+        self.podcasts_controller.as_ref().unwrap().try_borrow_mut().ok()?
+            .show();
+        None
+    }
+    fn clicked_sync(&mut self) -> Option<()> {
+        self.sync_controller.as_ref().unwrap().try_borrow_mut().ok()?
+            .show();
+        None
+    }
+    /// Keeps the sync window up to date with the sync in progress, if any.
+    fn check_sync(&mut self) {
+        let controller = self.sync_controller.as_ref().unwrap();
+        if sync::poll() {
+            let _ = controller.try_borrow_mut()
+                .map(|mut x| x.sync_finished());
+        }
+        else if sync::is_syncing() {
+            let _ = controller.try_borrow_mut()
+                .map(|mut x| x.update_progress());
+        }
+    }
+    /// Starts refreshing any podcasts that are due for it. If a refresh has
+    /// finished, scans the podcast directory for the new episodes.
+    fn check_podcasts(&mut self) {
+        if podcast::poll() {
+            let dir = prefs::get_podcast_directory();
+            if dir.is_dir() {
+                let dir = dir.to_string_lossy().into_owned();
+                if let Err(x) = self.scan_thread.rescan(vec![dir]) {
+                    warn!("Couldn't start podcast scan! {:?}", x);
+                }
+            }
+            let _ = self.podcasts_controller.as_ref().unwrap()
+                .try_borrow_mut().map(|mut x| x.podcasts_changed());
+        }
+    }
+    /// Sets off any alarms that are due. (If more than one is, the last one
+    /// wins.)
+    fn check_alarms(&mut self) {
+        if let Some(alarm) = alarm::poll(&alarm::SystemClock).pop() {
+            self.fire_alarm(&alarm);
+        }
+    }
+    /// Starts the alarm's playlist from the top, fading in, even if something
+    /// else was already playing.
+    fn fire_alarm(&mut self, alarm: &alarm::Alarm) {
+        let playlist_ref = match playlist::get_playlist_by_id
+            (alarm.playlist_id) {
+            Some(x) => x,
+            None => {
+                alarm::report_failure(alarm, "Its playlist was deleted.");
+                return
+            },
+        };
+        playback::send_command(PlaybackCommand::Stop);
+        if alarm.shuffle {
+            playlist_ref.write().unwrap().reshuffle();
+            if self.active_playlist.as_ref() == Some(&playlist_ref) {
+                self.shuffle_button.set_active(true);
+                let _ = self.remote.as_ref().unwrap().set_is_shuffled(true);
+                self.rebuild_playlist_view();
+            }
+        }
+        match alarm.volume {
+            Some(volume) => { self.remote_set_volume(volume as f64 / 100.0); },
+            None => playback::set_mute(false),
+        }
+        self.change_future_playlist(Some(playlist_ref));
+        playback::send_command(PlaybackCommand::FadeIn(alarm.ramp));
+        playback::send_command(PlaybackCommand::Play(None));
+        set_icon(&self.play_button, "tsong-pause");
+    }
     fn rescan(&mut self) {
-        match self.scan_thread.rescan(prefs::get_music_paths()) {
+        match self.scan_thread.rescan(scan::get_scan_paths()) {
             Ok(_) => (),
             Err(x) => warn!("Couldn't start music scan! {:?}", x),
         }
@@ -1592,10 +1984,15 @@ context.drag_finish(res.0, res.1, time);
             .set_selected_songs(&selected_songs[..]);
     }
     fn edit_playlist(&mut self, neu_code: String,
-                     neu_columns: Vec<playlist::Column>) {
-        self.active_playlist.as_ref()
-            .map(|x| x.write().unwrap()
-                 .set_rule_code_and_columns(neu_code, neu_columns));
+                     neu_columns: Vec<playlist::Column>,
+                     remember_positions: bool, shuffle_albums: bool,
+                     album_key: Vec<String>) {
+        if let Some(playlist) = self.active_playlist.as_ref() {
+            let mut playlist = playlist.write().unwrap();
+            let _ = playlist.set_rule_code_and_columns(neu_code, neu_columns);
+            playlist.set_remember_positions(remember_positions);
+            playlist.set_album_shuffle(shuffle_albums, album_key);
+        }
     }
     fn update_playlist_view(&self, playlist: RwLockReadGuard<Playlist>,
                             mut changed_songs: HashSet<SongID>)
@@ -2287,6 +2684,278 @@ fn check_drag_onto_playlist<'a>
     }
 }
 
+/// Builds the popover that changes the speed and pitch, and sets up A–B
+/// loops.
+fn build_practice_popover(button: &MenuButton) {
+    let tempo = playback::get_tempo();
+    let popover = Popover::new(Some(button));
+    let tempo_box = BoxBuilder::new()
+        .orientation(Orientation::Vertical).spacing(4).build();
+    tempo_box.add(&LabelBuilder::new()
+                  .label("Speed:").halign(Align::Start).build());
+    let speed_scale = Scale::with_range(Orientation::Horizontal,
+                                        playback::MIN_SPEED,
+                                        playback::MAX_SPEED, 0.05);
+    speed_scale.set_digits(2);
+    speed_scale.set_value(tempo.speed);
+    speed_scale.add_mark(1.0, PositionType::Bottom, None);
+    speed_scale.set_size_request(200, -1);
+    tempo_box.add(&speed_scale);
+    let preserve_pitch_box = CheckButton::with_label("Keep the pitch");
+    preserve_pitch_box.set_tooltip_text
+        (Some("If checked, changing the speed doesn't change the pitch. If \
+               unchecked, it does, like a tape being played too fast or too \
+               slow."));
+    preserve_pitch_box.set_active(tempo.preserve_pitch);
+    tempo_box.add(&preserve_pitch_box);
+    tempo_box.add(&LabelBuilder::new()
+                  .label("Pitch: (semitones)").halign(Align::Start).build());
+    let pitch_scale = Scale::with_range(Orientation::Horizontal,
+                                        -playback::MAX_PITCH_SHIFT,
+                                        playback::MAX_PITCH_SHIFT, 1.0);
+    pitch_scale.set_digits(0);
+    pitch_scale.set_value(tempo.pitch_shift);
+    pitch_scale.add_mark(0.0, PositionType::Bottom, None);
+    tempo_box.add(&pitch_scale);
+    let reset_button = ButtonBuilder::new().label("Normal").build();
+    tempo_box.add(&reset_button);
+    tempo_box.add(&SeparatorBuilder::new()
+                  .orientation(Orientation::Horizontal).build());
+    tempo_box.add(&LabelBuilder::new()
+                  .label("A–B repeat:").halign(Align::Start).build());
+    let ab_box = BoxBuilder::new()
+        .orientation(Orientation::Horizontal).spacing(4).build();
+    let set_a_button = ButtonBuilder::new().label("Set A")
+        .tooltip_text("Start the loop here. ([)").build();
+    let set_b_button = ButtonBuilder::new().label("Set B")
+        .tooltip_text("End the loop here, and start looping. (])").build();
+    let clear_loop_button = ButtonBuilder::new().label("Clear")
+        .tooltip_text("Stop looping. (\\)").build();
+    ab_box.pack_start(&set_a_button, true, true, 0);
+    ab_box.pack_start(&set_b_button, true, true, 0);
+    ab_box.pack_start(&clear_loop_button, true, true, 0);
+    tempo_box.add(&ab_box);
+    let repeats_box = BoxBuilder::new()
+        .orientation(Orientation::Horizontal).spacing(4).build();
+    repeats_box.pack_start(&LabelBuilder::new().label("Times to play:")
+                           .build(), false, false, 0);
+    let repeats_spin = SpinButton::with_range(0.0, 999.0, 1.0);
+    repeats_spin.set_tooltip_text(Some("0 = forever"));
+    repeats_box.pack_end(&repeats_spin, false, false, 0);
+    tempo_box.add(&repeats_box);
+    tempo_box.add(&LabelBuilder::new()
+                  .label("Saved loops:").halign(Align::Start).build());
+    let saved_loops_view = ComboBoxText::new();
+    tempo_box.add(&saved_loops_view);
+    let save_box = BoxBuilder::new()
+        .orientation(Orientation::Horizontal).spacing(4).build();
+    let loop_name_entry = Entry::new();
+    loop_name_entry.set_placeholder_text(Some("Name"));
+    let save_loop_button = ButtonBuilder::new().label("Save")
+        .tooltip_text("Save the current loop with this song.").build();
+    save_box.pack_start(&loop_name_entry, true, true, 0);
+    save_box.pack_start(&save_loop_button, false, false, 0);
+    tempo_box.add(&save_box);
+    tempo_box.show_all();
+    popover.add(&tempo_box);
+    button.set_popover(Some(&popover));
+    set_tempo_label(button, &tempo);
+    let button_clone = button.clone();
+    speed_scale.connect_value_changed(move |scale| {
+        playback::send_command(PlaybackCommand::SetSpeed(scale.get_value()));
+        set_tempo_label(&button_clone, &playback::Tempo {
+            speed: scale.get_value(),
+            ..playback::get_tempo()
+        });
+    });
+    let button_clone = button.clone();
+    pitch_scale.connect_value_changed(move |scale| {
+        playback::send_command(PlaybackCommand::SetPitchShift
+                               (scale.get_value()));
+        set_tempo_label(&button_clone, &playback::Tempo {
+            pitch_shift: scale.get_value(),
+            ..playback::get_tempo()
+        });
+    });
+    preserve_pitch_box.connect_toggled(move |check| {
+        playback::send_command(PlaybackCommand::SetPreservePitch
+                               (check.get_active()));
+    });
+    reset_button.connect_clicked(move |_| {
+        // (the scales will send the commands)
+        speed_scale.set_value(1.0);
+        pitch_scale.set_value(0.0);
+    });
+    set_a_button.connect_clicked(|_| {
+        playback::send_command(PlaybackCommand::Loop(LoopCommand::MarkStart));
+    });
+    set_b_button.connect_clicked(|_| {
+        playback::send_command(PlaybackCommand::Loop(LoopCommand::MarkEnd));
+    });
+    clear_loop_button.connect_clicked(|_| {
+        playback::send_command(PlaybackCommand::Loop(LoopCommand::Set(None)));
+    });
+    repeats_spin.connect_value_changed(|spin| {
+        let repeats = match spin.get_value_as_int() {
+            x if x <= 0 => None,
+            x => Some(x as u32),
+        };
+        playback::send_command(PlaybackCommand::Loop
+                               (LoopCommand::SetRepeats(repeats)));
+    });
+    // The saved loops belong to whatever song is playing, so refresh them
+    // each time the popover is shown.
+    let saved_loops_clone = saved_loops_view.clone();
+    let repeats_clone = repeats_spin.clone();
+    popover.connect_show(move |_| {
+        saved_loops_clone.remove_all();
+        if let Some((song, _)) = playback::get_active_song() {
+            for name in song.read().unwrap().get_practice_loops().keys() {
+                saved_loops_clone.append(Some(name), name);
+            }
+        }
+        let repeats = playback::get_ab_loop().and_then(|x| x.repeats);
+        repeats_clone.set_value(repeats.unwrap_or(0) as f64);
+    });
+    saved_loops_view.connect_changed(|view| {
+        let name = match view.get_active_id() {
+            Some(x) => x,
+            None => return,
+        };
+        let practice_loop = playback::get_active_song()
+            .and_then(|(song, _)| song.read().unwrap().get_practice_loops()
+                      .get(name.as_str()).cloned());
+        if let Some(practice_loop) = practice_loop {
+            playback::send_command(PlaybackCommand::Loop
+                                   (LoopCommand::Set(Some(practice_loop))));
+        }
+    });
+    save_loop_button.connect_clicked(move |_| {
+        let name = loop_name_entry.get_text();
+        let name = name.trim();
+        if name.is_empty() { return }
+        let ab_loop = match playback::get_ab_loop() {
+            Some(x) => x,
+            None => return,
+        };
+        let end = match ab_loop.end {
+            Some(x) => x,
+            None => return,
+        };
+        let song = match logical::get_song_by_song_id(ab_loop.song_id) {
+            Some(x) => x,
+            None => return,
+        };
+        let is_new = !song.read().unwrap().get_practice_loops()
+            .contains_key(name);
+        song.write().unwrap().set_practice_loop(name, Some(PracticeLoop {
+            start: ab_loop.start, end, repeats: ab_loop.repeats,
+        }));
+        if is_new {
+            saved_loops_view.append(Some(name), name);
+        }
+        loop_name_entry.set_text("");
+    });
+}
+
+/// Builds the menu of ways to make playback stop on its own.
+fn build_sleep_menu(button: &MenuButton,
+                    controller: &Rc<RefCell<Controller>>) {
+    // TODO: i18n
+    const STOP_AFTERS: &[(StopAfter, &str)] = &[
+        (StopAfter::Song, "Stop after this song"),
+        (StopAfter::Album, "Stop after this album"),
+        (StopAfter::Playlist, "Pause at end of playlist"),
+    ];
+    const SLEEP_MINUTES: &[u32] = &[15, 30, 45, 60, 90, 120];
+    let menu = Menu::new();
+    for &(stop_after, label) in STOP_AFTERS.iter() {
+        let item = MenuItem::with_label(label);
+        let controller = controller.clone();
+        item.connect_activate(move |_| {
+            let _ = controller.try_borrow()
+                .map(|x| x.set_stop_after(Some(stop_after)));
+        });
+        menu.append(&item);
+    }
+    menu.append(&SeparatorMenuItem::new());
+    let fade_out_item = CheckMenuItem::with_label("Fade out");
+    fade_out_item.set_active(true);
+    for &minutes in SLEEP_MINUTES.iter() {
+        let item = MenuItem::with_label(&format!("Sleep in {} minutes",
+                                                 minutes));
+        let controller = controller.clone();
+        let fade_out_item = fade_out_item.clone();
+        item.connect_activate(move |_| {
+            let fade_out = fade_out_item.get_active();
+            let _ = controller.try_borrow()
+                .map(|x| x.set_sleep_timer(Some(minutes as f64), fade_out));
+        });
+        menu.append(&item);
+    }
+    menu.append(&fade_out_item);
+    menu.append(&SeparatorMenuItem::new());
+    let cancel_item = MenuItem::with_label("Cancel");
+    let cancel_controller = controller.clone();
+    cancel_item.connect_activate(move |_| {
+        let _ = cancel_controller.try_borrow().map(|x| {
+            x.set_sleep_timer(None, false);
+            x.set_stop_after(None);
+        });
+    });
+    menu.append(&cancel_item);
+    menu.append(&SeparatorMenuItem::new());
+    let alarms_item = MenuItem::with_label("Alarms…");
+    let controller = controller.clone();
+    alarms_item.connect_activate(move |_| {
+        let _ = controller.try_borrow_mut().map(|mut x| x.clicked_alarms());
+    });
+    menu.append(&alarms_item);
+    menu.show_all();
+    button.set_popup(Some(&menu));
+}
+
+/// Asks the user for the URL and title of a stream to add. Returns `None` if
+/// they cancel.
+fn ask_for_stream(window: &ApplicationWindow) -> Option<(String, String)> {
+    let dialog = Dialog::with_buttons(Some("Add Stream"), Some(window),
+                                      DialogFlags::MODAL,
+                                      &[("Cancel", ResponseType::Cancel),
+                                        ("Add", ResponseType::Ok)]);
+    dialog.set_default_response(ResponseType::Ok);
+    let grid = GridBuilder::new().row_spacing(4).column_spacing(8)
+        .margin(8).build();
+    let url_entry = Entry::new();
+    url_entry.set_placeholder_text(Some("https://example.com/stream"));
+    url_entry.set_activates_default(true);
+    url_entry.set_hexpand(true);
+    let title_entry = Entry::new();
+    title_entry.set_placeholder_text(Some("(station name)"));
+    title_entry.set_activates_default(true);
+    grid.attach(&Label::new(Some("URL:")), 0, 0, 1, 1);
+    grid.attach(&url_entry, 1, 0, 1, 1);
+    grid.attach(&Label::new(Some("Title:")), 0, 1, 1, 1);
+    grid.attach(&title_entry, 1, 1, 1, 1);
+    dialog.get_content_area().add(&grid);
+    dialog.show_all();
+    let result = dialog.run();
+    let ret = (url_entry.get_text().to_string(),
+               title_entry.get_text().to_string());
+    dialog.close();
+    if result == ResponseType::Ok { Some(ret) } else { None }
+}
+
+/// Shows the speed and pitch on the button that changes them.
+fn set_tempo_label(button: &MenuButton, tempo: &playback::Tempo) {
+    // TODO: i18n
+    let label = if tempo.is_normal() { "1×".to_owned() }
+    else if tempo.pitch_shift == 0.0 { format!("{:.2}×", tempo.speed) }
+    else {
+        format!("{:.2}× {:+}", tempo.speed, tempo.pitch_shift.round())
+    };
+    button.set_label(&label);
+}
+
 fn set_volume_label(scale: &Scale, label: &Label) {
     let val = scale.get_value().floor().min(200.0).max(0.0) as i32;
     if val > 100 {
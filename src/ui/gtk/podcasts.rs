@@ -0,0 +1,422 @@
+use crate::*;
+use gtk::{
+    prelude::*,
+    BoxBuilder,
+    ButtonBoxBuilder, ButtonBoxStyle,
+    Button, ButtonBuilder,
+    ButtonsType,
+    CellRendererText,
+    CellRendererToggle,
+    CheckButton,
+    DialogFlags,
+    Entry,
+    LabelBuilder,
+    ListStore,
+    MessageDialog,
+    MessageType,
+    Orientation,
+    PolicyType,
+    ResponseType,
+    ScrolledWindowBuilder,
+    SeparatorBuilder,
+    SpinButton,
+    TreePath,
+    TreeView, TreeViewBuilder, TreeViewColumn,
+    Window, WindowBuilder, WindowType,
+};
+use glib::{
+    Type
+};
+use std::{
+    cell::RefCell,
+    rc::{Rc,Weak},
+    time::{SystemTime, UNIX_EPOCH},
+};
+use podcast::Podcast;
+
+const PODCAST_ID_COLUMN: u32 = 0;
+const PODCAST_TITLE_COLUMN: u32 = 1;
+const PODCAST_EPISODES_COLUMN: u32 = 2;
+const PODCAST_CHECKED_COLUMN: u32 = 3;
+
+const EPISODE_GUID_COLUMN: u32 = 0;
+const EPISODE_PLAYED_COLUMN: u32 = 1;
+const EPISODE_TITLE_COLUMN: u32 = 2;
+const EPISODE_DATE_COLUMN: u32 = 3;
+const EPISODE_DOWNLOADED_COLUMN: u32 = 4;
+
+pub struct Controller {
+    parent: Weak<RefCell<super::Controller>>,
+    window: Window,
+    podcasts_view: TreeView,
+    podcasts_model: ListStore,
+    url_entry: Entry,
+    subscribe_button: Button,
+    refresh_button: Button,
+    unsubscribe_button: Button,
+    form_box: gtk::Box,
+    limit_box: CheckButton,
+    keep_spin: SpinButton,
+    delete_played_box: CheckButton,
+    episodes_view: TreeView,
+    episodes_model: ListStore,
+}
+
+impl Controller {
+    pub fn new(parent: Weak<RefCell<super::Controller>>)
+    -> Rc<RefCell<Controller>> {
+        // TODO: i18n
+        let window = WindowBuilder::new()
+            .name("podcasts").type_(WindowType::Toplevel)
+            .title("Tsong - Podcasts").default_height(480).build();
+        let big_box = BoxBuilder::new()
+            .name("podcasts").spacing(4).orientation(Orientation::Vertical)
+            .build();
+        window.add(&big_box);
+        let podcasts_window = ScrolledWindowBuilder::new()
+            .hscrollbar_policy(PolicyType::Automatic)
+            .vscrollbar_policy(PolicyType::Automatic)
+            .vexpand(true).min_content_height(100)
+            .build();
+        let podcasts_view = TreeViewBuilder::new()
+            .headers_visible(true).build();
+        for &(title, column) in &[("Podcast", PODCAST_TITLE_COLUMN),
+                                  ("Episodes", PODCAST_EPISODES_COLUMN),
+                                  ("Checked", PODCAST_CHECKED_COLUMN)] {
+            let view_column = TreeViewColumn::new();
+            view_column.set_title(title);
+            view_column.set_expand(column == PODCAST_TITLE_COLUMN);
+            let cell = CellRendererText::new();
+            view_column.pack_start(&cell, true);
+            view_column.add_attribute(&cell, "text", column as i32);
+            podcasts_view.append_column(&view_column);
+        }
+        let podcasts_model = ListStore::new(&[Type::U64, Type::String,
+                                              Type::String, Type::String]);
+        podcasts_window.add(&podcasts_view);
+        big_box.add(&podcasts_window);
+        let subscribe_box = BoxBuilder::new()
+            .spacing(4).orientation(Orientation::Horizontal).build();
+        let url_entry = Entry::new();
+        url_entry.set_placeholder_text(Some("https://example.com/feed.xml"));
+        url_entry.set_hexpand(true);
+        subscribe_box.add(&url_entry);
+        let subscribe_button = ButtonBuilder::new()
+            .label("Subscribe").build();
+        subscribe_box.add(&subscribe_button);
+        big_box.add(&subscribe_box);
+        let list_button_box = ButtonBoxBuilder::new()
+            .layout_style(ButtonBoxStyle::Expand)
+            .build();
+        let refresh_button = ButtonBuilder::new()
+            .tooltip_text("Check for new episodes now. (Podcasts are also \
+                           checked every few hours, while Tsong is \
+                           running.)")
+            .label("Refresh").build();
+        list_button_box.add(&refresh_button);
+        let unsubscribe_button = ButtonBuilder::new()
+            .tooltip_text("Stop following this podcast, and delete its \
+                           downloaded episodes and its playlist.")
+            .label("Unsubscribe").build();
+        list_button_box.add(&unsubscribe_button);
+        big_box.add(&list_button_box);
+        big_box.add(&SeparatorBuilder::new()
+                    .orientation(Orientation::Horizontal).build());
+        let form_box = BoxBuilder::new()
+            .spacing(4).orientation(Orientation::Vertical).build();
+        big_box.add(&form_box);
+        let keep_box = BoxBuilder::new()
+            .spacing(4).orientation(Orientation::Horizontal).build();
+        let limit_box = CheckButton::with_label("Only keep the newest");
+        keep_box.add(&limit_box);
+        let keep_spin = SpinButton::with_range(1.0, 999.0, 1.0);
+        keep_box.add(&keep_spin);
+        keep_box.add(&LabelBuilder::new().label("episodes").build());
+        form_box.add(&keep_box);
+        let delete_played_box
+            = CheckButton::with_label("Delete episodes once they've been \
+                                       played");
+        form_box.add(&delete_played_box);
+        let episodes_window = ScrolledWindowBuilder::new()
+            .hscrollbar_policy(PolicyType::Automatic)
+            .vscrollbar_policy(PolicyType::Automatic)
+            .vexpand(true).min_content_height(150)
+            .build();
+        let episodes_view = TreeViewBuilder::new()
+            .headers_visible(true).build();
+        let played_column = TreeViewColumn::new();
+        played_column.set_title("Played");
+        let played_cell = CellRendererToggle::new();
+        played_column.pack_start(&played_cell, false);
+        played_column.add_attribute(&played_cell, "active",
+                                    EPISODE_PLAYED_COLUMN as i32);
+        episodes_view.append_column(&played_column);
+        for &(title, column) in &[("Episode", EPISODE_TITLE_COLUMN),
+                                  ("Date", EPISODE_DATE_COLUMN),
+                                  ("Downloaded", EPISODE_DOWNLOADED_COLUMN)] {
+            let view_column = TreeViewColumn::new();
+            view_column.set_title(title);
+            view_column.set_expand(column == EPISODE_TITLE_COLUMN);
+            let cell = CellRendererText::new();
+            view_column.pack_start(&cell, true);
+            view_column.add_attribute(&cell, "text", column as i32);
+            episodes_view.append_column(&view_column);
+        }
+        let episodes_model = ListStore::new(&[Type::String, Type::Bool,
+                                              Type::String, Type::String,
+                                              Type::String]);
+        episodes_window.add(&episodes_view);
+        form_box.add(&episodes_window);
+        let ret = Rc::new(RefCell::new(Controller {
+            parent, window, podcasts_view, podcasts_model, url_entry,
+            subscribe_button, refresh_button, unsubscribe_button, form_box,
+            limit_box, keep_spin, delete_played_box, episodes_view,
+            episodes_model,
+        }));
+        let this = ret.borrow();
+        this.window.connect_delete_event(move |window, _| {
+            window.hide_on_delete()
+        });
+        let controller = ret.clone();
+        this.subscribe_button.connect_clicked(move |_| {
+            let _ = controller.try_borrow_mut()
+                .map(|mut x| x.clicked_subscribe());
+        });
+        let controller = ret.clone();
+        this.url_entry.connect_activate(move |_| {
+            let _ = controller.try_borrow_mut()
+                .map(|mut x| x.clicked_subscribe());
+        });
+        let controller = ret.clone();
+        this.refresh_button.connect_clicked(move |_| {
+            let _ = controller.try_borrow_mut()
+                .map(|mut x| x.clicked_refresh());
+        });
+        let controller = ret.clone();
+        this.unsubscribe_button.connect_clicked(move |_| {
+            let _ = controller.try_borrow_mut()
+                .map(|mut x| x.clicked_unsubscribe());
+        });
+        let controller = ret.clone();
+        this.podcasts_view.connect_cursor_changed(move |_| {
+            let _ = controller.try_borrow_mut()
+                .map(|mut x| x.changed_selection());
+        });
+        let controller = ret.clone();
+        this.limit_box.connect_toggled(move |_| {
+            let _ = controller.try_borrow_mut()
+                .map(|mut x| x.changed_settings());
+        });
+        let controller = ret.clone();
+        this.keep_spin.connect_value_changed(move |_| {
+            let _ = controller.try_borrow_mut()
+                .map(|mut x| x.changed_settings());
+        });
+        let controller = ret.clone();
+        this.delete_played_box.connect_toggled(move |_| {
+            let _ = controller.try_borrow_mut()
+                .map(|mut x| x.changed_settings());
+        });
+        let controller = ret.clone();
+        played_cell.connect_toggled(move |_, path| {
+            let _ = controller.try_borrow_mut()
+                .map(|mut x| x.toggled_played(&path));
+        });
+        drop(this);
+        ret
+    }
+    /// Returns the podcast whose row is selected, if any.
+    fn get_selected_podcast(&self) -> Option<Podcast> {
+        let path = self.podcasts_view.get_cursor().0?;
+        let iter = self.podcasts_model.get_iter(&path)?;
+        let id = self.podcasts_model.get_value(&iter,
+                                               PODCAST_ID_COLUMN as i32)
+            .get::<u64>().ok()??;
+        podcast::get_podcasts().into_iter().find(|x| x.id == id)
+    }
+    /// Selects the row for the podcast with the given id.
+    fn select_podcast(&self, id: u64) {
+        let view = &self.podcasts_view;
+        self.podcasts_model.foreach(|model, path, iter| {
+            let found = model.get_value(iter, PODCAST_ID_COLUMN as i32)
+                .get::<u64>().ok().flatten();
+            if found == Some(id) {
+                view.set_cursor(path, None::<&TreeViewColumn>, false);
+                true
+            }
+            else { false }
+        });
+    }
+    fn populate_podcasts(&mut self) {
+        let selected = self.get_selected_podcast().map(|x| x.id);
+        self.podcasts_model.clear();
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)
+            .map(|x| x.as_secs() as i64).unwrap_or(0);
+        for podcast in podcast::get_podcasts().iter() {
+            let episodes = podcast::get_episodes(podcast.id);
+            let downloaded = episodes.iter()
+                .filter(|x| x.is_downloaded()).count();
+            let episodes = format!("{} of {}", downloaded, episodes.len());
+            let checked = match podcast.last_refreshed {
+                None => "Never".to_owned(),
+                Some(then) => pretty_ago(now - then),
+            };
+            self.podcasts_model.insert_with_values
+                (None, &[PODCAST_ID_COLUMN, PODCAST_TITLE_COLUMN,
+                         PODCAST_EPISODES_COLUMN, PODCAST_CHECKED_COLUMN],
+                 &[&podcast.id, &podcast.title, &episodes, &checked]);
+        }
+        self.podcasts_view.set_model(Some(&self.podcasts_model));
+        if let Some(id) = selected {
+            self.select_podcast(id);
+        }
+    }
+    fn populate_episodes(&mut self, podcast: Option<&Podcast>) {
+        self.episodes_model.clear();
+        if let Some(podcast) = podcast {
+            for episode in podcast::get_episodes(podcast.id).iter() {
+                let date = episode.metadata.get("date").cloned()
+                    .unwrap_or_else(String::new);
+                let downloaded = if episode.is_downloaded() { "Yes" }
+                else { "" };
+                self.episodes_model.insert_with_values
+                    (None, &[EPISODE_GUID_COLUMN, EPISODE_PLAYED_COLUMN,
+                             EPISODE_TITLE_COLUMN, EPISODE_DATE_COLUMN,
+                             EPISODE_DOWNLOADED_COLUMN],
+                     &[&episode.guid, &episode.played, &episode.get_title(),
+                       &date, &downloaded]);
+            }
+        }
+        self.episodes_view.set_model(Some(&self.episodes_model));
+    }
+    /// Fills in the form with the selected podcast's settings and episodes,
+    /// or makes it insensitive if none is selected.
+    fn changed_selection(&mut self) {
+        let podcast = self.get_selected_podcast();
+        self.refresh_button.set_sensitive(podcast.is_some()
+                                          && !podcast::is_refreshing());
+        self.unsubscribe_button.set_sensitive(podcast.is_some());
+        self.form_box.set_sensitive(podcast.is_some());
+        self.populate_episodes(podcast.as_ref());
+        let podcast = match podcast {
+            Some(x) => x,
+            None => return,
+        };
+        self.limit_box.set_active(podcast.keep_episodes.is_some());
+        self.keep_spin.set_sensitive(podcast.keep_episodes.is_some());
+        if let Some(keep) = podcast.keep_episodes {
+            self.keep_spin.set_value(keep as f64);
+        }
+        self.delete_played_box.set_active(podcast.delete_played);
+    }
+    fn changed_settings(&mut self) -> Option<()> {
+        let mut podcast = self.get_selected_podcast()?;
+        podcast.keep_episodes = if self.limit_box.get_active() {
+            Some(self.keep_spin.get_value_as_int().max(1) as u32)
+        } else { None };
+        podcast.delete_played = self.delete_played_box.get_active();
+        self.keep_spin.set_sensitive(podcast.keep_episodes.is_some());
+        podcast::update_podcast(&podcast);
+        None
+    }
+    fn toggled_played(&mut self, path: &TreePath) -> Option<()> {
+        let podcast = self.get_selected_podcast()?;
+        let iter = self.episodes_model.get_iter(path)?;
+        let guid = self.episodes_model.get_value(&iter,
+                                                 EPISODE_GUID_COLUMN as i32)
+            .get::<String>().ok()??;
+        let played = self.episodes_model.get_value(&iter,
+                                                   EPISODE_PLAYED_COLUMN
+                                                   as i32)
+            .get::<bool>().ok()??;
+        podcast::set_played(podcast.id, &guid, !played);
+        self.episodes_model.set_value(&iter, EPISODE_PLAYED_COLUMN,
+                                      &(!played).to_value());
+        None
+    }
+    fn clicked_subscribe(&mut self) -> Option<()> {
+        let url = self.url_entry.get_text().to_string();
+        if url.trim().is_empty() { return None }
+        match podcast::subscribe(&url) {
+            Ok((podcast, _)) => {
+                self.url_entry.set_text("");
+                self.populate_podcasts();
+                self.select_podcast(podcast.id);
+                self.changed_selection();
+                let parent = self.parent.upgrade()?;
+                let mut parent = parent.try_borrow_mut().ok()?;
+                parent.rebuild_playlists_model();
+                // get its episodes right away
+                parent.check_podcasts();
+            },
+            Err(x) => {
+                let error = MessageDialog::new(Some(&self.window),
+                                               DialogFlags::MODAL,
+                                               MessageType::Error,
+                                               ButtonsType::Ok,
+                                               &format!("Couldn't subscribe: \
+                                                         {}", x));
+                error.run();
+                error.close();
+            },
+        }
+        None
+    }
+    fn clicked_refresh(&mut self) -> Option<()> {
+        let podcast = self.get_selected_podcast()?;
+        if podcast::start_refresh(vec![podcast.id]) {
+            self.refresh_button.set_sensitive(false);
+        }
+        None
+    }
+    fn clicked_unsubscribe(&mut self) -> Option<()> {
+        let podcast = self.get_selected_podcast()?;
+        let confirm = MessageDialog::new(Some(&self.window),
+                                         DialogFlags::MODAL,
+                                         MessageType::Question,
+                                         ButtonsType::OkCancel,
+                                         &format!("Unsubscribe from {}? Its \
+                                                   downloaded episodes and \
+                                                   its playlist will be \
+                                                   deleted.", podcast.title));
+        let result = confirm.run();
+        confirm.close();
+        if result != ResponseType::Ok { return None }
+        podcast::unsubscribe(podcast.id);
+        self.populate_podcasts();
+        self.changed_selection();
+        let playlist_ref = podcast.playlist_id
+            .and_then(playlist::get_playlist_by_id)?;
+        let parent = self.parent.upgrade()?;
+        parent.try_borrow_mut().ok()?.forget_playlist(playlist_ref);
+        None
+    }
+    /// Called when a refresh has finished, or new episodes have been found
+    /// by a scan.
+    pub fn podcasts_changed(&mut self) {
+        if self.window.is_visible() {
+            self.populate_podcasts();
+            self.changed_selection();
+        }
+    }
+    pub fn show(&mut self) {
+        if !self.window.is_visible() {
+            self.populate_podcasts();
+            self.window.show_all();
+            self.changed_selection();
+        }
+        else {
+            self.window.present();
+        }
+    }
+}
+
+/// Describes how long ago something was, roughly.
+// TODO: i18n
+pub fn pretty_ago(secs: i64) -> String {
+    let minutes = secs.max(0) / 60;
+    if minutes < 1 { "Just now".to_owned() }
+    else if minutes < 60 { format!("{} min ago", minutes) }
+    else if minutes < 60 * 48 { format!("{} hr ago", minutes / 60) }
+    else { format!("{} days ago", minutes / (60 * 24)) }
+}
@@ -10,8 +10,10 @@ use gtk::{
     CellRendererText,
     CheckButton,
     ComboBox, ComboBoxBuilder,
+    ComboBoxText,
+    Entry, EntryBuilder,
     FileChooserDialog, FileChooserAction,
-    LabelBuilder,
+    Label, LabelBuilder,
     ListStore,
     Orientation,
     PolicyType,
@@ -20,6 +22,7 @@ use gtk::{
     Scale, ScaleBuilder,
     ScrolledWindowBuilder,
     SeparatorBuilder,
+    SpinButton,
     TreeView, TreeViewBuilder, TreeViewColumn,
     Window, WindowBuilder, WindowType,
 };
@@ -35,6 +38,14 @@ use portaudio::{
     HostApiIndex,
     PortAudio,
 };
+use prefs::{AudioBackend, BroadcastFormat};
+use dsp::ChannelLayout;
+
+/// Values in the first column of `hostapi_model` that don't correspond to a
+/// PortAudio host API, but to one of our other audio backends.
+const PULSE_BACKEND_ROW: u32 = u32::MAX - 1;
+const NULL_BACKEND_ROW: u32 = u32::MAX - 2;
+const FILE_BACKEND_ROW: u32 = u32::MAX - 3;
 
 pub struct Controller {
     window: Window,
@@ -47,17 +58,52 @@ pub struct Controller {
     delete_location_button: Button,
     new_location_button: Button,
     resample_audio_box: CheckButton,
+    fall_back_box: CheckButton,
+    transport_fades_box: CheckButton,
+    transport_fade_slider: Scale,
     show_decibels_box: CheckButton,
+    follow_symlinks_box: CheckButton,
+    play_video_files_box: CheckButton,
     hostapi_view: ComboBox,
     hostapi_model: ListStore,
     audiodev_view: ComboBox,
     audiodev_model: ListStore,
     locations_view: TreeView,
     locations_model: ListStore,
+    scan_exclude_entry: Entry,
     desired_latency_slider: Scale,
     decode_ahead_slider: Scale,
+    underrun_label: Label,
+    channel_layout_view: ComboBoxText,
+    balance_slider: Scale,
+    swap_channels_box: CheckButton,
+    eq_enabled_box: CheckButton,
+    eq_preset_view: ComboBoxText,
+    eq_sliders: Vec<Scale>,
+    broadcast_box: CheckButton,
+    broadcast_port_spin: SpinButton,
+    broadcast_format_view: ComboBoxText,
+    broadcast_all_interfaces_box: CheckButton,
+    broadcast_max_listeners_spin: SpinButton,
 }
 
+/// If the user moves the graphic EQ sliders away from a preset's values, the
+/// result is saved as a preset with this name.
+const CUSTOM_EQ_PRESET: &str = "Custom";
+
+/// The choices in `channel_layout_view`, in order.
+const CHANNEL_LAYOUTS: &[(ChannelLayout, &str)] = &[
+    (ChannelLayout::Stereo, "Stereo"),
+    (ChannelLayout::Mono, "Mono"),
+    (ChannelLayout::Original, "Same as the file"),
+];
+
+/// The choices in `broadcast_format_view`, in order.
+const BROADCAST_FORMATS: &[(BroadcastFormat, &str)] = &[
+    (BroadcastFormat::Opus, "Opus"),
+    (BroadcastFormat::Mp3, "MP3"),
+];
+
 impl Controller {
     pub fn new(parent: Weak<RefCell<super::Controller>>)
     -> Rc<RefCell<Controller>> {
@@ -119,6 +165,14 @@ impl Controller {
             .build();
         decode_ahead_slider.set_digits(1);
         big_box.add(&decode_ahead_slider);
+        let underrun_label = LabelBuilder::new()
+            .halign(Align::Start).wrap(true)
+            .tooltip_text("When decoding can't keep up with playback, the \
+                           audio drops out. Each time this happens, we decode \
+                           further ahead (and sometimes raise the latency) \
+                           until Tsong is restarted.")
+            .build();
+        big_box.add(&underrun_label);
         let decode_ahead_clone = decode_ahead_slider.clone();
         desired_latency_slider.connect_value_changed(move |slider| {
             let value = slider.get_value();
@@ -132,10 +186,157 @@ impl Controller {
                    sample rate for the selected output device. If unchecked, \
                    we will let the OS handle that for us. (Advanced)"));
         big_box.add(&resample_audio_box);
+        let fall_back_box = CheckButton::with_label
+            ("Fall back to the default device");
+        fall_back_box.set_tooltip_text
+            (Some("If checked, and the selected output device goes away \
+                   during playback (e.g. it gets unplugged), playback will \
+                   continue on the default device. If unchecked, playback \
+                   will be paused."));
+        big_box.add(&fall_back_box);
         // Another checkbox!
         let show_decibels_box = CheckButton::with_label
             ("Show decibels on volume slider");
         big_box.add(&show_decibels_box);
+        // Fades!
+        let transport_fades_box = CheckButton::with_label
+            ("Fade when pausing, resuming, or changing songs: (seconds)");
+        transport_fades_box.set_tooltip_text
+            (Some("If checked, the audio fades out briefly instead of cutting \
+                   off when you pause, stop, or skip to a different song, \
+                   and fades back in when it starts again."));
+        big_box.add(&transport_fades_box);
+        let transport_fade_slider = Scale::with_range
+            (Orientation::Horizontal, prefs::MIN_TRANSPORT_FADE_LENGTH,
+             prefs::MAX_TRANSPORT_FADE_LENGTH, 0.01);
+        transport_fade_slider.set_digits(2);
+        transport_fade_slider.set_value_pos(PositionType::Bottom);
+        big_box.add(&transport_fade_slider);
+        let transport_fade_slider_clone = transport_fade_slider.clone();
+        transport_fades_box.connect_toggled(move |button| {
+            transport_fade_slider_clone.set_sensitive(button.get_active());
+        });
+        // The channels!
+        big_box.add(&LabelBuilder::new()
+                    .label("Output Channels:").halign(Align::Start).build());
+        let channel_layout_view = ComboBoxText::new();
+        channel_layout_view.set_tooltip_text
+            (Some("How many channels to send to the output device. Surround \
+                   audio is mixed down to fit, and mono audio is sent to both \
+                   stereo speakers."));
+        for &(layout, label) in CHANNEL_LAYOUTS.iter() {
+            channel_layout_view.append(Some(layout.get_name()), label);
+        }
+        big_box.add(&channel_layout_view);
+        big_box.add(&LabelBuilder::new()
+                    .label("Balance:").halign(Align::Start).build());
+        let balance_slider = Scale::with_range(Orientation::Horizontal,
+                                               prefs::MIN_BALANCE,
+                                               prefs::MAX_BALANCE, 0.05);
+        balance_slider.set_digits(2);
+        balance_slider.set_value_pos(PositionType::Bottom);
+        balance_slider.add_mark(prefs::STANDARD_BALANCE, PositionType::Top,
+                                None);
+        balance_slider.set_tooltip_text
+            (Some("Moves the sound toward the left or right speaker. Has no \
+                   effect on mono output."));
+        big_box.add(&balance_slider);
+        let swap_channels_box = CheckButton::with_label
+            ("Swap left and right channels");
+        big_box.add(&swap_channels_box);
+        // The equalizer!
+        let eq_enabled_box = CheckButton::with_label("Equalizer");
+        eq_enabled_box.set_tooltip_text
+            (Some("If checked, the selected equalizer preset is applied to \
+                   every song. Songs with an \"eq_preset\" tag will use the \
+                   preset it names either way."));
+        big_box.add(&eq_enabled_box);
+        let eq_preset_view = ComboBoxText::new();
+        eq_preset_view.set_tooltip_text
+            (Some("Which equalizer preset to use. Presets can also adjust a \
+                   parametric equalizer, which can be edited in the \
+                   preferences file."));
+        big_box.add(&eq_preset_view);
+        let eq_box = BoxBuilder::new()
+            .orientation(Orientation::Horizontal).homogeneous(true)
+            .build();
+        let mut eq_sliders = Vec::with_capacity(dsp::GRAPHIC_EQ_BANDS);
+        for &frequency in dsp::GRAPHIC_EQ_FREQUENCIES.iter() {
+            let band_box = BoxBuilder::new()
+                .orientation(Orientation::Vertical).build();
+            let slider = Scale::with_range(Orientation::Vertical,
+                                           -dsp::MAX_EQ_GAIN,
+                                           dsp::MAX_EQ_GAIN, 0.5);
+            slider.set_inverted(true);
+            slider.set_digits(1);
+            slider.set_value_pos(PositionType::Bottom);
+            slider.add_mark(0.0, PositionType::Left, None);
+            slider.set_size_request(-1, 120);
+            band_box.pack_start(&slider, true, true, 0);
+            let label = if frequency >= 1000.0 {
+                format!("{}k", frequency / 1000.0)
+            } else { format!("{}", frequency.round()) };
+            band_box.add(&LabelBuilder::new().label(&label).build());
+            eq_box.add(&band_box);
+            eq_sliders.push(slider);
+        }
+        big_box.add(&eq_box);
+        // The broadcast!
+        let broadcast_row = BoxBuilder::new()
+            .orientation(Orientation::Horizontal).spacing(4).build();
+        let broadcast_box = CheckButton::with_label
+            ("Broadcast over HTTP on port:");
+        broadcast_box.set_tooltip_text
+            (Some("If checked, whatever you're hearing is also streamed over \
+                   HTTP, so that other computers can listen in by opening \
+                   http://this-computer:port/ in a media player. Unless \
+                   other computers are allowed to listen, only programs on \
+                   this computer can."));
+        broadcast_row.add(&broadcast_box);
+        let broadcast_port_spin = SpinButton::with_range(1.0, 65535.0, 1.0);
+        broadcast_row.add(&broadcast_port_spin);
+        let broadcast_format_view = ComboBoxText::new();
+        broadcast_format_view.set_tooltip_text
+            (Some("What to encode the broadcast as. MP3 is only available if \
+                   FFMPEG was built with LAME."));
+        for &(format, label) in BROADCAST_FORMATS.iter() {
+            broadcast_format_view.append(Some(format.get_name()), label);
+        }
+        broadcast_row.add(&broadcast_format_view);
+        big_box.add(&broadcast_row);
+        let broadcast_access_row = BoxBuilder::new()
+            .orientation(Orientation::Horizontal).spacing(4).build();
+        let broadcast_all_interfaces_box = CheckButton::with_label
+            ("Let other computers listen");
+        broadcast_all_interfaces_box.set_tooltip_text
+            (Some("If checked, anyone who can reach this computer over the \
+                   network can listen to the broadcast. If unchecked, only \
+                   programs running on this computer can."));
+        broadcast_access_row.add(&broadcast_all_interfaces_box);
+        broadcast_access_row.add(&LabelBuilder::new()
+                                 .label("Listeners at once:").build());
+        let broadcast_max_listeners_spin = SpinButton::with_range
+            (prefs::MIN_BROADCAST_MAX_LISTENERS as f64,
+             prefs::MAX_BROADCAST_MAX_LISTENERS as f64, 1.0);
+        broadcast_max_listeners_spin.set_tooltip_text
+            (Some("Anyone who tries to listen while this many others are \
+                   already listening will be turned away."));
+        broadcast_access_row.add(&broadcast_max_listeners_spin);
+        big_box.add(&broadcast_access_row);
+        let broadcast_port_spin_clone = broadcast_port_spin.clone();
+        let broadcast_format_view_clone = broadcast_format_view.clone();
+        let broadcast_all_interfaces_box_clone
+            = broadcast_all_interfaces_box.clone();
+        let broadcast_max_listeners_spin_clone
+            = broadcast_max_listeners_spin.clone();
+        broadcast_box.connect_toggled(move |button| {
+            broadcast_port_spin_clone.set_sensitive(button.get_active());
+            broadcast_format_view_clone.set_sensitive(button.get_active());
+            broadcast_all_interfaces_box_clone
+                .set_sensitive(button.get_active());
+            broadcast_max_listeners_spin_clone
+                .set_sensitive(button.get_active());
+        });
         // The music paths!
         big_box.add(&LabelBuilder::new()
                      .label("Music Locations:").halign(Align::Start).build());
@@ -148,13 +349,35 @@ impl Controller {
         let locations_view = TreeViewBuilder::new()
             .tooltip_text("List of locations on your filesystem that this \
                            copy of Tsong will scan for songs.\n\n\
-                           Use the buttons below to add and remove elements.")
-            .headers_visible(false).reorderable(true).build();
+                           Use the buttons below to add and remove elements. \
+                           Double click in the Include or Exclude column to \
+                           edit the comma-separated list of patterns for that \
+                           location, e.g. \"*/Podcasts/*, *.mid\".")
+            .headers_visible(true).reorderable(true).build();
         let location_column = TreeViewColumn::new();
+        location_column.set_title("Location");
+        location_column.set_expand(true);
         let location_cell = CellRendererText::new();
         location_column.pack_start(&location_cell, true);
         location_column.add_attribute(&location_cell, "text", 0);
         locations_view.append_column(&location_column);
+        let locations_model = ListStore::new(&[Type::String, Type::String,
+                                               Type::String]);
+        for &(title, column) in &[("Include", 1u32), ("Exclude", 2u32)] {
+            let pattern_column = TreeViewColumn::new();
+            pattern_column.set_title(title);
+            let pattern_cell = CellRendererText::new();
+            pattern_cell.set_property_editable(true);
+            pattern_column.pack_start(&pattern_cell, true);
+            pattern_column.add_attribute(&pattern_cell, "text", column as i32);
+            let model = locations_model.clone();
+            pattern_cell.connect_edited(move |_, path, new_text| {
+                if let Some(iter) = model.get_iter(&path) {
+                    model.set_value(&iter, column, &new_text.to_value());
+                }
+            });
+            locations_view.append_column(&pattern_column);
+        }
         locations_window.add(&locations_view);
         big_box.add(&locations_window);
         let location_button_box = ButtonBoxBuilder::new()
@@ -174,6 +397,32 @@ impl Controller {
         location_button_box.add(&new_location_button);
         big_box.add(&location_button_box);
         super::set_icon(&new_location_button, "tsong-add");
+        let scan_exclude_row = BoxBuilder::new()
+            .orientation(Orientation::Horizontal).spacing(4).build();
+        scan_exclude_row.add(&LabelBuilder::new()
+                             .label("Exclude everywhere:").build());
+        let scan_exclude_entry = EntryBuilder::new().hexpand(true)
+            .tooltip_text("Comma-separated list of patterns for files that \
+                           won't be scanned in any location, in addition to \
+                           each location's own Exclude patterns.")
+            .build();
+        scan_exclude_row.add(&scan_exclude_entry);
+        big_box.add(&scan_exclude_row);
+        let follow_symlinks_box = CheckButton::with_label
+            ("Follow symbolic links");
+        follow_symlinks_box.set_tooltip_text
+            (Some("If checked, symbolic links inside music locations will be \
+                   followed when scanning for songs. Each directory will only \
+                   be scanned once, even if there are several links to it."));
+        big_box.add(&follow_symlinks_box);
+        let play_video_files_box = CheckButton::with_label
+            ("Treat videos as songs");
+        play_video_files_box.set_tooltip_text
+            (Some("If checked, files that contain video as well as audio, \
+                   such as music videos and concert recordings, will be \
+                   added to the library and their audio played like any \
+                   other song. If unchecked, they will be skipped."));
+        big_box.add(&play_video_files_box);
         // The buttons!
         big_box.pack_start(&SeparatorBuilder::new()
                             .orientation(Orientation::Horizontal).build(),
@@ -203,15 +452,23 @@ impl Controller {
             parent,
             hostapi_view,
             audiodev_view,
-            locations_model: ListStore::new(&[Type::String]),
+            locations_model,
             locations_view,
+            scan_exclude_entry,
             apply_button,
             cancel_button,
             ok_button,
             delete_location_button,
             new_location_button,
-            decode_ahead_slider, desired_latency_slider,
-            resample_audio_box, show_decibels_box,
+            decode_ahead_slider, desired_latency_slider, underrun_label,
+            channel_layout_view, balance_slider, swap_channels_box,
+            eq_enabled_box, eq_preset_view, eq_sliders,
+            broadcast_box, broadcast_port_spin, broadcast_format_view,
+            broadcast_all_interfaces_box, broadcast_max_listeners_spin,
+            resample_audio_box, fall_back_box, show_decibels_box,
+            transport_fades_box, transport_fade_slider,
+            follow_symlinks_box,
+            play_video_files_box,
             hostapi_model: ListStore::new(&[Type::U32, Type::String]),
             audiodev_model: ListStore::new(&[Type::U32, Type::String]),
             me: None
@@ -230,6 +487,11 @@ impl Controller {
                 .map(|mut x| x.changed_hostapi());
         });
         let controller = ret.clone();
+        this.eq_preset_view.connect_changed(move |_| {
+            let _ = controller.try_borrow_mut()
+                .map(|mut x| x.changed_eq_preset());
+        });
+        let controller = ret.clone();
         this.apply_button.connect_clicked(move |_| {
             let _ = controller.try_borrow_mut()
                 .map(|mut x| x.clicked_apply());
@@ -293,6 +555,31 @@ impl Controller {
             }
             num_choices += 1;
         }
+        let backend = prefs::get_audio_backend();
+        let mut other_backends = Vec::new();
+        if cfg!(feature = "pulse") {
+            other_backends.push((PULSE_BACKEND_ROW, "PulseAudio (native)",
+                                 AudioBackend::Pulse));
+        }
+        // The null and file backends aren't for everyday listening, so they
+        // only show up here if they were chosen in the preferences file.
+        if backend == AudioBackend::Null {
+            other_backends.push((NULL_BACKEND_ROW, "No Output",
+                                 AudioBackend::Null));
+        }
+        if backend == AudioBackend::File {
+            other_backends.push((FILE_BACKEND_ROW, "WAV File",
+                                 AudioBackend::File));
+        }
+        for (value, name, which) in other_backends.into_iter() {
+            let new_row = self.hostapi_model.append();
+            self.hostapi_model.set_value(&new_row, 0, &value.to_value());
+            self.hostapi_model.set_value(&new_row, 1, &name.to_value());
+            if which == backend {
+                selected_iter = Some(new_row);
+            }
+            num_choices += 1;
+        }
         self.hostapi_view.set_model(Some(&self.hostapi_model));
         self.hostapi_view.set_active_iter(selected_iter.as_ref());
         self.hostapi_view.set_sensitive(num_choices > 1);
@@ -303,6 +590,16 @@ impl Controller {
         self.hostapi_model.get_value(&iter, 0).get::<u32>()
             .unwrap().unwrap() as HostApiIndex
     }
+    fn get_selected_backend(&mut self) -> AudioBackend {
+        let iter = self.hostapi_view.get_active_iter().unwrap();
+        match self.hostapi_model.get_value(&iter, 0).get::<u32>()
+            .unwrap().unwrap() {
+                PULSE_BACKEND_ROW => AudioBackend::Pulse,
+                NULL_BACKEND_ROW => AudioBackend::Null,
+                FILE_BACKEND_ROW => AudioBackend::File,
+                _ => AudioBackend::PortAudio,
+            }
+    }
     fn get_selected_dev(&mut self) -> Option<u32> {
         let iter = self.audiodev_view.get_active_iter().unwrap();
         let ret = self.audiodev_model.get_value(&iter, 0).get::<u32>()
@@ -311,6 +608,20 @@ impl Controller {
         else { Some(ret) }
     }
     fn populate_audiodev(&mut self) {
+        if self.get_selected_backend() != AudioBackend::PortAudio {
+            // Only PortAudio lets us pick a device. The others use the
+            // default device, or no device at all.
+            self.audiodev_model.clear();
+            let new_row = self.audiodev_model.append();
+            self.audiodev_model.set_value(&new_row, 0, &u32::MAX.to_value());
+            self.audiodev_model.set_value(&new_row, 1,
+                                          &"Default Device".to_value());
+            self.audiodev_view.set_model(Some(&self.audiodev_model));
+            self.audiodev_view.set_active_iter(Some(&new_row));
+            self.audiodev_view.set_sensitive(false);
+            return
+        }
+        self.audiodev_view.set_sensitive(true);
         let selected_api_index = self.get_selected_api();
         let selected_api_info = self.pa.host_api_info(selected_api_index)
             .unwrap();
@@ -374,22 +685,75 @@ impl Controller {
              0.1, 0.1, 0.1);
         self.decode_ahead_slider.set_adjustment(&decode_adjustment);
         self.decode_ahead_slider.set_fill_level(desired_latency * 3.0);
+        self.transport_fade_slider
+            .set_value(prefs::get_transport_fade_length());
         None
     }
+    fn populate_channels(&mut self) {
+        self.channel_layout_view.set_active_id
+            (Some(prefs::get_channel_layout().get_name()));
+        self.balance_slider.set_value(prefs::get_balance());
+        self.swap_channels_box.set_active(prefs::get_swap_channels());
+    }
+    fn populate_eq(&mut self) {
+        self.eq_enabled_box.set_active(prefs::get_eq_enabled());
+        self.eq_preset_view.remove_all();
+        let chosen = prefs::get_chosen_eq_preset();
+        for name in prefs::get_eq_preset_names().iter() {
+            self.eq_preset_view.append(Some(name), name);
+        }
+        if !self.eq_preset_view.set_active_id(Some(&chosen)) {
+            self.eq_preset_view.set_active_id(Some(dsp::FLAT_PRESET));
+        }
+        self.changed_eq_preset();
+    }
+    fn changed_eq_preset(&mut self) {
+        let preset = self.eq_preset_view.get_active_id()
+            .and_then(|x| prefs::get_eq_preset(&x))
+            .unwrap_or_default();
+        for (slider, &gain) in self.eq_sliders.iter()
+        .zip(preset.graphic.iter()) {
+            slider.set_value(gain);
+        }
+    }
+    fn populate_underrun_stats(&mut self) {
+        let stats = playback::get_underrun_stats();
+        // TODO: i18n
+        let text = if stats.count == 0 {
+            "No dropouts this session.".to_owned()
+        }
+        else {
+            format!("{} dropout{} this session, {:.2} seconds of silence in \
+                     all. Decoding {:.1} extra seconds ahead, with {:.2} \
+                     extra seconds of latency.",
+                    stats.count, if stats.count == 1 { "" } else { "s" },
+                    stats.total_silence, stats.extra_decode_ahead,
+                    stats.extra_latency)
+        };
+        self.underrun_label.set_text(&text);
+    }
     fn populate_locations(&mut self) {
         let src = prefs::get_music_paths();
         self.locations_model.clear();
         for path in src.iter() {
-            self.locations_model.insert_with_values(None, &[0], &[&path]);
+            let patterns = prefs::get_scan_patterns(path);
+            self.locations_model.insert_with_values
+                (None, &[0, 1, 2], &[&path, &patterns.include.join(", "),
+                                     &patterns.exclude.join(", ")]);
         }
         self.locations_view.set_model(Some(&self.locations_model));
+        self.scan_exclude_entry.set_text(&prefs::get_scan_exclude()
+                                         .join(", "));
     }
     fn clicked_apply(&mut self) -> Option<()> {
+        let backend = self.get_selected_backend();
         let api_index = self.get_selected_api();
         let dev_index = self.get_selected_dev();
-        let api_info = self.pa.host_api_info(api_index)
-            .unwrap();
-        let dev = dev_index.map(|dev_index| {
+        // (only meaningful if the backend is PortAudio)
+        let api_info = if backend == AudioBackend::PortAudio {
+            self.pa.host_api_info(api_index)
+        } else { None };
+        let dev = dev_index.filter(|_| api_info.is_some()).map(|dev_index| {
             let global_dev_index = self.pa.api_device_index_to_device_index
                 (api_index, dev_index as i32).unwrap();
             let dev_info = self.pa.device_info(global_dev_index).unwrap();
@@ -398,20 +762,40 @@ impl Controller {
         let mut dirs = Vec::new();
         self.locations_model.foreach(|model, _path, iter| {
             let value = model.get_value(&iter, 0);
-            match value.get() {
-                Ok(Some(x)) => dirs.push(x),
+            match value.get::<String>() {
+                Ok(Some(x)) => {
+                    let split = |column| -> Vec<String> {
+                        model.get_value(&iter, column).get::<String>().ok()
+                            .flatten().unwrap_or_default().split(',')
+                            .map(str::trim).filter(|x| !x.is_empty())
+                            .map(str::to_owned).collect()
+                    };
+                    prefs::set_scan_patterns(&x, prefs::ScanPatterns {
+                        include: split(1),
+                        exclude: split(2),
+                    });
+                    dirs.push(x)
+                },
                 _ => (),
             }
             false
         });
         prefs::set_music_paths(dirs);
+        prefs::set_scan_exclude(self.scan_exclude_entry.get_text().split(',')
+                                .map(str::trim).filter(|x| !x.is_empty())
+                                .map(str::to_owned).collect());
         // (we wrote this or-chain this way because we don't want a short
         // circuiting OR)
         let mut needs_restart = false;
         needs_restart =
-            prefs::set_chosen_audio_api_and_device
-            (&self.pa, api_index, api_info.name, dev)
+            prefs::set_audio_backend(backend)
             || needs_restart;
+        if let Some(api_info) = api_info {
+            needs_restart =
+                prefs::set_chosen_audio_api_and_device
+                (&self.pa, api_index, api_info.name, dev)
+                || needs_restart;
+        }
         needs_restart =
             prefs::set_desired_latency
             (self.desired_latency_slider.get_value())
@@ -426,12 +810,91 @@ impl Controller {
         needs_restart =
             prefs::set_resample_audio(self.resample_audio_box.get_active())
             || needs_restart;
+        needs_restart =
+            prefs::set_transport_fades(self.transport_fades_box.get_active())
+            || needs_restart;
+        needs_restart =
+            prefs::set_transport_fade_length
+            (self.transport_fade_slider.get_value())
+            || needs_restart;
+        let channel_layout = self.channel_layout_view.get_active_id()
+            .and_then(|id| CHANNEL_LAYOUTS.iter()
+                      .find(|(layout, _)| layout.get_name() == id.as_str()))
+            .map(|&(layout, _)| layout)
+            .unwrap_or_default();
+        needs_restart =
+            prefs::set_channel_layout(channel_layout)
+            || needs_restart;
+        needs_restart =
+            prefs::set_balance(self.balance_slider.get_value())
+            || needs_restart;
+        needs_restart =
+            prefs::set_swap_channels(self.swap_channels_box.get_active())
+            || needs_restart;
+        needs_restart =
+            prefs::set_eq_enabled(self.eq_enabled_box.get_active())
+            || needs_restart;
+        let preset_name = self.eq_preset_view.get_active_id()
+            .map(|x| x.to_string())
+            .unwrap_or_else(|| dsp::FLAT_PRESET.to_owned());
+        let mut preset = prefs::get_eq_preset(&preset_name)
+            .unwrap_or_default();
+        let mut graphic = [0.0; dsp::GRAPHIC_EQ_BANDS];
+        for (gain, slider) in graphic.iter_mut().zip(self.eq_sliders.iter()) {
+            *gain = slider.get_value();
+        }
+        let preset_name = if graphic != preset.graphic {
+            preset.graphic = graphic;
+            needs_restart =
+                prefs::set_eq_preset(CUSTOM_EQ_PRESET, preset)
+                || needs_restart;
+            CUSTOM_EQ_PRESET.to_owned()
+        } else { preset_name };
+        needs_restart =
+            prefs::set_chosen_eq_preset(&preset_name)
+            || needs_restart;
+        needs_restart =
+            prefs::set_fall_back_to_default_device
+            (self.fall_back_box.get_active())
+            || needs_restart;
+        needs_restart =
+            prefs::set_follow_symlinks(self.follow_symlinks_box.get_active())
+            || needs_restart;
+        needs_restart =
+            prefs::set_play_video_files(self.play_video_files_box.get_active())
+            || needs_restart;
+        // (in case we just made or changed the custom preset)
+        self.populate_eq();
         if needs_restart {
-            if playback::get_playback_status() == PlaybackStatus::Playing {
-                // force playback to be restarted
-                playback::send_command(PlaybackCommand::Pause);
-                playback::send_command(PlaybackCommand::Play(None));
-            }
+            // reopen the stream (if any) without interrupting playback
+            playback::send_command(PlaybackCommand::ReopenOutput);
+        }
+        let broadcast_format = self.broadcast_format_view.get_active_id()
+            .and_then(|id| BROADCAST_FORMATS.iter()
+                      .find(|(format, _)| format.get_name() == id.as_str()))
+            .map(|&(format, _)| format)
+            .unwrap_or_default();
+        let mut broadcast_changed = false;
+        broadcast_changed =
+            prefs::set_broadcast(self.broadcast_box.get_active())
+            || broadcast_changed;
+        broadcast_changed =
+            prefs::set_broadcast_port
+            (self.broadcast_port_spin.get_value_as_int() as u16)
+            || broadcast_changed;
+        broadcast_changed =
+            prefs::set_broadcast_format(broadcast_format)
+            || broadcast_changed;
+        broadcast_changed =
+            prefs::set_broadcast_all_interfaces
+            (self.broadcast_all_interfaces_box.get_active())
+            || broadcast_changed;
+        broadcast_changed =
+            prefs::set_broadcast_max_listeners
+            (self.broadcast_max_listeners_spin.get_value_as_int() as u32)
+            || broadcast_changed;
+        if broadcast_changed {
+            broadcast::restart();
         }
         let parent = self.parent.upgrade()?;
         let mut parent = parent.try_borrow_mut().ok()?;
@@ -482,7 +945,8 @@ impl Controller {
                 return None
             },
         };
-        self.locations_model.insert_with_values(None, &[0], &[&path]);
+        self.locations_model.insert_with_values(None, &[0, 1, 2],
+                                                &[&path, &"", &""]);
         None
     }
     fn cleanup(&mut self) -> Option<()> {
@@ -498,9 +962,34 @@ impl Controller {
             self.populate_hostapi();
             self.populate_locations();
             self.populate_sliders();
+            self.populate_underrun_stats();
+            self.populate_channels();
+            self.populate_eq();
+            let transport_fades = prefs::get_transport_fades();
+            self.transport_fades_box.set_active(transport_fades);
+            self.transport_fade_slider.set_sensitive(transport_fades);
             self.show_decibels_box.set_active
                 (prefs::get_show_decibels_on_volume_slider());
             self.resample_audio_box.set_active(prefs::get_resample_audio());
+            self.fall_back_box.set_active
+                (prefs::get_fall_back_to_default_device());
+            self.follow_symlinks_box.set_active(prefs::get_follow_symlinks());
+            self.play_video_files_box.set_active
+                (prefs::get_play_video_files());
+            let broadcast = prefs::get_broadcast();
+            self.broadcast_box.set_active(broadcast);
+            self.broadcast_port_spin.set_value
+                (prefs::get_broadcast_port() as f64);
+            self.broadcast_port_spin.set_sensitive(broadcast);
+            self.broadcast_format_view.set_active_id
+                (Some(prefs::get_broadcast_format().get_name()));
+            self.broadcast_format_view.set_sensitive(broadcast);
+            self.broadcast_all_interfaces_box.set_active
+                (prefs::get_broadcast_all_interfaces());
+            self.broadcast_all_interfaces_box.set_sensitive(broadcast);
+            self.broadcast_max_listeners_spin.set_value
+                (prefs::get_broadcast_max_listeners() as f64);
+            self.broadcast_max_listeners_spin.set_sensitive(broadcast);
             self.window.show_all();
         }
         else {
@@ -0,0 +1,528 @@
+use crate::*;
+use gtk::{
+    prelude::*,
+    BoxBuilder,
+    ButtonBoxBuilder, ButtonBoxStyle,
+    Button, ButtonBuilder,
+    ButtonsType,
+    CellRendererText,
+    CellRendererToggle,
+    ComboBoxText,
+    DialogFlags,
+    Entry,
+    FileChooserAction,
+    FileChooserButton,
+    FileChooserDialog,
+    LabelBuilder,
+    ListStore,
+    MessageDialog,
+    MessageType,
+    Orientation,
+    PolicyType,
+    ProgressBar,
+    ResponseType,
+    ScrolledWindowBuilder,
+    SeparatorBuilder,
+    SpinButton,
+    TreePath,
+    TreeView, TreeViewBuilder, TreeViewColumn,
+    Window, WindowBuilder, WindowType,
+};
+use glib::{
+    Type
+};
+use std::{
+    cell::RefCell,
+    rc::{Rc,Weak},
+    time::{SystemTime, UNIX_EPOCH},
+};
+use sync::{SyncFormat, SyncTarget};
+
+const TARGET_ID_COLUMN: u32 = 0;
+const TARGET_NAME_COLUMN: u32 = 1;
+const TARGET_FOLDER_COLUMN: u32 = 2;
+const TARGET_SYNCED_COLUMN: u32 = 3;
+
+const PLAYLIST_ID_COLUMN: u32 = 0;
+const PLAYLIST_CHOSEN_COLUMN: u32 = 1;
+const PLAYLIST_NAME_COLUMN: u32 = 2;
+
+pub struct Controller {
+    parent: Weak<RefCell<super::Controller>>,
+    window: Window,
+    targets_view: TreeView,
+    targets_model: ListStore,
+    name_entry: Entry,
+    add_button: Button,
+    sync_button: Button,
+    remove_button: Button,
+    progress_box: gtk::Box,
+    progress_bar: ProgressBar,
+    cancel_button: Button,
+    form_box: gtk::Box,
+    folder_button: FileChooserButton,
+    template_entry: Entry,
+    format_view: ComboBoxText,
+    bit_rate_box: gtk::Box,
+    bit_rate_spin: SpinButton,
+    playlists_view: TreeView,
+    playlists_model: ListStore,
+}
+
+impl Controller {
+    pub fn new(parent: Weak<RefCell<super::Controller>>)
+    -> Rc<RefCell<Controller>> {
+        // TODO: i18n
+        let window = WindowBuilder::new()
+            .name("sync").type_(WindowType::Toplevel)
+            .title("Tsong - Sync").default_height(560).build();
+        let big_box = BoxBuilder::new()
+            .name("sync").spacing(4).orientation(Orientation::Vertical)
+            .build();
+        window.add(&big_box);
+        let targets_window = ScrolledWindowBuilder::new()
+            .hscrollbar_policy(PolicyType::Automatic)
+            .vscrollbar_policy(PolicyType::Automatic)
+            .vexpand(true).min_content_height(100)
+            .build();
+        let targets_view = TreeViewBuilder::new()
+            .headers_visible(true).build();
+        for &(title, column) in &[("Player", TARGET_NAME_COLUMN),
+                                  ("Folder", TARGET_FOLDER_COLUMN),
+                                  ("Synced", TARGET_SYNCED_COLUMN)] {
+            let view_column = TreeViewColumn::new();
+            view_column.set_title(title);
+            view_column.set_expand(column == TARGET_FOLDER_COLUMN);
+            let cell = CellRendererText::new();
+            view_column.pack_start(&cell, true);
+            view_column.add_attribute(&cell, "text", column as i32);
+            targets_view.append_column(&view_column);
+        }
+        let targets_model = ListStore::new(&[Type::U64, Type::String,
+                                             Type::String, Type::String]);
+        targets_window.add(&targets_view);
+        big_box.add(&targets_window);
+        let add_box = BoxBuilder::new()
+            .spacing(4).orientation(Orientation::Horizontal).build();
+        let name_entry = Entry::new();
+        name_entry.set_placeholder_text(Some("My Phone"));
+        name_entry.set_hexpand(true);
+        add_box.add(&name_entry);
+        let add_button = ButtonBuilder::new()
+            .tooltip_text("Choose a folder (on a player, or anywhere else) \
+                           to sync playlists to.")
+            .label("Add…").build();
+        add_box.add(&add_button);
+        big_box.add(&add_box);
+        let list_button_box = ButtonBoxBuilder::new()
+            .layout_style(ButtonBoxStyle::Expand)
+            .build();
+        let sync_button = ButtonBuilder::new()
+            .tooltip_text("Bring the folder up to date with the chosen \
+                           playlists.")
+            .label("Sync Now").build();
+        list_button_box.add(&sync_button);
+        let remove_button = ButtonBuilder::new()
+            .tooltip_text("Stop syncing to this folder. Whatever is already \
+                           in it stays there.")
+            .label("Remove").build();
+        list_button_box.add(&remove_button);
+        big_box.add(&list_button_box);
+        let progress_box = BoxBuilder::new()
+            .spacing(4).orientation(Orientation::Horizontal).build();
+        let progress_bar = ProgressBar::new();
+        progress_bar.set_show_text(true);
+        progress_bar.set_hexpand(true);
+        progress_box.add(&progress_bar);
+        let cancel_button = ButtonBuilder::new()
+            .tooltip_text("Stop syncing. Songs that were already copied stay, \
+                           and won't be copied again next time.")
+            .label("Cancel").build();
+        progress_box.add(&cancel_button);
+        big_box.add(&progress_box);
+        big_box.add(&SeparatorBuilder::new()
+                    .orientation(Orientation::Horizontal).build());
+        let form_box = BoxBuilder::new()
+            .spacing(4).orientation(Orientation::Vertical).build();
+        big_box.add(&form_box);
+        let folder_box = BoxBuilder::new()
+            .spacing(4).orientation(Orientation::Horizontal).build();
+        folder_box.add(&LabelBuilder::new().label("Folder:").build());
+        let folder_button = FileChooserButton::new("Choose Sync Folder",
+                                                   FileChooserAction
+                                                   ::SelectFolder);
+        folder_button.set_hexpand(true);
+        folder_box.add(&folder_button);
+        form_box.add(&folder_box);
+        let template_box = BoxBuilder::new()
+            .spacing(4).orientation(Orientation::Horizontal)
+            .tooltip_text("Where each song goes in the folder. {key} is \
+                           replaced with that metadata, and {key1|key2} \
+                           uses key2 if there's no key1. Use / to make \
+                           folders.")
+            .build();
+        template_box.add(&LabelBuilder::new().label("Path:").build());
+        let template_entry = Entry::new();
+        template_entry.set_placeholder_text(Some(sync::DEFAULT_PATH_TEMPLATE));
+        template_entry.set_hexpand(true);
+        template_box.add(&template_entry);
+        form_box.add(&template_box);
+        let format_box = BoxBuilder::new()
+            .spacing(4).orientation(Orientation::Horizontal)
+            .tooltip_text("Songs in other formats, or at higher bit rates, \
+                           are converted. Songs that are only part of a \
+                           file are always converted, to FLAC if the format \
+                           is \"Original format\".")
+            .build();
+        format_box.add(&LabelBuilder::new().label("Format:").build());
+        let format_view = ComboBoxText::new();
+        for format in SyncFormat::ALL.iter() {
+            format_view.append(Some(&format.to_db_value().to_string()),
+                               format.get_name());
+        }
+        format_box.add(&format_view);
+        let bit_rate_box = BoxBuilder::new()
+            .spacing(4).orientation(Orientation::Horizontal).build();
+        bit_rate_box.add(&LabelBuilder::new().label("at").build());
+        let bit_rate_spin = SpinButton::with_range(32.0, 512.0, 16.0);
+        bit_rate_box.add(&bit_rate_spin);
+        bit_rate_box.add(&LabelBuilder::new().label("kbit/s").build());
+        format_box.add(&bit_rate_box);
+        form_box.add(&format_box);
+        let playlists_window = ScrolledWindowBuilder::new()
+            .hscrollbar_policy(PolicyType::Automatic)
+            .vscrollbar_policy(PolicyType::Automatic)
+            .vexpand(true).min_content_height(150)
+            .build();
+        let playlists_view = TreeViewBuilder::new()
+            .headers_visible(true).build();
+        let chosen_column = TreeViewColumn::new();
+        chosen_column.set_title("Sync");
+        let chosen_cell = CellRendererToggle::new();
+        chosen_column.pack_start(&chosen_cell, false);
+        chosen_column.add_attribute(&chosen_cell, "active",
+                                    PLAYLIST_CHOSEN_COLUMN as i32);
+        playlists_view.append_column(&chosen_column);
+        let name_column = TreeViewColumn::new();
+        name_column.set_title("Playlist");
+        name_column.set_expand(true);
+        let name_cell = CellRendererText::new();
+        name_column.pack_start(&name_cell, true);
+        name_column.add_attribute(&name_cell, "text",
+                                  PLAYLIST_NAME_COLUMN as i32);
+        playlists_view.append_column(&name_column);
+        let playlists_model = ListStore::new(&[Type::U64, Type::Bool,
+                                               Type::String]);
+        playlists_window.add(&playlists_view);
+        form_box.add(&playlists_window);
+        let ret = Rc::new(RefCell::new(Controller {
+            parent, window, targets_view, targets_model, name_entry,
+            add_button, sync_button, remove_button, progress_box,
+            progress_bar, cancel_button, form_box, folder_button,
+            template_entry, format_view, bit_rate_box, bit_rate_spin,
+            playlists_view, playlists_model,
+        }));
+        let this = ret.borrow();
+        this.window.connect_delete_event(move |window, _| {
+            window.hide_on_delete()
+        });
+        let controller = ret.clone();
+        this.add_button.connect_clicked(move |_| {
+            let _ = controller.try_borrow_mut()
+                .map(|mut x| x.clicked_add());
+        });
+        let controller = ret.clone();
+        this.name_entry.connect_activate(move |_| {
+            let _ = controller.try_borrow_mut()
+                .map(|mut x| x.clicked_add());
+        });
+        let controller = ret.clone();
+        this.sync_button.connect_clicked(move |_| {
+            let _ = controller.try_borrow_mut()
+                .map(|mut x| x.clicked_sync());
+        });
+        let controller = ret.clone();
+        this.remove_button.connect_clicked(move |_| {
+            let _ = controller.try_borrow_mut()
+                .map(|mut x| x.clicked_remove());
+        });
+        this.cancel_button.connect_clicked(|_| sync::cancel_sync());
+        let controller = ret.clone();
+        this.targets_view.connect_cursor_changed(move |_| {
+            let _ = controller.try_borrow_mut()
+                .map(|mut x| x.changed_selection());
+        });
+        let controller = ret.clone();
+        this.folder_button.connect_file_set(move |_| {
+            let _ = controller.try_borrow_mut()
+                .map(|mut x| x.changed_settings());
+        });
+        let controller = ret.clone();
+        this.template_entry.connect_changed(move |_| {
+            let _ = controller.try_borrow_mut()
+                .map(|mut x| x.changed_settings());
+        });
+        let controller = ret.clone();
+        this.format_view.connect_changed(move |_| {
+            let _ = controller.try_borrow_mut()
+                .map(|mut x| x.changed_settings());
+        });
+        let controller = ret.clone();
+        this.bit_rate_spin.connect_value_changed(move |_| {
+            let _ = controller.try_borrow_mut()
+                .map(|mut x| x.changed_settings());
+        });
+        let controller = ret.clone();
+        chosen_cell.connect_toggled(move |_, path| {
+            let _ = controller.try_borrow_mut()
+                .map(|mut x| x.toggled_playlist(&path));
+        });
+        drop(this);
+        ret
+    }
+    /// Returns the target whose row is selected, if any.
+    fn get_selected_target(&self) -> Option<SyncTarget> {
+        let path = self.targets_view.get_cursor().0?;
+        let iter = self.targets_model.get_iter(&path)?;
+        let id = self.targets_model.get_value(&iter, TARGET_ID_COLUMN as i32)
+            .get::<u64>().ok()??;
+        sync::get_targets().into_iter().find(|x| x.id == id)
+    }
+    /// Selects the row for the target with the given id.
+    fn select_target(&self, id: u64) {
+        let view = &self.targets_view;
+        self.targets_model.foreach(|model, path, iter| {
+            let found = model.get_value(iter, TARGET_ID_COLUMN as i32)
+                .get::<u64>().ok().flatten();
+            if found == Some(id) {
+                view.set_cursor(path, None::<&TreeViewColumn>, false);
+                true
+            }
+            else { false }
+        });
+    }
+    fn populate_targets(&mut self) {
+        let selected = self.get_selected_target().map(|x| x.id);
+        self.targets_model.clear();
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)
+            .map(|x| x.as_secs() as i64).unwrap_or(0);
+        for target in sync::get_targets().iter() {
+            let folder = target.directory.to_string_lossy();
+            let synced = match target.last_synced {
+                None => "Never".to_owned(),
+                Some(then) => super::podcasts::pretty_ago(now - then),
+            };
+            self.targets_model.insert_with_values
+                (None, &[TARGET_ID_COLUMN, TARGET_NAME_COLUMN,
+                         TARGET_FOLDER_COLUMN, TARGET_SYNCED_COLUMN],
+                 &[&target.id, &target.name, &folder, &synced]);
+        }
+        self.targets_view.set_model(Some(&self.targets_model));
+        if let Some(id) = selected {
+            self.select_target(id);
+        }
+    }
+    fn populate_playlists(&mut self, target: Option<&SyncTarget>) {
+        fn add_playlists(model: &ListStore, playlists: &[PlaylistRef],
+                         chosen: &[PlaylistID], depth: usize) {
+            for playlist_ref in playlists.iter() {
+                let playlist = playlist_ref.read().unwrap();
+                let label = format!("{}{}", "    ".repeat(depth),
+                                    playlist.get_name());
+                let id = playlist.get_id();
+                model.insert_with_values
+                    (None, &[PLAYLIST_ID_COLUMN, PLAYLIST_CHOSEN_COLUMN,
+                             PLAYLIST_NAME_COLUMN],
+                     &[&id.as_inner(), &chosen.contains(&id), &label]);
+                add_playlists(model, playlist.get_children(), chosen,
+                              depth + 1);
+            }
+        }
+        self.playlists_model.clear();
+        if let Some(target) = target {
+            let top_level = playlist::get_top_level_playlists().clone();
+            add_playlists(&self.playlists_model, &top_level[..],
+                          &target.playlist_ids[..], 0);
+        }
+        self.playlists_view.set_model(Some(&self.playlists_model));
+    }
+    /// Shows or hides the progress of the sync in progress, and makes the
+    /// buttons that start and stop syncs sensitive or not.
+    fn update_buttons(&mut self) {
+        let target = self.get_selected_target();
+        match sync::get_progress() {
+            Some(progress) => {
+                let name = sync::get_targets().into_iter()
+                    .find(|x| x.id == progress.target_id)
+                    .map(|x| x.name).unwrap_or_else(String::new);
+                self.progress_bar.set_fraction
+                    (progress.songs_done as f64
+                     / progress.songs_total.max(1) as f64);
+                self.progress_bar.set_text
+                    (Some(&format!("Syncing {}: {} of {} songs", name,
+                                   progress.songs_done,
+                                   progress.songs_total)));
+                self.progress_box.set_visible(true);
+                self.sync_button.set_sensitive(false);
+            },
+            None => {
+                self.progress_box.set_visible(false);
+                self.sync_button.set_sensitive(target.is_some());
+            },
+        }
+        self.remove_button.set_sensitive(target.is_some());
+    }
+    /// Fills in the form with the selected target's settings and playlists,
+    /// or makes it insensitive if none is selected.
+    fn changed_selection(&mut self) {
+        let target = self.get_selected_target();
+        self.update_buttons();
+        self.form_box.set_sensitive(target.is_some());
+        self.populate_playlists(target.as_ref());
+        let target = match target {
+            Some(x) => x,
+            None => return,
+        };
+        self.folder_button.set_filename(&target.directory);
+        self.template_entry.set_text(&target.path_template);
+        self.format_view.set_active_id(Some(&target.format.to_db_value()
+                                            .to_string()));
+        self.bit_rate_spin.set_value(target.bit_rate as f64);
+        self.bit_rate_box.set_sensitive(target.format.has_bit_rate());
+    }
+    fn changed_settings(&mut self) -> Option<()> {
+        let mut target = self.get_selected_target()?;
+        if let Some(directory) = self.folder_button.get_filename() {
+            target.directory = directory;
+        }
+        let template = self.template_entry.get_text();
+        target.path_template = if template.trim().is_empty() {
+            sync::DEFAULT_PATH_TEMPLATE.to_owned()
+        } else { template.to_string() };
+        if let Some(format) = self.format_view.get_active_id()
+            .and_then(|x| x.parse().ok()) {
+                target.format = SyncFormat::from_db_value(format);
+            }
+        target.bit_rate = self.bit_rate_spin.get_value_as_int().max(1)
+            as u32;
+        self.bit_rate_box.set_sensitive(target.format.has_bit_rate());
+        sync::update_target(&target);
+        self.populate_targets();
+        None
+    }
+    fn toggled_playlist(&mut self, path: &TreePath) -> Option<()> {
+        let mut target = self.get_selected_target()?;
+        let iter = self.playlists_model.get_iter(path)?;
+        let id = self.playlists_model.get_value(&iter,
+                                                PLAYLIST_ID_COLUMN as i32)
+            .get::<u64>().ok()??;
+        let chosen = self.playlists_model.get_value(&iter,
+                                                    PLAYLIST_CHOSEN_COLUMN
+                                                    as i32)
+            .get::<bool>().ok()??;
+        let id = PlaylistID::from_inner(id);
+        if chosen {
+            target.playlist_ids.retain(|x| *x != id);
+        }
+        else if !target.playlist_ids.contains(&id) {
+            target.playlist_ids.push(id);
+        }
+        sync::update_target(&target);
+        self.playlists_model.set_value(&iter, PLAYLIST_CHOSEN_COLUMN,
+                                       &(!chosen).to_value());
+        None
+    }
+    fn clicked_add(&mut self) -> Option<()> {
+        let dialog = FileChooserDialog::with_buttons
+            (Some("Choose Sync Folder"), Some(&self.window),
+             FileChooserAction::SelectFolder,
+             &[("_Cancel", ResponseType::Cancel),
+               ("_Open", ResponseType::Accept)]);
+        let response = dialog.run();
+        dialog.close();
+        if response != ResponseType::Accept { return None }
+        let directory = dialog.get_filename()?;
+        let name = self.name_entry.get_text().trim().to_owned();
+        let name = if !name.is_empty() { name }
+        else {
+            directory.file_name()
+                .map(|x| x.to_string_lossy().into_owned())
+                .unwrap_or_else(|| directory.to_string_lossy().into_owned())
+        };
+        match sync::create_target(&name, directory) {
+            Ok(target) => {
+                self.name_entry.set_text("");
+                self.populate_targets();
+                self.select_target(target.id);
+                self.changed_selection();
+            },
+            Err(x) => self.show_error(&format!("Couldn't add the folder: {}",
+                                               x)),
+        }
+        None
+    }
+    fn clicked_sync(&mut self) -> Option<()> {
+        let target = self.get_selected_target()?;
+        match sync::start_sync(target.id) {
+            Ok(_) => {
+                self.update_buttons();
+                let parent = self.parent.upgrade()?;
+                parent.try_borrow().ok()?.force_spinner_start();
+            },
+            Err(x) => self.show_error(&format!("Couldn't sync: {}", x)),
+        }
+        None
+    }
+    fn clicked_remove(&mut self) -> Option<()> {
+        let target = self.get_selected_target()?;
+        let confirm = MessageDialog::new(Some(&self.window),
+                                         DialogFlags::MODAL,
+                                         MessageType::Question,
+                                         ButtonsType::OkCancel,
+                                         &format!("Stop syncing to {}? \
+                                                   Whatever is already in \
+                                                   {} will stay there.",
+                                                  target.name,
+                                                  target.directory
+                                                  .to_string_lossy()));
+        let result = confirm.run();
+        confirm.close();
+        if result != ResponseType::Ok { return None }
+        sync::delete_target(target.id);
+        self.populate_targets();
+        self.changed_selection();
+        None
+    }
+    fn show_error(&self, message: &str) {
+        let error = MessageDialog::new(Some(&self.window),
+                                       DialogFlags::MODAL,
+                                       MessageType::Error,
+                                       ButtonsType::Ok,
+                                       message);
+        error.run();
+        error.close();
+    }
+    /// Called periodically, to keep the progress of a sync up to date.
+    pub fn update_progress(&mut self) {
+        if self.window.is_visible() {
+            self.update_buttons();
+        }
+    }
+    /// Called when a sync has finished.
+    pub fn sync_finished(&mut self) {
+        if self.window.is_visible() {
+            self.populate_targets();
+            self.update_buttons();
+        }
+    }
+    pub fn show(&mut self) {
+        if !self.window.is_visible() {
+            self.populate_targets();
+            self.window.show_all();
+            self.changed_selection();
+        }
+        else {
+            self.window.present();
+        }
+    }
+}
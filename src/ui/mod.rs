@@ -2,5 +2,6 @@ mod gtk;
 
 pub fn go() -> ! {
     gtk::go();
+    crate::playback::save_state();
     std::process::exit(0)
 }
@@ -0,0 +1,307 @@
+//! This module writes the user's metadata back into the files it came from.
+//!
+//! Tsong never changes the files in the library on its own. This only happens
+//! when the user asks for it, for particular songs, after being shown exactly
+//! what will change (see `plan` and `Plan::describe`). Each file is rewritten
+//! into a temporary copy next to it, with the new tags on top of the tags it
+//! already had, and the copy then takes the original's place. Since the file's
+//! contents change, so does its `FileID`; the physical file and the songs
+//! that use it are updated to match, so the songs keep their identity.
+//!
+//! Only files that are one whole song can be written to. The tags of a file
+//! that's divided into tracks belong to all of its songs at once.
+
+use crate::*;
+
+use anyhow::anyhow;
+use log::{error, info};
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    fs::{self, File},
+    path::{Path, PathBuf},
+};
+
+/// Metadata that only means something to Tsong, and never gets written into
+/// a file. (Neither does anything starting with `raw_`, nor the number keys
+/// that `make_file_tags` combines.)
+const INTERNAL_KEYS: &[&str] = &[
+    "duration", "song_id", "start_time", "end_time", "eq_preset",
+    "podcast_id", "podcast_guid", "played", "remember_position",
+    "loop_start", "loop_end", "metadata_load_error", "unchecked", "encoder",
+];
+
+/// If a rewritten file's duration is off by more than this many seconds (or
+/// by more than `DURATION_LEEWAY_RATIO` of the original), something went
+/// wrong, and it doesn't replace the original.
+const DURATION_LEEWAY: u32 = 2;
+/// See `DURATION_LEEWAY`. (Durations of some files without a proper header
+/// are only estimates, and the new header may be more accurate.)
+const DURATION_LEEWAY_RATIO: f64 = 0.1;
+
+/// Which errors are ours, for the `errors` module.
+const ERRORS_FROM: &str = "Write-back";
+
+/// Turns a song's metadata into the tags that should be written into a file.
+///
+/// Keys are FFMPEG's generic tag names, which its muxers turn into ID3v2
+/// frames, Vorbis comments, or MP4 atoms as appropriate. Tsong's `track#` and
+/// `#tracks` become `track` (e.g. `3/12`), `disc#` and `#discs` become `disc`,
+/// and `year` becomes `date` if there isn't already a `date`.
+pub fn make_file_tags(metadata: &BTreeMap<String, String>)
+-> BTreeMap<String, String> {
+    let mut ret: BTreeMap<String, String> = metadata.iter()
+        .filter(|(k, v)| !v.is_empty() && !k.starts_with("raw_")
+                && !k.starts_with('#') && !k.ends_with('#')
+                && !INTERNAL_KEYS.contains(&k.as_str()))
+        .map(|(k, v)| (k.clone(), v.clone()))
+        .collect();
+    for &(tag, index_key, count_key) in &[("track", "track#", "#tracks"),
+                                         ("disc", "disc#", "#discs")] {
+        if let Some(index) = metadata.get(index_key) {
+            let value = match metadata.get(count_key) {
+                Some(count) => format!("{}/{}", index, count),
+                None => index.clone(),
+            };
+            ret.insert(tag.to_owned(), value);
+        }
+    }
+    if let Some(year) = ret.remove("year") {
+        ret.entry("date".to_owned()).or_insert(year);
+    }
+    ret
+}
+
+/// One tag that writing back will change.
+#[derive(Clone,Debug)]
+pub struct TagChange {
+    pub key: String,
+    /// What the file has now, if anything.
+    pub old: Option<String>,
+    pub new: String,
+}
+
+/// A file that `plan` has decided to write to.
+#[derive(Clone,Debug)]
+pub struct PlannedFile {
+    pub song_id: SongID,
+    pub file_id: FileID,
+    pub path: PathBuf,
+    /// All of the tags to put on top of the file's own.
+    pub tags: BTreeMap<String, String>,
+    /// The ones that are actually different.
+    pub changes: Vec<TagChange>,
+}
+
+/// What writing back the metadata of some songs would do. Made by `plan`.
+#[derive(Clone,Debug,Default)]
+pub struct Plan {
+    /// Files whose tags will change.
+    pub files: Vec<PlannedFile>,
+    /// Files that already have the right tags.
+    pub up_to_date: Vec<PathBuf>,
+    /// Songs (or files of songs) that can't be written to, and why.
+    pub skipped: Vec<String>,
+}
+
+impl Plan {
+    /// Returns true if there's nothing to write.
+    pub fn is_empty(&self) -> bool { self.files.is_empty() }
+    /// Describes everything that would be done, for the user to check.
+    pub fn describe(&self) -> String {
+        let mut ret = String::new();
+        if !self.files.is_empty() {
+            ret.push_str("These files will be changed:\n");
+            for file in self.files.iter() {
+                let _ = write!(ret, "\n{}\n", file.path.display());
+                for change in file.changes.iter() {
+                    let _ = match change.old.as_ref() {
+                        Some(old) => writeln!(ret, "    {}: {:?} → {:?}",
+                                              change.key, old, change.new),
+                        None => writeln!(ret, "    {}: (none) → {:?}",
+                                         change.key, change.new),
+                    };
+                }
+            }
+        }
+        if !self.up_to_date.is_empty() {
+            if !ret.is_empty() { ret.push('\n') }
+            ret.push_str("These files already have the right tags:\n\n");
+            for path in self.up_to_date.iter() {
+                let _ = writeln!(ret, "{}", path.display());
+            }
+        }
+        if !self.skipped.is_empty() {
+            if !ret.is_empty() { ret.push('\n') }
+            ret.push_str("These can't be written to:\n\n");
+            for why in self.skipped.iter() {
+                let _ = writeln!(ret, "{}", why);
+            }
+        }
+        ret
+    }
+}
+
+/// Works out what writing back the metadata of the given songs would do,
+/// without changing anything. This reads every file's current tags, so it
+/// takes a moment; call from a background thread.
+pub fn plan(songs: &[LogicalSongRef]) -> Plan {
+    let mut ret = Plan::default();
+    for song_ref in songs.iter() {
+        let song = song_ref.read().unwrap();
+        if song.is_stream() {
+            ret.skipped.push(format!("{}: it's played from the network, not \
+                                      from a file", song));
+            continue
+        }
+        let tags = make_file_tags(song.get_metadata());
+        for (file_id, &track) in song.get_physical_files().iter()
+            .zip(song.get_physical_tracks().iter()) {
+                match plan_file(song.get_id(), file_id, track, &tags) {
+                    Ok(x) if x.changes.is_empty() =>
+                        ret.up_to_date.push(x.path),
+                    Ok(x) => ret.files.push(x),
+                    Err(x) => ret.skipped.push(format!("{}: {}", song, x)),
+                }
+            }
+    }
+    ret
+}
+
+/// Works out what writing the given tags into one file of a song would do.
+/// Returns why not, if it can't be done.
+fn plan_file(song_id: SongID, file_id: &FileID, track: u32,
+             tags: &BTreeMap<String, String>) -> Result<PlannedFile, String> {
+    if track != 0 {
+        return Err("it's one of several songs in the same file".to_owned())
+    }
+    if physical::needs_track_check(file_id) {
+        return Err("its file hasn't been checked for chapters yet (try \
+                    rescanning)".to_owned())
+    }
+    let file_ref = physical::get_file_by_id(file_id)
+        .ok_or_else(|| "its file is no longer in the library".to_owned())?;
+    let file = file_ref.read().unwrap();
+    if !file.get_tracks().is_empty() {
+        return Err("its file is divided into several songs".to_owned())
+    }
+    let mut paths: Vec<&PathBuf> = file.get_absolute_paths().iter()
+        .filter(|x| x.is_file()).collect();
+    paths.sort();
+    paths.dedup();
+    let path = match &paths[..] {
+        [] => return Err("its file couldn't be found".to_owned()),
+        [x] => (*x).clone(),
+        // (rewriting one copy would make it a different file from the others,
+        // and we can't tell which relative path goes with which copy)
+        _ => return Err("there's more than one copy of its file".to_owned()),
+    };
+    drop(file);
+    if ffmpeg::guess_container(&path).is_none() {
+        return Err(format!("{:?} isn't a kind of file we can write",
+                           path.file_name().unwrap_or_default()))
+    }
+    let current = physical::try_read_metadata(&path)
+        .map_err(|x| format!("couldn't read the tags of {:?}: {:#}",
+                             path, x))?;
+    let changes = tags.iter().filter_map(|(key, new)| {
+        // (FFMPEG ignores case when it looks up tags, and so do we)
+        let old = current.iter().find(|(k, _)| k.eq_ignore_ascii_case(key))
+            .map(|(_, v)| v.clone());
+        if old.as_ref() == Some(new) { None }
+        else { Some(TagChange { key: key.clone(), old, new: new.clone() }) }
+    }).collect();
+    Ok(PlannedFile {
+        song_id, file_id: *file_id, path, tags: tags.clone(), changes,
+    })
+}
+
+/// Writes the tags into every file in the plan that needs them. Call from a
+/// background thread. Errors are logged and reported to the `errors` module.
+/// Returns the songs whose files were changed.
+pub fn apply(plan: &Plan) -> Vec<SongID> {
+    errors::reset_from(ERRORS_FROM);
+    let mut ret = Vec::new();
+    for file in plan.files.iter() {
+        match write_file(file) {
+            Ok(()) => {
+                info!("Wrote tags to {:?}", file.path);
+                ret.push(file.song_id);
+            },
+            Err(x) => {
+                let wat = format!("Couldn't write tags to {:?}: {:#}",
+                                  file.path, x);
+                error!("{}", wat);
+                errors::from(ERRORS_FROM, wat);
+            },
+        }
+    }
+    ret
+}
+
+/// Rewrites one file with its new tags, and updates everything that knew it
+/// by its old ID.
+fn write_file(planned: &PlannedFile) -> anyhow::Result<()> {
+    let path = &planned.path;
+    let old_duration = physical::get_file_by_id(&planned.file_id)
+        .ok_or_else(|| anyhow!("It's no longer in the library"))?
+        .read().unwrap().get_duration();
+    let container = ffmpeg::guess_container(path)
+        .ok_or_else(|| anyhow!("Unknown kind of file"))?;
+    let temp_path = path.with_file_name(
+        format!(".{}.tsong-tmp", path.file_name().unwrap_or_default()
+                .to_string_lossy()));
+    let (new_id, size, duration) = match write_temp(path, &temp_path,
+                                                    &container,
+                                                    &planned.tags,
+                                                    old_duration) {
+        Ok(x) => x,
+        Err(x) => {
+            let _ = fs::remove_file(&temp_path);
+            return Err(x)
+        },
+    };
+    if new_id == planned.file_id {
+        // (the tags must have been different in name only)
+        let _ = fs::remove_file(&temp_path);
+        return Ok(())
+    }
+    // (the temp file only goes over the original once the library has
+    // agreed to take it)
+    let replaced = physical::replace_file(&planned.file_id, new_id, size,
+                                          duration, || {
+        fs::rename(&temp_path, path)?;
+        Ok(())
+    });
+    if let Err(x) = replaced {
+        let _ = fs::remove_file(&temp_path);
+        return Err(x)
+    }
+    logical::replace_physical_file(&planned.file_id, &new_id);
+    podcast::replaced_file(&planned.file_id, &new_id);
+    Ok(())
+}
+
+/// Writes the retagged copy of `path` to `temp_path`, and makes sure it's
+/// still the same song. Returns the copy's ID, size, and duration.
+fn write_temp(path: &Path, temp_path: &Path, container: &str,
+              tags: &BTreeMap<String, String>, old_duration: u32)
+-> anyhow::Result<(FileID, u64, u32)> {
+    ffmpeg::retag(path, temp_path, container, tags)?;
+    let mut avf = ffmpeg::AVFormat::open_input(temp_path)?;
+    avf.find_stream_info()?;
+    let stream = avf.find_best_stream()?
+        .ok_or_else(|| anyhow!("The new file has no audio in it"))?;
+    let duration = avf.estimate_duration(stream);
+    drop(avf);
+    let leeway = DURATION_LEEWAY
+        .max((old_duration as f64 * DURATION_LEEWAY_RATIO) as u32);
+    if (duration as i64 - old_duration as i64).abs() > leeway as i64 {
+        return Err(anyhow!("The new file is {} seconds long, instead of {}",
+                           duration, old_duration))
+    }
+    fs::set_permissions(temp_path, fs::metadata(path)?.permissions())?;
+    let size = fs::metadata(temp_path)?.len();
+    let id = FileID::from_file(File::open(temp_path)?)?;
+    Ok((id, size, duration))
+}